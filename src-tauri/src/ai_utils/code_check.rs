@@ -0,0 +1,267 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+static PYTHON_TRIPLE_QUOTE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?s)""".*?"""|'''.*?'''"#).unwrap());
+static SHELL_EXPANSION_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\$\{[^{}]*\}|\$#").unwrap());
+
+/// 生成代码的语法检查结果
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CodeCheckResult {
+    pub checked: bool,       // 是否对该语言执行了检查
+    pub valid: bool,         // 未发现问题时为 true
+    pub errors: Vec<String>, // 发现的问题描述
+}
+
+impl CodeCheckResult {
+    pub fn unchecked() -> Self {
+        Self {
+            checked: false,
+            valid: true,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn from_errors(errors: Vec<String>) -> Self {
+        Self {
+            checked: true,
+            valid: errors.is_empty(),
+            errors,
+        }
+    }
+}
+
+/// 各语言的注释与字符串语法
+struct LanguageSyntax {
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    char_literals: bool, // 单引号仅用于字符字面量（如 Rust 的 '{'）
+}
+
+fn language_syntax(language: &str) -> Option<LanguageSyntax> {
+    let syntax = match language.to_lowercase().as_str() {
+        "rust" | "rs" => LanguageSyntax {
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            // 单引号在 Rust 中也用于生命周期，不作为字符串检查
+            quotes: &['"'],
+            char_literals: true,
+        },
+        "c" | "cpp" | "c++" | "h" | "hpp" | "java" | "csharp" | "cs" | "go" | "kotlin"
+        | "swift" | "scala" | "dart" => LanguageSyntax {
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\''],
+            char_literals: false,
+        },
+        "javascript" | "js" | "typescript" | "ts" | "jsx" | "tsx" => LanguageSyntax {
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\'', '`'],
+            char_literals: false,
+        },
+        "php" => LanguageSyntax {
+            line_comments: &["//", "#"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\''],
+            char_literals: false,
+        },
+        "python" | "py" | "ruby" | "rb" | "r" | "bash" | "sh" | "shell" | "zsh" | "toml"
+        | "yaml" | "yml" => LanguageSyntax {
+            line_comments: &["#"],
+            block_comment: None,
+            quotes: &['"', '\''],
+            char_literals: false,
+        },
+        "sql" | "lua" => LanguageSyntax {
+            line_comments: &["--"],
+            block_comment: None,
+            quotes: &['"', '\''],
+            char_literals: false,
+        },
+        "json" => LanguageSyntax {
+            line_comments: &[],
+            block_comment: None,
+            quotes: &['"'],
+            char_literals: false,
+        },
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+/// 将 Shell 的 ${...} 参数展开和 $# 替换为普通变量，避免其中的 {、# 和引号被当作括号、注释或字符串
+fn strip_shell_parameter_expansions(code: &str) -> String {
+    let mut code = code.to_string();
+    // 嵌套的展开（如 ${a:-${b}}）由内向外逐层替换
+    while SHELL_EXPANSION_RE.is_match(&code) {
+        code = SHELL_EXPANSION_RE.replace_all(&code, "$$x").to_string();
+    }
+    code
+}
+
+/// 对非 xlang 代码进行尽力而为的括号与引号配对检查，不支持的语言返回未检查结果
+pub fn check_balance(code: &str, language: &str) -> CodeCheckResult {
+    let Some(syntax) = language_syntax(language) else {
+        return CodeCheckResult::unchecked();
+    };
+    let code = match language.to_lowercase().as_str() {
+        // Python 的三引号字符串按普通引号处理会误报，先将其整体移除
+        "python" | "py" => PYTHON_TRIPLE_QUOTE_RE.replace_all(code, "\"\"").to_string(),
+        "bash" | "sh" | "shell" | "zsh" => strip_shell_parameter_expansions(code),
+        _ => code.to_string(),
+    };
+
+    let mut errors = Vec::new();
+    let mut stack: Vec<(char, usize)> = Vec::new();
+    let chars: Vec<char> = code.chars().collect();
+    let mut line = 1;
+    let mut i = 0;
+
+    let starts_with = |i: usize, pattern: &str| {
+        pattern
+            .chars()
+            .enumerate()
+            .all(|(offset, c)| chars.get(i + offset) == Some(&c))
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if let Some(comment) = syntax.line_comments.iter().find(|p| starts_with(i, p)) {
+            i += comment.chars().count();
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        if let Some((open, close)) = syntax.block_comment {
+            if starts_with(i, open) {
+                let start_line = line;
+                i += open.chars().count();
+                while i < chars.len() && !starts_with(i, close) {
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    errors.push(format!("第 {} 行的块注释未闭合", start_line));
+                }
+                i += close.chars().count();
+                continue;
+            }
+        }
+
+        if syntax.char_literals && c == '\'' {
+            if chars.get(i + 2) == Some(&'\'') {
+                i += 3;
+                continue;
+            }
+            if chars.get(i + 1) == Some(&'\\') {
+                if let Some(end) = (i + 2..chars.len().min(i + 12)).find(|&j| chars[j] == '\'') {
+                    i = end + 1;
+                    continue;
+                }
+            }
+        }
+
+        if syntax.quotes.contains(&c) {
+            let start_line = line;
+            i += 1;
+            let mut closed = false;
+            while i < chars.len() {
+                match chars[i] {
+                    '\\' => i += 1,
+                    '\n' if c != '`' => break,
+                    ch if ch == c => {
+                        closed = true;
+                        break;
+                    }
+                    _ => {}
+                }
+                if chars.get(i) == Some(&'\n') {
+                    line += 1;
+                }
+                i += 1;
+            }
+            if closed {
+                i += 1;
+            } else {
+                errors.push(format!("第 {} 行的字符串引号 {} 未闭合", start_line, c));
+            }
+            continue;
+        }
+
+        match c {
+            '\n' => line += 1,
+            '(' | '[' | '{' => stack.push((c, line)),
+            ')' | ']' | '}' => {
+                let expected = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                match stack.pop() {
+                    Some((open, _)) if open == expected => {}
+                    Some((open, open_line)) => errors.push(format!(
+                        "第 {} 行的 {} 与第 {} 行的 {} 不匹配",
+                        line, c, open_line, open
+                    )),
+                    None => errors.push(format!("第 {} 行存在多余的 {}", line, c)),
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    for (open, open_line) in stack {
+        errors.push(format!("第 {} 行的 {} 未闭合", open_line, open));
+    }
+
+    CodeCheckResult::from_errors(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balanced_code_passes() {
+        let code = "fn main() {\n    let s = \"(不计入括号\";\n    // 注释中的 { 不计入\n    println!(\"{}\", s);\n    let c = '{';\n}\n";
+        assert_eq!(
+            check_balance(code, "rust"),
+            CodeCheckResult::from_errors(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_code_is_flagged() {
+        let result = check_balance("def f(x:\n    return [x, 1\n", "python");
+        assert!(result.checked);
+        assert!(!result.valid);
+        assert_eq!(result.errors.len(), 2);
+
+        let result = check_balance("const s = 'abc;\n", "javascript");
+        assert!(!result.valid);
+    }
+
+    #[test]
+    fn test_shell_parameter_expansion_is_ignored() {
+        let code = "echo \"${#items[@]} $#\"\nname=${file##*/}\necho ${name%.*} ${a:-${b}}\nsed \"s/x/${y//\\'/}/\" ${z}\n";
+        assert_eq!(
+            check_balance(code, "bash"),
+            CodeCheckResult::from_errors(Vec::new())
+        );
+        assert!(!check_balance("echo ${name\n", "sh").valid);
+    }
+
+    #[test]
+    fn test_unknown_language_is_unchecked() {
+        assert!(!check_balance("((", "markdown").checked);
+    }
+}
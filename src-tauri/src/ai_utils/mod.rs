@@ -1,3 +1,4 @@
 pub mod wolframalpha;
 pub mod mathworld;
-pub mod online_python_exec;
\ No newline at end of file
+pub mod online_python_exec;
+pub mod code_check;
\ No newline at end of file
@@ -164,10 +164,81 @@ pub struct WolframAlphaResult {
     pub related_queries: Vec<String>, // Related queries might be top-level
 }
 
+/// Wolfram 生成结果图像使用的主题
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WolframTheme {
+    #[default]
+    Light,
+    Dark,
+}
+
+impl WolframTheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WolframTheme::Light => "light",
+            WolframTheme::Dark => "dark",
+        }
+    }
+
+    pub fn location_id(&self) -> String {
+        format!("oi8ft_en_{}", self.as_str())
+    }
+}
+
+/// 查询的超时时间和消息数量限制，复杂的计算可以放宽，简单的查询可以收紧以尽快失败
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WolframQueryOptions {
+    pub init_timeout_secs: u64,      // 等待初始化响应的超时时间（秒）
+    pub message_timeout_secs: u64,   // 等待每条结果消息的超时时间（秒）
+    pub max_messages: usize,         // 最多接收的结果消息数量
+    pub theme: Option<WolframTheme>, // 结果图像的主题，未指定时跟随应用设置
+    pub connect_attempts: u32,       // 连接失败或中断时最多尝试的次数
+}
+
+impl Default for WolframQueryOptions {
+    fn default() -> Self {
+        Self {
+            init_timeout_secs: 15,
+            message_timeout_secs: 30,
+            max_messages: 50,
+            theme: None,
+            connect_attempts: 3,
+        }
+    }
+}
+
+impl WolframQueryOptions {
+    pub fn init_timeout(&self) -> Duration {
+        Duration::from_secs(self.init_timeout_secs)
+    }
+
+    pub fn message_timeout(&self) -> Duration {
+        Duration::from_secs(self.message_timeout_secs)
+    }
+
+    pub fn theme(&self) -> WolframTheme {
+        self.theme.unwrap_or_default()
+    }
+
+    /// 超时时间和消息数量限制为 0 时查询不可能完成，直接拒绝
+    pub fn validate(&self) -> Result<(), String> {
+        if self.init_timeout_secs == 0 || self.message_timeout_secs == 0 {
+            return Err("Wolfram Alpha 查询的超时时间必须大于 0 秒".to_string());
+        }
+        if self.max_messages == 0 {
+            return Err("Wolfram Alpha 查询的最大消息数量必须大于 0".to_string());
+        }
+        Ok(())
+    }
+}
+
 /// 实际的 WebSocket 通信实现，异步版本
 pub async fn wolfram_alpha_compute_async(
     query: &str,
     image_only: bool,
+    options: &WolframQueryOptions,
 ) -> Result<Vec<WolframAlphaResult>, WolframAlphaError> {
     info!("开始查询: {}", query);
     
@@ -213,7 +284,7 @@ pub async fn wolfram_alpha_compute_async(
         .map_err(|e| WolframAlphaError::WebSocketError(e.to_string()))?;
     
     // 2. 接收初始化响应
-    match timeout(Duration::from_secs(15), ws_stream.next()).await {
+    match timeout(options.init_timeout(), ws_stream.next()).await {
         Ok(Some(Ok(Message::Text(text)))) => {
             let resp_val: serde_json::Value = serde_json::from_str(&text)
                 .map_err(|e| WolframAlphaError::JsonError(e.to_string()))?;
@@ -234,7 +305,7 @@ pub async fn wolfram_alpha_compute_async(
     // 3. 发送查询消息
     let new_query_msg = NewQueryMessage {
         msg_type: "newQuery".to_string(),
-        location_id: "oi8ft_en_light".to_string(),
+        location_id: options.theme().location_id(),
         language: "en".to_string(),
         display_debugging_info: false,
         yellow_is_error: false,
@@ -245,7 +316,7 @@ pub async fn wolfram_alpha_compute_async(
         assumption: vec![],
         api_params: serde_json::json!({}),
         file: None,
-        theme: "light".to_string(),
+        theme: options.theme().as_str().to_string(),
     };
     
     let new_query_json = match serde_json::to_string(&new_query_msg) {
@@ -260,13 +331,12 @@ pub async fn wolfram_alpha_compute_async(
     // 4. 接收和处理结果
     let mut results: Vec<WolframAlphaResult> = Vec::new();
     let mut message_count = 0;
-    const MAX_MESSAGES: usize = 50; // 设置一个合理的最大消息数量限制
     
-    while let Ok(Some(msg_result)) = timeout(Duration::from_secs(30), ws_stream.next()).await {
+    while let Ok(Some(msg_result)) = timeout(options.message_timeout(), ws_stream.next()).await {
         // 检查是否超过最大迭代次数
         message_count += 1;
-        if message_count > MAX_MESSAGES {
-            warn!("达到最大消息数量限制 ({})，终止接收", MAX_MESSAGES);
+        if message_count > options.max_messages {
+            warn!("达到最大消息数量限制 ({})，终止接收", options.max_messages);
             break;
         }
         
@@ -423,27 +493,28 @@ pub async fn wolfram_alpha_compute_async(
 pub fn wolfram_alpha_compute(
     query: &str,
     image_only: bool,
+    options: &WolframQueryOptions,
 ) -> Result<Vec<WolframAlphaResult>, WolframAlphaError> {
     // 创建tokio运行时
     let rt = Runtime::new()
         .map_err(|e| WolframAlphaError::WebSocketError(e.to_string()))?;
     
     // 在运行时中执行异步函数
-    rt.block_on(wolfram_alpha_compute_async(query, image_only))
+    rt.block_on(wolfram_alpha_compute_async(query, image_only, options))
 }
 
 /// 不包含图像的查询，异步版本
 pub async fn wolfram_alpha_compute_without_image_async(
     query: &str,
 ) -> Result<Vec<WolframAlphaResult>, WolframAlphaError> {
-    wolfram_alpha_compute_async(query, true).await
+    wolfram_alpha_compute_async(query, true, &WolframQueryOptions::default()).await
 }
 
 /// 不包含图像的查询，同步版本
 pub fn wolfram_alpha_compute_without_image(
     query: &str,
 ) -> Result<Vec<WolframAlphaResult>, WolframAlphaError> {
-    wolfram_alpha_compute(query, true)
+    wolfram_alpha_compute(query, true, &WolframQueryOptions::default())
 }
 
 // --- Formatting Functions ---
@@ -737,9 +808,34 @@ fn escape_xml_attr(s: &str) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_query_options_reject_zero_limits() {
+        assert!(WolframQueryOptions::default().validate().is_ok());
+        for options in [
+            WolframQueryOptions {
+                init_timeout_secs: 0,
+                ..Default::default()
+            },
+            WolframQueryOptions {
+                message_timeout_secs: 0,
+                ..Default::default()
+            },
+            WolframQueryOptions {
+                max_messages: 0,
+                ..Default::default()
+            },
+        ] {
+            assert!(options.validate().is_err());
+        }
+    }
+
     #[test]
     fn test_wolfram_alpha_compute_basic() {
-        let result = wolfram_alpha_compute("integral of x^2", false);
+        let result = wolfram_alpha_compute(
+            "integral of x^2",
+            false,
+            &WolframQueryOptions::default(),
+        );
         println!("\nresult: {:?}", result);
         assert!(result.is_ok(), "Should successfully compute the query");
         let results = result.unwrap();
@@ -748,7 +844,11 @@ mod tests {
 
     #[test]
     fn test_wolfram_alpha_compute_image_only() {
-        let result = wolfram_alpha_compute("solve x^2 + 3x + 2 = 0", true);
+        let result = wolfram_alpha_compute(
+            "solve x^2 + 3x + 2 = 0",
+            true,
+            &WolframQueryOptions::default(),
+        );
         println!("result: {:?}", result);
         assert!(
             result.is_ok(),
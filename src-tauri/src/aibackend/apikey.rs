@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     io::Read,
     path::PathBuf,
     sync::{Arc, Mutex},
@@ -9,6 +10,9 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tauri::AppHandle;
 use tauri_plugin_fs::{FilePath, FsExt, OpenOptions};
 
+use crate::aibackend::error::AiErrorCode;
+use crate::logging::redact::mask_api_key;
+
 // 全局存储 app_handle
 static APP_HANDLE: Lazy<Mutex<Option<Arc<Box<AppHandle>>>>> = Lazy::new(|| Mutex::new(None));
 static APP_DATA_DIR: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
@@ -32,11 +36,12 @@ pub fn init(handle: Arc<Box<AppHandle>>, app_data_dir: PathBuf, app_config_dir:
     println!("This app_data: {:?}", app_data_dir);
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ApiKeyType {
     Gemini,
     DeepSeek,
     Coze,
+    Mock,
 }
 
 #[allow(dead_code)]
@@ -45,16 +50,23 @@ impl ApiKeyType {    pub fn to_string(&self) -> String {
             ApiKeyType::Gemini => "Gemini".to_string(),
             ApiKeyType::DeepSeek => "DeepSeek".to_string(),
             ApiKeyType::Coze => "Coze".to_string(),
+            ApiKeyType::Mock => "Mock".to_string(),
         }
     }    pub fn from_string(s: &str) -> Option<ApiKeyType> {
         match s {
             "Gemini" => Some(ApiKeyType::Gemini),
             "DeepSeek" => Some(ApiKeyType::DeepSeek),
             "Coze" => Some(ApiKeyType::Coze),
+            "Mock" => Some(ApiKeyType::Mock),
             _ => None,
         }
     }    pub fn get_all_types() -> Vec<ApiKeyType> {
-        vec![ApiKeyType::Gemini, ApiKeyType::DeepSeek, ApiKeyType::Coze]
+        vec![
+            ApiKeyType::Gemini,
+            ApiKeyType::DeepSeek,
+            ApiKeyType::Coze,
+            ApiKeyType::Mock,
+        ]
     }
 }
 
@@ -82,6 +94,8 @@ pub struct ApiKey {
     pub key: String,
     pub name: String,
     pub key_type: ApiKeyType,
+    #[serde(default)]
+    pub priority: u8, // 数值越大越优先使用
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -218,6 +232,37 @@ impl ApiKeyList {
         let index = rand::Rng::random_range(&mut rng, 0..self.keys.len());
         Some(self.keys[index].clone())
     }
+
+    /// 选择指定类型中优先级最高的可用密钥，同优先级的密钥随机选择
+    ///
+    /// 跳过无效和仍在限流冷却中的密钥，依次回退到较低优先级；全部不可用时仍返回优先级最高的密钥
+    pub fn select_key_by_priority(&self, key_type: ApiKeyType) -> Option<ApiKey> {
+        let keys = self.filter_by_type(key_type).keys;
+        let health = KEY_HEALTH.lock().unwrap();
+        let now = chrono::Local::now().timestamp();
+        pick_by_priority(&keys, |key| {
+            health
+                .get(&key.key)
+                .is_none_or(|record| record.is_available(now))
+        })
+    }
+}
+
+fn pick_by_priority(keys: &[ApiKey], available: impl Fn(&ApiKey) -> bool) -> Option<ApiKey> {
+    let available_keys: Vec<&ApiKey> = keys.iter().filter(|key| available(key)).collect();
+    let candidates = if available_keys.is_empty() {
+        keys.iter().collect()
+    } else {
+        available_keys
+    };
+    let top = candidates.iter().map(|key| key.priority).max()?;
+    let top_keys: Vec<&ApiKey> = candidates
+        .into_iter()
+        .filter(|key| key.priority == top)
+        .collect();
+    let mut rng = rand::rng();
+    let index = rand::Rng::random_range(&mut rng, 0..top_keys.len());
+    Some(top_keys[index].clone())
 }
 
 #[tauri::command]
@@ -236,3 +281,172 @@ pub fn get_api_key_list_or_create(config_name: &str) -> ApiKeyList {
 pub fn try_save_api_key_list(config_name: &str, list: ApiKeyList) -> Result<(), String> {
     list.save_to(config_name)
 }
+
+/// 密钥最近一次使用的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyHealth {
+    Unknown,     // 本次启动后尚未使用
+    Healthy,     // 最近一次请求成功
+    RateLimited, // 请求过于频繁或额度不足
+    Invalid,     // 密钥无效或无权限
+    Error,       // 服务端返回了其他错误
+}
+
+#[derive(Debug, Clone)]
+struct KeyHealthRecord {
+    health: KeyHealth,
+    checked_at: i64,
+    last_error: Option<String>,
+}
+
+// 密钥被限流后暂停使用的时间
+const RATE_LIMIT_COOLDOWN_SECS: i64 = 60;
+
+impl KeyHealthRecord {
+    fn is_available(&self, now: i64) -> bool {
+        match self.health {
+            KeyHealth::Invalid => false,
+            KeyHealth::RateLimited => now - self.checked_at >= RATE_LIMIT_COOLDOWN_SECS,
+            _ => true,
+        }
+    }
+}
+
+// 各密钥最近一次请求的结果，以密钥本身为索引，只保存在内存中
+static KEY_HEALTH: Lazy<Mutex<HashMap<String, KeyHealthRecord>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 记录密钥请求成功
+pub fn record_key_success(key: &str) {
+    record_key_health(key, KeyHealth::Healthy, None);
+}
+
+/// 根据错误类别记录密钥请求失败；网络错误和内容拦截与密钥本身无关，不改变其状态
+pub fn record_key_failure(key: &str, code: AiErrorCode, message: &str) {
+    let health = match code {
+        AiErrorCode::Auth => KeyHealth::Invalid,
+        AiErrorCode::RateLimit => KeyHealth::RateLimited,
+        AiErrorCode::Server | AiErrorCode::Unknown => KeyHealth::Error,
+        AiErrorCode::Network | AiErrorCode::ContentBlocked | AiErrorCode::Config => return,
+    };
+    record_key_health(key, health, Some(message.to_string()));
+}
+
+fn record_key_health(key: &str, health: KeyHealth, last_error: Option<String>) {
+    KEY_HEALTH.lock().unwrap().insert(
+        key.to_string(),
+        KeyHealthRecord {
+            health,
+            checked_at: chrono::Local::now().timestamp(),
+            last_error,
+        },
+    );
+}
+
+/// 设置界面显示的密钥状态，不包含完整的密钥
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKeyStatus {
+    pub name: String,
+    pub key_type: ApiKeyType,
+    pub masked_key: String,
+    pub health: KeyHealth,
+    pub checked_at: Option<i64>, // 最近一次使用的时间戳
+    pub last_error: Option<String>,
+}
+
+fn key_statuses(list: &ApiKeyList, health: &HashMap<String, KeyHealthRecord>) -> Vec<ApiKeyStatus> {
+    let mut statuses: Vec<ApiKeyStatus> = list
+        .keys
+        .iter()
+        .map(|key| {
+            let record = health.get(&key.key);
+            ApiKeyStatus {
+                name: key.name.clone(),
+                key_type: key.key_type.clone(),
+                masked_key: mask_api_key(&key.key),
+                health: record.map_or(KeyHealth::Unknown, |r| r.health),
+                checked_at: record.map(|r| r.checked_at),
+                last_error: record.and_then(|r| r.last_error.clone()),
+            }
+        })
+        .collect();
+    // 按类型分组，类型顺序与 get_all_types 一致
+    let types = ApiKeyType::get_all_types();
+    statuses.sort_by_key(|status| types.iter().position(|t| *t == status.key_type));
+    statuses
+}
+
+/// 列出所有 API 密钥及其最近一次使用的状态
+#[tauri::command]
+pub fn list_api_keys_status() -> Vec<ApiKeyStatus> {
+    let list = get_api_key_list_or_create("api_keys.json");
+    key_statuses(&list, &KEY_HEALTH.lock().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_statuses() {
+        let key = |key: &str, key_type: ApiKeyType| ApiKey {
+            key: key.to_string(),
+            name: format!("{}-name", key),
+            key_type,
+            priority: 0,
+        };
+        let list = ApiKeyList {
+            keys: vec![
+                key("sk-deepseek-0001", ApiKeyType::DeepSeek),
+                key("AIza-gemini-0002", ApiKeyType::Gemini),
+            ],
+        };
+        let mut health = HashMap::new();
+        health.insert(
+            "sk-deepseek-0001".to_string(),
+            KeyHealthRecord {
+                health: KeyHealth::RateLimited,
+                checked_at: 100,
+                last_error: Some("429".to_string()),
+            },
+        );
+
+        let statuses = key_statuses(&list, &health);
+        assert_eq!(statuses[0].key_type, ApiKeyType::Gemini);
+        assert_eq!(statuses[0].health, KeyHealth::Unknown);
+        assert_eq!(statuses[0].checked_at, None);
+        assert_eq!(statuses[1].masked_key, "***0001");
+        assert_eq!(statuses[1].health, KeyHealth::RateLimited);
+        assert_eq!(statuses[1].checked_at, Some(100));
+    }
+
+    #[test]
+    fn test_pick_by_priority() {
+        let key = |key: &str, priority: u8| ApiKey {
+            key: key.to_string(),
+            name: key.to_string(),
+            key_type: ApiKeyType::Gemini,
+            priority,
+        };
+        let keys = vec![key("free", 0), key("paid", 2), key("backup", 1)];
+
+        let picked = pick_by_priority(&keys, |_| true).unwrap();
+        assert_eq!(picked.key, "paid");
+        // 主密钥不可用时回退到次一级
+        let picked = pick_by_priority(&keys, |k| k.key != "paid").unwrap();
+        assert_eq!(picked.key, "backup");
+        // 全部不可用时仍选择优先级最高的
+        let picked = pick_by_priority(&keys, |_| false).unwrap();
+        assert_eq!(picked.key, "paid");
+        assert!(pick_by_priority(&[], |_| true).is_none());
+
+        let limited = KeyHealthRecord {
+            health: KeyHealth::RateLimited,
+            checked_at: 1000,
+            last_error: None,
+        };
+        assert!(!limited.is_available(1000 + RATE_LIMIT_COOLDOWN_SECS - 1));
+        assert!(limited.is_available(1000 + RATE_LIMIT_COOLDOWN_SECS));
+    }
+}
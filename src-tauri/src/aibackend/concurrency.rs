@@ -0,0 +1,63 @@
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// 默认同时进行的后台请求数量
+pub const DEFAULT_MAX_CONCURRENCY: usize = 3;
+
+struct Limiter {
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Limiter {
+    fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit)),
+        }
+    }
+}
+
+static LIMITER: Lazy<Mutex<Limiter>> =
+    Lazy::new(|| Mutex::new(Limiter::new(DEFAULT_MAX_CONCURRENCY)));
+
+/// 设置后台任务（批量生成标题、图片识别、工具调用等）同时进行的请求数量上限，为 0 时按 1 处理。
+/// 修改上限后新的请求使用新的信号量，已经开始的请求不受影响
+pub fn configure(max_concurrency: usize) {
+    let mut limiter = LIMITER.lock().unwrap();
+    if limiter.limit != max_concurrency.max(1) {
+        *limiter = Limiter::new(max_concurrency);
+    }
+}
+
+/// 在发起后台请求前获取许可，达到上限时等待其他请求完成；许可在返回值被丢弃时释放
+pub async fn acquire() -> OwnedSemaphorePermit {
+    let semaphore = Arc::clone(&LIMITER.lock().unwrap().semaphore);
+    semaphore
+        .acquire_owned()
+        .await
+        .expect("后台任务信号量不会被关闭")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_acquire_respects_limit() {
+        configure(1);
+        let permit = acquire().await;
+        assert!(tokio::time::timeout(Duration::from_millis(50), acquire())
+            .await
+            .is_err());
+
+        drop(permit);
+        assert!(tokio::time::timeout(Duration::from_millis(50), acquire())
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ChatHistory;
+
+// 上下文占用达到该比例时提醒用户，模型可能开始遗忘较早的对话
+const CONTEXT_WARNING_RATIO: f64 = 0.8;
+// 未知模型使用的保守上下文长度
+const DEFAULT_CONTEXT_LIMIT: usize = 32_768;
+// 已知模型的上下文长度（按模型名前缀匹配，先匹配的优先）
+const CONTEXT_LIMITS: [(&str, usize); 8] = [
+    ("gemini-1.5-pro", 2_097_152),
+    ("gemini-1.5-flash", 1_048_576),
+    ("gemini-2", 1_048_576),
+    ("gemini-1.0", 32_768),
+    ("deepseek-chat", 65_536),
+    ("deepseek-reasoner", 65_536),
+    ("coze", 32_768),
+    ("mock", 32_768),
+];
+
+/// 对话上下文的占用情况
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContextUsage {
+    pub estimated_tokens: usize, // 对话消息估算的 token 数
+    pub context_limit: usize,    // 模型的上下文长度
+    pub ratio: f64,              // 占用比例，可能超过 1
+    pub warning: bool,           // 是否达到提醒阈值
+}
+
+/// 查询模型的上下文长度，未知模型返回保守的默认值
+pub fn context_limit(model: &str) -> usize {
+    let model = model.trim_start_matches("models/");
+    CONTEXT_LIMITS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, limit)| *limit)
+        .unwrap_or(DEFAULT_CONTEXT_LIMIT)
+}
+
+/// 粗略估算文本的 token 数：汉字等宽字符约每字 1 个 token，其余字符约每 4 个 1 个 token
+pub fn estimate_tokens(text: &str) -> usize {
+    let (wide, narrow) = text.chars().fold((0usize, 0usize), |(wide, narrow), c| {
+        if c.is_ascii() {
+            (wide, narrow + 1)
+        } else {
+            (wide + 1, narrow)
+        }
+    });
+    wide + narrow.div_ceil(4)
+}
+
+/// 估算对话全部消息占用的上下文
+pub fn context_usage(chat: &ChatHistory, model: &str) -> ContextUsage {
+    let estimated_tokens = chat
+        .content
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum();
+    let context_limit = context_limit(model);
+    let ratio = estimated_tokens as f64 / context_limit as f64;
+    ContextUsage {
+        estimated_tokens,
+        context_limit,
+        ratio,
+        warning: ratio >= CONTEXT_WARNING_RATIO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_limit_and_estimate() {
+        assert_eq!(context_limit("gemini-2.5-flash"), 1_048_576);
+        assert_eq!(context_limit("models/gemini-1.5-pro-latest"), 2_097_152);
+        assert_eq!(context_limit("deepseek-reasoner"), 65_536);
+        assert_eq!(context_limit("unknown-model"), DEFAULT_CONTEXT_LIMIT);
+
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("你好"), 2);
+        assert_eq!(estimate_tokens("hello world"), 3);
+        assert_eq!(estimate_tokens("你好 world"), 4);
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::aibackend::context_usage::estimate_tokens;
+use crate::history_msg::history::{ChatHistory, ChatMessageType, TokenUsage};
+use crate::setting::setting::ModelPrice;
+
+/// 累计一次回答中各次请求返回的 token 用量，后端在请求时记录，生成结束后取出写入回复
+#[derive(Clone, Debug, Default)]
+pub struct UsageRecorder(Arc<Mutex<Option<TokenUsage>>>);
+
+impl UsageRecorder {
+    pub fn record(&self, usage: TokenUsage) {
+        let mut total = self.0.lock().unwrap();
+        let total = total.get_or_insert_with(TokenUsage::default);
+        total.prompt_tokens += usage.prompt_tokens;
+        total.completion_tokens += usage.completion_tokens;
+    }
+
+    /// 取出累计的用量并清空，没有请求返回用量时为 None
+    pub fn take(&self) -> Option<TokenUsage> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// 一条助手回复的用量：有 API 返回的用量时直接使用，否则估算，输入为发送时的全部上文，输出为回复本身（含思考过程）
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageCost {
+    pub message_index: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub cost: f64,
+    pub estimated: bool, // 是否按字符数估算
+}
+
+/// 对话的费用，没有记录用量的回复按字符数估算，只能作为参考
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CostBreakdown {
+    pub model: String,
+    pub priced: bool,    // 单价表中是否有该模型，没有时费用为 0
+    pub estimated: bool, // 是否有回复的用量是估算的
+    pub currency: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub prompt_cost: f64,
+    pub completion_cost: f64,
+    pub total_cost: f64,
+    pub messages: Vec<MessageCost>,
+}
+
+/// 按最长前缀查找模型单价
+pub fn find_price<'a>(prices: &'a [ModelPrice], model: &str) -> Option<&'a ModelPrice> {
+    let model = model.trim_start_matches("models/");
+    prices
+        .iter()
+        .filter(|price| !price.model.is_empty() && model.starts_with(&price.model))
+        .max_by_key(|price| price.model.len())
+}
+
+/// 按 model 的单价计算对话中每条助手回复的费用；单价表中没有该模型时不回退到其他模型，费用为 0 且 priced 为 false
+pub fn chat_cost(
+    chat: &ChatHistory,
+    model: &str,
+    prices: &[ModelPrice],
+    currency: &str,
+) -> CostBreakdown {
+    let price = find_price(prices, model);
+    let (prompt_price, completion_price) = price.map_or((0.0, 0.0), |p| (p.prompt, p.completion));
+
+    let mut context_tokens = 0;
+    let mut messages = Vec::new();
+    for (index, message) in chat.content.iter().enumerate() {
+        let tokens = estimate_tokens(&message.content);
+        if message.msgtype == ChatMessageType::Assistant {
+            let (prompt_tokens, completion_tokens) = match message.usage {
+                Some(usage) => (usage.prompt_tokens as usize, usage.completion_tokens as usize),
+                None => (
+                    context_tokens,
+                    estimate_tokens(message.raw_content.as_deref().unwrap_or(&message.content)),
+                ),
+            };
+            messages.push(MessageCost {
+                message_index: index,
+                prompt_tokens,
+                completion_tokens,
+                cost: (prompt_tokens as f64 * prompt_price
+                    + completion_tokens as f64 * completion_price)
+                    / 1_000_000.0,
+                estimated: message.usage.is_none(),
+            });
+        }
+        context_tokens += tokens;
+    }
+
+    let prompt_tokens: usize = messages.iter().map(|m| m.prompt_tokens).sum();
+    let completion_tokens: usize = messages.iter().map(|m| m.completion_tokens).sum();
+    let prompt_cost = prompt_tokens as f64 * prompt_price / 1_000_000.0;
+    let completion_cost = completion_tokens as f64 * completion_price / 1_000_000.0;
+    CostBreakdown {
+        model: model.to_string(),
+        priced: price.is_some(),
+        estimated: messages.iter().any(|m| m.estimated),
+        currency: currency.to_string(),
+        prompt_tokens,
+        completion_tokens,
+        prompt_cost,
+        completion_cost,
+        total_cost: prompt_cost + completion_cost,
+        messages,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(model: &str, prompt: f64, completion: f64) -> ModelPrice {
+        ModelPrice {
+            model: model.to_string(),
+            prompt,
+            completion,
+        }
+    }
+
+    #[test]
+    fn test_find_price_prefers_longest_prefix() {
+        let prices = vec![
+            price("gemini-2", 1.0, 1.0),
+            price("gemini-2.5-pro", 2.0, 2.0),
+        ];
+        assert_eq!(
+            find_price(&prices, "gemini-2.5-pro-exp").unwrap().prompt,
+            2.0
+        );
+        assert_eq!(
+            find_price(&prices, "models/gemini-2.0-flash")
+                .unwrap()
+                .prompt,
+            1.0
+        );
+        assert!(find_price(&prices, "deepseek-chat").is_none());
+    }
+
+    #[test]
+    fn test_chat_cost() {
+        let chat: ChatHistory = serde_json::from_str(
+            r#"{"id":1,"title":null,"time":"12:00","content":[
+                {"msgtype":"User","time":"12:00","content":"aaaa"},
+                {"msgtype":"Assistant","time":"12:00","content":"bbbbbbbb"},
+                {"msgtype":"User","time":"12:01","content":"cccc"},
+                {"msgtype":"Assistant","time":"12:01","content":"dddd","raw_content":"思考dddd"}
+            ]}"#,
+        )
+        .unwrap();
+        let cost = chat_cost(
+            &chat,
+            "test-model",
+            &[price("test", 1_000_000.0, 2_000_000.0)],
+            "$",
+        );
+
+        // 第一条回复：输入 1，输出 2；第二条回复：输入 1+2+1，输出 2+1
+        assert_eq!(cost.messages.len(), 2);
+        assert_eq!(cost.messages[1].prompt_tokens, 4);
+        assert_eq!(cost.messages[1].completion_tokens, 3);
+        assert_eq!(cost.prompt_tokens, 5);
+        assert_eq!(cost.completion_tokens, 5);
+        assert_eq!(cost.total_cost, 5.0 + 10.0);
+        assert!(cost.priced);
+        assert!(cost.estimated);
+
+        let unpriced = chat_cost(&chat, "other", &[], "$");
+        assert!(!unpriced.priced);
+        assert_eq!(unpriced.total_cost, 0.0);
+
+        // 记录了 API 返回的用量时按实际用量计算
+        let mut chat = chat;
+        chat.content[3].usage = Some(TokenUsage {
+            prompt_tokens: 100,
+            completion_tokens: 20,
+        });
+        let cost = chat_cost(&chat, "test-model", &[price("test", 1_000_000.0, 2_000_000.0)], "$");
+        assert_eq!(cost.messages[1].prompt_tokens, 100);
+        assert_eq!(cost.messages[1].completion_tokens, 20);
+        assert!(!cost.messages[1].estimated);
+        assert!(cost.messages[0].estimated);
+        assert_eq!(cost.total_cost, (1.0 + 100.0) + 2.0 * (2.0 + 20.0));
+    }
+
+    #[test]
+    fn test_usage_recorder_sums_requests() {
+        let recorder = UsageRecorder::default();
+        assert_eq!(recorder.take(), None);
+        recorder.record(TokenUsage { prompt_tokens: 10, completion_tokens: 2 });
+        recorder.clone().record(TokenUsage { prompt_tokens: 15, completion_tokens: 3 });
+        assert_eq!(recorder.take(), Some(TokenUsage { prompt_tokens: 25, completion_tokens: 5 }));
+        assert_eq!(recorder.take(), None);
+    }
+}
@@ -3,12 +3,15 @@ use futures_util::StreamExt;
 use reqwest;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::error::Error;
+use std::hash::{Hash, Hasher};
 
 use crate::aibackend::apikey::{ApiKey, ApiKeyType};
 use crate::aibackend::interface::AIChat;
-use crate::aibackend::template::{self, cot_template, TypesetInfo};
+use crate::aibackend::template::{self, cot_template, enabled_typeset_tools};
+use crate::history_msg::chat_state::ChatState;
 use crate::ChatHistory;
 
 const COZE_API_URL: &str = "https://api.coze.cn/v3/chat";
@@ -108,6 +111,15 @@ pub struct CozeChat {
     chat_id: u32,
     title: Option<String>,
     time: String,
+    // Coze 服务器端的会话，存在时只发送新消息，由服务器保存上下文
+    #[serde(default)]
+    conversation_id: Option<String>,
+    // 服务器会话中已保存的消息数量，与本地历史不一致时放弃该会话
+    #[serde(default)]
+    synced_messages: usize,
+    // 创建服务器会话时系统指令的哈希，服务器会话只在创建时收到系统指令
+    #[serde(default)]
+    synced_instruction: Option<u64>,
 }
 
 impl CozeChat {
@@ -122,115 +134,111 @@ impl CozeChat {
             chat_id: 0,
             title: None,
             time: chrono::Local::now().format("%H:%M").to_string(),
+            conversation_id: None,
+            synced_messages: 0,
+            synced_instruction: None,
         }
     }
 
+    /// 系统指令（人格、系统提示词、COT 或排版工具设置）与创建服务器会话时不同时放弃该会话，
+    /// 改为发送包含新系统指令的完整历史
+    fn drop_stale_conversation(&mut self, instruction_hash: u64) {
+        if self.synced_instruction != Some(instruction_hash) {
+            self.conversation_id = None;
+        }
+    }
+
+    /// 对话是否关闭了 COT（通过 set_parameter("cot", "false") 设置，或设置中 COT 详略为 off）
+    fn cot_disabled(&self) -> bool {
+        self.parameters.get("cot").map(|value| value == "false").unwrap_or(false)
+            || template::active_cot().is_none()
+    }
+
+    /// 本次请求的系统指令是否包含 COT 模板
+    pub(crate) fn uses_cot(&self) -> bool {
+        !self.cot_disabled()
+    }
+
     /// 构建系统指令，包含排版格式提示词
     fn build_system_instruction(&self) -> String {
         let base_prompt = self.system_prompt.clone().unwrap_or_else(|| "You are a helpful assistant".to_string());
+
+        // 关闭 COT 时只使用基础系统提示
+        if self.cot_disabled() {
+            return base_prompt;
+        }
         
-        // 使用 COT 模板，包含所有排版功能
-        cot_template(&[
-            TypesetInfo {
-                name: "mermaid_render".to_string(),
-                description: "render mermaid graph".to_string(),
-                detail: "render mermaid graph by using mermaid.js renderer, should write down CORRECT mermaid code for sucessfully rendering".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("mermaid_code".to_string(), Value::String("mermaid code which you what to render".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "pintora_render".to_string(),
-                description: "render pintora graph".to_string(),
-                detail: "render pintora graph by using pintora.js renderer, should write down CORRECT pintora code for sucessfully rendering".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("diagram".to_string(), Value::String("pintora code which you what to render".to_string()));
-                    args.insert("scale".to_string(), Value::Number(1.into()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "interactive_button".to_string(),
-                description: "show a interactive button signed `message`, when user clicks on it, then you will receive `command` text".to_string(),
-                detail: r#"show a interactive button signed `message`, when user clicks on it, then you will receive `command` text
-    It is a good way for you to show a button for user to click when user learns something new
-    - `message`: the text which you want to show on the button
-    - `command`: the text which will be sent when user clicks the button
-    > You can use it to give some hints to user, like "click me to send `Hello!`" or "click me to send `Bye!`"
-    "#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("message".to_string(), Value::String("click me to send `Hello!`".to_string()));
-                    args.insert("command".to_string(), Value::String("Hello!".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "typst_render".to_string(),
-                description: "render typst document".to_string(),
-                detail: "render typst document by using typst.ts renderer, should write down CORRECT typst code for successfully rendering mathematical formulas, diagrams, and professional documents".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("typst_code".to_string(), Value::String("typst code which you want to render".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "html_render".to_string(),
-                description: "render HTML content in a sandboxed environment".to_string(),
-                detail: r#"render HTML content safely in a sandboxed iframe, which tolerates malformed HTML without affecting the page layout
-    - `html`: the HTML content to render
-    - `title`: optional title for the HTML container (default: "HTML内容")
-    - `show_border`: optional boolean to show/hide border (default: true)"#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("html".to_string(), Value::String("<div>Your HTML content here</div>".to_string()));
-                    args.insert("title".to_string(), Value::String("HTML内容".to_string()));
-                    args.insert("show_border".to_string(), Value::Bool(true));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "katex_render".to_string(),
-                description: "render mathematical formulas".to_string(),
-                detail: "render mathematical formulas by using katex renderer, should write down CORRECT latex code for successfully rendering mathematical formulas. No need to wrap by `$`. Be careful with backslashes: use double backslashes (\\\\) for commands like \\\\alpha instead of \\alpha, as single backslashes may be interpreted as escape characters (e.g., \\n becomes a newline).".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("katex_code".to_string(), Value::String("Katex code which you want to render. No need to wrap by `$`. Remember to escape backslashes properly.".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "wolfram_alpha_compute".to_string(),
-                description: "compute queries using Wolfram Alpha".to_string(),
-                detail: r#"compute mathematical expressions, solve equations, convert units, and answer factual questions using Wolfram Alpha's computational engine
-    - `query`: the query to compute (e.g., mathematical expressions, word problems, unit conversions)
-    - `image_only`: optional boolean to return only image result (default: false)
-    - `format`: optional format for results, only `html` avaliable"#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("query".to_string(), Value::String("1+1".to_string()));
-                    args.insert("image_only".to_string(), Value::Bool(false));
-                    args.insert("format".to_string(), Value::String("html".to_string()));
-                    args
-                },
-            },
-        ], &base_prompt)
-    }    // 发送流式对话请求
+        // 使用 COT 模板，包含设置中启用的排版功能
+        cot_template(&enabled_typeset_tools(), &base_prompt)
+    }
+
+    // 发送流式对话请求：有服务器会话时只发送新消息，否则发送完整历史并创建新的会话
     pub async fn send_stream_request<F>(
-        &self,
+        &mut self,
         message: &str,
         callback: F,
     ) -> Result<String, Box<dyn Error>>
     where
         F: FnMut(String) + Send + 'static,
     {
-        // 构建包含系统指令的消息数组
+        let current_message = CozeMessage {
+            role: "user".to_string(),
+            content: message.to_string(),
+            content_type: "text".to_string(),
+        };
+
+        let instruction_hash = hash_instruction(&self.build_system_instruction());
+        self.drop_stale_conversation(instruction_hash);
+
+        let mut response = None;
+        if let Some(conversation_id) = self.conversation_id.clone() {
+            match self
+                .post_chat(Some(&conversation_id), vec![current_message.clone()])
+                .await
+            {
+                Ok(resp) => response = Some(resp),
+                Err(e) => {
+                    // 会话可能已过期或被删除，改为发送完整历史
+                    println!(
+                        "Coze 会话 {} 不可用，改为发送完整历史: {}",
+                        conversation_id, e
+                    );
+                    self.conversation_id = None;
+                }
+            }
+        }
+        let response = match response {
+            Some(resp) => resp,
+            None => {
+                let messages = self.stateless_messages(current_message);
+                self.post_chat(None, messages).await?
+            }
+        };
+
+        // 使用新的 Coze SSE 流式处理函数
+        let (response_text, conversation_id) =
+            process_coze_stream_response(response, callback).await?;
+        if conversation_id.is_some() {
+            self.conversation_id = conversation_id;
+            self.synced_instruction = Some(instruction_hash);
+        }
+
+        // 应用模板提取（关闭 COT 时回复中没有模板标记）
+        let final_text = if self.cot_disabled() {
+            response_text
+        } else if let Some(extracted) = template::extract_response(&response_text) {
+            extracted
+        } else {
+            response_text
+        };
+
+        Ok(final_text)
+    }
+
+    /// 没有服务器会话时发送的消息：系统指令、历史对话和当前用户消息
+    fn stateless_messages(&self, current_message: CozeMessage) -> Vec<CozeMessage> {
         let mut messages = vec![];
-        
+
         // 添加系统指令
         if !self.conversation_history.is_empty() || self.system_prompt.is_some() {
             messages.push(CozeMessage {
@@ -242,29 +250,41 @@ impl CozeChat {
                 content_type: "text".to_string(),
             });
         }
-          // 添加历史对话
+        // 添加历史对话
         for msg in &self.conversation_history {
             messages.push(msg.clone());
         }
-        
+
         // 添加当前用户消息
-        messages.push(CozeMessage {
-            role: "user".to_string(),
-            content: message.to_string(),
-            content_type: "text".to_string(),
-        });
+        messages.push(current_message);
+        messages
+    }
 
+    /// 发起对话请求，状态码不是成功时返回错误；消息由服务器保存，后续轮次可以复用会话
+    async fn post_chat(
+        &self,
+        conversation_id: Option<&str>,
+        messages: Vec<CozeMessage>,
+    ) -> Result<reqwest::Response, Box<dyn Error>> {
         let request_body = CozeRequest {
             bot_id: BOT_ID.to_string(),
             user_id: USER_ID.to_string(),
             stream: true, // 启用流式请求
-            auto_save_history: false,
+            auto_save_history: true,
             additional_messages: messages,
         };
+        let url = match conversation_id {
+            Some(id) => format!(
+                "{}?conversation_id={}",
+                COZE_API_URL,
+                urlencoding::encode(id)
+            ),
+            None => COZE_API_URL.to_string(),
+        };
 
         let response = self
             .client
-            .post(COZE_API_URL)
+            .post(url)
             .header("Content-Type", "application/json")
             .header("Authorization", &self.api_key)
             .json(&request_body)
@@ -276,23 +296,18 @@ impl CozeChat {
             let error_text = response.text().await?;
             return Err(format!("Request failed with status {}: {}", status, error_text).into());
         }
-
-        // 使用新的 Coze SSE 流式处理函数
-        let response_text = process_coze_stream_response(response, callback).await?;
-        
-        // 应用模板提取
-        let final_text = if let Some(extracted) = template::extract_response(&response_text) {
-            extracted
-        } else {
-            response_text
-        };
-        
-        Ok(final_text)
-    }// 获取对话结果
+        Ok(response)
+    }
 }
 
 // AIChat trait 将在 interface.rs 中通过其他方式实现
 
+fn hash_instruction(instruction: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    instruction.hash(&mut hasher);
+    hasher.finish()
+}
+
 /// 过滤系统元数据，只保留用户友好的内容
 fn filter_system_metadata(content: &str) -> String {
     // 检查是否包含系统元数据 JSON
@@ -316,11 +331,11 @@ fn filter_system_metadata(content: &str) -> String {
     content.to_string()
 }
 
-/// 解析 Coze SSE 流式响应
+/// 解析 Coze SSE 流式响应，返回回复内容和服务器创建或沿用的会话ID
 async fn process_coze_stream_response<F>(
     response: reqwest::Response,
     mut callback: F,
-) -> Result<String, Box<dyn Error>>
+) -> Result<(String, Option<String>), Box<dyn Error>>
 where
     F: FnMut(String) + Send + 'static,
 {
@@ -328,6 +343,7 @@ where
     let mut full_response = String::new();
     let mut has_received_data = false;
     let mut current_event_type = String::new();
+    let mut conversation_id = None;
 
     println!("Starting Coze SSE stream processing...");
 
@@ -336,7 +352,6 @@ where
             Ok(chunk) => {
                 has_received_data = true;
                 let chunk_str = String::from_utf8_lossy(&chunk);
-                println!("Received raw chunk: {}", chunk_str);
 
                 // 处理 SSE 格式的流式响应
                 for line in chunk_str.lines() {
@@ -371,6 +386,12 @@ where
                         match current_event_type.as_str() {
                             "conversation.chat.created" => {
                                 println!("Chat created");
+                                if let Ok(json_data) = serde_json::from_str::<Value>(data) {
+                                    conversation_id = json_data
+                                        .get("conversation_id")
+                                        .and_then(|id| id.as_str())
+                                        .map(|id| id.to_string());
+                                }
                             }
                             "conversation.chat.in_progress" => {
                                 println!("Chat in progress");
@@ -463,7 +484,6 @@ where
                                         json_data.get("content").and_then(|c| c.as_str())
                                     {
                                         if !content.is_empty() {
-                                            println!("Extracted generic content: {}", content);
                                             callback(content.to_string());
                                             full_response.push_str(content);
                                         }
@@ -510,12 +530,15 @@ where
 
     if full_response.is_empty() && has_received_data {
         println!("Warning: Received data but couldn't extract text");
-        return Ok("(Response received but requires different format parsing)".to_string());
+        return Ok((
+            "(Response received but requires different format parsing)".to_string(),
+            conversation_id,
+        ));
     } else if full_response.is_empty() {
         return Err("No text generated from the stream".into());
     }
-    println!("Completed Coze stream response: {}", full_response);
-    Ok(full_response)
+    println!("Completed Coze stream response: {} chars", full_response.chars().count());
+    Ok((full_response, conversation_id))
 }
 
 // AIChat trait implementation for CozeChat
@@ -552,6 +575,7 @@ impl AIChat for CozeChat {
             content: response.clone(),
             content_type: "text".to_string(),
         });
+        self.synced_messages = self.conversation_history.len();
 
         Ok(response)
     }
@@ -574,6 +598,9 @@ impl AIChat for CozeChat {
     }
 
     fn withdraw_response(&mut self) -> Result<String, Box<dyn Error>> {
+        // 服务器会话中仍保存着被撤回的回复，之后改为发送完整历史
+        self.conversation_id = None;
+
         // 移除最后的助手响应，返回最后的用户提示
         if let Some(last_message) = self.conversation_history.last() {
             if last_message.role == "assistant" {
@@ -637,6 +664,8 @@ impl AIChat for CozeChat {
                 crate::ChatMessageType::User => "user",
                 crate::ChatMessageType::Assistant => "assistant",
                 crate::ChatMessageType::System => "system", // 添加对系统消息的支持
+                // 本地记录的工具结果不属于 Coze 服务器会话，发送时跳过
+                crate::ChatMessageType::Tool => continue,
             };
 
             self.conversation_history.push(CozeMessage {
@@ -646,6 +675,11 @@ impl AIChat for CozeChat {
             });
         }
 
+        // 本地历史被编辑或删除过消息时，服务器会话的上下文已不一致
+        if self.conversation_history.len() != self.synced_messages {
+            self.conversation_id = None;
+        }
+
         Ok(())
     }
 
@@ -659,18 +693,14 @@ impl AIChat for CozeChat {
                 _ => continue, // 跳过未知类型
             };
 
-            chat_messages.push(crate::ChatMessage {
-                msgtype,
-                time: chrono::Local::now().format("%H:%M").to_string(),
-                content: message.content.clone(),
-            });
+            chat_messages.push(crate::ChatMessage::new(msgtype, message.content.clone()));
         }
 
         Ok(ChatHistory {
-            id: self.chat_id,
             title: self.title.clone(),
             time: self.time.clone(),
             content: chat_messages,
+            ..ChatState::empty_chat(self.chat_id)
         })
     }
 
@@ -911,4 +941,51 @@ pub mod tests {
 
         println!("Coze AIChat trait implementation test passed");
     }
+
+    #[test]
+    fn test_conversation_id_follows_history() {
+        let mut chat = CozeChat::new();
+        chat.conversation_id = Some("conv_1".to_string());
+        chat.synced_messages = 2;
+
+        // 保存后端状态时会清空消息，会话ID需要保留
+        let _ = chat.clear_context();
+        let mut restored = CozeChat::new();
+        restored.deserialize(AIChat::serialize(&chat)).unwrap();
+        assert_eq!(restored.conversation_id.as_deref(), Some("conv_1"));
+
+        let message = |msgtype, content: &str| crate::ChatMessage {
+            time: "12:00".to_string(),
+            ..crate::ChatMessage::new(msgtype, content.to_string())
+        };
+        let mut history = ChatHistory {
+            time: "12:00".to_string(),
+            content: vec![
+                message(crate::ChatMessageType::User, "你好"),
+                message(crate::ChatMessageType::Assistant, "你好！"),
+            ],
+            ..ChatState::empty_chat(1)
+        };
+        restored.load_from(&history).unwrap();
+        assert_eq!(restored.conversation_id.as_deref(), Some("conv_1"));
+
+        // 删除消息后服务器上下文不再一致，改为发送完整历史
+        history.content.pop();
+        restored.load_from(&history).unwrap();
+        assert!(restored.conversation_id.is_none());
+    }
+
+    #[test]
+    fn test_conversation_dropped_when_instruction_changes() {
+        let mut chat = CozeChat::new();
+        chat.conversation_id = Some("conv_1".to_string());
+        let instruction_hash = hash_instruction(&chat.build_system_instruction());
+        chat.synced_instruction = Some(instruction_hash);
+        chat.drop_stale_conversation(instruction_hash);
+        assert_eq!(chat.conversation_id.as_deref(), Some("conv_1"));
+
+        chat.set_system_prompt("你是一名数学老师".to_string()).unwrap();
+        chat.drop_stale_conversation(hash_instruction(&chat.build_system_instruction()));
+        assert!(chat.conversation_id.is_none());
+    }
 }
@@ -1,9 +1,15 @@
-use crate::aibackend::interface::AIChat;
+use crate::aibackend::cost::UsageRecorder;
+use crate::aibackend::interface::{parse_stop_sequences, AIChat};
 use crate::aibackend::openai_types::{
     ChatCompletionMessage, Content, MessageRole, Tool, ToolCall, 
-    ChatCompletionResponse, ChatCompletionStreamResponse,
+    ChatCompletionResponse, ChatCompletionStreamResponse, Usage,
 };
-use crate::aibackend::template::{self, cot_template, COT, TypesetInfo};
+use crate::aibackend::template::{self, cot_template, enabled_typeset_tools};
+use crate::aibackend::tool_loop::{
+    default_max_tool_iterations, parse_max_tool_iterations, run_tool_loop, ToolTurn,
+};
+use crate::history_msg::chat_state::ChatState;
+use crate::history_msg::history::TokenUsage;
 use crate::{ChatHistory, ChatMessage, ChatMessageType};
 use futures_util::StreamExt;
 use reqwest;
@@ -13,7 +19,7 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use super::apikey::{ApiKey, ApiKeyType};
 
@@ -31,6 +37,8 @@ struct DeepSeekMessage {
     tool_calls: Option<Vec<ToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<bool>, // 前缀续写（Beta）：模型从该助手消息的内容处继续生成
 }
 
 // 添加简化的请求结构用于 DeepSeek API
@@ -49,11 +57,21 @@ struct DeepSeekRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     presence_penalty: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     tools: Option<Vec<Tool>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_choice: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+// 流式请求的选项：在结束前单独返回整个请求的 token 用量
+#[derive(Clone, Debug, Serialize)]
+struct StreamOptions {
+    include_usage: bool,
 }
 
 // --- DeepSeek Chat Structure ---
@@ -69,8 +87,18 @@ pub struct DeepSeekChat {
     top_p: Option<f32>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    #[serde(default)]
+    stop: Option<Vec<String>>, // 停止序列
+    #[serde(default)]
+    cot_disabled: bool, // 不使用 COT 模板和 COT 指令
+    #[serde(default = "default_max_tool_iterations")]
+    max_tool_iterations: usize, // 多轮工具调用的最大轮数
     last_prompt: Option<String>,
     tools: Vec<Tool>,
+    #[serde(skip)]
+    reasoning_sink: ReasoningSink, // 接收推理模型的思考过程，不随对话保存
+    #[serde(skip)]
+    usage: UsageRecorder, // 本次回答各次请求返回的 token 用量，不随对话保存
 
     chat_id: u32,
     title: Option<String>,
@@ -86,6 +114,24 @@ fn build_deepseek_url(base_url: &str, endpoint: &str) -> String {
 
 /// 将 ChatCompletionMessage 转换为 DeepSeekMessage
 fn convert_to_deepseek_message(msg: &ChatCompletionMessage) -> DeepSeekMessage {
+    // 没有 tool_call_id 的工具消息来自对话记录（文本形式的 tool_code 调用），
+    // API 要求 tool 消息必须回应工具调用请求，因此作为用户侧的工具结果说明发送
+    if msg.role == MessageRole::tool && msg.tool_call_id.is_none() {
+        let Content::Text(text) = &msg.content;
+        return DeepSeekMessage {
+            role: "user".to_string(),
+            content: format!(
+                "[Tool result: {}]\n{}",
+                msg.name.as_deref().unwrap_or("tool"),
+                text
+            ),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            prefix: None,
+        };
+    }
+
     let role_str = match msg.role {
         MessageRole::user => "user",
         MessageRole::assistant => "assistant",
@@ -104,13 +150,96 @@ fn convert_to_deepseek_message(msg: &ChatCompletionMessage) -> DeepSeekMessage {
         name: msg.name.clone(),
         tool_calls: msg.tool_calls.clone(),
         tool_call_id: msg.tool_call_id.clone(),
+        prefix: None,
+    }
+}
+
+type ReasoningCallback = Arc<Mutex<dyn FnMut(String) + Send>>;
+
+/// 推理模型（deepseek-reasoner）单独返回的思考过程的接收者，未设置时丢弃思考过程
+#[derive(Clone, Default)]
+pub struct ReasoningSink(Option<ReasoningCallback>);
+
+impl ReasoningSink {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        Self(Some(Arc::new(Mutex::new(callback))))
+    }
+
+    fn send(&self, text: String) {
+        if let Some(callback) = &self.0 {
+            (callback.lock().unwrap())(text);
+        }
+    }
+}
+
+impl std::fmt::Debug for ReasoningSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.0.is_some() { "ReasoningSink(set)" } else { "ReasoningSink(none)" })
+    }
+}
+
+/// 流式响应中的一段内容
+#[derive(Debug, PartialEq)]
+enum StreamPiece {
+    Reasoning(String), // 推理模型的思考过程（reasoning_content）
+    Content(String),   // 回答内容
+    Usage(TokenUsage), // 结束前单独返回的 token 用量
+}
+
+fn token_usage(usage: &Usage) -> TokenUsage {
+    TokenUsage {
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+    }
+}
+
+/// 解析一个 SSE 数据块，按顺序返回其中的思考过程、回答片段和 token 用量
+fn parse_stream_chunk(chunk_str: &str) -> Vec<StreamPiece> {
+    let mut pieces = Vec::new();
+    for line in chunk_str.lines() {
+        let Some(data) = line.strip_prefix("data: ") else {
+            continue;
+        };
+        if data == "[DONE]" {
+            break;
+        }
+        let Ok(json_data) = serde_json::from_str::<ChatCompletionStreamResponse>(data) else {
+            continue;
+        };
+        if let Some(usage) = &json_data.usage {
+            pieces.push(StreamPiece::Usage(token_usage(usage)));
+        }
+        let Some(choice) = json_data.choices.first() else {
+            continue;
+        };
+        // 有 finish_reason 表示回答结束，之后只会收到 token 用量
+        if choice.finish_reason.is_some() {
+            println!("Stream finished with reason: {:?}", choice.finish_reason);
+            continue;
+        }
+        if let Some(delta) = &choice.delta {
+            if let Some(reasoning_content) = delta.reasoning_content.as_ref().filter(|r| !r.is_empty()) {
+                pieces.push(StreamPiece::Reasoning(reasoning_content.clone()));
+            }
+            if let Some(content) = delta.content.as_ref().filter(|c| !c.is_empty()) {
+                pieces.push(StreamPiece::Content(content.clone()));
+            }
+        }
     }
+    pieces
 }
 
 /// 解析流式响应块并通过回调函数返回文本
+///
+/// 推理模型的思考过程发送给 reasoning，不计入回答；回答内容通过 callback 返回
 async fn process_deepseek_stream_response<F>(
     response: reqwest::Response,
     mut callback: F,
+    reasoning: ReasoningSink,
+    usage: UsageRecorder,
 ) -> Result<String, Box<dyn Error>>
 where
     F: FnMut(String) + Send + 'static,
@@ -118,6 +247,7 @@ where
     let mut stream = response.bytes_stream();
     let mut full_response = String::new();
     let mut has_received_data = false;
+    let mut has_reasoning = false;
 
     println!("Starting DeepSeek stream processing...");
 
@@ -126,44 +256,19 @@ where
             Ok(chunk) => {
                 has_received_data = true;
                 let chunk_str = String::from_utf8_lossy(&chunk);
-                println!("Received raw chunk: {}", chunk_str);
 
                 // 处理 SSE 格式的流式响应
-                for line in chunk_str.lines() {
-                    if line.starts_with("data: ") {
-                        let data = &line[6..]; // 移除 "data: " 前缀
-                        
-                        if data == "[DONE]" {
-                            break;
-                        }                        if let Ok(json_data) = serde_json::from_str::<ChatCompletionStreamResponse>(data) {
-                            if let Some(choice) = json_data.choices.first() {
-                                // 检查是否有 finish_reason，如果有则表示流结束，不处理 tokens 信息
-                                if choice.finish_reason.is_some() {
-                                    println!("Stream finished with reason: {:?}", choice.finish_reason);
-                                    break;
-                                }
-                                
-                                if let Some(delta) = &choice.delta {
-                                    // 处理推理内容（思维链）
-                                    if let Some(reasoning_content) = &delta.reasoning_content {
-                                        if !reasoning_content.is_empty() {
-                                            println!("Extracted reasoning content: {}", reasoning_content);
-                                            callback(reasoning_content.clone());
-                                            full_response.push_str(reasoning_content);
-                                        }
-                                    }
-                                    
-                                    // 处理最终回复内容
-                                    if let Some(content) = &delta.content {
-                                        if !content.is_empty() {
-                                            println!("Extracted content: {}", content);
-                                            callback(content.clone());
-                                            full_response.push_str(content);
-                                        }
-                                    }
-                                }
-                            }
+                for piece in parse_stream_chunk(&chunk_str) {
+                    match piece {
+                        StreamPiece::Reasoning(text) => {
+                            has_reasoning = true;
+                            reasoning.send(text);
                         }
+                        StreamPiece::Content(text) => {
+                            callback(text.clone());
+                            full_response.push_str(&text);
+                        }
+                        StreamPiece::Usage(request_usage) => usage.record(request_usage),
                     }
                 }
             }
@@ -174,14 +279,16 @@ where
         }
     }
 
-    if full_response.is_empty() && has_received_data {
+    if full_response.is_empty() && has_reasoning {
+        return Err("模型只返回了思考过程，没有给出回答".into());
+    } else if full_response.is_empty() && has_received_data {
         println!("Warning: Received data but couldn't extract text");
         return Ok("(Response received but requires different format parsing)".to_string());
     } else if full_response.is_empty() {
         return Err("No text generated from the stream".into());
     }
 
-    println!("Completed DeepSeek stream response: {}", full_response);
+    println!("Completed DeepSeek stream response: {} chars", full_response.chars().count());
     Ok(full_response)
 }
 
@@ -198,8 +305,13 @@ impl DeepSeekChat {
             top_p: Some(0.95),
             frequency_penalty: Some(0.0),
             presence_penalty: Some(0.0),
+            stop: None,
+            cot_disabled: false,
+            max_tool_iterations: default_max_tool_iterations(),
             last_prompt: None,
             tools: Vec::new(),
+            reasoning_sink: ReasoningSink::default(),
+            usage: UsageRecorder::default(),
             chat_id: 0,
             title: None,
             time: "".to_string(),
@@ -212,104 +324,43 @@ impl DeepSeekChat {
         chat
     }
 
+    /// 设置推理模型思考过程的接收者
+    pub fn set_reasoning_sink(&mut self, sink: ReasoningSink) {
+        self.reasoning_sink = sink;
+    }
+
+    /// 取出上次回答各次请求返回的 token 用量之和
+    pub fn take_usage(&self) -> Option<TokenUsage> {
+        self.usage.take()
+    }
+
     // 检查是否为推理模型
     fn is_reasoning_model(&self) -> bool {
         self.model == "deepseek-reasoner"
-    }    fn build_system_instruction(&self) -> String {
-        // 推理模型不需要 COT 提示词，直接返回基础系统提示
-        if self.is_reasoning_model() {
+    }
+
+    /// 本次请求使用的 COT 模板，对话关闭 COT 或设置中 COT 详略为 off 时为 None
+    fn active_cot(&self) -> Option<&'static str> {
+        if self.cot_disabled {
+            None
+        } else {
+            template::active_cot()
+        }
+    }
+
+    /// 本次请求的系统提示是否包含 COT 模板，推理模型不使用 COT 模板
+    pub(crate) fn uses_cot(&self) -> bool {
+        !self.is_reasoning_model() && self.active_cot().is_some()
+    }
+
+    fn build_system_instruction(&self) -> String {
+        // 推理模型和关闭 COT 的对话不需要 COT 提示词，直接返回基础系统提示
+        if self.is_reasoning_model() || self.active_cot().is_none() {
             return self.system_prompt.clone();
         }
         
         // 非推理模型使用 COT 模板
-        return cot_template(&[
-            TypesetInfo {
-                name: "mermaid_render".to_string(),
-                description: "render mermaid graph".to_string(),
-                detail: "render mermaid graph by using mermaid.js renderer, should write down CORRECT mermaid code for sucessfully rendering".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("mermaid_code".to_string(), Value::String("mermaid code which you what to render".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "pintora_render".to_string(),
-                description: "render pintora graph".to_string(),
-                detail: "render pintora graph by using pintora.js renderer, should write down CORRECT pintora code for sucessfully rendering".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("diagram".to_string(), Value::String("pintora code which you what to render".to_string()));
-                    args.insert("scale".to_string(), Value::Number(1.into()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "interactive_button".to_string(),
-                description: "show a interactive button signed `message`, when user clicks on it, then you will receive `command` text".to_string(),
-                detail: r#"show a interactive button signed `message`, when user clicks on it, then you will receive `command` text
-    It is a good way for you to show a button for user to click when user learns something new
-    - `message`: the text which you want to show on the button
-    - `command`: the text which will be sent when user clicks the button
-    > You can use it to give some hints to user, like "click me to send `Hello!`" or "click me to send `Bye!`"
-    "#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("message".to_string(), Value::String("click me to send `Hello!`".to_string()));
-                    args.insert("command".to_string(), Value::String("Hello!".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "typst_render".to_string(),
-                description: "render typst document".to_string(),
-                detail: "render typst document by using typst.ts renderer, should write down CORRECT typst code for successfully rendering mathematical formulas, diagrams, and professional documents".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("typst_code".to_string(), Value::String("typst code which you want to render".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "html_render".to_string(),
-                description: "render HTML content in a sandboxed environment".to_string(),
-                detail: r#"render HTML content safely in a sandboxed iframe, which tolerates malformed HTML without affecting the page layout
-    - `html`: the HTML content to render
-    - `title`: optional title for the HTML container (default: "HTML内容")
-    - `show_border`: optional boolean to show/hide border (default: true)"#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("html".to_string(), Value::String("<div>Your HTML content here</div>".to_string()));
-                    args.insert("title".to_string(), Value::String("HTML内容".to_string()));
-                    args.insert("show_border".to_string(), Value::Bool(true));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "katex_render".to_string(),
-                description: "render mathematical formulas".to_string(),
-                detail: "render mathematical formulas by using katex renderer, should write down CORRECT latex code for successfully rendering mathematical formulas. No need to wrap by `$`. Be careful with backslashes: use double backslashes (\\\\) for commands like \\\\alpha instead of \\alpha, as single backslashes may be interpreted as escape characters (e.g., \\n becomes a newline).".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("katex_code".to_string(), Value::String("Katex code which you want to render. No need to wrap by `$`. Remember to escape backslashes properly.".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "wolfram_alpha_compute".to_string(),
-                description: "compute queries using Wolfram Alpha".to_string(),
-                detail: r#"compute mathematical expressions, solve equations, convert units, and answer factual questions using Wolfram Alpha's computational engine
-    - `query`: the query to compute (e.g., mathematical expressions, word problems, unit conversions)
-    - `image_only`: optional boolean to return only image result (default: false)
-    - `format`: optional format for results, only `html` avaliable"#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("query".to_string(), Value::String("1+1".to_string()));
-                    args.insert("image_only".to_string(), Value::Bool(false));
-                    args.insert("format".to_string(), Value::String("html".to_string()));
-                    args                },
-            },
-        ], &self.system_prompt);
+        return cot_template(&enabled_typeset_tools(), &self.system_prompt);
     }
 
     /// 构建请求体 - 修改为使用 DeepSeekRequest
@@ -332,6 +383,7 @@ impl DeepSeekChat {
                 name: None,
                 tool_calls: None,
                 tool_call_id: None,
+                prefix: None,
             });
         }
 
@@ -341,16 +393,19 @@ impl DeepSeekChat {
         }
 
         // 添加COT指令
-        all_messages.push(DeepSeekMessage {
-            role: "system".to_string(),
-            content: format!(
-                "# I have double checked that my basic COT settings are as follows:\n{}\nNow I will answer the user's request.\n",
-                COT
-            ),
-            name: None,
-            tool_calls: None,
-            tool_call_id: None,
-        });
+        if let Some(cot) = self.active_cot() {
+            all_messages.push(DeepSeekMessage {
+                role: "system".to_string(),
+                content: format!(
+                    "# I have double checked that my basic COT settings are as follows:\n{}\nNow I will answer the user's request.\n",
+                    cot
+                ),
+                name: None,
+                tool_calls: None,
+                tool_call_id: None,
+                prefix: None,
+            });
+        }
 
         DeepSeekRequest {
             model: self.model.clone(),
@@ -360,6 +415,7 @@ impl DeepSeekChat {
             top_p: self.top_p,
             frequency_penalty: self.frequency_penalty,
             presence_penalty: self.presence_penalty,
+            stop: self.stop.clone(),
             tools: tools.map(|t| t.to_vec()),
             tool_choice: if tools.is_some() && !tools.unwrap().is_empty() {
                 Some("auto".to_string())
@@ -367,9 +423,56 @@ impl DeepSeekChat {
                 None
             },
             stream: Some(stream),
+            stream_options: stream.then_some(StreamOptions { include_usage: true }),
         }
     }
 
+    /// 构建前缀续写请求：对话末尾追加以 prefix 开头的助手消息，且不附加 COT 指令，
+    /// 保证最后一条消息就是需要续写的助手消息
+    fn build_prefix_request(&self, prefix: &str) -> DeepSeekRequest {
+        let mut chat = self.clone();
+        chat.cot_disabled = true;
+        let mut request = chat.build_request_body(&self.messages, None, false);
+        request.messages.push(DeepSeekMessage {
+            role: "assistant".to_string(),
+            content: prefix.to_string(),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            prefix: Some(true),
+        });
+        request
+    }
+
+    /// 前缀续写（Beta）：让模型从 prefix 处继续生成回复，用于约束回复的开头或格式。
+    /// 返回包含前缀的完整回复，并将其作为助手消息追加到上下文中
+    pub async fn complete_with_prefix(
+        &mut self,
+        api_key: ApiKey,
+        prefix: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        if api_key.key_type != ApiKeyType::DeepSeek {
+            return Err("Invalid API key type for DeepSeek".into());
+        }
+
+        // 前缀续写只在 Beta 接口上提供
+        let mut beta = self.clone();
+        beta.base_url = format!("{}/beta", self.base_url.trim_end_matches('/'));
+        beta.cot_disabled = true;
+        let request_body = self.build_prefix_request(prefix);
+        let continuation = beta.stream_request(request_body, &api_key.key, |_| {}).await?;
+
+        let response = format!("{}{}", prefix, continuation);
+        self.messages.push(ChatCompletionMessage {
+            role: MessageRole::assistant,
+            content: Content::Text(response.clone()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        });
+        Ok(response)
+    }
+
     /// 核心流式处理函数 - 修改为使用 DeepSeekRequest
     async fn stream_request<F>(
         &self,
@@ -382,7 +485,6 @@ impl DeepSeekChat {
     {
         let client = reqwest::Client::new();
         let url = build_deepseek_url(&self.base_url, "chat/completions");
-        println!("request_body: {}", serde_json::to_string_pretty(&request_body).unwrap_or_default());        
 
         let response = client
             .post(&url)
@@ -399,9 +501,18 @@ impl DeepSeekChat {
         }
 
         if request_body.stream == Some(true) {
-            process_deepseek_stream_response(response, callback).await
+            process_deepseek_stream_response(
+                response,
+                callback,
+                self.reasoning_sink.clone(),
+                self.usage.clone(),
+            )
+            .await
         } else {
             let response_json: ChatCompletionResponse = response.json().await?;
+            if let Some(usage) = &response_json.usage {
+                self.usage.record(token_usage(usage));
+            }
             let text = response_json
                 .choices
                 .first()
@@ -410,8 +521,10 @@ impl DeepSeekChat {
                 })
                 .unwrap_or_default();
 
-            // 应用模板提取
-            let final_text = if let Some(extracted) = template::extract_response(&text) {
+            // 应用模板提取（关闭 COT 时回复中没有模板标记）
+            let final_text = if self.active_cot().is_none() {
+                text
+            } else if let Some(extracted) = template::extract_response(&text) {
                 extracted
             } else {
                 text
@@ -492,7 +605,10 @@ impl DeepSeekChat {
         }
 
         let response_json: ChatCompletionResponse = response.json().await?;
-        
+        if let Some(usage) = &response_json.usage {
+            self.usage.record(token_usage(usage));
+        }
+
         if let Some(choice) = response_json.choices.first() {
             let text_response = match &choice.message.content {
                 Content::Text(text) => Some(text.clone()),
@@ -518,50 +634,54 @@ impl DeepSeekChat {
             + Send
             + Sync,
     ) -> Result<String, Box<dyn Error>> {
-        let (initial_response_text, tool_calls) =
-            self.parse_tool_calls(api_key, messages, tools).await?;
-
-        if !tool_calls.is_empty() && !self.check_if_skip_tool_call(&tool_calls) {
-            let mut current_messages = messages.to_vec();
+        run_tool_loop(
+            messages,
+            self.max_tool_iterations,
+            |current_messages| async move {
+                let (text, tool_calls) =
+                    self.parse_tool_calls(api_key, &current_messages, tools).await?;
+                if tool_calls.is_empty() || self.check_if_skip_tool_call(&tool_calls) {
+                    return Ok(ToolTurn {
+                        text,
+                        calls: Vec::new(),
+                        assistant_message: None,
+                    });
+                }
 
-            // 添加模型的回复
-            if let Some(text) = initial_response_text {
-                current_messages.push(ChatCompletionMessage {
+                // 添加模型的回复
+                let assistant_message = text.clone().map(|text| ChatCompletionMessage {
                     role: MessageRole::assistant,
                     content: Content::Text(text),
                     name: None,
                     tool_calls: Some(tool_calls.clone()),
                     tool_call_id: None,
                 });
-            }
-
+                Ok(ToolTurn {
+                    text,
+                    calls: tool_calls,
+                    assistant_message,
+                })
+            },
             // 执行工具调用
-            for tool_call in &tool_calls {
+            |tool_call: ToolCall| {
                 let args: HashMap<String, Value> = if let Some(args_str) = &tool_call.function.arguments {
                     serde_json::from_str(args_str).unwrap_or_default()
                 } else {
                     HashMap::new()
                 };
-
-                let result = tool_call_processor(tool_call.function.name.clone(), args).await?;
-
-                current_messages.push(ChatCompletionMessage {
-                    role: MessageRole::tool,
-                    content: Content::Text(result),
-                    name: Some(tool_call.function.name.clone()),
-                    tool_calls: None,
-                    tool_call_id: Some(tool_call.id.clone()),
-                });
-            }
-
-            return self
-                .chat_deepseek_simple(api_key, &current_messages, Some(tools))
-                .await;
-        } else if let Some(text) = initial_response_text {
-            return Ok(text);
-        } else {
-            return Err("DeepSeek response contained neither text nor tool calls.".into());
-        }
+                let result = tool_call_processor(tool_call.function.name.clone(), args);
+                async move {
+                    Ok(ChatCompletionMessage {
+                        role: MessageRole::tool,
+                        content: Content::Text(result.await?),
+                        name: Some(tool_call.function.name),
+                        tool_calls: None,
+                        tool_call_id: Some(tool_call.id),
+                    })
+                }
+            },
+        )
+        .await
     }
 
     /// 检查是否需要跳过工具调用
@@ -655,6 +775,13 @@ impl AIChat for DeepSeekChat {
                         .map_err(|e| format!("Invalid presence_penalty value: {}", e))?,
                 )
             }
+            "stop" => self.stop = parse_stop_sequences(&value)?,
+            "cot" => {
+                self.cot_disabled = !value
+                    .parse::<bool>()
+                    .map_err(|e| format!("Invalid cot value: {}", e))?
+            }
+            "max_tool_iterations" => self.max_tool_iterations = parse_max_tool_iterations(&value)?,
             "model" => self.model = value,
             _ => return Err(format!("Unknown parameter: {}", key).into()),
         }
@@ -761,9 +888,14 @@ impl AIChat for DeepSeekChat {
                     ChatMessageType::User => MessageRole::user,
                     ChatMessageType::Assistant => MessageRole::assistant,
                     ChatMessageType::System => MessageRole::system,
+                    ChatMessageType::Tool => MessageRole::tool,
                 },
                 content: Content::Text(msg.content.clone()),
-                name: Some(msg.time.clone()),
+                // 工具消息的 name 为工具名称，其余消息借用 name 保存时间
+                name: match msg.msgtype {
+                    ChatMessageType::Tool => msg.tool_name.clone(),
+                    _ => Some(msg.time.clone()),
+                },
                 tool_calls: None,
                 tool_call_id: None,
             })
@@ -779,23 +911,128 @@ impl AIChat for DeepSeekChat {
             content: self
                 .messages
                 .iter()
-                .map(|msg| ChatMessage {
-                    msgtype: match msg.role {
+                .map(|msg| {
+                    let msgtype = match msg.role {
                         MessageRole::user => ChatMessageType::User,
                         MessageRole::assistant => ChatMessageType::Assistant,
                         MessageRole::system => ChatMessageType::System,
+                        MessageRole::tool => ChatMessageType::Tool,
                         _ => ChatMessageType::User,
-                    },
-                    content: match &msg.content {
+                    };
+                    let content = match &msg.content {
                         Content::Text(text) => text.clone(),
-                    },
-                    time: msg.name.clone().unwrap_or_default(),
+                    };
+                    ChatMessage {
+                        time: match msg.role {
+                            MessageRole::tool => String::new(),
+                            _ => msg.name.clone().unwrap_or_default(),
+                        },
+                        tool_name: match msg.role {
+                            MessageRole::tool => msg.name.clone(),
+                            _ => None,
+                        },
+                        ..ChatMessage::new(msgtype, content)
+                    }
                 })
                 .collect(),
             time: self.time.clone(),
             title: self.title.clone(),
-            id: self.chat_id,
+            ..ChatState::empty_chat(self.chat_id)
         };
         Ok(chat_history)
     }
-}
\ No newline at end of file
+}
+
+/// 获取当前账号可用的 DeepSeek 模型列表
+pub async fn fetch_available_models(api_key: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    println!("🌐 [DEBUG] Starting to fetch model list from DeepSeek API");
+
+    let client = reqwest::Client::new();
+    let url = build_deepseek_url(DEEPSEEK_API_BASE_URL, "models");
+
+    println!("📡 [DEBUG] Sending GET request to: {}", url);
+
+    let response = client
+        .get(&url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .send()
+        .await?;
+
+    let status = response.status();
+    println!("📊 [DEBUG] API response status: {}", status);
+
+    if !status.is_success() {
+        let error_text = response.text().await?;
+        println!("❌ [DEBUG] API request failed: {} - {}", status, error_text);
+        return Err(format!("Failed to fetch models ({}): {}", status, error_text).into());
+    }
+
+    let response_json: Value = response.json().await?;
+
+    // 返回格式与 OpenAI 兼容: { "object": "list", "data": [{ "id": "deepseek-chat", ... }] }
+    let models: Vec<String> = response_json
+        .get("data")
+        .and_then(|d| d.as_array())
+        .map(|data| {
+            data.iter()
+                .filter_map(|model| model.get("id").and_then(|id| id.as_str()))
+                .map(|id| id.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    println!("🎯 [DEBUG] DeepSeek model list ({} models): {:?}", models.len(), models);
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_prefix_request() {
+        let mut chat = DeepSeekChat::new();
+        chat.system_prompt = "系统提示".to_string();
+        chat.messages.push(ChatCompletionMessage {
+            role: MessageRole::user,
+            content: Content::Text("用 JSON 列出三种排序算法".to_string()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        });
+
+        let request = chat.build_prefix_request("```json\n");
+        let body = serde_json::to_value(&request).unwrap();
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2]["role"], "assistant");
+        assert_eq!(messages[2]["content"], "```json\n");
+        assert_eq!(messages[2]["prefix"], true);
+        // 普通消息不带 prefix 字段，续写时也不使用 COT 模板
+        assert!(messages[1].get("prefix").is_none());
+        assert!(!messages[0]["content"].as_str().unwrap().contains(template::COT));
+        assert!(!chat.cot_disabled);
+    }
+
+    #[test]
+    fn test_parse_reasoner_stream_chunk() {
+        let chunk = concat!(
+            "data: {\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"deepseek-reasoner\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":null,\"reasoning_content\":\"先计算 1+1\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"deepseek-reasoner\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"答案是 2\",\"reasoning_content\":null},\"finish_reason\":null}]}\n\n",
+            "data: {\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"deepseek-reasoner\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"deepseek-reasoner\",\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":30,\"total_tokens\":42}}\n\n",
+            "data: [DONE]\n",
+        );
+        assert_eq!(
+            parse_stream_chunk(chunk),
+            vec![
+                StreamPiece::Reasoning("先计算 1+1".to_string()),
+                StreamPiece::Content("答案是 2".to_string()),
+                StreamPiece::Usage(TokenUsage {
+                    prompt_tokens: 12,
+                    completion_tokens: 30,
+                }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+
+// 从后端错误信息中提取 HTTP 状态码，如 "API request failed (429 Too Many Requests)"
+static STATUS_CODE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:\(|status )([1-5]\d\d)\b").unwrap());
+
+// 网络层错误的特征文本（reqwest 和流式读取产生的错误）
+const NETWORK_PATTERNS: [&str; 8] = [
+    "error sending request",
+    "connection",
+    "timed out",
+    "timeout",
+    "dns error",
+    "stream error",
+    "broken pipe",
+    "network",
+];
+
+/// 生成回复失败的错误类别
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AiErrorCode {
+    Network,        // 网络连接失败或中断，通常重试即可
+    Auth,           // API 密钥无效或无权限
+    RateLimit,      // 请求过于频繁或额度不足
+    Server,         // 服务端错误
+    ContentBlocked, // 内容被安全策略拦截
+    Config,         // 本地配置错误，如缺少 API 密钥
+    Unknown,
+}
+
+/// 生成回复失败的错误，错误类别和信息分开发送给前端
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AiError {
+    pub code: AiErrorCode,
+    pub message: String,
+}
+
+impl AiError {
+    pub fn new(code: AiErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// 根据后端返回的错误信息判断错误类别
+    pub fn classify(message: impl Into<String>) -> Self {
+        let message = message.into();
+        let lower = message.to_lowercase();
+        let code = match status_code(&message) {
+            Some(401 | 403) => AiErrorCode::Auth,
+            Some(429) => AiErrorCode::RateLimit,
+            Some(500..=599) => AiErrorCode::Server,
+            Some(_) => AiErrorCode::Unknown,
+            None if lower.contains("blocked") || lower.contains("safety") => {
+                AiErrorCode::ContentBlocked
+            }
+            None if lower.contains("invalid api key") => AiErrorCode::Config,
+            None if NETWORK_PATTERNS.iter().any(|p| lower.contains(p)) => AiErrorCode::Network,
+            None => AiErrorCode::Unknown,
+        };
+        Self { code, message }
+    }
+
+    pub fn is_network(&self) -> bool {
+        self.code == AiErrorCode::Network
+    }
+
+    /// 是否为所选模型不存在或当前密钥不可用的错误，如 Gemini 返回 404 "models/... is not found"，
+    /// DeepSeek 返回 "Model Not Exist"；换用后端的默认模型可能成功
+    pub fn is_model_not_found(&self) -> bool {
+        let lower = self.message.to_lowercase();
+        lower.contains("model not exist")
+            || lower.contains("model_not_found")
+            || (lower.contains("model")
+                && (status_code(&self.message) == Some(404)
+                    || lower.contains("not found")
+                    || lower.contains("does not exist")))
+    }
+
+    /// 是否为服务不可用导致的错误（密钥、限流、网络、服务端），换用其他后端可能成功；
+    /// 内容被拦截等与请求内容有关的错误不属于此类
+    pub fn is_infrastructure(&self) -> bool {
+        matches!(
+            self.code,
+            AiErrorCode::Network
+                | AiErrorCode::Auth
+                | AiErrorCode::RateLimit
+                | AiErrorCode::Server
+                | AiErrorCode::Config
+        )
+    }
+}
+
+fn status_code(message: &str) -> Option<u16> {
+    STATUS_CODE_RE
+        .captures(message)
+        .and_then(|captures| captures[1].parse::<u16>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            (
+                "API request failed (401 Unauthorized): invalid key",
+                AiErrorCode::Auth,
+            ),
+            (
+                "Request failed with status 429 Too Many Requests: slow down",
+                AiErrorCode::RateLimit,
+            ),
+            (
+                "API request failed (503 Service Unavailable): overloaded",
+                AiErrorCode::Server,
+            ),
+            (
+                "error sending request for url (https://api.deepseek.com/chat/completions)",
+                AiErrorCode::Network,
+            ),
+            (
+                "Stream error: error decoding response body",
+                AiErrorCode::Network,
+            ),
+            (
+                "Content blocked due to safety concerns.",
+                AiErrorCode::ContentBlocked,
+            ),
+            ("Invalid API key type for Gemini", AiErrorCode::Config),
+            ("No text generated from the stream", AiErrorCode::Unknown),
+        ];
+        for (message, code) in cases {
+            assert_eq!(AiError::classify(message).code, code, "{}", message);
+        }
+        assert!(AiError::classify("API request failed (429 Too Many Requests)").is_infrastructure());
+        assert!(!AiError::classify("Content blocked due to safety concerns.").is_infrastructure());
+    }
+
+    #[test]
+    fn test_is_model_not_found() {
+        assert!(AiError::classify(
+            "API request failed (404 Not Found): models/gemini-1.0-pro is not found for API version v1beta"
+        )
+        .is_model_not_found());
+        assert!(AiError::classify("API request failed (400 Bad Request): Model Not Exist").is_model_not_found());
+        assert!(!AiError::classify("API request failed (404 Not Found): page missing").is_model_not_found());
+        assert!(!AiError::classify("API request failed (429 Too Many Requests)").is_model_not_found());
+    }
+}
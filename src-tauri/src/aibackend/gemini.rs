@@ -1,8 +1,16 @@
-use crate::aibackend::interface::AIChat;
+use crate::aibackend::cost::UsageRecorder;
+use crate::aibackend::image_resize;
+use crate::aibackend::interface::{parse_stop_sequences, AIChat};
 use crate::aibackend::openai_types::{
     ChatCompletionMessage, Content, JSONSchemaType, MessageRole, Tool,
 };
-use crate::aibackend::template::{self, gemini_chat_instruction, COT};
+use crate::aibackend::template::{self, gemini_chat_instruction};
+use crate::aibackend::tool_loop::{
+    default_max_tool_iterations, parse_max_tool_iterations, run_tool_loop, ToolTurn,
+};
+use crate::history_msg::chat_state::ChatState;
+use crate::history_msg::history::TokenUsage;
+use crate::logging::redact::mask_api_key;
 use crate::{ChatHistory, ChatMessage, ChatMessageType};
 use base64::Engine;
 use futures_util::StreamExt;
@@ -13,10 +21,14 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
 
 use super::apikey::{ApiKey, ApiKeyType};
-use super::template::{cot_template, TypesetInfo};
+use super::template::{cot_template, enabled_typeset_tools};
 
 // --- Enums and Structs ---
 
@@ -38,6 +50,50 @@ pub enum HarmBlockThreshold {
     BlockSome,
 }
 
+impl HarmCategory {
+    /// 所有需要配置的安全类别
+    const ALL: [HarmCategory; 4] = [
+        HarmCategory::HateSpeech,
+        HarmCategory::Harassment,
+        HarmCategory::SexuallyExplicit,
+        HarmCategory::DangerousContent,
+    ];
+
+    fn as_api_str(&self) -> &'static str {
+        match self {
+            HarmCategory::HateSpeech => "HARM_CATEGORY_HATE_SPEECH",
+            HarmCategory::Harassment => "HARM_CATEGORY_HARASSMENT",
+            HarmCategory::SexuallyExplicit => "HARM_CATEGORY_SEXUALLY_EXPLICIT",
+            HarmCategory::DangerousContent => "HARM_CATEGORY_DANGEROUS_CONTENT",
+        }
+    }
+}
+
+impl HarmBlockThreshold {
+    fn as_api_str(&self) -> &'static str {
+        match self {
+            HarmBlockThreshold::BlockNone => "BLOCK_NONE",
+            HarmBlockThreshold::BlockOnly => "BLOCK_ONLY_HIGH",
+            HarmBlockThreshold::BlockSome => "BLOCK_MEDIUM_AND_ABOVE",
+            HarmBlockThreshold::BlockMost => "BLOCK_LOW_AND_ABOVE",
+        }
+    }
+
+    /// 将设置中的安全等级 (none/low/default) 映射为阈值
+    pub fn from_safety_level(level: &str) -> Option<Self> {
+        match level {
+            "none" => Some(HarmBlockThreshold::BlockNone),
+            "low" => Some(HarmBlockThreshold::BlockOnly),
+            "default" => Some(HarmBlockThreshold::BlockSome),
+            _ => None,
+        }
+    }
+}
+
+fn default_safety_threshold() -> HarmBlockThreshold {
+    HarmBlockThreshold::BlockNone
+}
+
 /// 用于Gemini API完成原因的枚举
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub enum FinishReason {
@@ -55,6 +111,13 @@ struct ToolCall {
     args: HashMap<String, Value>,
 }
 
+/// 随用户消息发送的图片（base64 编码）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageAttachment {
+    pub mime_type: String,
+    pub data: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GeminiChat {
     base_url: String, // Note: This base_url seems unused for direct Gemini calls
@@ -65,6 +128,18 @@ pub struct GeminiChat {
     max_tokens: Option<u32>,
     top_p: Option<f32>,
     top_k: Option<u32>,
+    #[serde(default)]
+    stop: Option<Vec<String>>, // 停止序列
+    #[serde(default)]
+    cot_disabled: bool, // 不使用 COT 模板和 COT 指令
+    #[serde(default = "default_max_tool_iterations")]
+    max_tool_iterations: usize, // 多轮工具调用的最大轮数
+    #[serde(skip)]
+    pending_images: Vec<ImageAttachment>, // 仅随本轮用户消息发送的图片，不保存到后端状态
+    #[serde(skip)]
+    attachment_parts: Vec<Value>, // 发送前由 pending_images 生成的请求片段，较大的图片为 Files API 的 fileData 引用
+    #[serde(skip)]
+    usage: UsageRecorder, // 本次回答各次请求返回的 token 用量，不随对话保存
     last_prompt: Option<String>,
     tools: Vec<Tool>, // Consider if this needs to be stored if tools are passed per call
     
@@ -74,6 +149,10 @@ pub struct GeminiChat {
     // URL 上下文工具配置
     url_context_enabled: bool, // 是否启用 URL 上下文工具
 
+    // 安全过滤阈值，应用于所有安全类别
+    #[serde(default = "default_safety_threshold")]
+    safety_threshold: HarmBlockThreshold,
+
     chat_id: u32,  // 用于唯一标识聊天会话
     title: Option<String>, // 聊天标题
     time: String,  // 聊天时间
@@ -81,23 +160,19 @@ pub struct GeminiChat {
 
 // --- Constants ---
 const GEMINI_API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+// 通过请求头传递 API 密钥，避免密钥出现在 URL 及包含 URL 的错误信息中
+const GEMINI_API_KEY_HEADER: &str = "x-goog-api-key";
 
 // --- Helper Functions ---
 
-/// 构建 Gemini API URL
-fn build_gemini_url(model: &str, api_key: &str, action: &str) -> String {
-    format!(
-        "{}/{}:{}?key={}",
-        GEMINI_API_BASE_URL, model, action, api_key
-    )
+/// 构建 Gemini API URL（API 密钥通过请求头传递）
+fn build_gemini_url(model: &str, action: &str) -> String {
+    format!("{}/{}:{}", GEMINI_API_BASE_URL, model, action)
 }
 
 /// 构建 Gemini API URL，支持流式传输
-fn build_gemini_stream_url(model: &str, api_key: &str) -> String {
-    format!(
-        "{}/{}:streamGenerateContent?key={}",
-        GEMINI_API_BASE_URL, model, api_key
-    )
+fn build_gemini_stream_url(model: &str) -> String {
+    format!("{}/{}:streamGenerateContent", GEMINI_API_BASE_URL, model)
 }
 
 /// 转换 OpenAI 工具为 Gemini 格式
@@ -145,6 +220,16 @@ fn convert_tools_to_gemini_format(tools: &[Tool]) -> Value {
     })
 }
 
+/// 读取响应中的 usageMetadata，思考过程消耗的 token 按输出计费；流式响应中每个片段的用量是累计值
+fn parse_gemini_usage(response_json: &Value) -> Option<TokenUsage> {
+    let metadata = response_json.get("usageMetadata")?;
+    let count = |key: &str| metadata.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    Some(TokenUsage {
+        prompt_tokens: count("promptTokenCount"),
+        completion_tokens: count("candidatesTokenCount") + count("thoughtsTokenCount"),
+    })
+}
+
 /// 解析 Gemini API 响应，检查安全并提取文本
 fn parse_gemini_response(response_json: &Value) -> Result<String, Box<dyn Error>> {
     if let Some(candidates) = response_json.get("candidates").and_then(|c| c.as_array()) {
@@ -264,6 +349,7 @@ fn parse_gemini_tool_call_response(
 async fn process_stream_response<F>(
     response: reqwest::Response,
     mut callback: F,
+    usage: &UsageRecorder,
 ) -> Result<String, Box<dyn Error>>
 where
     F: FnMut(String) + Send + 'static,
@@ -271,6 +357,7 @@ where
     let mut stream = response.bytes_stream();
     let mut full_response = String::new();
     let mut has_received_data = false;
+    let mut stream_usage = None; // 最后一个片段中的累计用量
 
     // 字符级解析变量
     let mut buffer = String::new();
@@ -286,7 +373,6 @@ where
             Ok(chunk) => {
                 has_received_data = true; // 标记已收到数据
                 let chunk_str = String::from_utf8_lossy(&chunk);
-                println!("Received raw chunk: {}", chunk_str); // 调试输出
 
                 // 逐字符处理，模拟Python示例中的逻辑
                 for c in chunk_str.chars() {
@@ -328,11 +414,11 @@ where
                     else if buffer_lv == 1 && !buffer.is_empty() {
                         // 补充右花括号，因为右花括号已被读取但未加入buffer
                         buffer.push('}');
-                        println!("Completed buffer: {}", buffer);
 
                         // 解析整个对象
                         match serde_json::from_str::<Value>(&buffer) {
                             Ok(json_value) => {
+                                stream_usage = parse_gemini_usage(&json_value).or(stream_usage);
                                 // 提取文本内容
                                 if let Some(candidates) =
                                     json_value.get("candidates").and_then(|c| c.as_array())
@@ -346,7 +432,6 @@ where
                                                         part.get("text").and_then(|t| t.as_str())
                                                     {
                                                         if !text.is_empty() {
-                                                            println!("Extracted text: {}", text);
                                                             let processed_text = process_reasoning_content(text);
                                                             callback(processed_text.clone());
                                                             full_response.push_str(&processed_text);
@@ -359,7 +444,7 @@ where
                                 }
                             }
                             Err(e) => {
-                                println!("Failed to parse JSON object: {} - Buffer: {}", e, buffer);
+                                println!("Failed to parse JSON object: {} ({} bytes)", e, buffer.len());
                             }
                         }
 
@@ -378,7 +463,7 @@ where
 
     // 处理最后可能未处理完的buffer
     if !buffer.is_empty() {
-        println!("Processing remaining buffer: {}", buffer);
+        println!("Processing remaining buffer: {} bytes", buffer.len());
         // 如果buffer不为空，尝试修复并解析
         if buffer.starts_with('{') && !buffer.ends_with('}') {
             buffer.push('}');
@@ -386,6 +471,7 @@ where
 
         match serde_json::from_str::<Value>(&buffer) {
             Ok(json_value) => {
+                stream_usage = parse_gemini_usage(&json_value).or(stream_usage);
                 // 提取文本与前面相同
                 if let Some(candidates) = json_value.get("candidates").and_then(|c| c.as_array()) {
                     if let Some(candidate) = candidates.get(0) {
@@ -407,6 +493,9 @@ where
             Err(_) => {} // 忽略最后一个不完整对象的解析错误
         }
     }
+    if let Some(stream_usage) = stream_usage {
+        usage.record(stream_usage);
+    }
 
     // 检查响应是否为空，但之前收到过数据
     if full_response.is_empty() && has_received_data {
@@ -418,7 +507,7 @@ where
     }
 
     // 返回完整响应
-    println!("Completed stream response: {}", full_response);
+    println!("Completed stream response: {} chars", full_response.chars().count());
     Ok(full_response)
 }
 
@@ -435,10 +524,17 @@ impl GeminiChat {
             max_tokens: Some(8192 * 2), // 设置默认值
             top_p: Some(0.95),      // 设置默认值
             top_k: Some(40),        // 设置默认值
+            stop: None,
+            cot_disabled: false,
+            max_tool_iterations: default_max_tool_iterations(),
+            pending_images: Vec::new(),
+            attachment_parts: Vec::new(),
+            usage: UsageRecorder::default(),
             last_prompt: None,
             tools: Vec::new(),
             google_search_enabled: false, // 默认禁用 Google 搜索
             url_context_enabled: false, // 默认禁用 URL 上下文工具
+            safety_threshold: default_safety_threshold(), // 默认不过滤
             chat_id: 0,                    // 初始化为0或其他默认值
             title: None, // 初始化标题
             time: "".to_string(),          // 初始化时间
@@ -452,6 +548,45 @@ impl GeminiChat {
         chat
     }
 
+    /// 为下一次提问附加图片：检查图片数量和类型，超过最长边设置的图片先缩小；
+    /// 内联数据超出请求大小限制的图片在发送时通过 Files API 上传
+    pub async fn attach_images(&mut self, images: Vec<ImageAttachment>) -> Result<(), String> {
+        if images.len() > MAX_IMAGES_PER_TURN {
+            return Err(format!("每次最多附加 {} 张图片", MAX_IMAGES_PER_TURN));
+        }
+        if let Some(image) = images.iter().find(|image| !image.mime_type.starts_with("image/")) {
+            return Err(format!("不支持的图片类型: {}", image.mime_type));
+        }
+        // 解码和缩放较耗时，放到阻塞线程中执行
+        self.pending_images =
+            tokio::task::spawn_blocking(move || images.into_iter().map(downscale_attachment).collect())
+                .await
+                .map_err(|e| format!("图片处理失败: {}", e))?;
+        Ok(())
+    }
+
+    /// 取出上次回答各次请求返回的 token 用量之和
+    pub fn take_usage(&self) -> Option<TokenUsage> {
+        self.usage.take()
+    }
+
+    pub fn has_pending_images(&self) -> bool {
+        !self.pending_images.is_empty()
+    }
+
+    /// 将待发送的图片转换为请求片段，内联数据的总大小超出限制后其余图片先上传到 Files API
+    async fn prepare_attachment_parts(&mut self, api_key: &str) -> Result<(), Box<dyn Error>> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut inline_budget = INLINE_DATA_LIMIT;
+        let mut parts = Vec::with_capacity(self.pending_images.len());
+        for image in &self.pending_images {
+            let data = engine.decode(&image.data)?;
+            parts.push(build_attachment_part(api_key, &data, &image.mime_type, &mut inline_budget).await?);
+        }
+        self.attachment_parts = parts;
+        Ok(())
+    }
+
     /// 启用或禁用 Google 搜索依据功能
     pub fn set_google_search_enabled(&mut self, enabled: bool) {
         self.google_search_enabled = enabled;
@@ -470,99 +605,30 @@ impl GeminiChat {
     /// 检查是否启用了 URL 上下文工具功能
     pub fn is_url_context_enabled(&self) -> bool {
         self.url_context_enabled
-    }    fn build_system_instruction(&self) -> String {
-        let typeset_infos = vec![
-            TypesetInfo {
-                name: "mermaid_render".to_string(),
-                description: "render mermaid graph".to_string(),
-                detail: "render mermaid graph by using mermaid.js renderer, should write down CORRECT mermaid code for sucessfully rendering".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("mermaid_code".to_string(), Value::String("mermaid code which you what to render".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "pintora_render".to_string(),
-                description: "render pintora graph".to_string(),
-                detail: "render pintora graph by using pintora.js renderer, should write down CORRECT pintora code for sucessfully rendering".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("diagram".to_string(), Value::String("pintora code which you what to render".to_string()));
-                    args.insert("scale".to_string(), Value::Number(1.into()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "interactive_button".to_string(),
-                description: "show a interactive button signed `message`, when user clicks on it, then you will receive `command` text".to_string(),
-                detail: r#"show a interactive button signed `message`, when user clicks on it, then you will receive `command` text
-    It is a good way for you to show a button for user to click when user learns something new
-    - `message`: the text which you want to show on the button
-    - `command`: the text which will be sent when user clicks the button
-    > You can use it to give some hints to user, like "click me to send `Hello!`" or "click me to send `Bye!`"
-    "#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("message".to_string(), Value::String("click me to send `Hello!`".to_string()));
-                    args.insert("command".to_string(), Value::String("Hello!".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "typst_render".to_string(),
-                description: "render typst document".to_string(),
-                detail: "render typst document by using typst.ts renderer, should write down CORRECT typst code for successfully rendering mathematical formulas, diagrams, and professional documents".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("typst_code".to_string(), Value::String("typst code which you want to render".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "html_render".to_string(),
-                description: "render HTML content in a sandboxed environment".to_string(),
-                detail: r#"render HTML content safely in a sandboxed iframe, which tolerates malformed HTML without affecting the page layout
-    - `html`: the HTML content to render
-    - `title`: optional title for the HTML container (default: "HTML内容")
-    - `show_border`: optional boolean to show/hide border (default: true)"#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("html".to_string(), Value::String("<div>Your HTML content here</div>".to_string()));
-                    args.insert("title".to_string(), Value::String("HTML内容".to_string()));
-                    args.insert("show_border".to_string(), Value::Bool(true));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "katex_render".to_string(),
-                description: "render mathematical formulas".to_string(),
-                detail: "render mathematical formulas by using katex renderer, should write down CORRECT latex code for successfully rendering mathematical formulas. No need to wrap by `$`. Be careful with backslashes: use double backslashes (\\\\) for commands like \\\\alpha instead of \\alpha, as single backslashes may be interpreted as escape characters (e.g., \\n becomes a newline).".to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("katex_code".to_string(), Value::String("Katex code which you want to render. No need to wrap by `$`. Remember to escape backslashes properly.".to_string()));
-                    args
-                },
-            },
-            TypesetInfo {
-                name: "wolfram_alpha_compute".to_string(),
-                description: "compute queries using Wolfram Alpha".to_string(),
-                detail: r#"compute mathematical expressions, solve equations, convert units, and answer factual questions using Wolfram Alpha's computational engine
-    - `query`: the query to compute (e.g., mathematical expressions, word problems, unit conversions)
-    - `image_only`: optional boolean to return only image result (default: false)
-    - `format`: optional format for results, only `html` avaliable"#.to_string(),
-                args: {
-                    let mut args = HashMap::new();
-                    args.insert("query".to_string(), Value::String("1+1".to_string()));
-                    args.insert("image_only".to_string(), Value::Bool(false));
-                    args.insert("format".to_string(), Value::String("html".to_string()));
-                    args
-                },
-            },
-        ];
+    }
+
+    /// 本次请求使用的 COT 模板，对话关闭 COT 或设置中 COT 详略为 off 时为 None
+    fn active_cot(&self) -> Option<&'static str> {
+        if self.cot_disabled {
+            None
+        } else {
+            template::active_cot()
+        }
+    }
+
+    /// 本次请求的系统提示是否包含 COT 模板
+    pub(crate) fn uses_cot(&self) -> bool {
+        self.active_cot().is_some()
+    }
+
+    fn build_system_instruction(&self) -> String {
+        // 关闭 COT 时只使用基础系统提示
+        if self.active_cot().is_none() {
+            return self.system_prompt.clone();
+        }
 
         // 推理模型和非推理模型都使用相同的模板，但推理模型会自动处理 <thought> 标签
-        cot_template(&typeset_infos, &self.system_prompt)
+        cot_template(&enabled_typeset_tools(), &self.system_prompt)
     }
     /// 转换OpenAI格式的消息为Gemini格式的请求体
     fn build_gemini_request_body(
@@ -570,22 +636,37 @@ impl GeminiChat {
         messages: &[ChatCompletionMessage],
         tools: Option<&[Tool]>,
     ) -> Result<Value, Box<dyn Error>> {
+        // 附加的图片随当前（最后一条）用户消息发送
+        let last_user_index = messages
+            .iter()
+            .rposition(|message| message.role == MessageRole::user);
         let mut gemini_messages: Vec<Value> = messages
             .iter()
-            .filter_map(|message| {
+            .enumerate()
+            // Gemini 的 contents 不支持 system 角色，系统消息（如对话摘要）放入 systemInstruction
+            .filter(|(_, message)| message.role != MessageRole::system)
+            .filter_map(|(index, message)| {
                 let Content::Text(content) = &message.content;
                 {
                     if !content.is_empty() {
                         let role = match message.role {
                             MessageRole::assistant => "model",
-                            MessageRole::user => "user",
-                            // Gemini 不直接支持 system 角色在 contents 中，通常通过指令或首条消息模拟
-                            MessageRole::system => return None, // 或者转换为 user/model 消息
+                            MessageRole::user | MessageRole::system => "user",
                             MessageRole::function | MessageRole::tool => "function", // Gemini 使用 function 角色表示工具结果
                         };
 
-                        // 处理工具调用和结果的特殊格式
-                        if message.role == MessageRole::tool {
+                        // 处理工具调用和结果的特殊格式；没有 tool_call_id 的工具消息来自对话记录
+                        // （文本形式的 tool_code 调用），没有对应的 functionCall，作为用户侧的工具结果说明发送
+                        if message.role == MessageRole::tool && message.tool_call_id.is_none() {
+                            Some(json!({
+                                "role": "user",
+                                "parts": [{ "text": format!(
+                                    "[Tool result: {}]\n{}",
+                                    message.name.as_deref().unwrap_or("tool"),
+                                    content
+                                ) }]
+                            }))
+                        } else if message.role == MessageRole::tool {
                             Some(json!({
                                 "role": role,
                                 "parts": [{
@@ -625,9 +706,13 @@ impl GeminiChat {
                                 "parts": [{"functionCall": function_calls[0]}] // Gemini 当前似乎只支持单个 functionCall part
                             }))
                         } else {
+                            let mut parts = vec![json!({ "text": content })];
+                            if Some(index) == last_user_index {
+                                parts.extend(self.attachment_parts.iter().cloned());
+                            }
                             Some(json!({
                                 "role": role,
-                                "parts": [{ "text": content }]
+                                "parts": parts
                             }))
                         }
                     } else {
@@ -645,14 +730,27 @@ impl GeminiChat {
             }),
         ); // 添加系统指令
 
-        gemini_messages.push(
-            json!({
-                "role": "model",
-                "parts": [
-                    { "text": format!("# I have double checked that my basic COT settings are as follows:\n{}\nNow I will answer the user's request.\n", COT) }
-                ]
-            }),
-        ); // 添加用户指令
+        if let Some(cot) = self.active_cot() {
+            gemini_messages.push(
+                json!({
+                    "role": "model",
+                    "parts": [
+                        { "text": format!("# I have double checked that my basic COT settings are as follows:\n{}\nNow I will answer the user's request.\n", cot) }
+                    ]
+                }),
+            ); // 添加用户指令
+        }
+
+        // 所有安全类别使用同一个阈值
+        let safety_settings: Vec<Value> = HarmCategory::ALL
+            .iter()
+            .map(|category| {
+                json!({
+                    "category": category.as_api_str(),
+                    "threshold": self.safety_threshold.as_api_str()
+                })
+            })
+            .collect();
 
         let mut request_body = json!({
             "contents": gemini_messages,
@@ -664,16 +762,15 @@ impl GeminiChat {
                 //"responseMimeType": "text/plain", // 通常不需要
 
             },
-            "safetySettings": [
-                { "category": "HARM_CATEGORY_HATE_SPEECH", "threshold": "BLOCK_NONE" },
-                { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE" },
-                { "category": "HARM_CATEGORY_SEXUALLY_EXPLICIT", "threshold": "BLOCK_NONE" },
-                { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "threshold": "BLOCK_NONE" }
-            ]
-            // systemInstruction 可以在这里添加，如果模型支持
-            // "systemInstruction": { "parts": [{"text": self.system_prompt}]}
+            "safetySettings": safety_settings
         });
 
+        // Gemini 最多支持 5 个停止序列
+        if let Some(stop) = &self.stop {
+            let stop: Vec<&String> = stop.iter().take(5).collect();
+            request_body["generationConfig"]["stopSequences"] = json!(stop);
+        }
+
         if let Some(active_tools) = tools {
             if !active_tools.is_empty() {
                 let tool_config = convert_tools_to_gemini_format(active_tools);
@@ -720,14 +817,16 @@ impl GeminiChat {
                 }
             }
         }
+        let mut system_parts = Vec::new();
         if !self.system_prompt.is_empty() {
-            // Use as_object_mut to modify the existing JSON Value
-            if let Some(obj) = request_body.as_object_mut() {
-                obj.insert(
-                    "systemInstruction".to_string(),
-                    json!({ "parts": [{"text": gemini_chat_instruction()}] }), // Correct Gemini format
-                );
-            }
+            system_parts.push(json!({ "text": gemini_chat_instruction() }));
+        }
+        system_parts.extend(messages.iter().filter_map(|message| {
+            let Content::Text(content) = &message.content;
+            (message.role == MessageRole::system && !content.is_empty()).then(|| json!({ "text": content }))
+        }));
+        if !system_parts.is_empty() {
+            request_body["systemInstruction"] = json!({ "parts": system_parts });
         }
         Ok(request_body)
     }
@@ -735,6 +834,7 @@ impl GeminiChat {
     /// 核心流式处理函数 - 所有Gemini API调用都通过这个接口
     async fn stream_request<F>(
         &self,
+        api_key: &str,
         request_body: Value,
         url: String,
         callback: F,
@@ -744,7 +844,12 @@ impl GeminiChat {
     {
         let client = reqwest::Client::new();
 
-        let response = client.post(&url).json(&request_body).send().await?;
+        let response = client
+            .post(&url)
+            .header(GEMINI_API_KEY_HEADER, api_key)
+            .json(&request_body)
+            .send()
+            .await?;
 
         let status = response.status();
         if !status.is_success() {
@@ -755,10 +860,13 @@ impl GeminiChat {
         // 检查是否是流式响应或普通响应
         if url.contains("stream") {
             // 处理流式响应，使用全局函数而不是重复实现
-            process_stream_response(response, callback).await
+            process_stream_response(response, callback, &self.usage).await
         } else {
             // 处理普通响应（转换为单个回调）
             let response_json: Value = response.json().await?;
+            if let Some(usage) = parse_gemini_usage(&response_json) {
+                self.usage.record(usage);
+            }
             match parse_gemini_response(&response_json) {
                 Ok(text) => {
                     let mut callback_clone = callback;
@@ -787,13 +895,13 @@ impl GeminiChat {
 
         // 根据是否使用流式传输选择URL
         let url = if use_streaming {
-            build_gemini_stream_url(&self.model, api_key)
+            build_gemini_stream_url(&self.model)
         } else {
-            build_gemini_url(&self.model, api_key, "generateContent")
+            build_gemini_url(&self.model, "generateContent")
         };
 
         // 使用统一的流处理接口
-        self.stream_request(request_body, url, callback).await
+        self.stream_request(api_key, request_body, url, callback).await
     }
 
     /// 简化的非流式聊天接口 (内部复用流式接口)
@@ -822,6 +930,9 @@ impl GeminiChat {
 
         // 如果需要，应用模板提取
         let final_response = full_response.lock().unwrap().clone();
+        if self.active_cot().is_none() {
+            return Ok(final_response);
+        }
         if let Some(extracted) = template::extract_response(&final_response) {
             return Ok(extracted);
         }
@@ -838,11 +949,16 @@ impl GeminiChat {
     ) -> Result<(Option<String>, Vec<ToolCall>), Box<dyn Error>> {
         // 使用chat_gemini获取响应，然后解析工具调用
         let request_body = self.build_gemini_request_body(messages, Some(tools))?;
-        let url = build_gemini_url(&self.model, api_key, "generateContent");
+        let url = build_gemini_url(&self.model, "generateContent");
 
         // 发送请求
         let client = reqwest::Client::new();
-        let response = client.post(&url).json(&request_body).send().await?;
+        let response = client
+            .post(&url)
+            .header(GEMINI_API_KEY_HEADER, api_key)
+            .json(&request_body)
+            .send()
+            .await?;
 
         let status = response.status();
         if !status.is_success() {
@@ -851,6 +967,9 @@ impl GeminiChat {
         }
 
         let response_json: Value = response.json().await?;
+        if let Some(usage) = parse_gemini_usage(&response_json) {
+            self.usage.record(usage);
+        }
         parse_gemini_tool_call_response(&response_json)
     }
 
@@ -867,53 +986,51 @@ impl GeminiChat {
             + Send
             + Sync,
     ) -> Result<String, Box<dyn Error>> {
-        // 1. 第一次调用：发送包含工具的请求，解析工具调用
-        let (initial_response_text, tool_calls) =
-            self.parse_tool_calls(api_key, messages, tools).await?;
-
-        // 2. 检查是否有工具调用需要执行
-        if !tool_calls.is_empty() && !self.check_if_skip_tool_call(&tool_calls) {
-            let mut tool_result_messages = Vec::new();
-
-            // 添加模型的回复（可能包含思考过程或函数调用请求）
-            let mut current_messages = messages.to_vec(); // 克隆消息列表
-            if let Some(text) = initial_response_text {
-                current_messages.push(ChatCompletionMessage {
+        run_tool_loop(
+            messages,
+            self.max_tool_iterations,
+            |current_messages| async move {
+                // 发送包含工具的请求，解析工具调用
+                let (text, tool_calls) =
+                    self.parse_tool_calls(api_key, &current_messages, tools).await?;
+                if tool_calls.is_empty() || self.check_if_skip_tool_call(&tool_calls) {
+                    // 没有工具调用或跳过时，直接返回文本响应
+                    return Ok(ToolTurn {
+                        text,
+                        calls: Vec::new(),
+                        assistant_message: None,
+                    });
+                }
+
+                // 添加模型的回复（可能包含思考过程或函数调用请求）
+                let assistant_message = text.clone().map(|text| ChatCompletionMessage {
                     role: MessageRole::assistant, // 'model'
                     content: Content::Text(text), // 模型可能的回应文本
                     name: None,
                     tool_calls: None,
                     tool_call_id: None,
                 });
-            }
-
-            // 3. 执行工具调用
-            for tool_call in &tool_calls {
-                let result =
-                    tool_call_processor(tool_call.name.clone(), tool_call.args.clone()).await?;
-
-                // 4. 构建工具结果消息 (Function Response)
-                tool_result_messages.push(ChatCompletionMessage {
-                    role: MessageRole::tool,            // 'function' role in Gemini
-                    content: Content::Text(result),     // 工具执行结果
-                    name: Some(tool_call.name.clone()), // 必须提供工具名称
-                    tool_calls: None,
-                    tool_call_id: None,
-                });
-            }
-
-            // 5. 第二次调用：发送包含工具结果的请求
-            current_messages.extend(tool_result_messages);
-            return self
-                .chat_gemini(api_key, &current_messages, Some(tools))
-                .await;
-        } else if let Some(text) = initial_response_text {
-            // 如果没有工具调用或跳过，直接返回初始文本响应
-            return Ok(text);
-        } else {
-            // 如果既没有工具调用也没有文本响应（理论上不应发生）
-            return Err("Gemini response contained neither text nor tool calls.".into());
-        }
+                Ok(ToolTurn {
+                    text,
+                    calls: tool_calls,
+                    assistant_message,
+                })
+            },
+            // 执行工具调用并构建工具结果消息 (Function Response)
+            |tool_call: ToolCall| {
+                let result = tool_call_processor(tool_call.name.clone(), tool_call.args);
+                async move {
+                    Ok(ChatCompletionMessage {
+                        role: MessageRole::tool, // 'function' role in Gemini
+                        content: Content::Text(result.await?), // 工具执行结果
+                        name: Some(tool_call.name.clone()), // 必须提供工具名称
+                        tool_calls: None,
+                        tool_call_id: Some(tool_call.name), // Gemini 没有调用 ID，用工具名称标记为对工具调用的回应
+                    })
+                }
+            },
+        )
+        .await
     }
 
     /// 检查是否需要跳过工具调用 (假设有个名为 skip_tool_call 的特殊工具)
@@ -1007,6 +1124,7 @@ impl AIChat for GeminiChat {
                 )
             }
             "model" => self.model = value,
+            "max_tool_iterations" => self.max_tool_iterations = parse_max_tool_iterations(&value)?,
             "google_search" => {
                 self.google_search_enabled = value
                     .parse::<bool>()
@@ -1017,6 +1135,16 @@ impl AIChat for GeminiChat {
                     .parse::<bool>()
                     .map_err(|e| format!("Invalid url_context value: {}", e))?
             }
+            "safety_level" => {
+                self.safety_threshold = HarmBlockThreshold::from_safety_level(&value)
+                    .ok_or_else(|| format!("Invalid safety_level value: {}", value))?
+            }
+            "stop" => self.stop = parse_stop_sequences(&value)?,
+            "cot" => {
+                self.cot_disabled = !value
+                    .parse::<bool>()
+                    .map_err(|e| format!("Invalid cot value: {}", e))?
+            }
             // 可以添加 top_k 等其他参数
             _ => return Err(format!("Unknown parameter: {}", key).into()),
         }
@@ -1080,15 +1208,24 @@ impl AIChat for GeminiChat {
         self.last_prompt = Some(prompt.clone());
 
         // 使用流式API调用Gemini
-        let response = self
-            .chat_stream(
-                &api_key.key,
-                &current_messages,
-                None,
-                true, // 使用真正的流式传输
-                callback,
-            )
-            .await?;
+        let prepared = self.prepare_attachment_parts(&api_key.key).await.map_err(|e| e.to_string());
+        let response = match prepared {
+            Ok(()) => {
+                self.chat_stream(
+                    &api_key.key,
+                    &current_messages,
+                    None,
+                    true, // 使用真正的流式传输
+                    callback,
+                )
+                .await
+            }
+            Err(e) => Err(format!("图片上传失败: {}", e).into()),
+        };
+        // 图片只随本轮提问发送，后续对话不再重复发送
+        self.pending_images.clear();
+        self.attachment_parts.clear();
+        let response = response?;
 
         // 创建助手消息并添加到历史
         let assistant_message = ChatCompletionMessage {
@@ -1133,9 +1270,14 @@ impl AIChat for GeminiChat {
                     ChatMessageType::User => MessageRole::user,
                     ChatMessageType::Assistant => MessageRole::assistant,
                     ChatMessageType::System => MessageRole::system,
+                    ChatMessageType::Tool => MessageRole::tool,
                 },
                 content: Content::Text(msg.content.clone()),
-                name: Some(msg.time.clone()), // 假设时间戳作为名称
+                // 工具消息的 name 为工具名称，其余消息假设时间戳作为名称
+                name: match msg.msgtype {
+                    ChatMessageType::Tool => msg.tool_name.clone(),
+                    _ => Some(msg.time.clone()),
+                },
                 tool_calls: None,
                 tool_call_id: None,
             })
@@ -1152,22 +1294,33 @@ impl AIChat for GeminiChat {
             content: self
                 .messages
                 .iter()
-                .map(|msg| ChatMessage {
-                    msgtype: match msg.role {
+                .map(|msg| {
+                    let msgtype = match msg.role {
                         MessageRole::user => ChatMessageType::User,
                         MessageRole::assistant => ChatMessageType::Assistant,
                         MessageRole::system => ChatMessageType::System,
+                        MessageRole::tool => ChatMessageType::Tool,
                         _ => ChatMessageType::User, // 默认处理
-                    },
-                    content: match &msg.content {
+                    };
+                    let content = match &msg.content {
                         Content::Text(text) => text.clone(),
-                    },
-                    time: msg.name.clone().unwrap_or_default(), // 假设名称作为时间戳
+                    };
+                    ChatMessage {
+                        time: match msg.role {
+                            MessageRole::tool => String::new(),
+                            _ => msg.name.clone().unwrap_or_default(), // 假设名称作为时间戳
+                        },
+                        tool_name: match msg.role {
+                            MessageRole::tool => msg.name.clone(),
+                            _ => None,
+                        },
+                        ..ChatMessage::new(msgtype, content)
+                    }
                 })
                 .collect(),
             time: self.time.clone(),
             title: self.title.clone(),
-            id: self.chat_id,
+            ..ChatState::empty_chat(self.chat_id)
         };
         Ok(chat_history)
     }
@@ -1175,26 +1328,179 @@ impl AIChat for GeminiChat {
 
 // --- Standalone Functions ---
 
-/// 图像到文本转换函数 (保持不变，但使用辅助函数构建 URL)
-#[allow(dead_code)]
-pub async fn image_to_text(api_key: &str, image_data: &[u8]) -> Result<String, Box<dyn Error>> {
+// 图像识别使用的模型
+const IMAGE_TO_TEXT_MODEL: &str = "gemini-2.0-flash";
+// Files API 上传地址和文件查询地址
+const GEMINI_UPLOAD_URL: &str = "https://generativelanguage.googleapis.com/upload/v1beta/files";
+const GEMINI_FILES_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+// 一次请求中内联数据（base64 编码后）的总大小上限，超出的附件改用 Files API 上传，避免请求体超出 20MB 的限制
+const INLINE_DATA_LIMIT: usize = 15 * 1024 * 1024;
+// 每条用户消息最多附加的图片数量
+const MAX_IMAGES_PER_TURN: usize = 10;
+// 等待上传的文件处理完成的最长时间
+const FILE_PROCESSING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// 通过 Files API 上传的文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeminiFile {
+    pub name: String, // 文件资源名，形如 files/abc123
+    pub uri: String,  // 在请求中通过 fileData 引用的地址
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    #[serde(default)]
+    pub state: String, // PROCESSING / ACTIVE / FAILED
+}
+
+/// 将文件上传到 Gemini Files API，等待服务端处理完成后返回文件信息（上传的文件会在 48 小时后自动删除）
+pub async fn gemini_upload_file(
+    api_key: &str,
+    bytes: &[u8],
+    mime: &str,
+) -> Result<GeminiFile, Box<dyn Error>> {
     let client = reqwest::Client::new();
-    let base64_image = base64::engine::general_purpose::STANDARD.encode(image_data);
 
-    let request_json = json!({
+    // 发起可恢复上传，获取本次上传的地址
+    let response = client
+        .post(GEMINI_UPLOAD_URL)
+        .header(GEMINI_API_KEY_HEADER, api_key)
+        .header("X-Goog-Upload-Protocol", "resumable")
+        .header("X-Goog-Upload-Command", "start")
+        .header("X-Goog-Upload-Header-Content-Length", bytes.len().to_string())
+        .header("X-Goog-Upload-Header-Content-Type", mime)
+        .json(&json!({ "file": { "display_name": "npulearn-upload" } }))
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await?;
+        return Err(format!("File upload failed ({}): {}", status, error_text).into());
+    }
+    let upload_url = response
+        .headers()
+        .get("x-goog-upload-url")
+        .and_then(|value| value.to_str().ok())
+        .ok_or("File upload failed: missing upload URL")?
+        .to_string();
+
+    // 一次性上传全部内容并结束上传
+    let response = client
+        .post(&upload_url)
+        .header(GEMINI_API_KEY_HEADER, api_key)
+        .header("X-Goog-Upload-Offset", "0")
+        .header("X-Goog-Upload-Command", "upload, finalize")
+        .body(bytes.to_vec())
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await?;
+        return Err(format!("File upload failed ({}): {}", status, error_text).into());
+    }
+    let mut response_json: Value = response.json().await?;
+    let mut file: GeminiFile = serde_json::from_value(response_json["file"].take())?;
+
+    // PDF、视频等文件需要服务端处理后才能在请求中引用
+    let deadline = Instant::now() + FILE_PROCESSING_TIMEOUT;
+    while file.state == "PROCESSING" {
+        if Instant::now() >= deadline {
+            return Err(format!("File processing timed out: {}", file.name).into());
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let response = client
+            .get(format!("{}/{}", GEMINI_FILES_BASE_URL, file.name))
+            .header(GEMINI_API_KEY_HEADER, api_key)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await?;
+            return Err(format!("Failed to query file state ({}): {}", status, error_text).into());
+        }
+        file = response.json().await?;
+    }
+    if file.state == "FAILED" {
+        return Err(format!("File processing failed: {}", file.name).into());
+    }
+    Ok(file)
+}
+
+/// 构建附件的请求片段：base64 编码后不超过 inline_budget 的数据通过 inlineData 内联并扣除相应额度，
+/// 较大的数据先上传到 Files API 再通过 fileData 引用。同一请求的多个附件共用一个额度
+pub async fn build_attachment_part(
+    api_key: &str,
+    data: &[u8],
+    mime: &str,
+    inline_budget: &mut usize,
+) -> Result<Value, Box<dyn Error>> {
+    // base64 编码后的长度
+    let encoded_len = data.len().div_ceil(3) * 4;
+    if encoded_len <= *inline_budget {
+        *inline_budget -= encoded_len;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+        return Ok(json!({ "inlineData": { "mimeType": mime, "data": encoded } }));
+    }
+    println!("附件大小 {} 字节，改用 Files API 上传", data.len());
+    let file = gemini_upload_file(api_key, data, mime).await?;
+    Ok(json!({ "fileData": { "mimeType": file.mime_type, "fileUri": file.uri } }))
+}
+
+/// 缩小超过最长边设置的图片附件，无需缩小或无法解码时保持原样
+fn downscale_attachment(image: ImageAttachment) -> ImageAttachment {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let Ok(data) = engine.decode(&image.data) else {
+        return image;
+    };
+    match image_resize::downscale_image(&data) {
+        Some(resized) => ImageAttachment {
+            mime_type: "image/jpeg".to_string(),
+            data: engine.encode(resized),
+        },
+        None => image,
+    }
+}
+
+/// 构建图像识别请求体
+async fn build_image_to_text_body(
+    api_key: &str,
+    image_data: &[u8],
+) -> Result<Value, Box<dyn Error>> {
+    // 解码和缩放较耗时，放到阻塞线程中执行
+    let original = image_data.to_vec();
+    let resized = tokio::task::spawn_blocking(move || image_resize::downscale_image(&original))
+        .await
+        .ok()
+        .flatten();
+    let image_data = resized.as_deref().unwrap_or(image_data);
+    let mime = infer::get(image_data)
+        .map(|kind| kind.mime_type())
+        .unwrap_or("image/jpeg");
+    let mut inline_budget = INLINE_DATA_LIMIT;
+    let image_part = build_attachment_part(api_key, image_data, mime, &mut inline_budget).await?;
+
+    Ok(json!({
         "contents": [{
             "parts": [
                 { "text": "# You are an image desciptor, Only output what the Image is, if the image contains TEXT, you should use Markdown to output the text" },
-                { "inlineData": { "mimeType": "image/jpeg", "data": base64_image } }
+                image_part
             ]
         }]
         // 可以添加 generationConfig 和 safetySettings
-    });
+    }))
+}
 
-    let model = "gemini-2.0-flash";
-    let url = build_gemini_url(model, api_key, "generateContent");
+/// 图像到文本转换函数 (保持不变，但使用辅助函数构建 URL)
+#[allow(dead_code)]
+pub async fn image_to_text(api_key: &str, image_data: &[u8]) -> Result<String, Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    let request_json = build_image_to_text_body(api_key, image_data).await?;
+    let url = build_gemini_url(IMAGE_TO_TEXT_MODEL, "generateContent");
 
-    let response = client.post(&url).json(&request_json).send().await?;
+    let response = client
+        .post(&url)
+        .header(GEMINI_API_KEY_HEADER, api_key)
+        .json(&request_json)
+        .send()
+        .await?;
 
     let status = response.status(); // Store status before consuming response
     if !response.status().is_success() {
@@ -1206,11 +1512,40 @@ pub async fn image_to_text(api_key: &str, image_data: &[u8]) -> Result<String, B
     parse_gemini_response(&response_json) // 复用解析逻辑
 }
 
+/// 流式图像到文本转换，每收到一段识别结果就通过回调返回，用于显示 OCR 进度
+pub async fn image_to_text_stream<F>(
+    api_key: &str,
+    image_data: &[u8],
+    callback: F,
+) -> Result<String, Box<dyn Error>>
+where
+    F: FnMut(String) + Send + 'static,
+{
+    let client = reqwest::Client::new();
+    let request_json = build_image_to_text_body(api_key, image_data).await?;
+    let url = build_gemini_stream_url(IMAGE_TO_TEXT_MODEL);
+
+    let response = client
+        .post(&url)
+        .header(GEMINI_API_KEY_HEADER, api_key)
+        .json(&request_json)
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await?;
+        return Err(format!("API request failed ({}): {}", status, error_text).into());
+    }
+
+    process_stream_response(response, callback, &UsageRecorder::default()).await
+}
+
 /// 获取可用的Gemini模型列表
 #[allow(dead_code)]
 pub async fn fetch_available_models(api_key: &str) -> Result<Vec<String>, Box<dyn Error>> {
     println!("🌐 [DEBUG] Starting to fetch model list from Gemini API");
-    println!("🔑 [DEBUG] Using API key: {}...", &api_key[..std::cmp::min(10, api_key.len())]);
+    println!("🔑 [DEBUG] Using API key: {}", mask_api_key(api_key));
     
     let client = reqwest::Client::new();
     let url = "https://generativelanguage.googleapis.com/v1beta/openai/models";
@@ -1269,6 +1604,63 @@ pub async fn fetch_available_models(api_key: &str) -> Result<Vec<String>, Box<dy
     Ok(models)
 }
 
+/// 模型列表缓存有效期
+const MODEL_LIST_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// 模型列表缓存: (API key, 获取时间, 模型列表)
+static MODEL_LIST_CACHE: Lazy<Mutex<Option<(String, Instant, Vec<String>)>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// 是否已有后台刷新任务在运行，避免重复请求
+static MODEL_LIST_REFRESHING: AtomicBool = AtomicBool::new(false);
+
+/// 获取可用的Gemini模型列表（带缓存）
+///
+/// 缓存新鲜时直接返回；缓存过期时先返回旧列表并在后台刷新；
+/// `force_refresh` 为 true 或没有该 key 的缓存时同步请求 API。
+pub async fn fetch_available_models_cached(
+    api_key: &str,
+    force_refresh: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    if !force_refresh {
+        let cached = MODEL_LIST_CACHE
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(key, _, _)| key == api_key)
+            .map(|(_, fetched_at, models)| (fetched_at.elapsed() < MODEL_LIST_CACHE_TTL, models.clone()));
+
+        if let Some((fresh, models)) = cached {
+            if fresh {
+                println!("📦 [DEBUG] Using cached Gemini model list ({} models)", models.len());
+            } else if !MODEL_LIST_REFRESHING.swap(true, Ordering::SeqCst) {
+                println!("♻️ [DEBUG] Gemini model cache expired, refreshing in background");
+                let api_key = api_key.to_string();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = refresh_model_cache(&api_key).await {
+                        println!("❌ [DEBUG] Background model refresh failed: {}", e);
+                    }
+                    MODEL_LIST_REFRESHING.store(false, Ordering::SeqCst);
+                });
+            }
+            return Ok(models);
+        }
+    }
+
+    Ok(refresh_model_cache(api_key).await?)
+}
+
+/// 从 API 获取模型列表并写入缓存，空列表不会覆盖已有缓存
+async fn refresh_model_cache(api_key: &str) -> Result<Vec<String>, String> {
+    let models = fetch_available_models(api_key)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !models.is_empty() {
+        *MODEL_LIST_CACHE.lock().unwrap() = Some((api_key.to_string(), Instant::now(), models.clone()));
+    }
+    Ok(models)
+}
+
 /// 检查模型是否符合我们的过滤条件
 fn is_valid_gemini_model(model_id: &str) -> bool {
     // 检查是否匹配 gemini-[1-10].[0-10]-* 模式
@@ -0,0 +1,119 @@
+use std::io::Cursor;
+use std::sync::RwLock;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageReader, Rgb, RgbImage};
+use once_cell::sync::Lazy;
+
+// 默认发送给视觉模型的图片最长边（像素）
+pub const DEFAULT_MAX_IMAGE_DIMENSION: u32 = 2048;
+
+// 默认缩小后重新编码的 JPEG 质量
+pub const DEFAULT_IMAGE_JPEG_QUALITY: u8 = 85;
+
+struct ResizeOptions {
+    max_dimension: u32,
+    jpeg_quality: u8,
+}
+
+static OPTIONS: Lazy<RwLock<ResizeOptions>> = Lazy::new(|| {
+    RwLock::new(ResizeOptions {
+        max_dimension: DEFAULT_MAX_IMAGE_DIMENSION,
+        jpeg_quality: DEFAULT_IMAGE_JPEG_QUALITY,
+    })
+});
+
+/// 设置发送给视觉模型（图片识别、带图提问）的图片最长边和重新编码的 JPEG 质量，最长边为 0 时不缩小图片
+pub fn configure(max_dimension: u32, jpeg_quality: u8) {
+    let mut options = OPTIONS.write().unwrap();
+    options.max_dimension = max_dimension;
+    options.jpeg_quality = jpeg_quality.clamp(1, 100);
+}
+
+/// 最长边超过设置的图片按原比例缩小并重新编码为 JPEG；无需缩小或无法解码时返回 None，调用方继续使用原图
+pub fn downscale_image(data: &[u8]) -> Option<Vec<u8>> {
+    let (max_dimension, jpeg_quality) = {
+        let options = OPTIONS.read().unwrap();
+        (options.max_dimension, options.jpeg_quality)
+    };
+    downscale_with(data, max_dimension, jpeg_quality)
+}
+
+fn downscale_with(data: &[u8], max_dimension: u32, jpeg_quality: u8) -> Option<Vec<u8>> {
+    if max_dimension == 0 {
+        return None;
+    }
+
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let (width, height) = decoder.dimensions();
+    if width.max(height) <= max_dimension {
+        return None;
+    }
+    // 重新编码后不再保留 EXIF 信息，需要先按拍摄方向旋转（手机照片常见）
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+    image.apply_orientation(orientation);
+
+    let resized = image.resize(max_dimension, max_dimension, FilterType::Lanczos3);
+    let mut encoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut encoded, jpeg_quality)
+        .encode_image(&flatten_on_white(&resized))
+        .ok()?;
+    println!(
+        "图片从 {}x{} 缩小到 {}x{}，大小 {} -> {} 字节",
+        width,
+        height,
+        resized.width(),
+        resized.height(),
+        data.len(),
+        encoded.len()
+    );
+    Some(encoded)
+}
+
+/// JPEG 不支持透明度，将透明部分合成到白色背景上，避免截图等透明区域变成黑色
+fn flatten_on_white(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        let alpha = pixel[3] as u16;
+        Rgb([0, 1, 2].map(|i| ((pixel[i] as u16 * alpha + 255 * (255 - alpha)) / 255) as u8))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgba, RgbaImage};
+
+    fn png_bytes(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_pixel(width, height, Rgba([200, 30, 30, 0]));
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_downscale_image() {
+        let png = png_bytes(300, 100);
+        let resized = downscale_with(&png, 150, 80).unwrap();
+        let decoded = image::load_from_memory(&resized).unwrap();
+        assert_eq!(image::guess_format(&resized).unwrap(), ImageFormat::Jpeg);
+        assert_eq!((decoded.width(), decoded.height()), (150, 50));
+        // 透明像素合成到白色背景上
+        assert!(decoded.to_rgb8().get_pixel(75, 25).0.iter().all(|&c| c > 240));
+
+        // 未超过最长边、未限制或无法解码时保持原图
+        assert!(downscale_with(&png, 300, 80).is_none());
+        assert!(downscale_with(&png, 0, 80).is_none());
+        assert!(downscale_with(b"not an image", 150, 80).is_none());
+    }
+}
@@ -1,8 +1,11 @@
 use std::error::Error;
+use std::sync::{Arc, Mutex};
 
+use crate::history_msg::history::TokenUsage;
 use crate::ChatHistory;
 
-use super::{apikey::ApiKey, deepseek::DeepSeekChat, gemini::GeminiChat, coze::CozeChat};
+use super::{apikey::ApiKey, deepseek::{DeepSeekChat, ReasoningSink}, gemini::GeminiChat, coze::CozeChat, mock::MockChat};
+use super::response_cache::{self, CachedResponse};
 
 
 #[allow(dead_code)]
@@ -56,6 +59,52 @@ pub enum AIChatType {
     Gemini(GeminiChat),
     DeepSeek(DeepSeekChat),
     Coze(CozeChat),
+    Mock(MockChat),
+}
+
+impl AIChatType {
+    fn backend_name(&self) -> &'static str {
+        match self {
+            AIChatType::Gemini(_) => "Gemini",
+            AIChatType::DeepSeek(_) => "DeepSeek",
+            AIChatType::Coze(_) => "Coze",
+            AIChatType::Mock(_) => "Mock",
+        }
+    }
+
+    /// 设置推理模型思考过程的接收者，目前只有 DeepSeek 的推理模型会单独返回思考过程
+    pub fn set_reasoning_sink(&mut self, sink: ReasoningSink) {
+        if let AIChatType::DeepSeek(chat) = self {
+            chat.set_reasoning_sink(sink);
+        }
+    }
+
+    /// 本次请求的系统提示是否包含 COT 模板，与各后端构建系统提示时的判断一致；Mock 不使用系统提示
+    pub(crate) fn uses_cot(&self) -> bool {
+        match self {
+            AIChatType::Gemini(chat) => chat.uses_cot(),
+            AIChatType::DeepSeek(chat) => chat.uses_cot(),
+            AIChatType::Coze(chat) => chat.uses_cot(),
+            AIChatType::Mock(_) => false,
+        }
+    }
+
+    /// 取出上次回答中 API 返回的 token 用量，不返回用量的后端为 None
+    pub(crate) fn take_usage(&self) -> Option<TokenUsage> {
+        match self {
+            AIChatType::Gemini(chat) => chat.take_usage(),
+            AIChatType::DeepSeek(chat) => chat.take_usage(),
+            _ => None,
+        }
+    }
+
+    /// 本轮提问是否附带图片，附带图片的请求不使用回复缓存
+    fn has_attachments(&self) -> bool {
+        match self {
+            AIChatType::Gemini(chat) => chat.has_pending_images(),
+            _ => false,
+        }
+    }
 }
 
 impl AIChat for AIChatType {    async fn generate_response_stream<F>(
@@ -66,7 +115,31 @@ impl AIChat for AIChatType {    async fn generate_response_stream<F>(
     ) -> Result<String, Box<dyn Error>>
     where
         F: FnMut(String) + Send + 'static,
-    {        match self {
+    {
+        // 启用回复缓存时，相同上下文中的相同提示词直接重放缓存的回复
+        let cache_key = (response_cache::is_enabled() && !self.has_attachments())
+            .then(|| response_cache::cache_key(self.backend_name(), &self.serialize(), &prompt));
+        if let Some(key) = cache_key {
+            if let Some(cached) = response_cache::get(key) {
+                println!("命中回复缓存，跳过 API 请求");
+                return Ok(cached.replay(callback));
+            }
+        }
+
+        // 记录流式片段，以便缓存后按相同方式重放
+        let streamed = Arc::new(Mutex::new(String::new()));
+        let callback = {
+            let streamed = Arc::clone(&streamed);
+            let mut callback = callback;
+            move |text: String| {
+                if cache_key.is_some() {
+                    streamed.lock().unwrap().push_str(&text);
+                }
+                callback(text);
+            }
+        };
+
+        let result = match self {
             AIChatType::Gemini(chat) => {
                 chat.generate_response_stream(api_key, prompt, callback)
                     .await
@@ -79,7 +152,17 @@ impl AIChat for AIChatType {    async fn generate_response_stream<F>(
                 chat.generate_response_stream(api_key, prompt, callback)
                     .await
             }
+            AIChatType::Mock(chat) => {
+                chat.generate_response_stream(api_key, prompt, callback)
+                    .await
+            }
+        };
+
+        if let (Some(key), Ok(response)) = (cache_key, &result) {
+            let streamed = streamed.lock().unwrap().clone();
+            response_cache::insert(key, CachedResponse { streamed, response: response.clone() });
         }
+        result
     }    async fn regenerate_response_stream<F>(
         &mut self,
         api_key: ApiKey,
@@ -91,46 +174,55 @@ impl AIChat for AIChatType {    async fn generate_response_stream<F>(
             AIChatType::Gemini(chat) => chat.regenerate_response_stream(api_key, callback).await,
             AIChatType::DeepSeek(chat) => chat.regenerate_response_stream(api_key, callback).await,
             AIChatType::Coze(chat) => chat.regenerate_response_stream(api_key, callback).await,
+            AIChatType::Mock(chat) => chat.regenerate_response_stream(api_key, callback).await,
         }
     }    fn withdraw_response(&mut self) -> Result<String, Box<dyn Error>> {        match self {
             AIChatType::Gemini(chat) => chat.withdraw_response(),
             AIChatType::DeepSeek(chat) => chat.withdraw_response(),
             AIChatType::Coze(chat) => chat.withdraw_response(),
+            AIChatType::Mock(chat) => chat.withdraw_response(),
         }
     }    fn clear_context(&mut self) -> Result<String, Box<dyn Error>> {        match self {
             AIChatType::Gemini(chat) => chat.clear_context(),
             AIChatType::DeepSeek(chat) => chat.clear_context(),
             AIChatType::Coze(chat) => chat.clear_context(),
+            AIChatType::Mock(chat) => chat.clear_context(),
         }
     }    fn set_system_prompt(&mut self, prompt: String) -> Result<String, Box<dyn Error>> {        match self {
             AIChatType::Gemini(chat) => chat.set_system_prompt(prompt),
             AIChatType::DeepSeek(chat) => chat.set_system_prompt(prompt),
             AIChatType::Coze(chat) => chat.set_system_prompt(prompt),
+            AIChatType::Mock(chat) => chat.set_system_prompt(prompt),
         }
     }    fn set_parameter(&mut self, key: String, value: String) -> Result<(), Box<dyn Error>> {        match self {
             AIChatType::Gemini(chat) => chat.set_parameter(key, value),
             AIChatType::DeepSeek(chat) => chat.set_parameter(key, value),
             AIChatType::Coze(chat) => chat.set_parameter(key, value),
+            AIChatType::Mock(chat) => chat.set_parameter(key, value),
         }
     }    fn serialize(&self) -> String {        match self {
             AIChatType::Gemini(chat) => chat.serialize(),
             AIChatType::DeepSeek(chat) => chat.serialize(),
             AIChatType::Coze(chat) => chat.serialize(),
+            AIChatType::Mock(chat) => chat.serialize(),
         }
     }    fn deserialize(&mut self, data: String) -> Result<(), Box<dyn Error>> {        match self {
             AIChatType::Gemini(chat) => chat.deserialize(data),
             AIChatType::DeepSeek(chat) => chat.deserialize(data),
             AIChatType::Coze(chat) => chat.deserialize(data),
+            AIChatType::Mock(chat) => chat.deserialize(data),
         }
     }    fn load_from(&mut self, chat_history: &ChatHistory) -> Result<(), Box<dyn Error>> {        match self {
             AIChatType::Gemini(chat) => chat.load_from(chat_history),
             AIChatType::DeepSeek(chat) => chat.load_from(chat_history),
             AIChatType::Coze(chat) => chat.load_from(chat_history),
+            AIChatType::Mock(chat) => chat.load_from(chat_history),
         }
     }    fn save_to(&self) -> Result<ChatHistory, Box<dyn Error>> {        match self {
             AIChatType::Gemini(chat) => chat.save_to(),
             AIChatType::DeepSeek(chat) => chat.save_to(),
             AIChatType::Coze(chat) => chat.save_to(),
+            AIChatType::Mock(chat) => chat.save_to(),
         }
     }    async fn execute_tool_call(
         &mut self,
@@ -140,6 +232,15 @@ impl AIChat for AIChatType {    async fn generate_response_stream<F>(
             AIChatType::Gemini(chat) => chat.execute_tool_call(tool_name, args).await,
             AIChatType::DeepSeek(chat) => chat.execute_tool_call(tool_name, args).await,
             AIChatType::Coze(chat) => chat.execute_tool_call(tool_name, args).await,
+            AIChatType::Mock(chat) => chat.execute_tool_call(tool_name, args).await,
         }
     }
 }
+
+/// 解析 set_parameter 传入的停止序列（JSON 字符串数组），忽略空字符串，结果为空时返回 None
+pub(crate) fn parse_stop_sequences(value: &str) -> Result<Option<Vec<String>>, String> {
+    let sequences: Vec<String> =
+        serde_json::from_str(value).map_err(|e| format!("Invalid stop value: {}", e))?;
+    let sequences: Vec<String> = sequences.into_iter().filter(|s| !s.is_empty()).collect();
+    Ok(if sequences.is_empty() { None } else { Some(sequences) })
+}
@@ -0,0 +1,309 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::aibackend::apikey::{ApiKey, ApiKeyType};
+use crate::aibackend::interface::AIChat;
+use crate::history_msg::chat_state::ChatState;
+use crate::ChatHistory;
+
+// 默认的模拟回复，覆盖 Markdown、公式和代码块，便于演示渲染效果
+const DEFAULT_MOCK_RESPONSE: &str = r#"这是一条来自**离线模拟后端**的回复，不需要 API 密钥和网络连接。
+
+- 支持 Markdown 列表与**强调**
+- 行内公式：$E = mc^2$
+
+$$\int_0^1 x^2 \, dx = \frac{1}{3}$$
+
+```python
+print("Hello, NPULearn!")
+```"#;
+// 每个流式片段包含的字符数
+const DEFAULT_CHUNK_SIZE: usize = 8;
+// 两个流式片段之间的默认延迟（毫秒）
+const DEFAULT_CHUNK_DELAY_MS: u64 = 30;
+
+/// 模拟对话的消息记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockMessage {
+    role: String,
+    content: String,
+}
+
+/// 离线模拟后端，按片段流式返回预设回复，用于测试和演示
+///
+/// 支持的参数（通过 `set_parameter` 设置）：
+/// - `response`：预设回复内容
+/// - `echo`：为 `true` 时在回复前回显用户输入
+/// - `chunk_size`：每个流式片段的字符数
+/// - `chunk_delay_ms`：片段之间的延迟
+/// - `error`：设置后不输出任何内容，直接返回该错误，用于测试失败处理
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MockChat {
+    conversation_history: Vec<MockMessage>,
+    system_prompt: Option<String>,
+    parameters: HashMap<String, String>,
+    chat_id: u32,
+    title: Option<String>,
+    time: String,
+}
+
+impl MockChat {
+    pub fn new() -> Self {
+        Self {
+            conversation_history: Vec::new(),
+            system_prompt: None,
+            parameters: HashMap::new(),
+            chat_id: 0,
+            title: None,
+            time: chrono::Local::now().format("%H:%M").to_string(),
+        }
+    }
+
+    /// 离线后端使用的内置密钥
+    pub fn built_in_key() -> ApiKey {
+        ApiKey {
+            key: "mock".to_string(),
+            name: "Mock Offline".to_string(),
+            key_type: ApiKeyType::Mock,
+            priority: 0,
+        }
+    }
+
+    fn parameter<T: std::str::FromStr>(&self, key: &str, default: T) -> T {
+        self.parameters
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+
+    /// 根据参数生成对用户输入的完整回复
+    fn build_response(&self, prompt: &str) -> String {
+        let response = self
+            .parameters
+            .get("response")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_MOCK_RESPONSE.to_string());
+        if self.parameter("echo", false) {
+            format!("> {}\n\n{}", prompt.replace('\n', "\n> "), response)
+        } else {
+            response
+        }
+    }
+
+    /// 按字符切分回复并依次回调，模拟流式输出
+    async fn stream_response<F>(&self, response: &str, mut callback: F)
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let chunk_size = self.parameter("chunk_size", DEFAULT_CHUNK_SIZE).max(1);
+        let delay = Duration::from_millis(self.parameter("chunk_delay_ms", DEFAULT_CHUNK_DELAY_MS));
+
+        let chars: Vec<char> = response.chars().collect();
+        for chunk in chars.chunks(chunk_size) {
+            callback(chunk.iter().collect());
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+impl Default for MockChat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AIChat for MockChat {
+    async fn generate_response_stream<F>(
+        &mut self,
+        api_key: ApiKey,
+        prompt: String,
+        callback: F,
+    ) -> Result<String, Box<dyn Error>>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        if api_key.key_type != ApiKeyType::Mock {
+            return Err("Invalid API key type for Mock".into());
+        }
+        if let Some(error) = self.parameters.get("error") {
+            return Err(error.clone().into());
+        }
+
+        self.conversation_history.push(MockMessage {
+            role: "user".to_string(),
+            content: prompt.clone(),
+        });
+
+        let response = self.build_response(&prompt);
+        self.stream_response(&response, callback).await;
+
+        self.conversation_history.push(MockMessage {
+            role: "assistant".to_string(),
+            content: response.clone(),
+        });
+
+        Ok(response)
+    }
+
+    async fn regenerate_response_stream<F>(
+        &mut self,
+        api_key: ApiKey,
+        callback: F,
+    ) -> Result<String, Box<dyn Error>>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let last_prompt = self.withdraw_response()?;
+        self.generate_response_stream(api_key, last_prompt, callback)
+            .await
+    }
+
+    fn withdraw_response(&mut self) -> Result<String, Box<dyn Error>> {
+        // 移除最后一轮对话，返回其中的用户输入
+        if let Some(last_message) = self.conversation_history.last() {
+            if last_message.role == "assistant" {
+                self.conversation_history.pop();
+            }
+        }
+        match self.conversation_history.pop() {
+            Some(message) if message.role == "user" => Ok(message.content),
+            _ => Err("No previous prompt found".into()),
+        }
+    }
+
+    fn clear_context(&mut self) -> Result<String, Box<dyn Error>> {
+        self.conversation_history.clear();
+        Ok("Context cleared".to_string())
+    }
+
+    fn set_system_prompt(&mut self, prompt: String) -> Result<String, Box<dyn Error>> {
+        self.system_prompt = Some(prompt);
+        Ok("System prompt set".to_string())
+    }
+
+    fn set_parameter(&mut self, key: String, value: String) -> Result<(), Box<dyn Error>> {
+        self.parameters.insert(key, value);
+        Ok(())
+    }
+
+    fn serialize(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|e| {
+            eprintln!("Mock serialization error: {}", e);
+            "{}".to_string()
+        })
+    }
+
+    fn deserialize(&mut self, data: String) -> Result<(), Box<dyn Error>> {
+        *self = serde_json::from_str(&data)?;
+        Ok(())
+    }
+
+    fn load_from(&mut self, chat_history: &ChatHistory) -> Result<(), Box<dyn Error>> {
+        self.chat_id = chat_history.id;
+        self.title = chat_history.title.clone();
+        self.time = chat_history.time.clone();
+
+        self.conversation_history = chat_history
+            .content
+            .iter()
+            .map(|message| MockMessage {
+                role: match message.msgtype {
+                    crate::ChatMessageType::User => "user",
+                    crate::ChatMessageType::Assistant => "assistant",
+                    crate::ChatMessageType::System => "system",
+                    crate::ChatMessageType::Tool => "tool",
+                }
+                .to_string(),
+                content: message.content.clone(),
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn save_to(&self) -> Result<ChatHistory, Box<dyn Error>> {
+        let content = self
+            .conversation_history
+            .iter()
+            .filter_map(|message| {
+                let msgtype = match message.role.as_str() {
+                    "user" => crate::ChatMessageType::User,
+                    "assistant" => crate::ChatMessageType::Assistant,
+                    "system" => crate::ChatMessageType::System,
+                    "tool" => crate::ChatMessageType::Tool,
+                    _ => return None,
+                };
+                Some(crate::ChatMessage::new(msgtype, message.content.clone()))
+            })
+            .collect();
+
+        Ok(ChatHistory {
+            title: self.title.clone(),
+            time: self.time.clone(),
+            content,
+            ..ChatState::empty_chat(self.chat_id)
+        })
+    }
+
+    async fn execute_tool_call(
+        &mut self,
+        tool_name: String,
+        args: String,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(format!("[mock] {}({})", tool_name, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_mock_stream_response() {
+        let mut chat = MockChat::new();
+        chat.set_parameter("response".to_string(), "你好，这是模拟回复".to_string())
+            .unwrap();
+        chat.set_parameter("echo".to_string(), "true".to_string())
+            .unwrap();
+        chat.set_parameter("chunk_size".to_string(), "3".to_string())
+            .unwrap();
+        chat.set_parameter("chunk_delay_ms".to_string(), "0".to_string())
+            .unwrap();
+
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let collected = Arc::clone(&chunks);
+        let response = chat
+            .generate_response_stream(MockChat::built_in_key(), "问题".to_string(), move |text| {
+                collected.lock().unwrap().push(text)
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response, "> 问题\n\n你好，这是模拟回复");
+        let chunks = chunks.lock().unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), response);
+        assert_eq!(chat.save_to().unwrap().content.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mock_regenerate() {
+        let mut chat = MockChat::new();
+        chat.set_parameter("chunk_delay_ms".to_string(), "0".to_string())
+            .unwrap();
+        chat.generate_response_stream(MockChat::built_in_key(), "第一个问题".to_string(), |_| {})
+            .await
+            .unwrap();
+        chat.regenerate_response_stream(MockChat::built_in_key(), |_| {})
+            .await
+            .unwrap();
+
+        let history = chat.save_to().unwrap();
+        assert_eq!(history.content.len(), 2);
+        assert_eq!(history.content[0].content, "第一个问题");
+    }
+}
@@ -4,4 +4,13 @@ pub mod gemini;
 pub mod deepseek;
 pub mod template;
 pub mod coze;
-pub mod openai_types;
\ No newline at end of file
+pub mod mock;
+pub mod openai_types;
+pub mod response_cache;
+pub mod context_usage;
+pub mod cost;
+pub mod error;
+pub mod concurrency;
+pub mod tool_loop;
+pub mod stream_flush;
+pub mod image_resize;
@@ -0,0 +1,165 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+// 缓存的最大条目数，超出时移除最早的条目
+const MAX_ENTRIES: usize = 200;
+// 后端状态中与对话内容无关、每次请求都可能变化的字段，不参与缓存键计算
+const VOLATILE_FIELDS: [&str; 4] = ["chat_id", "title", "time", "last_prompt"];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TTL_SECS: AtomicU64 = AtomicU64::new(3600);
+static CACHE: Lazy<Mutex<ResponseCache>> = Lazy::new(|| Mutex::new(ResponseCache::default()));
+
+/// 设置是否启用回复缓存及缓存有效期，关闭时清空已有缓存
+pub fn configure(enabled: bool, ttl_secs: u64) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    TTL_SECS.store(ttl_secs, Ordering::Relaxed);
+    if !enabled {
+        CACHE.lock().unwrap().entries.clear();
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// 根据后端类型、后端状态（包含模型、系统提示词、消息历史和生成参数）和提示词计算缓存键
+pub fn cache_key(backend: &str, state: &str, prompt: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    backend.hash(&mut hasher);
+    match serde_json::from_str::<serde_json::Value>(state) {
+        Ok(serde_json::Value::Object(mut map)) => {
+            for field in VOLATILE_FIELDS {
+                map.remove(field);
+            }
+            serde_json::Value::Object(map).to_string().hash(&mut hasher);
+        }
+        _ => state.hash(&mut hasher),
+    }
+    prompt.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn get(key: u64) -> Option<CachedResponse> {
+    let ttl = Duration::from_secs(TTL_SECS.load(Ordering::Relaxed));
+    CACHE.lock().unwrap().get(key, ttl, Instant::now())
+}
+
+pub fn insert(key: u64, response: CachedResponse) {
+    let ttl = Duration::from_secs(TTL_SECS.load(Ordering::Relaxed));
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(key, response, ttl, Instant::now());
+}
+
+/// 缓存的回复：流式片段拼接的原始内容和最终返回的回复
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedResponse {
+    pub streamed: String,
+    pub response: String,
+}
+
+impl CachedResponse {
+    /// 按行通过回调重放流式内容，返回最终回复
+    pub fn replay<F>(self, mut callback: F) -> String
+    where
+        F: FnMut(String),
+    {
+        for line in self.streamed.split_inclusive('\n') {
+            callback(line.to_string());
+        }
+        self.response
+    }
+}
+
+#[derive(Default)]
+struct ResponseCache {
+    entries: HashMap<u64, (Instant, CachedResponse)>,
+}
+
+impl ResponseCache {
+    fn get(&mut self, key: u64, ttl: Duration, now: Instant) -> Option<CachedResponse> {
+        match self.entries.get(&key) {
+            Some((created, response)) if now.duration_since(*created) < ttl => {
+                Some(response.clone())
+            }
+            Some(_) => {
+                self.entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: u64, response: CachedResponse, ttl: Duration, now: Instant) {
+        self.entries
+            .retain(|_, (created, _)| now.duration_since(*created) < ttl);
+        if self.entries.len() >= MAX_ENTRIES {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (created, _))| *created)
+                .map(|(key, _)| *key)
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (now, response));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key_ignores_volatile_fields() {
+        let state = r#"{"model":"m","messages":[],"time":"10:00","chat_id":1}"#;
+        let later = r#"{"model":"m","messages":[],"time":"10:05","chat_id":2}"#;
+        assert_eq!(
+            cache_key("Mock", state, "你好"),
+            cache_key("Mock", later, "你好")
+        );
+        assert_ne!(
+            cache_key("Mock", state, "你好"),
+            cache_key("Mock", state, "再见")
+        );
+        assert_ne!(
+            cache_key("Mock", state, "你好"),
+            cache_key("Gemini", state, "你好")
+        );
+        let other_model = r#"{"model":"n","messages":[],"time":"10:00","chat_id":1}"#;
+        assert_ne!(
+            cache_key("Mock", state, "你好"),
+            cache_key("Mock", other_model, "你好")
+        );
+    }
+
+    #[test]
+    fn test_cache_expires_after_ttl() {
+        let mut cache = ResponseCache::default();
+        let ttl = Duration::from_secs(60);
+        let now = Instant::now();
+        let response = CachedResponse {
+            streamed: "第一行\n第二行".to_string(),
+            response: "第二行".to_string(),
+        };
+        cache.insert(1, response.clone(), ttl, now);
+        assert_eq!(
+            cache.get(1, ttl, now + Duration::from_secs(30)),
+            Some(response.clone())
+        );
+        assert_eq!(cache.get(1, ttl, now + Duration::from_secs(61)), None);
+
+        let mut chunks = Vec::new();
+        assert_eq!(response.replay(|chunk| chunks.push(chunk)), "第二行");
+        assert_eq!(chunks, vec!["第一行\n", "第二行"]);
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+/// 合并流式回复的片段：累计到 min_chars 个字符或距上次刷新超过 interval 时才刷新界面，
+/// 避免逐 token 重新渲染和发送事件造成卡顿。interval 为 0 时每个片段都刷新。
+/// 模型停顿时没有新片段触发刷新，需要定时调用 `poll` 刷新尚未显示的内容
+pub struct FlushThrottle {
+    interval: Duration,
+    min_chars: usize,
+    pending_chars: usize,
+    last_flush: Option<Instant>,
+}
+
+impl FlushThrottle {
+    pub fn new(interval_ms: u64, min_chars: usize) -> Self {
+        Self {
+            interval: Duration::from_millis(interval_ms),
+            min_chars,
+            pending_chars: 0,
+            last_flush: None,
+        }
+    }
+
+    /// 记录新到达的片段，返回是否应该刷新；第一个片段总是立即刷新
+    pub fn push(&mut self, chunk: &str) -> bool {
+        self.push_at(chunk, Instant::now())
+    }
+
+    /// 刷新间隔，定时调用 `poll` 的周期
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// 没有新片段时检查是否应该刷新：有尚未刷新的内容且距上次刷新超过 interval
+    pub fn poll(&mut self) -> bool {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> bool {
+        let due = self.pending_chars > 0
+            && self
+                .last_flush
+                .is_some_and(|last| now.duration_since(last) >= self.interval);
+        if due {
+            self.pending_chars = 0;
+            self.last_flush = Some(now);
+        }
+        due
+    }
+
+    fn push_at(&mut self, chunk: &str, now: Instant) -> bool {
+        self.pending_chars += chunk.chars().count();
+        let due = match self.last_flush {
+            None => true,
+            Some(_) if self.interval.is_zero() => true,
+            Some(_) if self.min_chars > 0 && self.pending_chars >= self.min_chars => true,
+            Some(last) => now.duration_since(last) >= self.interval,
+        };
+        if due {
+            self.pending_chars = 0;
+            self.last_flush = Some(now);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_throttle() {
+        let start = Instant::now();
+        let mut throttle = FlushThrottle::new(100, 10);
+        assert!(throttle.push_at("你", start));
+        assert!(!throttle.push_at("好", start + Duration::from_millis(20)));
+        // 字符数达到上限时立即刷新
+        assert!(throttle.push_at("0123456789", start + Duration::from_millis(30)));
+        assert!(!throttle.push_at("a", start + Duration::from_millis(80)));
+        // 距上次刷新超过间隔时刷新
+        assert!(throttle.push_at("b", start + Duration::from_millis(140)));
+
+        // 停顿时定时检查，刷新合并中的片段
+        assert!(!throttle.push_at("c", start + Duration::from_millis(150)));
+        assert!(!throttle.poll_at(start + Duration::from_millis(200)));
+        assert!(throttle.poll_at(start + Duration::from_millis(260)));
+        assert!(!throttle.poll_at(start + Duration::from_millis(400)));
+
+        let mut unthrottled = FlushThrottle::new(0, 10);
+        assert!(unthrottled.push_at("a", start));
+        assert!(unthrottled.push_at("b", start));
+    }
+}
@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::future::Future;
+
+use crate::aibackend::openai_types::ChatCompletionMessage;
+
+// 默认最多执行的工具调用轮数，避免模型反复请求工具导致无限循环
+pub const DEFAULT_MAX_TOOL_ITERATIONS: usize = 5;
+
+// 超过工具调用轮数上限时附加在部分结果后的说明
+pub const TOOL_LOOP_LIMIT_NOTE: &str = "（工具调用次数已达上限，以上为部分结果）";
+
+pub fn default_max_tool_iterations() -> usize {
+    DEFAULT_MAX_TOOL_ITERATIONS
+}
+
+/// 解析 max_tool_iterations 参数，至少为 1 轮，为 0 时模型请求的工具永远不会执行
+pub fn parse_max_tool_iterations(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("Invalid max_tool_iterations value: must be at least 1".to_string()),
+        Ok(iterations) => Ok(iterations),
+        Err(e) => Err(format!("Invalid max_tool_iterations value: {}", e)),
+    }
+}
+
+/// 一次带工具的模型请求的结果
+pub struct ToolTurn<C> {
+    pub text: Option<String>, // 模型回复的文本
+    pub calls: Vec<C>,        // 模型请求执行的工具，为空时表示已经给出最终回答
+    pub assistant_message: Option<ChatCompletionMessage>, // 执行工具前需要追加到上下文中的模型回复
+}
+
+/// 多轮工具调用：反复请求模型并执行其请求的工具，直到模型不再请求工具为止。
+/// 执行了 max_iterations 轮工具后模型仍在请求工具时停止，返回已得到的文本并附上说明
+pub async fn run_tool_loop<C, Req, ReqFut, Exec, ExecFut>(
+    messages: &[ChatCompletionMessage],
+    max_iterations: usize,
+    mut request: Req,
+    mut execute: Exec,
+) -> Result<String, Box<dyn Error>>
+where
+    Req: FnMut(Vec<ChatCompletionMessage>) -> ReqFut,
+    ReqFut: Future<Output = Result<ToolTurn<C>, Box<dyn Error>>>,
+    Exec: FnMut(C) -> ExecFut,
+    ExecFut: Future<Output = Result<ChatCompletionMessage, Box<dyn Error>>>,
+{
+    let mut current_messages = messages.to_vec();
+    let mut partial = Vec::new();
+
+    for _ in 0..max_iterations {
+        let turn = request(current_messages.clone()).await?;
+        if turn.calls.is_empty() {
+            return turn
+                .text
+                .ok_or_else(|| "模型回复中既没有文本也没有工具调用".into());
+        }
+
+        partial.extend(turn.text.filter(|text| !text.trim().is_empty()));
+        current_messages.extend(turn.assistant_message);
+        for call in turn.calls {
+            current_messages.push(execute(call).await?);
+        }
+    }
+
+    println!("工具调用已达到 {} 轮上限，停止继续调用", max_iterations);
+    partial.push(TOOL_LOOP_LIMIT_NOTE.to_string());
+    Ok(partial.join("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aibackend::openai_types::{Content, MessageRole};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_parse_max_tool_iterations() {
+        assert_eq!(parse_max_tool_iterations("3"), Ok(3));
+        assert!(parse_max_tool_iterations("0").is_err());
+        assert!(parse_max_tool_iterations("-1").is_err());
+        assert!(parse_max_tool_iterations("abc").is_err());
+    }
+
+    fn tool_message(result: String) -> ChatCompletionMessage {
+        ChatCompletionMessage {
+            role: MessageRole::tool,
+            content: Content::Text(result),
+            name: Some("search".to_string()),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tool_loop_stops_at_limit() {
+        let executed = AtomicUsize::new(0);
+        let result = run_tool_loop(
+            &[],
+            3,
+            |messages| async move {
+                Ok(ToolTurn {
+                    text: Some(format!("第 {} 步", messages.len() + 1)),
+                    calls: vec!["search"],
+                    assistant_message: None,
+                })
+            },
+            |name| {
+                executed.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(tool_message(format!("{} 的结果", name))) }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(executed.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            result,
+            format!("第 1 步\n\n第 2 步\n\n第 3 步\n\n{}", TOOL_LOOP_LIMIT_NOTE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_loop_returns_final_answer() {
+        let result = run_tool_loop(
+            &[],
+            DEFAULT_MAX_TOOL_ITERATIONS,
+            |messages| async move {
+                Ok(ToolTurn {
+                    text: Some("答案".to_string()),
+                    calls: if messages.is_empty() { vec![()] } else { vec![] },
+                    assistant_message: None,
+                })
+            },
+            |_| async { Ok(tool_message("结果".to_string())) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, "答案");
+    }
+}
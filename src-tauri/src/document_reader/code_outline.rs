@@ -0,0 +1,211 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use xlang_frontend::parser::ast::build_ast;
+use xlang_frontend::parser::lexer::lexer;
+
+/// 超过该行数的代码文件只上传结构大纲
+pub const LARGE_CODE_MAX_LINES: usize = 3000;
+/// 超过该字符数的代码文件只上传结构大纲
+pub const LARGE_CODE_MAX_CHARS: usize = 200_000;
+/// 大纲最多列出的声明数量
+const MAX_OUTLINE_ITEMS: usize = 500;
+/// 单条声明最多保留的字符数
+const MAX_SIGNATURE_CHARS: usize = 120;
+
+static RUST_DECL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r#"^\s*(pub(\([^)]*\))?\s+)?((async|const|unsafe|default)\s+|extern\s+"[^"]*"\s+)*(fn|struct|enum|trait|impl|mod|type|union|macro_rules!)[\s<{(]"#,
+    )
+    .unwrap()
+});
+static PYTHON_DECL_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\s*(async\s+)?(def|class)\s+\w+").unwrap());
+static SCRIPT_DECL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^\s*(export\s+)?(default\s+)?(declare\s+)?(abstract\s+)?(async\s+)?(function\*?|class|interface|type|enum|namespace)\s+\w+|^\s*(export\s+)?(const|let|var)\s+\w+\s*(:[^=]+)?=\s*(async\s+)?(function\b|\([^)]*\)\s*(:[^=]+)?=>|\w+\s*=>)",
+    )
+    .unwrap()
+});
+// 其他语言按常见的声明关键字识别，只看缩进较浅的行，避免把函数体内的语句列入大纲
+static KEYWORD_DECL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^ {0,4}(\t)?([\w@\[\]]+\s+)*(class|struct|interface|enum|trait|record|object|protocol|extension|namespace|module|func|fun|fn|def|function|sub|procedure|impl)\s+[\w:.<>]+",
+    )
+    .unwrap()
+});
+// C 系语言的函数定义：顶格的“返回类型 名称(参数)”且不以分号结尾
+static C_FUNCTION_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(\s{0,4})[A-Za-z_][\w\s\*&:<>,\[\]]*[\s\*&]~?[A-Za-z_][\w:]*\s*\([^;]*\)\s*(const\s*)?(\{.*)?$")
+        .unwrap()
+});
+// 形似函数定义但实际是控制语句的行
+const CONTROL_KEYWORDS: [&str; 8] = [
+    "if", "else", "for", "while", "switch", "return", "catch", "do",
+];
+
+/// 大纲中的一条声明
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutlineItem {
+    pub line: usize, // 声明所在行，从 1 开始
+    pub signature: String,
+}
+
+/// 代码文件是否过大，需要以大纲代替完整内容
+pub fn is_large_code(content: &str) -> bool {
+    content.lines().count() > LARGE_CODE_MAX_LINES || content.chars().count() > LARGE_CODE_MAX_CHARS
+}
+
+/// 提取代码中的函数、类等声明；xlang 使用解析器得到顶层声明，解析失败或其他语言按行匹配
+pub fn extract_outline(content: &str, language: &str) -> Vec<OutlineItem> {
+    if language == "xlang" {
+        if let Some(items) = extract_xlang_outline(content) {
+            return items;
+        }
+    }
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| is_declaration(line, language))
+        .map(|(index, line)| OutlineItem {
+            line: index + 1,
+            signature: clean_signature(line),
+        })
+        .collect()
+}
+
+/// 生成大文件的说明和结构大纲，用于代替完整的代码内容
+pub fn summarize_large_code(content: &str, language: &str) -> String {
+    let total_lines = content.lines().count();
+    let items = extract_outline(content, language);
+
+    let mut summary = format!(
+        "> ⚠️ 文件过大（{} 行，{} 个字符），以下仅为代码结构大纲，未包含完整实现。如需讨论具体实现，请上传相关片段。\n\n",
+        total_lines,
+        content.chars().count()
+    );
+    if items.is_empty() {
+        summary.push_str("*（未能识别出函数或类型声明）*\n");
+        return summary;
+    }
+
+    summary.push_str(&format!("**{} 代码大纲**\n\n", language));
+    for item in items.iter().take(MAX_OUTLINE_ITEMS) {
+        summary.push_str(&format!(
+            "- 第 {} 行：`{}`\n",
+            item.line,
+            item.signature.replace('`', "'")
+        ));
+    }
+    if items.len() > MAX_OUTLINE_ITEMS {
+        summary.push_str(&format!(
+            "\n*（仅列出前 {} 项，共 {} 项声明）*\n",
+            MAX_OUTLINE_ITEMS,
+            items.len()
+        ));
+    }
+    summary
+}
+
+fn is_declaration(line: &str, language: &str) -> bool {
+    match language {
+        "rust" => RUST_DECL_RE.is_match(line),
+        "python" => PYTHON_DECL_RE.is_match(line),
+        "javascript" | "typescript" | "jsx" | "tsx" | "vue" | "svelte" => {
+            SCRIPT_DECL_RE.is_match(line)
+        }
+        "c" | "cpp" | "java" | "csharp" => KEYWORD_DECL_RE.is_match(line) || is_c_function(line),
+        _ => KEYWORD_DECL_RE.is_match(line),
+    }
+}
+
+fn is_c_function(line: &str) -> bool {
+    if !C_FUNCTION_RE.is_match(line) {
+        return false;
+    }
+    let first_word = line
+        .trim_start()
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or("");
+    !CONTROL_KEYWORDS.contains(&first_word)
+}
+
+/// 去掉声明行首尾的空白和左花括号，过长时截断
+fn clean_signature(line: &str) -> String {
+    let signature = line.trim().trim_end_matches('{').trim_end();
+    if signature.chars().count() > MAX_SIGNATURE_CHARS {
+        let truncated: String = signature.chars().take(MAX_SIGNATURE_CHARS).collect();
+        format!("{}…", truncated)
+    } else {
+        signature.to_string()
+    }
+}
+
+/// 使用 xlang 解析器提取顶层表达式所在的行，解析失败时返回 None
+fn extract_xlang_outline(content: &str) -> Option<Vec<OutlineItem>> {
+    let tokens = lexer::tokenize(content);
+    let tokens = lexer::reject_comment(&tokens);
+    let ast = build_ast(&tokens).ok()?;
+
+    let mut items: Vec<OutlineItem> = Vec::new();
+    for node in &ast.children {
+        let Some(position) = node.start_token.map(|token| token.position) else {
+            continue;
+        };
+        let Some(prefix) = content.get(..position) else {
+            continue;
+        };
+        let line = prefix.matches('\n').count() + 1;
+        // 同一行的多个表达式只记录一次
+        if items.last().is_some_and(|item| item.line == line) {
+            continue;
+        }
+        let text = content.lines().nth(line - 1).unwrap_or("");
+        if text.trim().is_empty() {
+            continue;
+        }
+        items.push(OutlineItem {
+            line,
+            signature: clean_signature(text),
+        });
+    }
+    Some(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_outline() {
+        let rust = "use std::fmt;\n\npub struct Point {\n    x: i32,\n}\n\nimpl Point {\n    pub async fn new() -> Self {\n        let f = 1;\n    }\n}\n";
+        let lines: Vec<usize> = extract_outline(rust, "rust")
+            .iter()
+            .map(|item| item.line)
+            .collect();
+        assert_eq!(lines, vec![3, 7, 8]);
+
+        let cpp = "#include <vector>\nint add(int a, int b) {\n    if (a > b) {\n        return a;\n    }\n    foo(a);\n}\nclass Shape {\n};\n";
+        let items = extract_outline(cpp, "cpp");
+        let signatures: Vec<&str> = items.iter().map(|item| item.signature.as_str()).collect();
+        assert_eq!(signatures, vec!["int add(int a, int b)", "class Shape"]);
+
+        let ts =
+            "export const handler = async (req: Request) => {\n};\nexport default class App {}\n";
+        assert_eq!(extract_outline(ts, "typescript").len(), 2);
+    }
+
+    #[test]
+    fn test_summarize_large_code() {
+        let source: String = (0..LARGE_CODE_MAX_LINES)
+            .map(|i| format!("def f{}():\n    return {}\n", i, i))
+            .collect();
+        assert!(is_large_code(&source));
+        assert!(!is_large_code("def f():\n    pass\n"));
+
+        let summary = summarize_large_code(&source, "python");
+        assert!(summary.starts_with("> ⚠️ 文件过大（6000 行"));
+        assert!(summary.contains("- 第 3 行：`def f1():`"));
+        assert!(summary.contains("共 3000 项声明"));
+    }
+}
@@ -0,0 +1,112 @@
+/// 表格最多显示的行数（不含表头）
+const MAX_ROWS: usize = 200;
+/// 表格最多显示的列数
+const MAX_COLUMNS: usize = 20;
+
+/// 根据扩展名选择分隔符
+pub fn delimiter_for_extension(extension: &str) -> u8 {
+    match extension.to_lowercase().as_str() {
+        "tsv" => b'\t',
+        _ => b',',
+    }
+}
+
+/// 将 CSV 文本解析为 Markdown 表格，超出行列上限时截断并附加说明
+pub fn csv_to_markdown_table(content: &str, delimiter: u8) -> Result<String, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        // 允许各行字段数不同，缺少的单元格补为空
+        .flexible(true)
+        .from_reader(content.as_bytes());
+
+    let mut records: Vec<Vec<String>> = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("CSV 解析失败: {}", e))?;
+        records.push(record.iter().map(|field| field.to_string()).collect());
+    }
+
+    if records.is_empty() {
+        return Err("CSV 文件为空".to_string());
+    }
+
+    let total_rows = records.len() - 1;
+    let total_columns = records.iter().map(|r| r.len()).max().unwrap_or(0);
+    let columns = total_columns.min(MAX_COLUMNS);
+
+    let mut table = String::new();
+    for (index, record) in records.iter().take(MAX_ROWS + 1).enumerate() {
+        let cells: Vec<String> = (0..columns)
+            .map(|i| escape_cell(record.get(i).map(|s| s.as_str()).unwrap_or("")))
+            .collect();
+        table.push_str(&format!("| {} |\n", cells.join(" | ")));
+
+        // 表头分隔行
+        if index == 0 {
+            table.push_str(&format!("|{}\n", " --- |".repeat(columns)));
+        }
+    }
+
+    let mut notes = Vec::new();
+    if total_rows > MAX_ROWS {
+        notes.push(format!("仅显示前 {} 行，共 {} 行", MAX_ROWS, total_rows));
+    }
+    if total_columns > MAX_COLUMNS {
+        notes.push(format!(
+            "仅显示前 {} 列，共 {} 列",
+            MAX_COLUMNS, total_columns
+        ));
+    }
+    if !notes.is_empty() {
+        table.push_str(&format!("\n*（{}）*\n", notes.join("，")));
+    }
+
+    Ok(table)
+}
+
+/// 转义单元格中会破坏表格结构的字符
+fn escape_cell(cell: &str) -> String {
+    cell.trim()
+        .replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", " ")
+        .replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_to_markdown_table() {
+        let csv = "姓名,成绩\n张三,90\n\"李|四\",85\n";
+        let table = csv_to_markdown_table(csv, b',').unwrap();
+        assert_eq!(
+            table,
+            "| 姓名 | 成绩 |\n| --- | --- |\n| 张三 | 90 |\n| 李\\|四 | 85 |\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_rows_are_capped() {
+        let mut csv = String::from("id\n");
+        for i in 0..(MAX_ROWS + 5) {
+            csv.push_str(&format!("{}\n", i));
+        }
+        let table = csv_to_markdown_table(&csv, b',').unwrap();
+        assert_eq!(
+            table.lines().filter(|l| l.starts_with('|')).count(),
+            MAX_ROWS + 2
+        );
+        assert!(table.contains(&format!("共 {} 行", MAX_ROWS + 5)));
+    }
+
+    #[test]
+    fn test_ragged_csv_is_padded() {
+        let table = csv_to_markdown_table("a,b\n1,2,3\n4\n", b',').unwrap();
+        assert_eq!(
+            table,
+            "| a | b |  |\n| --- | --- | --- |\n| 1 | 2 | 3 |\n| 4 |  |  |\n"
+        );
+    }
+}
@@ -1,18 +1,99 @@
 pub mod word_reader;
 pub mod pdf_reader;
 pub mod text_reader;
+pub mod csv_reader;
+pub mod code_outline;
 
 use std::path::Path;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
 
 #[cfg(target_os = "android")]
 use std::pin::Pin;
 
-#[cfg(target_os = "android")]
 use std::future::Future;
 
 #[cfg(target_os = "android")]
 use tokio::fs;
 
+// 支持的扩展名及其类型，文件读取和文件选择器都以此为准
+const SUPPORTED_EXTENSIONS: &[(&[&str], &str)] = &[
+    // 文本文件
+    (
+        &["txt", "md", "markdown", "log", "cfg", "conf", "ini", "env"],
+        "text",
+    ),
+    // Office 文档
+    (&["doc", "docx", "rtf"], "word"),
+    // PDF
+    (&["pdf"], "pdf"),
+    // 数据文件
+    (&["csv", "tsv"], "csv"),
+    (&["json"], "json"),
+    (&["xml"], "xml"),
+    (&["html", "htm"], "html"),
+    // 编程语言文件
+    (&["rs", "rust"], "rust"),
+    (&["py", "pyw"], "python"),
+    (&["js"], "javascript"),
+    (&["jsx"], "jsx"),
+    (&["ts"], "typescript"),
+    (&["tsx"], "tsx"),
+    (&["java"], "java"),
+    (&["c", "h", "hpp"], "c"),
+    (&["cpp", "cxx", "cc"], "cpp"),
+    (&["cs"], "csharp"),
+    (&["go"], "go"),
+    (&["php"], "php"),
+    (&["rb"], "ruby"),
+    (&["swift"], "swift"),
+    (&["kt"], "kotlin"),
+    (&["scala"], "scala"),
+    (&["dart"], "dart"),
+    (&["lua"], "lua"),
+    (&["perl", "pl"], "perl"),
+    (&["r"], "r"),
+    (&["sql"], "sql"),
+    (&["sh", "bash"], "bash"),
+    (&["zsh"], "zsh"),
+    (&["ps1", "psm1"], "powershell"),
+    (&["bat", "cmd"], "batch"),
+    (&["vbs"], "vbscript"),
+    (&["yaml", "yml"], "yaml"),
+    (&["toml"], "toml"),
+    (&["css"], "css"),
+    (&["scss", "sass"], "scss"),
+    (&["less"], "less"),
+    (&["vue"], "vue"),
+    (&["svelte"], "svelte"),
+    (&["makefile", "cmake"], "makefile"),
+    (&["dockerfile"], "dockerfile"),
+    (&["gitignore", "gitattributes"], "gitconfig"),
+    (&["x", "xlang"], "xlang"),
+];
+
+// 归入“配置文件”分类的语言
+const CONFIG_LANGUAGES: [&str; 5] = ["yaml", "toml", "makefile", "dockerfile", "gitconfig"];
+
+// 文件选择器中分类的显示顺序
+const CATEGORY_ORDER: [&str; 4] = ["文档文件", "编程文件", "配置文件", "数据文件"];
+
+/// 按分类列出支持的文件类型及其扩展名
+pub fn supported_document_types() -> Vec<(String, Vec<String>)> {
+    CATEGORY_ORDER
+        .iter()
+        .map(|category| {
+            let extensions = SUPPORTED_EXTENSIONS
+                .iter()
+                .filter(|(_, kind)| DocumentType::from_kind(kind).category() == *category)
+                .flat_map(|(extensions, _)| extensions.iter().map(|ext| ext.to_string()))
+                .collect();
+            (category.to_string(), extensions)
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub enum DocumentType {
     Text,
@@ -28,61 +109,36 @@ pub enum DocumentType {
 
 impl DocumentType {
     pub fn from_extension(ext: &str) -> Self {
-        match ext.to_lowercase().as_str() {
-            // 文本文件
-            "txt" | "md" | "markdown" | "log" | "cfg" | "conf" | "ini" | "env" => Self::Text,
-            
-            // Office 文档
-            "doc" | "docx" | "rtf" => Self::Word,
-            
-            // PDF
+        let ext = ext.to_lowercase();
+        SUPPORTED_EXTENSIONS
+            .iter()
+            .find(|(extensions, _)| extensions.contains(&ext.as_str()))
+            .map(|(_, kind)| Self::from_kind(kind))
+            .unwrap_or(Self::Other(ext))
+    }
+
+    // 扩展名表中的类型名：文档和数据类型使用固定名称，其余为编程语言名
+    fn from_kind(kind: &str) -> Self {
+        match kind {
+            "text" => Self::Text,
+            "word" => Self::Word,
             "pdf" => Self::Pdf,
-            
-            // 数据文件
-            "csv" | "tsv" => Self::Csv,
+            "csv" => Self::Csv,
             "json" => Self::Json,
             "xml" => Self::Xml,
-            "html" | "htm" => Self::Html,
-              // 编程语言文件
-            "rs" | "rust" => Self::Code("rust".to_string()),
-            "py" | "pyw" => Self::Code("python".to_string()),
-            "js" => Self::Code("javascript".to_string()),
-            "jsx" => Self::Code("jsx".to_string()),
-            "ts" => Self::Code("typescript".to_string()),
-            "tsx" => Self::Code("tsx".to_string()),
-            "java" => Self::Code("java".to_string()),
-            "c" => Self::Code("c".to_string()),
-            "cpp" | "cxx" | "cc" => Self::Code("cpp".to_string()),
-            "h" | "hpp" => Self::Code("c".to_string()),
-            "cs" => Self::Code("csharp".to_string()),
-            "go" => Self::Code("go".to_string()),
-            "php" => Self::Code("php".to_string()),
-            "rb" => Self::Code("ruby".to_string()),
-            "swift" => Self::Code("swift".to_string()),
-            "kt" => Self::Code("kotlin".to_string()),
-            "scala" => Self::Code("scala".to_string()),
-            "dart" => Self::Code("dart".to_string()),
-            "lua" => Self::Code("lua".to_string()),
-            "perl" | "pl" => Self::Code("perl".to_string()),
-            "r" => Self::Code("r".to_string()),
-            "sql" => Self::Code("sql".to_string()),
-            "sh" | "bash" => Self::Code("bash".to_string()),
-            "zsh" => Self::Code("zsh".to_string()),
-            "ps1" | "psm1" => Self::Code("powershell".to_string()),
-            "bat" | "cmd" => Self::Code("batch".to_string()),
-            "vbs" => Self::Code("vbscript".to_string()),
-            "yaml" | "yml" => Self::Code("yaml".to_string()),            "toml" => Self::Code("toml".to_string()),
-            "css" => Self::Code("css".to_string()),
-            "scss" | "sass" => Self::Code("scss".to_string()),
-            "less" => Self::Code("less".to_string()),
-            "vue" => Self::Code("vue".to_string()),
-            "svelte" => Self::Code("svelte".to_string()),
-            "makefile" | "cmake" => Self::Code("makefile".to_string()),
-            "dockerfile" => Self::Code("dockerfile".to_string()),
-            "gitignore" | "gitattributes" => Self::Code("gitconfig".to_string()),
-            
-            // 其他格式
-            other => Self::Other(other.to_string()),
+            "html" => Self::Html,
+            language => Self::Code(language.to_string()),
+        }
+    }
+
+    /// 文件选择器中显示的分类名
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::Text | Self::Word | Self::Pdf => "文档文件",
+            Self::Csv | Self::Json | Self::Xml | Self::Html => "数据文件",
+            Self::Code(lang) if CONFIG_LANGUAGES.contains(&lang.as_str()) => "配置文件",
+            Self::Code(_) => "编程文件",
+            Self::Other(_) => "其他文件",
         }
     }
     
@@ -107,11 +163,129 @@ impl DocumentType {
     }
 }
 
+// 上传文件消息的默认模板，{content} 为按设置决定是否包裹代码块后的文件内容
+pub const DEFAULT_UPLOAD_TEMPLATE: &str = "📎 **上传文件: {name}**\n\n{content}";
+
+/// 上传文件内容是否包裹在代码块中
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodeFenceMode {
+    Always, // 所有文件都包裹（默认）
+    Auto,   // Word、PDF 和纯文本按正文呈现，代码和数据文件包裹
+    Never,  // 都不包裹
+}
+
+impl CodeFenceMode {
+    pub fn from_setting(value: &str) -> Self {
+        match value {
+            "auto" => Self::Auto,
+            "never" => Self::Never,
+            _ => Self::Always,
+        }
+    }
+
+    fn should_fence(&self, doc_type: &DocumentType) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => !matches!(
+                doc_type,
+                DocumentType::Word | DocumentType::Pdf | DocumentType::Text
+            ),
+        }
+    }
+}
+
+struct UploadFormat {
+    template: String,
+    fence: CodeFenceMode,
+}
+
+static UPLOAD_FORMAT: Lazy<RwLock<UploadFormat>> = Lazy::new(|| {
+    RwLock::new(UploadFormat {
+        template: DEFAULT_UPLOAD_TEMPLATE.to_string(),
+        fence: CodeFenceMode::Always,
+    })
+});
+
+/// 设置上传文件消息的模板（支持 {name}、{lang}、{content} 占位符，为空时使用默认模板）和代码块包裹方式
+pub fn set_upload_format(template: &str, fence: &str) {
+    let mut format = UPLOAD_FORMAT.write().unwrap();
+    format.template = if template.trim().is_empty() {
+        DEFAULT_UPLOAD_TEMPLATE.to_string()
+    } else {
+        template.to_string()
+    };
+    format.fence = CodeFenceMode::from_setting(fence);
+}
+
+fn render_upload_template(
+    template: &str,
+    file_name: &str,
+    language_hint: &str,
+    content: &str,
+) -> String {
+    // 最后替换 {content}，避免文件内容中的占位符被替换
+    template
+        .replace("{name}", file_name)
+        .replace("{lang}", language_hint)
+        .replace("{content}", content)
+}
+
+/// 按当前设置生成上传文件的消息内容
+fn format_upload_message(file_name: &str, doc_type: &DocumentType, content: &str) -> String {
+    let format = UPLOAD_FORMAT.read().unwrap();
+    let language_hint = doc_type.get_language_hint();
+    let body = if format.fence.should_fence(doc_type) {
+        format!("```{}\n{}\n```", language_hint, content)
+    } else {
+        content.to_string()
+    };
+    render_upload_template(&format.template, file_name, &language_hint, &body)
+}
+
+/// 已经是 Markdown 的内容（CSV/TSV 转换得到的表格、大文件的代码大纲）不包裹代码块
+fn format_upload_markdown(file_name: &str, doc_type: &DocumentType, markdown: &str) -> String {
+    let format = UPLOAD_FORMAT.read().unwrap();
+    render_upload_template(
+        &format.template,
+        file_name,
+        &doc_type.get_language_hint(),
+        markdown,
+    )
+}
+
+// 内容嗅探读取的文件头长度
+const SNIFF_LEN: usize = 8192;
+
+/// 根据文件头的魔数推断文件扩展名，用于没有扩展名或扩展名无法识别的文件
+///
+/// 无法识别且不含空字节的内容按纯文本处理，其余情况返回 None
+pub fn sniff_extension(header: &[u8]) -> Option<String> {
+    if let Some(kind) = infer::get(header) {
+        let extension = kind.extension();
+        return DocumentType::from_extension(extension)
+            .is_supported()
+            .then(|| extension.to_string());
+    }
+    (!header.is_empty() && !header.contains(&0)).then(|| "txt".to_string())
+}
+
+/// 读取文件头并推断扩展名
+fn sniff_file_extension(path: &Path) -> Option<String> {
+    use std::io::Read;
+
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    std::fs::File::open(path)
+        .and_then(|file| file.take(SNIFF_LEN as u64).read_to_end(&mut header))
+        .ok()?;
+    sniff_extension(&header)
+}
+
 /// 读取文档内容的统一接口
 pub async fn read_document(file_path: &str) -> Result<String, String> {
     println!("Processing file path: {}", file_path);
     
-    // 处理Android content URI
+    // 处理Android content URI：调用方通常已通过 ContentResolver 复制到本地，这里仅作为猜测文件路径的后备
     #[cfg(target_os = "android")]
     {
         if file_path.starts_with("content://") {
@@ -126,12 +300,20 @@ pub async fn read_document(file_path: &str) -> Result<String, String> {
         return Err("文件不存在".to_string());
     }
     
-    let extension = path
+    let mut extension = path
         .extension()
         .and_then(|ext| ext.to_str())
         .unwrap_or("")
         .to_lowercase();
     
+    // 没有扩展名或扩展名无法识别时，根据文件内容判断类型
+    if !DocumentType::from_extension(&extension).is_supported() {
+        if let Some(sniffed) = sniff_file_extension(path) {
+            println!("根据文件内容识别为 .{} 文件", sniffed);
+            extension = sniffed;
+        }
+    }
+    
     let doc_type = DocumentType::from_extension(&extension);
     
     if !doc_type.is_supported() {
@@ -151,13 +333,118 @@ pub async fn read_document(file_path: &str) -> Result<String, String> {
         .unwrap_or("未知文件");
     
     // 格式化文件内容
-    let language_hint = doc_type.get_language_hint();
-    let formatted_content = format!(
-        "📎 **上传文件: {}**\n\n```{}\n{}\n```",
-        file_name, language_hint, content
-    );
-    
-    Ok(formatted_content)
+    Ok(format_document(file_name, &doc_type, &extension, &content))
+}
+
+// 合并读取多个文档时全部内容的字符数上限，由各文档平分
+pub const COMBINED_DOCUMENTS_BUDGET: usize = 120_000;
+
+// 文档内容超出分配的长度时附加的说明
+const TRUNCATED_NOTE: &str = "\n\n……（内容过长，已截断）";
+
+/// 在总字符数预算内为每篇文档分配长度：短于平均份额的文档保留全部内容，
+/// 剩余的预算再平分给较长的文档
+fn split_budget(lengths: &[usize], budget: usize) -> Vec<usize> {
+    let mut limits = vec![0; lengths.len()];
+    let mut remaining: Vec<usize> = (0..lengths.len()).collect();
+    remaining.sort_by_key(|&i| lengths[i]);
+    let mut budget_left = budget;
+    for (done, &i) in remaining.iter().enumerate() {
+        let share = budget_left / (remaining.len() - done);
+        limits[i] = lengths[i].min(share);
+        budget_left -= limits[i];
+    }
+    limits
+}
+
+/// 将多篇文档合并为一条消息：开头是目录，每篇文档前加上来源标题，
+/// 总长度不超过 budget 个字符，超出分配长度的文档会被截断
+pub fn combine_documents(documents: &[(String, String)], budget: usize) -> String {
+    let lengths: Vec<usize> = documents
+        .iter()
+        .map(|(_, content)| content.chars().count())
+        .collect();
+    let limits = split_budget(&lengths, budget);
+
+    let mut toc = format!("📚 **共 {} 篇文档**\n", documents.len());
+    let mut sections = Vec::with_capacity(documents.len());
+    for (index, ((name, content), (&length, &limit))) in documents
+        .iter()
+        .zip(lengths.iter().zip(&limits))
+        .enumerate()
+    {
+        let truncated = limit < length;
+        toc.push_str(&format!(
+            "\n{}. {}{}",
+            index + 1,
+            name,
+            if truncated { "（已截断）" } else { "" }
+        ));
+        let mut body: String = content.chars().take(limit).collect();
+        if truncated {
+            body.push_str(TRUNCATED_NOTE);
+        }
+        sections.push(format!("## 文档 {}：{}\n\n{}", index + 1, name, body));
+    }
+
+    std::iter::once(toc)
+        .chain(sections)
+        .collect::<Vec<_>>()
+        .join("\n\n---\n\n")
+}
+
+/// 依次使用 read 读取多篇文档并合并为一条消息，便于在一次提问中比较多篇论文；
+/// 读取失败的文档在对应位置记录失败原因
+pub async fn read_documents_combined<F, Fut>(paths: &[String], mut read: F) -> Result<String, String>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<String, String>>,
+{
+    if paths.is_empty() {
+        return Err("没有选择任何文件".to_string());
+    }
+    let mut documents = Vec::with_capacity(paths.len());
+    for path in paths {
+        let content = read(path.clone())
+            .await
+            .unwrap_or_else(|e| format!("读取失败: {}", e));
+        documents.push((document_display_name(path), content));
+    }
+    Ok(combine_documents(&documents, COMBINED_DOCUMENTS_BUDGET))
+}
+
+/// 文件路径或 content URI 中用于显示的文件名
+pub fn document_display_name(path: &str) -> String {
+    path.rsplit(['/', '\\'])
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or(path)
+        .to_string()
+}
+
+/// 按文件类型生成上传消息：CSV/TSV 转为表格，过大的代码文件只保留结构大纲，其余按模板格式化
+fn format_document(
+    file_name: &str,
+    doc_type: &DocumentType,
+    extension: &str,
+    content: &str,
+) -> String {
+    // CSV/TSV 文件优先转换为 Markdown 表格，解析失败时保留原始内容
+    if matches!(doc_type, DocumentType::Csv) {
+        let delimiter = csv_reader::delimiter_for_extension(extension);
+        if let Ok(table) = csv_reader::csv_to_markdown_table(content, delimiter) {
+            return format_upload_markdown(file_name, doc_type, &table);
+        }
+    }
+
+    if let DocumentType::Code(language) = doc_type {
+        if code_outline::is_large_code(content) {
+            let outline = code_outline::summarize_large_code(content, language);
+            return format_upload_markdown(file_name, doc_type, &outline);
+        }
+    }
+
+    format_upload_message(file_name, doc_type, content)
 }
 
 #[cfg(target_os = "android")]
@@ -183,8 +470,18 @@ async fn read_android_content_uri(content_uri: &str) -> Result<String, String> {
     let extension = if file_name.contains('.') {
         file_name.split('.').last().unwrap_or("txt").to_lowercase()
     } else {
-        // 如果没有扩展名，默认为文本文件
-        "txt".to_string()
+        String::new()
+    };
+    // 没有扩展名或扩展名无法识别时，尝试找到实际文件并根据内容判断类型
+    let extension = if DocumentType::from_extension(&extension).is_supported() {
+        extension
+    } else {
+        let sniffed = match find_android_file_path(content_uri, &file_name).await {
+            Ok(actual_path) => sniff_file_extension(Path::new(&actual_path)),
+            Err(_) => None,
+        };
+        // 无法判断时沿用原来的做法，按文本文件处理
+        sniffed.unwrap_or_else(|| "txt".to_string())
     };
       let doc_type = DocumentType::from_extension(&extension);
     
@@ -214,11 +511,7 @@ async fn read_android_content_uri(content_uri: &str) -> Result<String, String> {
         }
     };
     
-    let language_hint = doc_type.get_language_hint();
-    let formatted_content = format!(
-        "📎 **上传文件: {}**\n\n```{}\n{}\n```",
-        file_name, language_hint, content    );
-    Ok(formatted_content)
+    Ok(format_document(&file_name, &doc_type, &extension, &content))
 }
 
 #[cfg(target_os = "android")]
@@ -302,3 +595,73 @@ async fn try_read_with_tauri_fs(_content_uri: &str) -> Result<String, String> {
     // 简单的占位符，让其他方法继续尝试
     Err("Tauri FS not available".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_upload_message() {
+        let code = DocumentType::Code("rust".to_string());
+        assert_eq!(
+            format_upload_message("main.rs", &code, "fn main() {}"),
+            "📎 **上传文件: main.rs**\n\n```rust\nfn main() {}\n```"
+        );
+
+        set_upload_format("<file name=\"{name}\" lang=\"{lang}\">\n{content}\n</file>", "auto");
+        assert_eq!(
+            format_upload_message("notes.docx", &DocumentType::Word, "正文 {name}"),
+            "<file name=\"notes.docx\" lang=\"text\">\n正文 {name}\n</file>"
+        );
+        assert!(format_upload_message("main.rs", &code, "fn main() {}").contains("```rust"));
+
+        set_upload_format("", "always");
+        assert!(format_upload_message("notes.docx", &DocumentType::Word, "正文").starts_with("📎"));
+    }
+
+    #[test]
+    fn test_combine_documents() {
+        assert_eq!(split_budget(&[10, 100, 1000], 300), vec![10, 100, 190]);
+        assert_eq!(split_budget(&[500, 500], 300), vec![150, 150]);
+
+        let documents = vec![
+            ("a.pdf".to_string(), "短文".to_string()),
+            ("b.pdf".to_string(), "长".repeat(20)),
+        ];
+        let combined = combine_documents(&documents, 12);
+        assert!(combined.starts_with("📚 **共 2 篇文档**\n\n1. a.pdf\n2. b.pdf（已截断）"));
+        assert!(combined.contains("## 文档 1：a.pdf\n\n短文\n\n---"));
+        assert!(combined.contains(&format!("## 文档 2：b.pdf\n\n{}{}", "长".repeat(10), TRUNCATED_NOTE)));
+        assert_eq!(document_display_name("/home/user/论文.pdf"), "论文.pdf");
+        assert_eq!(document_display_name("C:\\docs\\a.docx"), "a.docx");
+    }
+
+    #[test]
+    fn test_sniff_extension() {
+        assert_eq!(sniff_extension(b"%PDF-1.7\n%\xe2\xe3").as_deref(), Some("pdf"));
+        assert_eq!(sniff_extension("纯文本内容".as_bytes()).as_deref(), Some("txt"));
+        // 无法识别的二进制内容和不支持的类型
+        assert_eq!(sniff_extension(&[0x00, 0x01, 0x02, 0x03]), None);
+        assert_eq!(sniff_extension(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]), None);
+    }
+
+    #[test]
+    fn test_supported_document_types() {
+        let types = supported_document_types();
+        let categories: Vec<&str> = types.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(categories, CATEGORY_ORDER);
+        for (category, extensions) in &types {
+            assert!(!extensions.is_empty());
+            for ext in extensions {
+                let doc_type = DocumentType::from_extension(ext);
+                assert!(doc_type.is_supported());
+                assert_eq!(doc_type.category(), category);
+            }
+        }
+        assert!(
+            matches!(DocumentType::from_extension("PY"), DocumentType::Code(lang) if lang == "python")
+        );
+        assert_eq!(DocumentType::from_extension("yml").category(), "配置文件");
+        assert!(!DocumentType::from_extension("exe").is_supported());
+    }
+}
@@ -0,0 +1,75 @@
+use comrak::nodes::NodeValue;
+use comrak::{parse_document, Arena};
+use serde::{Deserialize, Serialize};
+
+use crate::aibackend::template::extract_response;
+
+use super::renderer::markdown_options;
+
+/// 消息中的一个代码块
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CodeBlock {
+    pub language: String, // 代码块语言（围栏信息的第一个单词），未标注时为空
+    pub content: String,
+}
+
+/// 从助手消息中提取回答部分的所有代码块（忽略思维链中的代码）
+pub fn extract_message_code_blocks(content: &str) -> Vec<CodeBlock> {
+    let response = extract_response(content).unwrap_or_else(|| content.to_string());
+    extract_code_blocks(&response)
+}
+
+/// 使用与渲染器相同的 Markdown 解析选项提取所有代码块
+pub fn extract_code_blocks(markdown: &str) -> Vec<CodeBlock> {
+    let arena = Arena::new();
+    let options = markdown_options(false);
+    let root = parse_document(&arena, markdown, &options);
+
+    root.descendants()
+        .filter_map(|node| match &node.data.borrow().value {
+            NodeValue::CodeBlock(code_block) => Some(CodeBlock {
+                language: code_block
+                    .info
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or("")
+                    .to_string(),
+                content: code_block.literal.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let markdown =
+            "说明\n\n```rust title=main.rs\nfn main() {}\n```\n\n中间文字\n\n```\nplain\n```\n";
+        let blocks = extract_code_blocks(markdown);
+        assert_eq!(
+            blocks,
+            vec![
+                CodeBlock {
+                    language: "rust".to_string(),
+                    content: "fn main() {}\n".to_string(),
+                },
+                CodeBlock {
+                    language: String::new(),
+                    content: "plain\n".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_message_code_blocks_skips_cot() {
+        let content = "<|start_header|>think<|end_header|>```python\nprint(1)\n```<|start_header|>typeset_and_respond<|end_header|>```rust\nlet x = 1;\n```";
+        let blocks = extract_message_code_blocks(content);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].language, "rust");
+    }
+}
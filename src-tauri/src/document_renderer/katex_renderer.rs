@@ -0,0 +1,47 @@
+/// 使用 KaTeX 将 LaTeX 公式渲染为 MathML，无需浏览器即可显示
+pub fn render_katex_mathml(code: &str, display_mode: bool) -> Result<String, String> {
+    let opts = katex::Opts::builder()
+        .display_mode(display_mode)
+        .output_type(katex::OutputType::Mathml)
+        .build()
+        .map_err(|e| format!("KaTeX 选项构建失败: {}", e))?;
+    katex::render_with_opts(code.trim(), &opts).map_err(|e| format!("KaTeX 渲染失败: {}", e))
+}
+
+/// 渲染公式，失败时返回转义后的原始 LaTeX 代码
+pub fn render_katex_or_source(code: &str, display_mode: bool) -> String {
+    render_katex_mathml(code, display_mode).unwrap_or_else(|e| {
+        println!("{}", e);
+        let class = if display_mode {
+            "math-display"
+        } else {
+            "math-inline"
+        };
+        format!(
+            "<span class=\"{}\">{}</span>",
+            class,
+            html_escape::encode_text(code)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_katex_mathml() {
+        let html = render_katex_mathml("E = mc^2", true).unwrap();
+        assert!(html.contains("<math"));
+        assert!(html.contains("display=\"block\""));
+    }
+
+    #[test]
+    fn test_render_katex_falls_back_to_source() {
+        assert!(render_katex_mathml("\\frac{", false).is_err());
+        assert_eq!(
+            render_katex_or_source("\\frac{", false),
+            "<span class=\"math-inline\">\\frac{</span>"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// mermaid-cli 的可执行文件名，Windows 上 npm 安装的是 .cmd 脚本
+#[cfg(windows)]
+const MERMAID_CLI: &str = "mmdc.cmd";
+#[cfg(not(windows))]
+const MERMAID_CLI: &str = "mmdc";
+
+// 用于区分同时进行的多个渲染任务的临时文件
+static RENDER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// 使用 mermaid-cli（mmdc）将 Mermaid 代码渲染为 SVG，供导出等无浏览器的场景使用。
+/// 图表代码无效时返回 mmdc 输出的错误信息
+pub fn render_mermaid_svg(code: &str) -> Result<String, String> {
+    if code.trim().is_empty() {
+        return Err("Mermaid 代码为空".to_string());
+    }
+
+    let id = format!(
+        "npulearn-mermaid-{}-{}",
+        std::process::id(),
+        RENDER_COUNTER.fetch_add(1, Ordering::SeqCst)
+    );
+    let input = std::env::temp_dir().join(format!("{}.mmd", id));
+    let output = std::env::temp_dir().join(format!("{}.svg", id));
+    std::fs::write(&input, code).map_err(|e| format!("无法写入 Mermaid 临时文件: {}", e))?;
+
+    let result = run_mermaid_cli(&input, &output);
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+    result
+}
+
+fn run_mermaid_cli(input: &Path, output: &Path) -> Result<String, String> {
+    let result = Command::new(MERMAID_CLI)
+        .arg("--quiet")
+        .arg("--input")
+        .arg(input)
+        .arg("--output")
+        .arg(output)
+        .arg("--backgroundColor")
+        .arg("transparent")
+        .output()
+        .map_err(|e| {
+            format!(
+                "无法运行 mermaid-cli（{}），请通过 npm install -g @mermaid-js/mermaid-cli 安装: {}",
+                MERMAID_CLI, e
+            )
+        })?;
+
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(format!("Mermaid 渲染失败: {}", summarize_cli_error(&stderr)));
+    }
+
+    std::fs::read_to_string(output).map_err(|e| format!("无法读取 Mermaid 渲染结果: {}", e))
+}
+
+/// 提取 mmdc 错误输出中的错误说明，去掉其后的 JavaScript 调用栈
+fn summarize_cli_error(stderr: &str) -> String {
+    let summary = stderr
+        .lines()
+        .take_while(|line| !line.trim_start().starts_with("at "))
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if summary.is_empty() {
+        "mermaid-cli 没有输出错误信息".to_string()
+    } else {
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_cli_error() {
+        let stderr = "\nError: Parse error on line 2:\n...graph TD A--\n-----------------^\nExpecting 'AMP', got 'EOF'\n    at Parser.parseError (file:///mermaid.js:1:1)\n    at Parser.parse (file:///mermaid.js:2:2)\n";
+        assert_eq!(
+            summarize_cli_error(stderr),
+            "Error: Parse error on line 2:\n...graph TD A--\n-----------------^\nExpecting 'AMP', got 'EOF'"
+        );
+        assert_eq!(summarize_cli_error(""), "mermaid-cli 没有输出错误信息");
+        assert!(render_mermaid_svg("  \n").is_err());
+    }
+}
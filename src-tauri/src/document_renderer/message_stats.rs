@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+
+use super::plaintext::{markdown_to_plaintext, message_to_plaintext};
+
+// 阅读速度：中文按每分钟 300 字，英文按每分钟 200 词估算
+const CHINESE_CHARS_PER_MINUTE: f64 = 300.0;
+const ENGLISH_WORDS_PER_MINUTE: f64 = 200.0;
+
+/// 消息的字数统计与阅读时间估算
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageStats {
+    pub characters: usize,      // 非空白字符数
+    pub chinese_chars: usize,   // 中文字数（汉字按字计）
+    pub english_words: usize,   // 英文单词数（含数字）
+    pub reading_minutes: usize, // 估算阅读时间（分钟，至少 1 分钟）
+}
+
+/// 统计助手消息中用户可见的回答部分（去除思维链和 Markdown 标记）
+pub fn assistant_message_stats(content: &str) -> MessageStats {
+    text_stats(&message_to_plaintext(content))
+}
+
+/// 统计普通 Markdown 消息
+pub fn markdown_message_stats(content: &str) -> MessageStats {
+    text_stats(&markdown_to_plaintext(content))
+}
+
+fn text_stats(text: &str) -> MessageStats {
+    let characters = text.chars().filter(|c| !c.is_whitespace()).count();
+    let chinese_chars = text.chars().filter(|c| is_cjk(*c)).count();
+    let english_words = text
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '\'' && c != '-')
+        .filter(|word| word.chars().any(|c| c.is_ascii_alphanumeric()))
+        .count();
+
+    let minutes = chinese_chars as f64 / CHINESE_CHARS_PER_MINUTE
+        + english_words as f64 / ENGLISH_WORDS_PER_MINUTE;
+    let reading_minutes = if characters == 0 {
+        0
+    } else {
+        (minutes.ceil() as usize).max(1)
+    };
+
+    MessageStats {
+        characters,
+        chinese_chars,
+        english_words,
+        reading_minutes,
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{4E00}'..='\u{9FFF}'   // 中日韩统一表意文字
+        | '\u{3400}'..='\u{4DBF}' // 扩展 A
+        | '\u{F900}'..='\u{FAFF}' // 兼容表意文字
+        | '\u{20000}'..='\u{2A6DF}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_stats() {
+        let stats = markdown_message_stats("# 标题\n\n使用 **Rust** 编写 hello-world 程序。");
+        assert_eq!(stats.chinese_chars, 8);
+        assert_eq!(stats.english_words, 2);
+        assert_eq!(stats.reading_minutes, 1);
+    }
+
+    #[test]
+    fn test_assistant_stats_ignore_cot() {
+        let content = "<|start_header|>think<|end_header|>这段思考不应计入<|start_header|>typeset_and_respond<|end_header|>答案";
+        let stats = assistant_message_stats(content);
+        assert_eq!(stats.characters, 2);
+        assert_eq!(stats.chinese_chars, 2);
+    }
+
+    #[test]
+    fn test_reading_time_for_long_text() {
+        let stats = markdown_message_stats(&"学".repeat(900));
+        assert_eq!(stats.reading_minutes, 3);
+        assert_eq!(markdown_message_stats("").reading_minutes, 0);
+    }
+}
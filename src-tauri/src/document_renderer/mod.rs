@@ -1,2 +1,10 @@
+pub mod code_blocks;
+pub mod katex_renderer;
+pub mod mermaid_renderer;
+pub mod message_stats;
+pub mod plaintext;
 pub mod renderer;
+pub mod text_diff;
+pub mod tool_code;
+pub mod typst_renderer;
 pub mod wolfram;
\ No newline at end of file
@@ -0,0 +1,114 @@
+use comrak::nodes::{AstNode, ListType, NodeValue};
+use comrak::{parse_document, Arena, ComrakOptions};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::aibackend::template::extract_response;
+
+static TITLE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<\|start_title\|>.*?<\|end_title\|>").unwrap());
+static BLANK_LINES_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{3,}").unwrap());
+
+/// 提取助手消息中用户可见的回答部分（去除思维链与标题标记），并转换为纯文本
+pub fn message_to_plaintext(content: &str) -> String {
+    let response = extract_response(content).unwrap_or_else(|| content.to_string());
+    let response = TITLE_RE.replace_all(&response, "");
+    markdown_to_plaintext(&response)
+}
+
+/// 将 Markdown 转换为便于阅读的纯文本，保留段落、列表和代码块内容，去除格式标记
+pub fn markdown_to_plaintext(markdown: &str) -> String {
+    let arena = Arena::new();
+    let mut options = ComrakOptions::default();
+    options.extension.strikethrough = true;
+    options.extension.table = true;
+    options.extension.tasklist = true;
+
+    let root = parse_document(&arena, markdown, &options);
+    let mut output = String::new();
+    render_block(root, &mut output, 0);
+
+    // 合并多余空行
+    BLANK_LINES_RE.replace_all(output.trim(), "\n\n").to_string()
+}
+
+fn render_block<'a>(node: &'a AstNode<'a>, output: &mut String, depth: usize) {
+    match &node.data.borrow().value {
+        NodeValue::Paragraph | NodeValue::Heading(_) => {
+            output.push_str(&render_inline(node));
+            output.push_str("\n\n");
+        }
+        NodeValue::CodeBlock(code_block) => {
+            output.push_str(code_block.literal.trim_end());
+            output.push_str("\n\n");
+        }
+        NodeValue::List(list) => {
+            let mut number = list.start;
+            for item in node.children() {
+                let marker = match list.list_type {
+                    ListType::Bullet => "- ".to_string(),
+                    ListType::Ordered => format!("{}. ", number),
+                };
+                number += 1;
+
+                let mut item_text = String::new();
+                for child in item.children() {
+                    render_block(child, &mut item_text, depth + 1);
+                }
+                let indent = "  ".repeat(depth);
+                let item_text = item_text.trim().replace("\n\n", "\n");
+                output.push_str(&format!("{}{}{}\n", indent, marker, item_text));
+            }
+            output.push('\n');
+        }
+        NodeValue::Table(_) => {
+            for row in node.children() {
+                let cells: Vec<String> = row.children().map(|cell| render_inline(cell)).collect();
+                output.push_str(&cells.join("\t"));
+                output.push('\n');
+            }
+            output.push('\n');
+        }
+        NodeValue::HtmlBlock(_) | NodeValue::ThematicBreak => {}
+        _ => {
+            for child in node.children() {
+                render_block(child, output, depth);
+            }
+        }
+    }
+}
+
+fn render_inline<'a>(node: &'a AstNode<'a>) -> String {
+    let mut text = String::new();
+    for child in node.children() {
+        match &child.data.borrow().value {
+            NodeValue::Text(literal) => text.push_str(literal),
+            NodeValue::Code(code) => text.push_str(&code.literal),
+            NodeValue::SoftBreak | NodeValue::LineBreak => text.push('\n'),
+            NodeValue::HtmlInline(_) => {}
+            _ => text.push_str(&render_inline(child)),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_to_plaintext() {
+        let markdown = "# 标题\n\n这是**加粗**和`代码`，[链接](https://example.com)。\n\n- 第一项\n- 第二项\n\n```rust\nfn main() {}\n```";
+        let text = markdown_to_plaintext(markdown);
+        assert_eq!(
+            text,
+            "标题\n\n这是加粗和代码，链接。\n\n- 第一项\n- 第二项\n\nfn main() {}"
+        );
+    }
+
+    #[test]
+    fn test_message_to_plaintext_strips_cot() {
+        let content = "<|start_title|>测试<|end_title|><|start_header|>think<|end_header|>思考过程<|start_header|>typeset_and_respond<|end_header|>答案是 *42*";
+        assert_eq!(message_to_plaintext(content), "答案是 42");
+    }
+}
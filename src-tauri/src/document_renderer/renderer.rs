@@ -1,18 +1,48 @@
 use comrak::{markdown_to_html, ComrakOptions};
 use ammonia::clean;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub fn convert_markdown_with_latex(markdown: &str) -> String {
-    // 将输入的 markdown 按照特殊标记分割成思考过程和最终回答
-    let parts: Vec<&str> = markdown.split("<|start_header|>typeset_and_respond<|end_header|>").collect();
-    
-    // 设置 Comrak Markdown 转换选项
+// 思考过程和最终回答之间的分隔标记
+const RESPONSE_HEADER: &str = "<|start_header|>typeset_and_respond<|end_header|>";
+
+// 行内代码片段
+static INLINE_CODE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"`+[^`]*`+").unwrap());
+
+// 安全渲染模式：开启后转义消息中的原始 HTML，仅保留 Markdown 语法、代码块和公式
+static SAFE_RENDERING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_safe_rendering(enabled: bool) {
+    SAFE_RENDERING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_safe_rendering() -> bool {
+    SAFE_RENDERING.load(Ordering::Relaxed)
+}
+
+/// 设置 Comrak Markdown 转换选项
+pub(crate) fn markdown_options(safe_rendering: bool) -> ComrakOptions<'static> {
     let mut options = ComrakOptions::default();
     options.extension.strikethrough = true;
     options.extension.table = true;
     options.extension.autolink = true;
     options.extension.tasklist = true;
-    options.render.unsafe_ = true; // 允许原始 HTML
+    if safe_rendering {
+        options.render.escape = true; // 转义原始 HTML，使其以文本形式显示
+    } else {
+        options.render.unsafe_ = true; // 允许原始 HTML
+    }
     options.render.hardbreaks = true; // 将单个换行符视为硬断行（实际换行）
+    options
+}
+
+pub fn convert_markdown_with_latex(markdown: &str) -> String {
+    // 将输入的 markdown 按照特殊标记分割成思考过程和最终回答
+    let parts: Vec<&str> = markdown.split("<|start_header|>typeset_and_respond<|end_header|>").collect();
+    
+    let options = markdown_options(is_safe_rendering());
 
     // 如果没有特殊标记或只有一个部分，直接转换整个 markdown
     if parts.len() <= 1 {
@@ -45,3 +75,99 @@ pub fn convert_markdown_with_latex(markdown: &str) -> String {
     
     result
 }
+
+/// 流式显示时补全未闭合的代码块围栏和 `$$` 公式，避免未完成的内容渲染错乱；结果只用于显示，不应保存
+///
+/// 只处理最后一个回答标记之后的部分，之前的思考过程已经生成完毕
+pub fn close_unbalanced_markup(markdown: &str) -> Cow<'_, str> {
+    let tail_start = markdown
+        .rfind(RESPONSE_HEADER)
+        .map_or(0, |index| index + RESPONSE_HEADER.len());
+    let tail = &markdown[tail_start..];
+
+    let mut open_fence: Option<&str> = None;
+    let mut display_math_open = false;
+    for line in tail.lines() {
+        let trimmed = line.trim_start();
+        let fence_char = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let fence_len = fence_char.map_or(0, |f| trimmed.chars().take_while(|c| *c == f).count());
+        let fence = &trimmed[..fence_len];
+        let is_fence = fence_len >= 3;
+
+        match open_fence {
+            // 闭合围栏必须使用相同字符、长度不小于开始围栏，且后面没有其他内容
+            Some(open) => {
+                if is_fence && fence.starts_with(open) && trimmed[fence_len..].trim().is_empty() {
+                    open_fence = None;
+                }
+            }
+            None if is_fence && !(fence.starts_with('`') && trimmed[fence_len..].contains('`')) => {
+                open_fence = Some(fence);
+            }
+            None => {
+                // 行内代码中的 `$$` 不是公式
+                let text = INLINE_CODE_RE.replace_all(line, "");
+                let delimiters = text
+                    .matches("$$")
+                    .count()
+                    .saturating_sub(text.matches("\\$$").count());
+                if delimiters % 2 == 1 {
+                    display_math_open = !display_math_open;
+                }
+            }
+        }
+    }
+
+    if open_fence.is_none() && !display_math_open {
+        return Cow::Borrowed(markdown);
+    }
+    let mut closed = markdown.to_string();
+    if !closed.ends_with('\n') {
+        closed.push('\n');
+    }
+    match open_fence {
+        Some(fence) => closed.push_str(fence),
+        None => closed.push_str("$$"),
+    }
+    Cow::Owned(closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_unbalanced_markup() {
+        assert_eq!(
+            close_unbalanced_markup("示例：\n```rust\nfn main() {"),
+            "示例：\n```rust\nfn main() {\n```"
+        );
+        assert_eq!(
+            close_unbalanced_markup("````md\n```\n内部"),
+            "````md\n```\n内部\n````"
+        );
+        assert_eq!(
+            close_unbalanced_markup("公式\n$$\na^2 + b^2"),
+            "公式\n$$\na^2 + b^2\n$$"
+        );
+        // 代码块中的 $$ 不计入公式
+        let balanced = "```sh\necho $$\n```\n`$$` 和 $$x$$";
+        assert!(matches!(
+            close_unbalanced_markup(balanced),
+            Cow::Borrowed(_)
+        ));
+        // 思考过程中未闭合的代码块不影响回答部分
+        let with_thinking = format!("```\n思考{}回答", RESPONSE_HEADER);
+        assert_eq!(close_unbalanced_markup(&with_thinking), with_thinking);
+    }
+
+    #[test]
+    fn test_safe_rendering_escapes_raw_html() {
+        let markdown = "<div style=\"position:fixed\">x</div>\n\n```html\n<b>code</b>\n```\n\n$a<b$";
+        let html = markdown_to_html(markdown, &markdown_options(true));
+        assert!(!html.contains("<div style"));
+        assert!(html.contains("&lt;div"));
+        assert!(html.contains("<pre>"));
+        assert!(html.contains("$a&lt;b$"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use similar::{capture_diff_slices_deadline, Algorithm, DiffTag};
+
+// 长文本比较的最长耗时，超时后退化为较粗粒度的差异
+const DIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffKind {
+    Unchanged,
+    Added,
+    Removed,
+}
+
+/// 差异片段：Removed 只出现在第一段文本中，Added 只出现在第二段文本中
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiffSpan {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+/// 按词比较两段文本，英文单词、数字和连续空白各作为一个词，汉字和标点逐字比较
+pub fn diff_texts(text_a: &str, text_b: &str) -> Vec<DiffSpan> {
+    let old = tokenize(text_a);
+    let new = tokenize(text_b);
+    let ops = capture_diff_slices_deadline(
+        Algorithm::Myers,
+        &old,
+        &new,
+        Some(Instant::now() + DIFF_TIMEOUT),
+    );
+
+    let mut spans: Vec<DiffSpan> = Vec::new();
+    for op in ops {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let parts: &[(DiffKind, &[&str])] = match tag {
+            DiffTag::Equal => &[(DiffKind::Unchanged, &old[old_range])],
+            DiffTag::Delete => &[(DiffKind::Removed, &old[old_range])],
+            DiffTag::Insert => &[(DiffKind::Added, &new[new_range])],
+            DiffTag::Replace => &[
+                (DiffKind::Removed, &old[old_range]),
+                (DiffKind::Added, &new[new_range]),
+            ],
+        };
+        for (kind, tokens) in parts {
+            push_span(&mut spans, *kind, &tokens.concat());
+        }
+    }
+    spans
+}
+
+// 合并相邻的同类片段，便于前端高亮
+fn push_span(spans: &mut Vec<DiffSpan>, kind: DiffKind, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.kind == kind => last.text.push_str(text),
+        _ => spans.push(DiffSpan {
+            kind,
+            text: text.to_string(),
+        }),
+    }
+}
+
+fn tokenize(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let same_word = |next: char| {
+            (c.is_ascii_alphanumeric() && next.is_ascii_alphanumeric())
+                || (c.is_whitespace() && next.is_whitespace())
+        };
+        match chars.peek() {
+            Some(&(_, next)) if same_word(next) => {}
+            _ => {
+                let end = index + c.len_utf8();
+                tokens.push(&text[start..end]);
+                start = end;
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(kind: DiffKind, text: &str) -> DiffSpan {
+        DiffSpan {
+            kind,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diff_texts() {
+        assert_eq!(
+            diff_texts("The answer is 42.", "The answer is 43."),
+            vec![
+                span(DiffKind::Unchanged, "The answer is "),
+                span(DiffKind::Removed, "42"),
+                span(DiffKind::Added, "43"),
+                span(DiffKind::Unchanged, "."),
+            ]
+        );
+        assert_eq!(
+            diff_texts("快速排序的复杂度", "归并排序的复杂度"),
+            vec![
+                span(DiffKind::Removed, "快速"),
+                span(DiffKind::Added, "归并"),
+                span(DiffKind::Unchanged, "排序的复杂度"),
+            ]
+        );
+        assert_eq!(
+            diff_texts("相同", "相同"),
+            vec![span(DiffKind::Unchanged, "相同")]
+        );
+        assert!(diff_texts("", "").is_empty());
+    }
+}
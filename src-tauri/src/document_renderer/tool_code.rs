@@ -0,0 +1,140 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+
+// tool_code 代码块从开始围栏到结束围栏的部分，第一个捕获组为代码内容
+const TOOL_CODE_BLOCK: &str = r"^[ \t]*```[ \t]*tool_code[^\n]*\n(.*?)^[ \t]*```[ \t]*";
+
+/// 文本中的 tool_code 代码块，模板解析、导出和流式执行共用同一规则
+pub static TOOL_CODE_BLOCK_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(&format!("(?sm){}$", TOOL_CODE_BLOCK)).unwrap());
+
+// 完整的 tool_code 代码块：要求结束围栏后已经出现换行，避免把仍在输出的围栏当作结束
+static TOOL_CODE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(&format!(r"(?sm){}\r?\n", TOOL_CODE_BLOCK)).unwrap());
+static WOLFRAM_CALL_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)wolfram_alpha_compute\s*\((.*)\)").unwrap());
+static QUERY_ARG_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"\bquery\s*=\s*"((?:[^"\\]|\\.)*)""#).unwrap());
+static IMAGE_ONLY_ARG_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\bimage_only\s*=\s*(?i:(true))").unwrap());
+
+/// 流式回复中已经完整输出的 tool_code 代码块
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ToolCodeBlock {
+    pub index: usize,  // 代码块在回复中的序号，从 0 开始
+    pub offset: usize, // 代码块在回复中的起始字节位置
+    pub code: String,
+}
+
+/// 可以在后端直接执行的工具调用
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerTool {
+    Wolfram { query: String, image_only: bool },
+}
+
+impl ServerTool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerTool::Wolfram { .. } => "wolfram_alpha_compute",
+        }
+    }
+}
+
+/// 在流式累积的回复中逐步查找新完成的 tool_code 代码块，每个代码块只返回一次
+#[derive(Debug, Default)]
+pub struct ToolCodeScanner {
+    scanned: usize, // 已扫描到的位置（上一个代码块的结束位置）
+    next_index: usize,
+}
+
+impl ToolCodeScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn scan(&mut self, buffer: &str) -> Vec<ToolCodeBlock> {
+        let mut blocks = Vec::new();
+        let Some(rest) = buffer.get(self.scanned..) else {
+            return blocks;
+        };
+        let mut end = self.scanned;
+        for captures in TOOL_CODE_RE.captures_iter(rest) {
+            let whole = captures.get(0).unwrap();
+            blocks.push(ToolCodeBlock {
+                index: self.next_index,
+                offset: self.scanned + whole.start(),
+                code: captures[1].to_string(),
+            });
+            self.next_index += 1;
+            end = self.scanned + whole.end();
+        }
+        self.scanned = end;
+        blocks
+    }
+
+    /// 回复生成结束后调用：回复以 tool_code 代码块结尾时结束围栏后没有换行，补上换行后再扫描一次
+    pub fn finish(&mut self, buffer: &str) -> Vec<ToolCodeBlock> {
+        self.scan(&format!("{}\n", buffer))
+    }
+}
+
+/// 识别代码块中可以在后端执行的工具调用，目前只有 Wolfram Alpha 计算
+pub fn parse_server_tool(code: &str) -> Option<ServerTool> {
+    let args = WOLFRAM_CALL_RE.captures(code)?.get(1)?.as_str();
+    let query = QUERY_ARG_RE.captures(args)?[1]
+        .replace("\\\"", "\"")
+        .replace("\\\\", "\\");
+    if query.trim().is_empty() {
+        return None;
+    }
+    Some(ServerTool::Wolfram {
+        query,
+        image_only: IMAGE_ONLY_ARG_RE.is_match(args),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_streamed_tool_code() {
+        let mut scanner = ToolCodeScanner::new();
+        let mut buffer = "先计算积分：\n```tool_code\nprint(default_api.wolfram_alpha_compute(query=\"integrate x^2, x\", image_only=True))\n```".to_string();
+        // 结束围栏后尚未出现换行时可能仍在输出，暂不执行
+        assert!(scanner.scan(&buffer).is_empty());
+
+        buffer.push_str(
+            "\n结果如上。\n```tool_code\nprint(default_api.katex_render(katex_code=\"x\"))\n```\n",
+        );
+        let blocks = scanner.scan(&buffer);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].index, 0);
+        assert_eq!(blocks[0].offset, "先计算积分：\n".len());
+        assert_eq!(blocks[1].index, 1);
+        assert!(scanner.scan(&buffer).is_empty());
+
+        assert_eq!(
+            parse_server_tool(&blocks[0].code),
+            Some(ServerTool::Wolfram {
+                query: "integrate x^2, x".to_string(),
+                image_only: true,
+            })
+        );
+        assert_eq!(parse_server_tool(&blocks[1].code), None);
+    }
+
+    #[test]
+    fn test_finish_scans_trailing_tool_code() {
+        let mut scanner = ToolCodeScanner::new();
+        let buffer = "计算：\n```tool_code\nprint(default_api.wolfram_alpha_compute(query=\"1+1\"))\n```";
+        assert!(scanner.scan(buffer).is_empty());
+
+        // 回复以代码块结尾，生成结束时执行
+        let blocks = scanner.finish(buffer);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].offset, "计算：\n".len());
+        assert!(scanner.finish(buffer).is_empty());
+    }
+}
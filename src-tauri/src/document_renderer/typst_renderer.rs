@@ -0,0 +1,117 @@
+use chrono::Datelike;
+use comemo::Prehashed;
+use once_cell::sync::Lazy;
+use typst::diag::{FileError, FileResult, SourceDiagnostic};
+use typst::eval::Tracer;
+use typst::foundations::{Bytes, Datetime};
+use typst::layout::Abs;
+use typst::syntax::{FileId, Source};
+use typst::text::{Font, FontBook};
+use typst::{Library, World, WorldExt};
+
+// 渲染时在用户代码前添加的页面与字体设置，与前端 typst 渲染器保持一致
+const TYPST_PREAMBLE: &str = r#"#set page(width: auto, height: auto, margin: 8pt)
+#set text(font: ("Noto Sans SC", "Source Han Sans SC", "PingFang SC", "Microsoft YaHei", "SimSun", "New Computer Modern"), size: 16pt)
+"#;
+
+// 只使用 typst-assets 内置的字体，在首次渲染时加载一次；不扫描系统字体目录，避免首次渲染加载大量字体
+static FONTS: Lazy<(Prehashed<FontBook>, Vec<Font>)> = Lazy::new(|| {
+    let fonts: Vec<Font> = typst_assets::fonts()
+        .flat_map(|data| Font::iter(Bytes::from_static(data)))
+        .collect();
+    let book = FontBook::from_fonts(&fonts);
+    (Prehashed::new(book), fonts)
+});
+
+static LIBRARY: Lazy<Prehashed<Library>> = Lazy::new(|| Prehashed::new(Library::default()));
+
+/// 仅包含单个源文件的编译环境，不支持导入外部文件和包
+struct TypstWorld {
+    source: Source,
+}
+
+impl World for TypstWorld {
+    fn library(&self) -> &Prehashed<Library> {
+        &LIBRARY
+    }
+
+    fn book(&self) -> &Prehashed<FontBook> {
+        &FONTS.0
+    }
+
+    fn main(&self) -> Source {
+        self.source.clone()
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if id == self.source.id() {
+            Ok(self.source.clone())
+        } else {
+            Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
+        }
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        FONTS.1.get(index).cloned()
+    }
+
+    fn today(&self, _offset: Option<i64>) -> Option<Datetime> {
+        let now = chrono::Local::now();
+        Datetime::from_ymd(now.year(), now.month() as u8, now.day() as u8)
+    }
+}
+
+/// 将 Typst 代码编译为 SVG，编译失败时返回诊断信息
+pub fn render_typst_svg(code: &str) -> Result<String, String> {
+    let world = TypstWorld {
+        source: Source::detached(format!("{}{}", TYPST_PREAMBLE, code)),
+    };
+    let mut tracer = Tracer::new();
+    let document = typst::compile(&world, &mut tracer)
+        .map_err(|diagnostics| format_diagnostics(&world, &diagnostics))?;
+    Ok(typst_svg::svg_merged(&document, Abs::zero()))
+}
+
+fn format_diagnostics(world: &TypstWorld, diagnostics: &[SourceDiagnostic]) -> String {
+    let preamble_lines = TYPST_PREAMBLE.lines().count();
+    let messages: Vec<String> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            // 行号换算为用户代码中的位置
+            let line = world
+                .range(diagnostic.span)
+                .and_then(|range| world.source.byte_to_line(range.start))
+                .filter(|line| *line >= preamble_lines)
+                .map(|line| format!("第 {} 行: ", line - preamble_lines + 1))
+                .unwrap_or_default();
+            let mut message = format!("{}{}", line, diagnostic.message);
+            for hint in &diagnostic.hints {
+                message.push_str(&format!("\n提示: {}", hint));
+            }
+            message
+        })
+        .collect();
+    format!("Typst 编译失败:\n{}", messages.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_typst_math() {
+        let svg = render_typst_svg("$ sum_(i=1)^n i = (n(n+1))/2 $").unwrap();
+        assert!(svg.starts_with("<svg"));
+    }
+
+    #[test]
+    fn test_render_typst_reports_errors() {
+        let error = render_typst_svg("#let x = ").unwrap_err();
+        assert!(error.starts_with("Typst 编译失败"));
+        assert!(error.contains("第 1 行"));
+    }
+}
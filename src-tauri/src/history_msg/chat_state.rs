@@ -0,0 +1,442 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use super::history::{
+    raw_title_from_history, save_history, ChatHistory, ChatMessage, ChatMessageType,
+};
+use super::timestamp;
+
+// 窗口尚未选择对话时默认使用对话1
+const DEFAULT_CHAT_ID: u32 = 1;
+
+/// 新建对话时的对话数量上限
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatLimit {
+    Unlimited,
+    EvictOldest(usize), // 超出上限时删除最早更新的未置顶对话
+    Reject(usize),      // 超出上限时拒绝创建
+}
+
+/// 因超出对话数量上限被删除的对话
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct EvictedChat {
+    pub id: u32,
+    pub title: String,
+}
+
+/// 收藏的消息及其所在对话
+#[derive(Clone, Debug, Serialize)]
+pub struct StarredMessage {
+    pub chat_id: u32,
+    pub chat_title: String,
+    pub message_index: usize,
+    pub(crate) msgtype: ChatMessageType,
+    pub time: String,
+    pub content: String,
+    pub note: Option<String>,
+}
+
+/// 应用的对话状态，由 Tauri 托管（`app.manage`），命令通过 `State<ChatState>` 访问
+///
+/// 历史记录由所有窗口共享，当前对话按窗口标签分别记录
+pub struct ChatState {
+    pub(crate) history: Mutex<HashMap<u32, ChatHistory>>,
+    pub(crate) current_chat_ids: Mutex<HashMap<String, u32>>, // 窗口标签 -> 当前活跃的对话ID
+    pub(crate) next_chat_id: Mutex<u32>,                      // 下一个新建对话的ID
+    pub(crate) startup_cleanup_count: Mutex<usize>,           // 启动时自动清理的过期对话数量
+}
+
+impl Default for ChatState {
+    fn default() -> Self {
+        Self {
+            history: Mutex::new(HashMap::new()),
+            current_chat_ids: Mutex::new(HashMap::new()),
+            next_chat_id: Mutex::new(DEFAULT_CHAT_ID + 1),
+            startup_cleanup_count: Mutex::new(0),
+        }
+    }
+}
+
+impl ChatState {
+    /// 载入历史记录，并确保新建对话的ID不与已有对话冲突
+    pub fn load(&self, map: HashMap<u32, ChatHistory>) {
+        let mut history = self.history.lock().unwrap();
+        if let Some(max_id) = map.keys().max() {
+            let mut next_id = self.next_chat_id.lock().unwrap();
+            if *max_id >= *next_id {
+                *next_id = max_id + 1;
+            }
+        }
+        *history = map;
+    }
+
+    /// 指定窗口当前活跃的对话ID
+    pub fn current_chat_id(&self, window: &str) -> u32 {
+        self.current_chat_ids
+            .lock()
+            .unwrap()
+            .get(window)
+            .copied()
+            .unwrap_or(DEFAULT_CHAT_ID)
+    }
+
+    pub fn set_current_chat_id(&self, window: &str, id: u32) {
+        self.current_chat_ids
+            .lock()
+            .unwrap()
+            .insert(window.to_string(), id);
+    }
+
+    /// 窗口关闭后移除其当前对话记录
+    pub fn remove_window(&self, window: &str) {
+        self.current_chat_ids.lock().unwrap().remove(window);
+    }
+
+    /// 切换窗口的当前对话，返回该对话的消息（对话不存在时为空）
+    pub fn select_chat(&self, window: &str, id: u32) -> Vec<ChatMessage> {
+        self.set_current_chat_id(window, id);
+        let history = self.history.lock().unwrap();
+        history
+            .get(&id)
+            .map(|chat| chat.content.clone())
+            .unwrap_or_default()
+    }
+
+    /// 分配新的对话ID
+    fn allocate_chat_id(&self) -> u32 {
+        let mut next_id = self.next_chat_id.lock().unwrap();
+        let new_id = *next_id;
+        *next_id += 1;
+        new_id
+    }
+
+    pub(crate) fn empty_chat(id: u32) -> ChatHistory {
+        ChatHistory {
+            id,
+            title: None, // deprecated
+            time: chrono::Local::now().format("%H:%M").to_string(),
+            content: vec![],
+            backend_state: None,
+            context_archive: Vec::new(),
+            output_language: None,
+            generation_profile: None,
+            updated_at: chrono::Local::now().timestamp(),
+            pinned: false,
+            disable_cot: false,
+            sort_order: None,
+            chat_parameters: BTreeMap::new(),
+            language_locked: false,
+        }
+    }
+
+    /// 创建新对话并设为窗口的当前对话，返回新对话的ID
+    pub fn create_chat(&self, window: &str) -> Result<u32, String> {
+        self.create_chat_within(window, ChatLimit::Unlimited)
+            .map(|(new_id, _)| new_id)
+    }
+
+    /// 按对话数量上限创建新对话并设为窗口的当前对话，返回新对话的ID和因此删除的对话
+    pub fn create_chat_within(
+        &self,
+        window: &str,
+        limit: ChatLimit,
+    ) -> Result<(u32, Vec<EvictedChat>), String> {
+        self.insert_chat_within(window, limit, None, Self::empty_chat)
+    }
+
+    /// 按对话数量上限加入由 `build` 根据新ID生成的对话并设为窗口的当前对话，返回新对话的ID和因此删除的对话；
+    /// `keep` 指定的对话不会被删除
+    fn insert_chat_within(
+        &self,
+        window: &str,
+        limit: ChatLimit,
+        keep: Option<u32>,
+        build: impl FnOnce(u32) -> ChatHistory,
+    ) -> Result<(u32, Vec<EvictedChat>), String> {
+        let mut history = self.history.lock().unwrap();
+
+        let mut evicted = Vec::new();
+        if let ChatLimit::EvictOldest(max) | ChatLimit::Reject(max) = limit {
+            let excess = (history.len() + 1).saturating_sub(max.max(1));
+            if excess > 0 {
+                if let ChatLimit::Reject(_) = limit {
+                    return Err(format!("对话数量已达上限（{}），请先删除不需要的对话", max));
+                }
+                let mut candidates: Vec<&ChatHistory> = history
+                    .values()
+                    .filter(|chat| !chat.pinned && Some(chat.id) != keep)
+                    .collect();
+                if candidates.len() < excess {
+                    return Err(format!(
+                        "对话数量已达上限（{}），且没有可删除的未置顶对话",
+                        max
+                    ));
+                }
+                candidates.sort_by_key(|chat| (chat.updated_at, chat.id));
+                evicted = candidates[..excess]
+                    .iter()
+                    .map(|chat| EvictedChat {
+                        id: chat.id,
+                        title: raw_title_from_history(chat),
+                    })
+                    .collect();
+            }
+        }
+
+        // 先加入新对话，删除旧对话时正在显示它们的窗口会切换到新对话
+        let new_id = self.allocate_chat_id();
+        history.insert(new_id, build(new_id));
+        self.set_current_chat_id(window, new_id);
+        let evicted_ids: Vec<u32> = evicted.iter().map(|chat| chat.id).collect();
+        self.remove_chats(&mut history, &evicted_ids);
+        save_history(&history)?;
+        Ok((new_id, evicted))
+    }
+
+    /// 按对话数量上限复制整个对话为新对话并设为窗口的当前对话，标题添加“(副本)”后缀并更新时间，
+    /// 返回新对话的ID和因此删除的对话；被复制的对话不会被删除
+    pub fn duplicate_chat(
+        &self,
+        window: &str,
+        id: u32,
+        limit: ChatLimit,
+    ) -> Result<(u32, Vec<EvictedChat>), String> {
+        let mut copy = self
+            .history
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| format!("对话ID {}不存在", id))?;
+
+        self.insert_chat_within(window, limit, Some(id), |new_id| {
+            copy.title = Some(format!("{} (副本)", raw_title_from_history(&copy).trim()));
+            copy.id = new_id;
+            copy.pinned = false;
+            copy.sort_order = None;
+            copy.touch();
+            copy
+        })
+    }
+
+    /// 按对话数量上限将打开的导出对话加入历史记录并设为窗口的当前对话，返回分配的新ID和因此删除的对话
+    pub fn add_imported_chat(
+        &self,
+        window: &str,
+        mut chat: ChatHistory,
+        limit: ChatLimit,
+    ) -> Result<(u32, Vec<EvictedChat>), String> {
+        self.insert_chat_within(window, limit, None, |new_id| {
+            chat.id = new_id;
+            chat.pinned = false;
+            chat.sort_order = None;
+            chat.touch();
+            chat
+        })
+    }
+
+    /// 从历史记录中移除对话；正在显示这些对话的窗口切换到剩余最新的对话，没有剩余对话时创建一个新的空对话
+    fn remove_chats(&self, history: &mut HashMap<u32, ChatHistory>, ids: &[u32]) {
+        for id in ids {
+            history.remove(id);
+        }
+
+        let mut current_ids = self.current_chat_ids.lock().unwrap();
+        if current_ids
+            .values()
+            .any(|current_id| ids.contains(current_id))
+        {
+            let new_id = match history.keys().max() {
+                Some(&new_id) => new_id,
+                None => {
+                    let new_id = self.allocate_chat_id();
+                    history.insert(new_id, Self::empty_chat(new_id));
+                    new_id
+                }
+            };
+            for current_id in current_ids
+                .values_mut()
+                .filter(|current_id| ids.contains(current_id))
+            {
+                *current_id = new_id;
+            }
+        }
+    }
+
+    /// 删除对话，正在显示该对话的窗口会切换到其他对话
+    pub fn delete_chat(&self, window: &str, id: u32) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        if !history.contains_key(&id) {
+            return Err(format!("对话ID {}不存在", id));
+        }
+
+        // 发起删除的窗口可能尚未选择过对话，此时使用默认对话
+        self.current_chat_ids
+            .lock()
+            .unwrap()
+            .entry(window.to_string())
+            .or_insert(DEFAULT_CHAT_ID);
+        self.remove_chats(&mut history, &[id]);
+        save_history(&history)
+    }
+
+    /// 删除最后更新时间早于 `cutoff`（Unix 时间戳，秒）的未置顶对话，返回删除的数量
+    pub fn cleanup_chats_before(&self, cutoff: i64) -> Result<usize, String> {
+        let mut history = self.history.lock().unwrap();
+        let expired: Vec<u32> = history
+            .values()
+            .filter(|chat| !chat.pinned && chat.updated_at < cutoff)
+            .map(|chat| chat.id)
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        self.remove_chats(&mut history, &expired);
+        save_history(&history)?;
+        Ok(expired.len())
+    }
+
+    pub fn set_pinned(&self, id: u32, pinned: bool) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        let chat = history
+            .get_mut(&id)
+            .ok_or_else(|| format!("对话ID {}不存在", id))?;
+        chat.pinned = pinned;
+        save_history(&history)
+    }
+
+    /// 按 ordered_ids 中的先后顺序设置对话的手动排序位置，未列出的对话保持原有位置
+    pub fn reorder_chats(&self, ordered_ids: &[u32]) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        if let Some(missing) = ordered_ids.iter().find(|id| !history.contains_key(id)) {
+            return Err(format!("对话ID {}不存在", missing));
+        }
+        for (position, id) in ordered_ids.iter().enumerate() {
+            history.get_mut(id).unwrap().sort_order = Some(position as i64);
+        }
+        save_history(&history)
+    }
+
+    pub fn rename_chat(&self, id: u32, new_title: String) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        let chat = history
+            .get_mut(&id)
+            .ok_or_else(|| format!("对话ID {}不存在", id))?;
+        chat.title = Some(new_title);
+        save_history(&history)
+    }
+
+    /// 删除对话中的一条消息，返回删除后的消息列表
+    pub fn delete_message(
+        &self,
+        chat_id: u32,
+        message_index: usize,
+    ) -> Result<Vec<ChatMessage>, String> {
+        let mut history = self.history.lock().unwrap();
+        let chat = history
+            .get_mut(&chat_id)
+            .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+        if message_index >= chat.content.len() {
+            return Err(format!("消息索引 {} 超出范围", message_index));
+        }
+        chat.content.remove(message_index);
+        let content = chat.content.clone();
+
+        save_history(&history)?;
+        Ok(content)
+    }
+
+    /// 在 index 处插入消息并保存，index 超出范围时追加到末尾；返回插入的消息之后的位置。
+    /// 用于把回复结束后才完成的工具调用结果记录在对应的助手回复之后
+    pub fn insert_messages(
+        &self,
+        chat_id: u32,
+        index: usize,
+        messages: Vec<ChatMessage>,
+    ) -> Result<usize, String> {
+        let mut history = self.history.lock().unwrap();
+        let chat = history
+            .get_mut(&chat_id)
+            .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+        let index = index.min(chat.content.len());
+        if messages.is_empty() {
+            return Ok(index);
+        }
+        let end = index + messages.len();
+        chat.content.splice(index..index, messages);
+        save_history(&history)?;
+        Ok(end)
+    }
+
+    /// 设置消息的笔记，空白笔记视为删除
+    pub fn set_message_note(
+        &self,
+        chat_id: u32,
+        message_index: usize,
+        note: Option<String>,
+    ) -> Result<(), String> {
+        self.update_message(chat_id, message_index, |message| {
+            message.note = note
+                .map(|note| note.trim().to_string())
+                .filter(|note| !note.is_empty());
+        })
+    }
+
+    /// 切换消息的收藏状态，返回切换后的状态
+    pub fn toggle_message_star(&self, chat_id: u32, message_index: usize) -> Result<bool, String> {
+        let mut starred = false;
+        self.update_message(chat_id, message_index, |message| {
+            message.starred = !message.starred;
+            starred = message.starred;
+        })?;
+        Ok(starred)
+    }
+
+    fn update_message(
+        &self,
+        chat_id: u32,
+        message_index: usize,
+        update: impl FnOnce(&mut ChatMessage),
+    ) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        let chat = history
+            .get_mut(&chat_id)
+            .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+        let message = chat
+            .content
+            .get_mut(message_index)
+            .ok_or_else(|| format!("消息索引 {} 超出范围", message_index))?;
+        update(message);
+        save_history(&history)
+    }
+
+    /// 所有对话中收藏的消息，最近更新的对话在前
+    pub fn starred_messages(&self) -> Vec<StarredMessage> {
+        let history = self.history.lock().unwrap();
+        let mut chats: Vec<&ChatHistory> = history.values().collect();
+        chats.sort_by_key(|chat| std::cmp::Reverse(chat.updated_at));
+
+        chats
+            .into_iter()
+            .flat_map(|chat| {
+                let chat_title = raw_title_from_history(chat);
+                chat.content
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, message)| message.starred)
+                    .map(move |(message_index, message)| StarredMessage {
+                        chat_id: chat.id,
+                        chat_title: chat_title.clone(),
+                        message_index,
+                        msgtype: message.msgtype.clone(),
+                        time: timestamp::display(&message.time),
+                        content: message.content.clone(),
+                        note: message.note.clone(),
+                    })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,561 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine as _};
+use once_cell::sync::Lazy;
+
+use crate::document_renderer::katex_renderer::render_katex_or_source;
+use crate::document_renderer::mermaid_renderer::render_mermaid_svg;
+use crate::document_renderer::tool_code::{parse_server_tool, ServerTool, TOOL_CODE_BLOCK_RE};
+use crate::document_renderer::typst_renderer::render_typst_svg;
+use crate::history_msg::history::{get_title_from_history, ChatHistory, ChatMessageType};
+use crate::history_msg::timestamp::{self, TimestampFormat};
+
+// 导出页面内联样式，保证单个 HTML 文件在任意浏览器中可直接打开
+const EXPORT_STYLE: &str = r#"
+body { margin: 0; background: #f5f6f8; color: #1f2328; font-family: -apple-system, "Segoe UI", "Noto Sans SC", "Microsoft YaHei", sans-serif; line-height: 1.6; }
+.chat-export { max-width: 860px; margin: 0 auto; padding: 24px 16px 48px; }
+.chat-export h1 { font-size: 1.5em; margin: 0 0 4px; }
+.chat-export .export-meta { color: #6e7781; font-size: 0.85em; margin-bottom: 24px; }
+.message { margin: 16px 0; padding: 12px 16px; border-radius: 10px; box-shadow: 0 1px 2px rgba(0, 0, 0, 0.08); overflow-wrap: anywhere; }
+.message.user { background: #dbeafe; margin-left: 15%; white-space: pre-wrap; }
+.message.assistant { background: #ffffff; margin-right: 5%; }
+.message.system { background: #fef3c7; font-size: 0.9em; white-space: pre-wrap; }
+.message.tool { background: #ecfdf5; margin-right: 5%; font-size: 0.9em; }
+.message-header { font-size: 0.8em; color: #6e7781; margin-bottom: 6px; }
+pre { background: #0d1117; color: #e6edf3; padding: 12px; border-radius: 6px; overflow-x: auto; }
+code { font-family: "JetBrains Mono", Consolas, "Courier New", monospace; font-size: 0.9em; }
+:not(pre) > code { background: #eff1f3; padding: 1px 4px; border-radius: 4px; }
+table { border-collapse: collapse; margin: 8px 0; }
+th, td { border: 1px solid #d0d7de; padding: 4px 8px; }
+img { max-width: 100%; }
+blockquote { margin: 8px 0; padding-left: 12px; border-left: 4px solid #d0d7de; color: #57606a; }
+details.thinking-details { background: #f6f8fa; border-radius: 6px; padding: 6px 10px; margin-bottom: 10px; }
+summary.thinking-summary { cursor: pointer; color: #57606a; }
+.math-inline, .math-display { font-family: "Latin Modern Math", "Cambria Math", "Times New Roman", serif; color: #0b3d91; }
+.math-display { display: block; text-align: center; margin: 10px 0; overflow-x: auto; white-space: pre-wrap; }
+.typst-render, .mermaid-render { text-align: center; margin: 10px 0; overflow-x: auto; }
+"#;
+
+/// 将整个对话渲染为一个自包含的 HTML 文档，助手消息以 assistant_name 署名
+pub fn chat_to_html_document(chat: &ChatHistory, assistant_name: &str) -> String {
+    let title = get_title_from_history(chat);
+
+    let mut body = String::new();
+    for message in &chat.content {
+        let (class, role_name) = match message.msgtype {
+            ChatMessageType::User => ("user", "用户"),
+            ChatMessageType::Assistant => ("assistant", assistant_name),
+            ChatMessageType::System => ("system", "系统"),
+            ChatMessageType::Tool => ("tool", message.tool_name.as_deref().unwrap_or("工具")),
+        };
+        let rendered = match message.msgtype {
+            ChatMessageType::Assistant => {
+                wrap_static_math(&render_static_tool_calls(&message.render_body(), render_mermaid_svg))
+            }
+            _ => message.render_body(),
+        };
+        body.push_str(&format!(
+            "<div class=\"message {}\">\n<div class=\"message-header\">{} · {}</div>\n{}\n</div>\n",
+            class,
+            html_escape::encode_text(role_name),
+            html_escape::encode_text(&timestamp::display_with(
+                &message.time,
+                TimestampFormat::DateTime
+            )),
+            rendered
+        ));
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="zh-CN">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>{style}</style>
+</head>
+<body>
+<div class="chat-export">
+<h1>{title}</h1>
+<div class="export-meta">由 NPULearn 导出于 {exported_at}</div>
+{body}</div>
+</body>
+</html>
+"#,
+        title = title,
+        style = EXPORT_STYLE,
+        exported_at = chrono::Local::now().format("%Y-%m-%d %H:%M"),
+        body = body
+    )
+}
+
+/// 导出文件所在的目录不存在时创建该目录
+pub(crate) fn ensure_parent_dir(path: &Path) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("无法创建导出目录: {}", e))?;
+        }
+    }
+    Ok(())
+}
+
+/// 将对话导出为 HTML 文件
+pub fn export_chat_html_to(chat: &ChatHistory, assistant_name: &str, path: &str) -> Result<(), String> {
+    let path = Path::new(path);
+    ensure_parent_dir(path)?;
+    std::fs::write(path, chat_to_html_document(chat, assistant_name))
+        .map_err(|e| format!("无法写入导出文件: {}", e))
+}
+
+/// 将整个对话导出为 Markdown 文档：助手消息中的排版调用在后端渲染为内嵌图片或公式，
+/// 后端工具调用（如 Wolfram Alpha）使用 tool_results 中以代码块内容为键的预先计算结果替换，
+/// 无法渲染的代码块保持原样
+pub fn chat_to_markdown_document(
+    chat: &ChatHistory,
+    assistant_name: &str,
+    tool_results: &HashMap<String, String>,
+) -> String {
+    let mut document = format!(
+        "# {}\n\n> 由 NPULearn 导出于 {}\n",
+        get_title_from_history(chat),
+        chrono::Local::now().format("%Y-%m-%d %H:%M")
+    );
+
+    for message in &chat.content {
+        let role_name = match message.msgtype {
+            ChatMessageType::User => "用户",
+            ChatMessageType::Assistant => assistant_name,
+            ChatMessageType::System => "系统",
+            ChatMessageType::Tool => message.tool_name.as_deref().unwrap_or("工具"),
+        };
+        let body = match message.msgtype {
+            ChatMessageType::Assistant => {
+                render_markdown_tool_calls(&message.content, tool_results, render_mermaid_svg)
+            }
+            _ => message.content.clone(),
+        };
+        document.push_str(&format!(
+            "\n## {} · {}\n\n{}\n",
+            role_name,
+            timestamp::display_with(&message.time, TimestampFormat::DateTime),
+            body.trim_end()
+        ));
+    }
+
+    document
+}
+
+/// 收集对话助手消息中需要由后端执行的工具调用，返回（代码块内容, 工具调用），相同代码块只返回一次
+pub fn collect_server_tool_calls(chat: &ChatHistory) -> Vec<(String, ServerTool)> {
+    let mut seen = HashSet::new();
+    chat.content
+        .iter()
+        .filter(|message| message.msgtype == ChatMessageType::Assistant)
+        .flat_map(|message| {
+            TOOL_CODE_BLOCK_RE
+                .captures_iter(&message.content)
+                .map(|caps| caps[1].to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|code| seen.insert(code.clone()))
+        .filter_map(|code| parse_server_tool(&code).map(|tool| (code, tool)))
+        .collect()
+}
+
+/// 将对话导出为 Markdown 文件
+pub fn export_chat_markdown_to(
+    chat: &ChatHistory,
+    assistant_name: &str,
+    tool_results: &HashMap<String, String>,
+    path: &str,
+) -> Result<(), String> {
+    let path = Path::new(path);
+    ensure_parent_dir(path)?;
+    std::fs::write(
+        path,
+        chat_to_markdown_document(chat, assistant_name, tool_results),
+    )
+    .map_err(|e| format!("无法写入导出文件: {}", e))
+}
+
+/// 将对话导出为可分享的 JSON 文件，他人可通过 open_exported_chat 打开后继续对话。
+/// 不包含后端状态（可能含有 API 密钥）和本机文件路径
+pub fn export_chat_json_to(chat: &ChatHistory, path: &str) -> Result<(), String> {
+    let mut chat = chat.clone();
+    chat.backend_state = None;
+    for message in &mut chat.content {
+        message.source_path = None;
+    }
+
+    let path = Path::new(path);
+    ensure_parent_dir(path)?;
+    let json = serde_json::to_string_pretty(&chat).map_err(|e| format!("无法序列化对话: {}", e))?;
+    std::fs::write(path, json).map_err(|e| format!("无法写入导出文件: {}", e))
+}
+
+/// 解析导出的对话文件，接受单个对话或只含一个对话的历史记录文件。
+/// 后端状态和本机文件路径来自他人的环境，解析时丢弃
+pub fn parse_exported_chat(contents: &str) -> Result<ChatHistory, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(contents).map_err(|e| format!("文件不是有效的 JSON: {}", e))?;
+    let is_single_chat = value.get("content").is_some_and(|content| content.is_array());
+    let mut chat = if is_single_chat {
+        serde_json::from_value::<ChatHistory>(value)
+            .map_err(|e| format!("文件不是有效的导出对话: {}", e))?
+    } else {
+        let chats = serde_json::from_value::<HashMap<u32, ChatHistory>>(value)
+            .map_err(|_| "文件不是导出的对话或历史记录".to_string())?;
+        if chats.len() != 1 {
+            return Err(format!(
+                "文件中包含 {} 个对话，只能打开包含一个对话的文件",
+                chats.len()
+            ));
+        }
+        chats.into_values().next().unwrap()
+    };
+
+    if chat.content.is_empty() {
+        return Err("导出的对话中没有消息".to_string());
+    }
+    chat.backend_state = None;
+    for message in &mut chat.content {
+        message.source_path = None;
+    }
+    if chat.title.is_none() {
+        chat.title = Some(get_title_from_history(&chat));
+    }
+    timestamp::migrate_legacy(&mut chat.content, chat.updated_at);
+    Ok(chat)
+}
+
+// Mermaid 渲染函数，导出时使用 mermaid-cli
+type MermaidRenderer = fn(&str) -> Result<String, String>;
+
+/// 替换 Markdown 中的 tool_code 代码块：Typst 和 Mermaid 渲染为内嵌 SVG 图片，KaTeX 还原为 `$$` 公式，
+/// 后端工具调用使用预先计算的结果，其余情况保留原始代码。render_mermaid 为 Mermaid 渲染函数，测试时可替换
+fn render_markdown_tool_calls(
+    markdown: &str,
+    tool_results: &HashMap<String, String>,
+    render_mermaid: MermaidRenderer,
+) -> String {
+    TOOL_CODE_BLOCK_RE
+        .replace_all(markdown, |caps: &regex::Captures| {
+            let code = &caps[1];
+            if let Some(result) = tool_results.get(code) {
+                return result.trim_end().to_string();
+            }
+
+            let mut rendered: Vec<String> = extract_string_args(code, "typst_render", "typst_code")
+                .iter()
+                .filter_map(|typst_code| match render_typst_svg(typst_code) {
+                    Ok(svg) => Some(format!(
+                        "![Typst](data:image/svg+xml;base64,{})",
+                        general_purpose::STANDARD.encode(svg)
+                    )),
+                    Err(e) => {
+                        println!("导出时渲染 Typst 失败: {}", e);
+                        None
+                    }
+                })
+                .collect();
+            rendered.extend(
+                extract_string_args(code, "mermaid_render", "mermaid_code")
+                    .iter()
+                    .filter_map(|mermaid_code| match render_mermaid(mermaid_code) {
+                        Ok(svg) => Some(format!(
+                            "![Mermaid](data:image/svg+xml;base64,{})",
+                            general_purpose::STANDARD.encode(svg)
+                        )),
+                        Err(e) => {
+                            println!("导出时渲染 Mermaid 失败: {}", e);
+                            None
+                        }
+                    }),
+            );
+            rendered.extend(
+                extract_string_args(code, "katex_render", "katex_code")
+                    .iter()
+                    .map(|katex_code| format!("$$\n{}\n$$", katex_code.trim())),
+            );
+            if rendered.is_empty() {
+                caps[0].to_string()
+            } else {
+                rendered.join("\n\n")
+            }
+        })
+        .to_string()
+}
+
+/// 将对话中助手消息内嵌的 base64 图片（如 Wolfram 计算结果）按顺序编号写入目录，返回写入的文件路径
+pub fn export_chat_images_to(chat: &ChatHistory, dir: &Path) -> Result<Vec<String>, String> {
+    let images: Vec<(String, Vec<u8>)> = chat
+        .content
+        .iter()
+        .filter(|message| message.msgtype == ChatMessageType::Assistant)
+        .flat_map(|message| extract_data_uri_images(&message.content))
+        .collect();
+    if images.is_empty() {
+        return Err("对话中没有可导出的图片".to_string());
+    }
+
+    std::fs::create_dir_all(dir).map_err(|e| format!("无法创建导出目录: {}", e))?;
+
+    let mut written = Vec::new();
+    for (index, (extension, data)) in images.iter().enumerate() {
+        let path = dir.join(format!(
+            "chat-{}-image-{:03}.{}",
+            chat.id,
+            index + 1,
+            extension
+        ));
+        std::fs::write(&path, data).map_err(|e| format!("无法写入图片 {:?}: {}", path, e))?;
+        written.push(path.to_string_lossy().to_string());
+    }
+    Ok(written)
+}
+
+// base64 图片 data URI，分组为图片类型和数据
+static DATA_URI_IMAGE_RE: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(r"data:image/([a-zA-Z0-9.+-]+);base64,([A-Za-z0-9+/=]+)").unwrap()
+});
+
+/// 提取文本中的 base64 图片 data URI，返回（文件扩展名, 图片数据），相同图片只保留一次
+fn extract_data_uri_images(content: &str) -> Vec<(String, Vec<u8>)> {
+    let mut seen = HashSet::new();
+    DATA_URI_IMAGE_RE
+        .captures_iter(content)
+        .filter(|caps| seen.insert(caps[2].to_string()))
+        .filter_map(|caps| {
+            let extension = match caps[1].to_lowercase().as_str() {
+                "svg+xml" => "svg".to_string(),
+                "jpeg" => "jpg".to_string(),
+                other => other.to_string(),
+            };
+            general_purpose::STANDARD
+                .decode(&caps[2])
+                .ok()
+                .map(|data| (extension, data))
+        })
+        .collect()
+}
+
+// 渲染后的 HTML 中的 tool_code 代码块
+static HTML_TOOL_CODE_RE: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(r#"(?s)<pre><code class="language-tool_code">(.*?)</code></pre>"#).unwrap()
+});
+
+// 工具调用的第一个字符串参数，如 `typst_render(typst_code="...")`，分组为函数名、参数名和参数值
+static TOOL_STRING_ARG_RE: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(r#"(\w+)\s*\(\s*(\w+)\s*=\s*"((?:[^"\\]|\\.)*)""#).unwrap()
+});
+
+/// 在后端渲染 `tool_code` 中的排版调用（typst_render、mermaid_render 和 katex_render），替换为静态内容，渲染失败时保留原始代码
+fn render_static_tool_calls(html: &str, render_mermaid: MermaidRenderer) -> String {
+    HTML_TOOL_CODE_RE
+        .replace_all(html, |caps: &regex::Captures| {
+            let code = html_escape::decode_html_entities(&caps[1]).to_string();
+            let mut rendered: Vec<String> =
+                extract_string_args(&code, "typst_render", "typst_code")
+                    .iter()
+                    .filter_map(|typst_code| match render_typst_svg(typst_code) {
+                        Ok(svg) => Some(format!("<div class=\"typst-render\">{}</div>", svg)),
+                        Err(e) => {
+                            println!("导出时渲染 Typst 失败: {}", e);
+                            None
+                        }
+                    })
+                    .collect();
+            rendered.extend(
+                extract_string_args(&code, "mermaid_render", "mermaid_code")
+                    .iter()
+                    .filter_map(|mermaid_code| match render_mermaid(mermaid_code) {
+                        Ok(svg) => Some(format!("<div class=\"mermaid-render\">{}</div>", svg)),
+                        Err(e) => {
+                            println!("导出时渲染 Mermaid 失败: {}", e);
+                            None
+                        }
+                    }),
+            );
+            rendered.extend(
+                extract_string_args(&code, "katex_render", "katex_code")
+                    .iter()
+                    .map(|katex_code| render_katex_or_source(katex_code, true)),
+            );
+            if rendered.is_empty() {
+                caps[0].to_string()
+            } else {
+                rendered.join("\n")
+            }
+        })
+        .to_string()
+}
+
+/// 从工具调用代码中提取指定函数的字符串参数，如 `typst_render(typst_code="...")`
+fn extract_string_args(code: &str, function: &str, arg: &str) -> Vec<String> {
+    TOOL_STRING_ARG_RE
+        .captures_iter(code)
+        .filter(|caps| &caps[1] == function && &caps[2] == arg)
+        .map(|caps| unescape_string_literal(&caps[3]))
+        .collect()
+}
+
+fn unescape_string_literal(literal: &str) -> String {
+    let mut result = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// 将 HTML 中的 `$...$` 与 `$$...$$` 公式渲染为 MathML，跳过代码块中的内容
+fn wrap_static_math(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        // 代码块和行内代码原样保留
+        let code_start = [rest.find("<pre"), rest.find("<code")]
+            .into_iter()
+            .flatten()
+            .min();
+        let (text, code) = match code_start {
+            Some(start) => {
+                let close_tag = if rest[start..].starts_with("<pre") {
+                    "</pre>"
+                } else {
+                    "</code>"
+                };
+                let end = rest[start..]
+                    .find(close_tag)
+                    .map(|e| start + e + close_tag.len())
+                    .unwrap_or(rest.len());
+                (&rest[..start], &rest[start..end])
+            }
+            None => (rest, ""),
+        };
+
+        result.push_str(&wrap_math_segment(text));
+        result.push_str(code);
+        rest = &rest[text.len() + code.len()..];
+    }
+
+    result
+}
+
+// 行间公式 `$$...$$`
+static DISPLAY_MATH_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"(?s)\$\$(.+?)\$\$").unwrap());
+
+// 行内公式 `$...$`：紧贴分隔符内侧不能是空白，结束的 `$` 后不能紧跟数字（第二个分组为其后的字符），
+// 避免把“$5 和 $10”这样的金额当作公式
+static INLINE_MATH_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"\$([^\s$](?:[^$\n]*?[^\s$])?)\$(\D|$)").unwrap());
+
+fn wrap_math_segment(text: &str) -> String {
+    // 公式内容在 HTML 中已被转义，渲染前先还原
+    let text = DISPLAY_MATH_RE.replace_all(text, |caps: &regex::Captures| {
+        render_katex_or_source(&html_escape::decode_html_entities(&caps[1]), true)
+    });
+    INLINE_MATH_RE
+        .replace_all(&text, |caps: &regex::Captures| {
+            let math = render_katex_or_source(&html_escape::decode_html_entities(&caps[1]), false);
+            format!("{}{}", math, &caps[2])
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_static_math_skips_code() {
+        let html = "<p>面积 $S = \\pi r^2$</p><pre><code>echo $HOME$</code></pre><p>$$a+b$$</p>";
+        let wrapped = wrap_static_math(html);
+        assert!(wrapped
+            .contains("<annotation encoding=\"application/x-tex\">S = \\pi r^2</annotation>"));
+        assert!(wrapped.contains("<code>echo $HOME$</code>"));
+        assert!(wrapped.contains("display=\"block\""));
+    }
+
+    #[test]
+    fn test_wrap_static_math_ignores_currency() {
+        for html in ["<p>价格从 $5 涨到 $10</p>", "<p>花了 $5,$6 两笔</p>", "<p>$ x $</p>"] {
+            assert_eq!(wrap_static_math(html), html);
+        }
+        let wrapped = wrap_static_math("<p>$x$，共 $5</p>");
+        assert!(!wrapped.contains("$x$"));
+        assert!(wrapped.ends_with("，共 $5</p>"));
+    }
+
+    #[test]
+    fn test_parse_exported_chat() {
+        let chat = r#"{"id":7,"title":"极限","time":"12:00","content":[{"msgtype":"User","time":"12:00","content":"求极限","source_path":"C:/a.pdf"}],"backend_state":{"backend":"Gemini","model":"m","data":"{}"}}"#;
+        let parsed = parse_exported_chat(chat).unwrap();
+        assert_eq!(parsed.title.as_deref(), Some("极限"));
+        assert_eq!(parsed.content[0].content, "求极限");
+        assert!(parsed.content[0].source_path.is_none());
+        assert!(parsed.backend_state.is_none());
+
+        // 只含一个对话的历史记录文件同样可以打开
+        let single = format!(r#"{{"7":{}}}"#, chat);
+        assert_eq!(parse_exported_chat(&single).unwrap().content.len(), 1);
+
+        let multiple = format!(r#"{{"7":{},"8":{}}}"#, chat, chat);
+        assert!(parse_exported_chat(&multiple).unwrap_err().contains("2 个对话"));
+        assert!(parse_exported_chat(r#"{"id":1,"title":null,"time":"12:00","content":[]}"#).is_err());
+        assert!(parse_exported_chat(r#"{"messages":[]}"#).is_err());
+        assert!(parse_exported_chat("not json").is_err());
+    }
+
+    #[test]
+    fn test_extract_data_uri_images() {
+        let content = "![Image](data:image/png;base64,iVBORw0KGgo=)\n<img src=\"data:image/svg+xml;base64,PHN2Zy8+\" />\n![Image](data:image/png;base64,iVBORw0KGgo=)";
+        let images = extract_data_uri_images(content);
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].0, "png");
+        assert_eq!(images[1], ("svg".to_string(), b"<svg/>".to_vec()));
+    }
+
+    #[test]
+    fn test_extract_string_args() {
+        let code = r#"print(default_api.typst_render(typst_code="$ a^2 $\n#text(\"x\")"))"#;
+        assert_eq!(
+            extract_string_args(code, "typst_render", "typst_code"),
+            vec!["$ a^2 $\n#text(\"x\")".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_render_markdown_tool_calls() {
+        let markdown = "公式如下：\n```tool_code\nprint(default_api.katex_render(katex_code=\"a^2+b^2\"))\n```\n计算：\n```tool_code\nprint(default_api.wolfram_alpha_compute(query=\"1+1\"))\n```\n其他：\n```tool_code\nprint(default_api.mermaid_render(mermaid_code=\"graph TD\"))\n```";
+        let mut tool_results = HashMap::new();
+        tool_results.insert(
+            "print(default_api.wolfram_alpha_compute(query=\"1+1\"))\n".to_string(),
+            "**Expr:** 2\n\n".to_string(),
+        );
+
+        // 没有安装 mermaid-cli 时保留原始代码
+        let rendered = render_markdown_tool_calls(markdown, &tool_results, |_| Err("无法运行 mermaid-cli".to_string()));
+        assert!(rendered.contains("公式如下：\n$$\na^2+b^2\n$$\n计算："));
+        assert!(rendered.contains("计算：\n**Expr:** 2\n其他："));
+        assert!(rendered.contains("```tool_code\nprint(default_api.mermaid_render"));
+
+        let rendered = render_markdown_tool_calls(markdown, &tool_results, |code| Ok(format!("<svg>{}</svg>", code)));
+        let svg = general_purpose::STANDARD.encode("<svg>graph TD</svg>");
+        assert!(rendered.contains(&format!("其他：\n![Mermaid](data:image/svg+xml;base64,{})", svg)));
+    }
+}
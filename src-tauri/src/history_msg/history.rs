@@ -1,24 +1,28 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::{io::Read, path::PathBuf, sync::Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
 use base64::{engine::general_purpose, Engine as _};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tauri::AppHandle;
-use tauri_plugin_fs::{FilePath, FsExt, OpenOptions};
 
-use crate::document_renderer::renderer::convert_markdown_with_latex;
-static APP_HANDLE: Lazy<Mutex<Option<Arc<Box<AppHandle>>>>> = Lazy::new(|| Mutex::new(None));
+use crate::document_renderer::renderer::{close_unbalanced_markup, convert_markdown_with_latex};
+use crate::history_msg::timestamp;
 static APP_DATA_DIR: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
 
 static FILE_NAME: &str = "chat_history.json";
 
+// 生成失败时写入对话的助手消息前缀，用于识别可以重新发送的失败回复
+pub(crate) const GENERATION_ERROR_PREFIX: &str = "生成回复时出错: ";
+pub(crate) const REGENERATION_ERROR_PREFIX: &str = "重新生成回复时出错: ";
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub(crate) enum ChatMessageType {
     User,
     System,
     Assistant,
+    Tool, // 后端执行的工具调用及其结果，单独记录在对应的助手回复之后
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -26,7 +30,40 @@ pub(crate) struct ChatMessage {
     pub(crate) msgtype: ChatMessageType,
     pub(crate) time: String,
     pub(crate) content: String,
+    // 消息是否已生成完毕，流式生成过程中自动保存的部分回复为 false
+    #[serde(default = "default_complete")]
+    pub(crate) complete: bool,
+    // 上传文件生成的消息记录原始文件路径，用于重新读取文件
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source_path: Option<String>,
+    // 模型返回的原始回复（含思维链等未提取的内容），仅在与 content 不同时保存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) raw_content: Option<String>,
+    // 用户为复习添加的笔记
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) note: Option<String>,
+    // 用户收藏的消息，可在所有对话中汇总查看
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) starred: bool,
+    // 工具消息对应的工具名称（如 wolfram_alpha_compute）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tool_name: Option<String>,
+    // API 返回的助手回复 token 用量，用于计算费用；后端未返回用量时为空
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) usage: Option<TokenUsage>,
+}
+
+/// 一次回答实际消耗的 token 数，多轮工具调用时为各次请求之和
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
 }
+
+fn default_complete() -> bool {
+    true
+}
+
 #[allow(dead_code)]
 impl ChatMessage {
     // HTML 转义函数，保留换行符为 <br> 标签
@@ -40,33 +77,82 @@ impl ChatMessage {
             .replace("\n", "<br>")
     }
 
+    /// 渲染消息正文（不含隐藏的原始消息标签），Assistant 消息走 Markdown 渲染，其余类型仅做转义
+    pub(crate) fn render_body(&self) -> String {
+        match self.msgtype {
+            ChatMessageType::Assistant | ChatMessageType::Tool => {
+                convert_markdown_with_latex(&self.content)
+            }
+            _ => Self::escape_html(&self.content),
+        }
+    }
+
+    /// 当前时间的完整消息，其余字段为默认值，需要时通过结构体更新语法覆盖
+    pub(crate) fn new(msgtype: ChatMessageType, content: String) -> Self {
+        Self {
+            msgtype,
+            time: timestamp::now(),
+            content,
+            complete: true,
+            source_path: None,
+            raw_content: None,
+            note: None,
+            starred: false,
+            tool_name: None,
+            usage: None,
+        }
+    }
+
+    /// 记录一次工具调用结果的消息，content 为 Markdown
+    pub(crate) fn tool_result(tool_name: &str, content: String) -> Self {
+        Self {
+            tool_name: Some(tool_name.to_string()),
+            ..Self::new(ChatMessageType::Tool, content)
+        }
+    }
+
     pub(crate) fn markdown_to_html(&self) -> Self {
-        // 使用 UTF-8 编码确保中文等非ASCII字符能正确编码
-        let original_bytes = self.content.as_bytes();
-        let original_base64 = general_purpose::STANDARD.encode(original_bytes);
+        self.with_rendered_body(self.render_body())
+    }
 
-        let new_content = match self.msgtype {
+    /// 渲染正在流式生成的消息：补全未闭合的代码块和公式后显示，隐藏标签中仍保存原始内容
+    fn streaming_html(&self) -> Self {
+        let body = match self.msgtype {
             ChatMessageType::Assistant => {
-                let html_content = convert_markdown_with_latex(&self.content);
-                // 在 Assistant 消息中添加不可见标签保存原始消息
-                format!("{}<div class=\"original-message\" style=\"display:none;\" data-content=\"{}\"></div>", html_content, original_base64)
-            }
-            _ => {
-                let escaped_content = Self::escape_html(&self.content);
-                // 在其他类型消息中也添加不可见标签
-                format!("{}<div class=\"original-message\" style=\"display:none;\" data-content=\"{}\"></div>", escaped_content, original_base64)
+                convert_markdown_with_latex(&close_unbalanced_markup(&self.content))
             }
+            _ => self.render_body(),
         };
+        self.with_rendered_body(body)
+    }
+
+    fn with_rendered_body(&self, body: String) -> Self {
+        // 使用 UTF-8 编码确保中文等非ASCII字符能正确编码
+        let original_bytes = self.content.as_bytes();
+        let original_base64 = general_purpose::STANDARD.encode(original_bytes);
+
+        // 在消息中添加不可见标签保存原始消息
+        let new_content = format!(
+            "{}<div class=\"original-message\" style=\"display:none;\" data-content=\"{}\"></div>",
+            body,
+            original_base64
+        );
 
         return Self {
             msgtype: self.msgtype.clone(),
-            time: self.time.clone(),
+            time: timestamp::display(&self.time),
             content: new_content,
+            complete: self.complete,
+            source_path: self.source_path.clone(),
+            raw_content: None, // 原始回复仅在需要时单独获取
+            note: self.note.clone(),
+            starred: self.starred,
+            tool_name: self.tool_name.clone(),
+            usage: self.usage,
         };
     }
 
     pub(crate) fn markdown_to_html_vec(messages: &Vec<Self>) -> Vec<Self> {
-        println!("messages: {:?}", messages);
         let mut html_messages = Vec::new();
         for message in messages {
             html_messages.push(message.markdown_to_html());
@@ -75,33 +161,289 @@ impl ChatMessage {
     }
 }
 
+/// 对话关联的 AI 后端状态（模型参数、工具等），消息内容以 ChatHistory 为准
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct BackendState {
+    pub(crate) backend: String,   // 后端类型: Gemini, DeepSeek, Coze
+    pub(crate) model: String,     // 生成该状态时使用的模型名称
+    pub(crate) data: String,      // AIChat::serialize 的结果
+}
+
+impl BackendState {
+    /// 检查保存的状态是否可用于指定的后端和模型
+    pub(crate) fn is_compatible(&self, backend: &str, model: &str) -> bool {
+        self.backend == backend && self.model == model
+    }
+}
+
+/// 被摘要替换的早期消息，用于撤销压缩
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct ContextArchive {
+    pub(crate) summary: String,            // 替换这些消息的摘要内容
+    pub(crate) messages: Vec<ChatMessage>, // 原始消息
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub(crate) struct ChatHistory {
     pub(crate) id: u32,
     pub(crate) title: Option<String>,
     pub(crate) time: String,
     pub(crate) content: Vec<ChatMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) backend_state: Option<BackendState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) context_archive: Vec<ContextArchive>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) output_language: Option<String>, // 覆盖全局设置的回答语言
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) generation_profile: Option<String>, // 覆盖全局设置的生成参数预设
+    #[serde(default = "default_updated_at")]
+    pub(crate) updated_at: i64, // 最后一次有新消息的时间（Unix 时间戳，秒），用于清理过期对话
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) pinned: bool, // 置顶的对话不会被自动清理
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) disable_cot: bool, // 不使用思维链模板，适合简单的快速问答
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sort_order: Option<i64>, // 手动拖动排序后的位置，越小越靠前
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) chat_parameters: BTreeMap<String, String>, // 对话设置的模型参数，每次请求时应用，优先于生成参数预设
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) language_locked: bool, // 用户修改过该对话的回答语言，不再自动识别
+}
+
+// 旧版本的历史记录没有更新时间，从载入时开始计算保留期限
+fn default_updated_at() -> i64 {
+    chrono::Local::now().timestamp()
 }
 
 #[allow(dead_code)]
 impl ChatHistory {
+    /// 删除可以重新得到的冗余数据：与从消息中提取的结果相同的标题、与消息内容相同的原始回复和空白笔记，
+    /// 返回删除的字段数量
+    pub(crate) fn strip_redundant_fields(&mut self) -> usize {
+        let mut removed = 0;
+        if let Some(title) = self.title.take() {
+            if title == get_title_from_history(self) {
+                removed += 1;
+            } else {
+                self.title = Some(title);
+            }
+        }
+        for message in &mut self.content {
+            if message.raw_content.as_ref() == Some(&message.content) {
+                message.raw_content = None;
+                removed += 1;
+            }
+            if message.note.as_ref().is_some_and(|note| note.trim().is_empty()) {
+                message.note = None;
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// 对话是否没有手动设置的标题。载入历史记录时会为每个对话补全标题，
+    /// 因此与从消息中提取的标题或默认标题相同的标题也视为未命名
+    pub(crate) fn is_untitled(&self) -> bool {
+        let Some(title) = &self.title else {
+            return true;
+        };
+        let derived = title_from_messages(self);
+        *title == derived
+            || *title == escape_title(&derived)
+            || *title == format!("未命名对话 - {}", self.id)
+    }
+
+    /// 撤销最后一轮对话：移除末尾的工具结果、助手回复及其对应的用户消息，返回是否有消息被移除
+    pub(crate) fn pop_last_turn(&mut self) -> bool {
+        let mut removed = false;
+        while self.content.last().map(|m| m.msgtype == ChatMessageType::Tool).unwrap_or(false) {
+            self.content.pop();
+            removed = true;
+        }
+        if self.content.last().map(|m| m.msgtype == ChatMessageType::Assistant).unwrap_or(false) {
+            self.content.pop();
+            removed = true;
+        }
+        if self.content.last().map(|m| m.msgtype == ChatMessageType::User).unwrap_or(false) {
+            self.content.pop();
+            removed = true;
+        }
+        removed
+    }
+
+    /// 最后一条助手回复是生成失败的错误信息时，移除该回复及对应的用户消息，返回用户消息内容
+    pub(crate) fn pop_failed_turn(&mut self) -> Option<String> {
+        let [.., user, assistant] = self.content.as_slice() else {
+            return None;
+        };
+        let failed = assistant.msgtype == ChatMessageType::Assistant
+            && (assistant.content.starts_with(GENERATION_ERROR_PREFIX)
+                || assistant.content.starts_with(REGENERATION_ERROR_PREFIX));
+        if !failed || user.msgtype != ChatMessageType::User {
+            return None;
+        }
+        self.content.pop();
+        self.content.pop().map(|message| message.content)
+    }
+
+    /// 对话内容的简短预览：第一条用户消息（不含上传的文件）去除多余空白后的前 max_chars 个字符
+    pub(crate) fn preview(&self, max_chars: usize) -> String {
+        let Some(message) = self
+            .content
+            .iter()
+            .find(|m| m.msgtype == ChatMessageType::User && m.source_path.is_none())
+        else {
+            return String::new();
+        };
+        let text = message.content.split_whitespace().collect::<Vec<_>>().join(" ");
+        match text.char_indices().nth(max_chars) {
+            Some((end, _)) => format!("{}…", &text[..end]),
+            None => text,
+        }
+    }
+
+    /// 删除内容为空的消息，并合并内容相同的连续助手回复（保留第一条），返回删除的消息数量
+    pub(crate) fn remove_empty_and_duplicate_messages(&mut self) -> usize {
+        let before = self.content.len();
+        self.content.retain(|m| !m.content.trim().is_empty());
+        self.content.dedup_by(|current, previous| {
+            let duplicate = current.msgtype == ChatMessageType::Assistant
+                && previous.msgtype == ChatMessageType::Assistant
+                && current.content == previous.content;
+            // 被合并的回复上的收藏和备注保留到留下的回复上
+            if duplicate {
+                previous.starred |= current.starred;
+                if previous.note.is_none() {
+                    previous.note = current.note.take();
+                }
+            }
+            duplicate
+        });
+        before - self.content.len()
+    }
+
+    /// 识别用户消息（不含上传的文件）的主要语言，pending 为尚未加入对话的新消息
+    pub(crate) fn dominant_language(&self, pending: Option<&str>) -> Option<&'static str> {
+        let text = self
+            .content
+            .iter()
+            .filter(|m| m.msgtype == ChatMessageType::User && m.source_path.is_none())
+            .map(|m| m.content.as_str())
+            .chain(pending)
+            .collect::<Vec<_>>()
+            .join("\n");
+        detect_language(&text)
+    }
+
+    /// 更新对话的显示时间和最后更新时间
+    pub(crate) fn touch(&mut self) {
+        let now = chrono::Local::now();
+        self.time = now.format("%H:%M").to_string();
+        self.updated_at = now.timestamp();
+    }
+
+    /// 对话末尾是否有未完成的助手回复（生成过程中程序退出）
+    pub(crate) fn has_incomplete_message(&self) -> bool {
+        self.content
+            .last()
+            .map(|m| m.msgtype == ChatMessageType::Assistant && !m.complete)
+            .unwrap_or(false)
+    }
+
+    /// 移除末尾未完成的助手回复及其对应的用户消息（由流式生成时的自动保存写入）
+    pub(crate) fn drop_partial_turn(&mut self) {
+        if self.has_incomplete_message() {
+            self.content.pop();
+            if self.content.last().map(|m| m.msgtype == ChatMessageType::User).unwrap_or(false) {
+                self.content.pop();
+            }
+        }
+    }
+
     pub(crate) fn markdown_to_html(&self) -> Self {
         let mut content = self.content.clone();
         for i in 0..content.len() {
             content[i] = content[i].markdown_to_html();
         }
-        return Self {
+        self.with_rendered_content(content)
+    }
+
+    /// 使用已渲染的消息构造发送到前端的对话
+    fn with_rendered_content(&self, content: Vec<ChatMessage>) -> Self {
+        Self {
             id: self.id,
             title: self.title.clone(),
             time: self.time.clone(),
             content,
-        };
+            backend_state: None, // 后端状态无需发送到前端
+            context_archive: Vec::new(),
+            output_language: self.output_language.clone(),
+            generation_profile: self.generation_profile.clone(),
+            updated_at: self.updated_at,
+            pinned: self.pinned,
+            disable_cot: self.disable_cot,
+            sort_order: self.sort_order,
+            chat_parameters: self.chat_parameters.clone(),
+            language_locked: self.language_locked,
+        }
+    }
+}
+
+// 进行语言识别所需的最少字符数（不含空白），过短的文本容易识别错误
+const MIN_LANGUAGE_DETECT_CHARS: usize = 15;
+
+/// 识别文本的语言，返回与回答语言设置一致的语言名称；文本过短或无法可靠识别时返回 None
+pub(crate) fn detect_language(text: &str) -> Option<&'static str> {
+    use whatlang::Lang;
+
+    if text.chars().filter(|c| !c.is_whitespace()).count() < MIN_LANGUAGE_DETECT_CHARS {
+        return None;
+    }
+    let info = whatlang::detect(text).filter(|info| info.is_reliable())?;
+    Some(match info.lang() {
+        Lang::Cmn => "简体中文",
+        Lang::Eng => "English",
+        Lang::Jpn => "日本語",
+        Lang::Kor => "한국어",
+        Lang::Fra => "Français",
+        Lang::Deu => "Deutsch",
+        Lang::Spa => "Español",
+        Lang::Rus => "Русский",
+        lang => lang.eng_name(),
+    })
+}
+
+/// 流式生成时的 HTML 渲染缓存
+///
+/// 生成过程中只有最后一条消息在变化，其余消息只渲染一次，之后每个片段只重新渲染最后一条消息
+pub(crate) struct StreamingHtml {
+    rendered: Vec<ChatMessage>, // 除最后一条外已渲染的消息
+}
+
+impl StreamingHtml {
+    pub(crate) fn new() -> Self {
+        Self {
+            rendered: Vec::new(),
+        }
+    }
+
+    /// 结果与 `ChatHistory::markdown_to_html` 相同，但最后一条消息中未闭合的代码块和公式会被临时补全；
+    /// 调用之间除最后一条外的消息不能被修改
+    pub(crate) fn render(&mut self, history: &ChatHistory) -> ChatHistory {
+        let finished = history.content.len().saturating_sub(1);
+        self.rendered.truncate(finished);
+        for message in &history.content[self.rendered.len()..finished] {
+            self.rendered.push(message.markdown_to_html());
+        }
+
+        let mut content = self.rendered.clone();
+        content.extend(history.content.last().map(ChatMessage::streaming_html));
+        history.with_rendered_content(content)
     }
 }
 
-pub fn init(handle: Arc<Box<AppHandle>>, app_data_dir: PathBuf) {
-    let mut app_handle = APP_HANDLE.lock().unwrap();
-    *app_handle = Some(handle);
+pub fn init(app_data_dir: PathBuf) {
     let mut app_data = APP_DATA_DIR.lock().unwrap();
     *app_data = Some(app_data_dir.clone());
     if !app_data_dir.exists() {
@@ -109,62 +451,44 @@ pub fn init(handle: Arc<Box<AppHandle>>, app_data_dir: PathBuf) {
     }
 }
 
-// #[tauri::command]
-pub fn load_history() -> Result<HashMap<u32, ChatHistory>, String> {
-    let app_handle_lock = APP_HANDLE.lock().unwrap();
-    let app_handle = app_handle_lock
-        .as_ref()
-        .ok_or_else(|| "App handle not initialized".to_string())?;
-
-    let fs = app_handle.fs();
+/// 历史记录文件路径，数据目录不存在时创建
+fn history_file_path() -> Result<PathBuf, String> {
+    data_file_path(FILE_NAME)
+}
 
+/// 应用数据目录中指定文件的路径，数据目录不存在时创建
+pub(crate) fn data_file_path(file_name: &str) -> Result<PathBuf, String> {
     let app_data_dir_lock = APP_DATA_DIR.lock().unwrap();
     let app_data_dir = app_data_dir_lock
         .as_ref()
         .ok_or_else(|| "App data directory not initialized".to_string())?;
 
-    // 确保配置目录存在
-    let path_buf = PathBuf::from(app_data_dir);
-    if !path_buf.exists() {
-        std::fs::create_dir_all(&path_buf)
+    // 确保数据目录存在
+    if !app_data_dir.exists() {
+        std::fs::create_dir_all(app_data_dir)
             .map_err(|e| format!("Failed to create data directory: {}", e))?;
     }
+    Ok(app_data_dir.join(file_name))
+}
 
-    let mut opt = OpenOptions::new();
-    let file_path = FilePath::Path(path_buf.join(FILE_NAME));
-    println!("file_path: {:?}", file_path);
-
-    let file = fs.open(file_path, opt.read(true).write(false).create(false).clone());
+// #[tauri::command]
+pub fn load_history() -> Result<HashMap<u32, ChatHistory>, String> {
+    load_history_from(&history_file_path()?)
+}
 
-    if let std::io::Result::Err(e) = file {
-        return Err(format!("Failed to open file: {}", e));
-    }
-    let mut file = file.unwrap();
-    let mut contents = String::new();
+/// 从指定文件读取历史记录
+pub fn load_history_from(path: &Path) -> Result<HashMap<u32, ChatHistory>, String> {
+    println!("file_path: {:?}", path);
 
-    if let Err(e) = file.read_to_string(&mut contents) {
-        return Err(format!("Failed to read file content: {}", e));
-    }
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to open file: {}", e))?;
 
     if contents.trim().is_empty() {
         return Ok(HashMap::new());
     }
 
     match serde_json::from_str::<HashMap<u32, ChatHistory>>(&contents) {
-        Ok(chat_history) => {
-            // 替换原始标题
-            let mut updated_history = HashMap::new();
-            for (id, mut history) in chat_history {
-                let new_title = history
-                    .title
-                    .as_ref()
-                    .cloned()
-                    .unwrap_or(get_title_from_history(&history));
-                history.title = Some(new_title);
-                updated_history.insert(id, history);
-            }
-            Ok(updated_history)
-        }
+        Ok(chat_history) => Ok(with_default_titles(chat_history)),
         Err(e) => {
             println!("Failed to parse JSON: {}", e);
             Err(format!("Failed to parse chat history: {}", e))
@@ -172,6 +496,141 @@ pub fn load_history() -> Result<HashMap<u32, ChatHistory>, String> {
     }
 }
 
+/// 为没有手动设置标题的对话填入从消息中提取的标题，并迁移旧版本只保存了时分的消息时间
+fn with_default_titles(chat_history: HashMap<u32, ChatHistory>) -> HashMap<u32, ChatHistory> {
+    let mut updated_history = HashMap::new();
+    for (id, mut history) in chat_history {
+        let new_title = history
+            .title
+            .as_ref()
+            .cloned()
+            .unwrap_or(get_title_from_history(&history));
+        history.title = Some(new_title);
+        timestamp::migrate_legacy(&mut history.content, history.updated_at);
+        updated_history.insert(id, history);
+    }
+    updated_history
+}
+
+// 历史记录文件中每个对话条目的开头，如 `"12": {`
+static ENTRY_START_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r#""(\d+)"\s*:\s*\{"#).unwrap());
+
+/// 修复历史记录文件的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct RepairReport {
+    pub recovered: usize,    // 成功恢复的对话数量
+    pub lost: usize,         // 无法解析而丢弃的对话数量
+    pub lost_ids: Vec<u32>,  // 丢弃的对话ID
+    pub backup_path: String, // 原文件备份的位置
+}
+
+/// 宽松解析历史记录：逐个解析对话条目，跳过损坏的条目，返回恢复的对话和丢弃的对话ID
+pub fn salvage_history(contents: &str) -> (HashMap<u32, ChatHistory>, Vec<u32>) {
+    if let Ok(chat_history) = serde_json::from_str::<HashMap<u32, ChatHistory>>(contents) {
+        return (with_default_titles(chat_history), Vec::new());
+    }
+
+    let mut recovered = HashMap::new();
+    let mut lost = Vec::new();
+    let mut parsed_end = 0;
+    for captures in ENTRY_START_RE.captures_iter(contents) {
+        let whole = captures.get(0).unwrap();
+        // 已解析的对话内部出现的同样格式不是新的条目
+        if whole.start() < parsed_end {
+            continue;
+        }
+        let Ok(id) = captures[1].parse::<u32>() else {
+            continue;
+        };
+        let body_start = whole.end() - 1;
+        let mut entries =
+            serde_json::Deserializer::from_str(&contents[body_start..]).into_iter::<ChatHistory>();
+        match entries.next() {
+            Some(Ok(chat)) if chat.id == id => {
+                parsed_end = body_start + entries.byte_offset();
+                recovered.insert(id, chat);
+            }
+            _ => lost.push(id),
+        }
+    }
+    // 同一ID先损坏后又成功恢复时不计入丢失
+    lost.retain(|id| !recovered.contains_key(id));
+    lost.sort_unstable();
+    lost.dedup();
+    (with_default_titles(recovered), lost)
+}
+
+/// 修复指定的历史记录文件：备份原文件后写入可以恢复的对话
+pub fn repair_history_file(
+    path: &Path,
+) -> Result<(HashMap<u32, ChatHistory>, RepairReport), String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("无法读取历史记录文件: {}", e))?;
+    let (recovered, lost_ids) = salvage_history(&contents);
+
+    let backup_path = path.with_extension(format!(
+        "json.bak-{}",
+        chrono::Local::now().format("%Y%m%d%H%M%S")
+    ));
+    std::fs::copy(path, &backup_path).map_err(|e| format!("无法备份历史记录文件: {}", e))?;
+    save_history_to(path, &recovered)?;
+
+    let report = RepairReport {
+        recovered: recovered.len(),
+        lost: lost_ids.len(),
+        lost_ids,
+        backup_path: backup_path.to_string_lossy().to_string(),
+    };
+    Ok((recovered, report))
+}
+
+/// 修复应用数据目录中的历史记录文件
+pub fn repair_history() -> Result<(HashMap<u32, ChatHistory>, RepairReport), String> {
+    let path = history_file_path()?;
+    if !path.exists() {
+        return Err("历史记录文件不存在".to_string());
+    }
+    repair_history_file(&path)
+}
+
+/// 压缩历史记录文件的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactReport {
+    pub chats: usize,          // 对话数量
+    pub removed_fields: usize, // 删除的冗余字段数量
+    pub size_before: u64,      // 压缩前的文件大小（字节）
+    pub size_after: u64,       // 压缩后的文件大小（字节）
+}
+
+/// 压缩指定的历史记录文件：删除各对话的冗余字段后重新写入，返回文件大小的变化
+pub fn compact_history_file(
+    path: &Path,
+    history: &mut HashMap<u32, ChatHistory>,
+) -> Result<CompactReport, String> {
+    let size_before = std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+    let removed_fields = history
+        .values_mut()
+        .map(ChatHistory::strip_redundant_fields)
+        .sum();
+    save_history_to(path, history)?;
+    let size_after = std::fs::metadata(path)
+        .map(|meta| meta.len())
+        .map_err(|e| format!("无法读取历史记录文件大小: {}", e))?;
+
+    Ok(CompactReport {
+        chats: history.len(),
+        removed_fields,
+        size_before,
+        size_after,
+    })
+}
+
+/// 压缩应用数据目录中的历史记录文件
+pub fn compact_history(history: &mut HashMap<u32, ChatHistory>) -> Result<CompactReport, String> {
+    compact_history_file(&history_file_path()?, history)
+}
+
 fn escape_title(title: &str) -> String {
     title
         .replace("&", "&amp;")
@@ -184,9 +643,19 @@ fn escape_title(title: &str) -> String {
 }
 
 pub fn get_title_from_history(history: &ChatHistory) -> String {
+    escape_title(&raw_title_from_history(history))
+}
+
+/// 未转义的对话标题：优先使用手动设置的标题，否则从消息中的标题标记提取
+pub fn raw_title_from_history(history: &ChatHistory) -> String {
     if let Some(title) = &history.title {
-        return escape_title(title);
+        return title.clone();
     }
+    title_from_messages(history)
+}
+
+/// 不考虑手动设置的标题，从消息中的标题标记提取标题，没有时使用默认标题
+fn title_from_messages(history: &ChatHistory) -> String {
     // 查找 `<|start_title|>` 和 `<|end_title|>` 标记之间的内容
     let start_tag = "<|start_title|>";
     let end_tag = "<|end_title|>";
@@ -196,49 +665,398 @@ pub fn get_title_from_history(history: &ChatHistory) -> String {
                 if end_index > start_index + start_tag.len() {
                     // 提取标题内容
                     let title = &message.content[start_index + start_tag.len()..end_index];
-                    return escape_title(title);
+                    return title.to_string();
                 }
             }
         }
     }
     // 如果没有找到标题，返回默认标题
-    escape_title(&format!("未命名对话 - {}", history.id))
+    format!("未命名对话 - {}", history.id)
+}
+
+// 历史记录写入的序号，调用方持有历史记录锁时分配，序号顺序与修改顺序一致
+static SAVE_SEQ: AtomicU64 = AtomicU64::new(0);
+// 已写入文件的最大序号，同时保证同一时间只有一次写入
+static LAST_SAVED_SEQ: Mutex<u64> = Mutex::new(0);
+
+/// 历史记录的快照，可以在释放历史记录锁后写入文件
+pub struct HistorySnapshot {
+    seq: u64,
+    path: PathBuf,
+    history: HashMap<u32, ChatHistory>,
+}
+
+impl HistorySnapshot {
+    /// 复制当前的历史记录，需要在持有历史记录锁时调用
+    pub fn capture(history: &HashMap<u32, ChatHistory>) -> Result<Self, String> {
+        Ok(Self {
+            seq: SAVE_SEQ.fetch_add(1, Ordering::SeqCst) + 1,
+            path: history_file_path()?,
+            history: history.clone(),
+        })
+    }
+
+    /// 写入快照，之后拍摄的历史记录已经写入时跳过，避免旧内容覆盖新内容
+    pub fn save(self) -> Result<(), String> {
+        save_if_newer(self.seq, &self.path, &self.history)
+    }
 }
 
 // #[tauri::command]
 pub fn save_history(history: &HashMap<u32, ChatHistory>) -> Result<(), String> {
-    let app_handle_lock = APP_HANDLE.lock().unwrap();
-    let app_handle = app_handle_lock
-        .as_ref()
-        .ok_or_else(|| "App handle not initialized".to_string())?;
-    let fs = app_handle.fs();
+    let seq = SAVE_SEQ.fetch_add(1, Ordering::SeqCst) + 1;
+    save_if_newer(seq, &history_file_path()?, history)
+}
 
-    let app_data_dir_lock = APP_DATA_DIR.lock().unwrap();
-    let app_data_dir = app_data_dir_lock
-        .as_ref()
-        .ok_or_else(|| "App data directory not initialized".to_string())?;
-    let mut opt = OpenOptions::new();
+fn save_if_newer(seq: u64, path: &Path, history: &HashMap<u32, ChatHistory>) -> Result<(), String> {
+    let mut last_saved = LAST_SAVED_SEQ.lock().unwrap();
+    if seq < *last_saved {
+        return Ok(());
+    }
+    save_history_to(path, history)?;
+    *last_saved = seq;
+    Ok(())
+}
+
+/// 将历史记录按对话ID排序写入指定文件：先写入临时文件再替换原文件，写入中断时原文件保持完整
+pub fn save_history_to(path: &Path, history: &HashMap<u32, ChatHistory>) -> Result<(), String> {
+    println!("file_path: {:?}", path);
 
-    let path_buf = PathBuf::from(app_data_dir);
-    let file_path = FilePath::Path(path_buf.join(FILE_NAME));
-    println!("file_path: {:?}", file_path);
+    let sorted: BTreeMap<&u32, &ChatHistory> = history.iter().collect();
+    let temp_path = path.with_extension("json.tmp");
+    let file =
+        std::fs::File::create(&temp_path).map_err(|e| format!("Failed to open file: {}", e))?;
+    serde_json::to_writer_pretty(file, &sorted)
+        .map_err(|e| format!("Failed to write file: {}", e))?;
+    std::fs::rename(&temp_path, path).map_err(|e| format!("Failed to replace file: {}", e))
+}
 
-    let file = fs.open(
-        file_path,
-        opt.read(false)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .clone(),
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_msg::chat_state::ChatState;
 
-    if let std::io::Result::Err(e) = file {
-        return Err(format!("Failed to open file: {}", e));
+    #[test]
+    fn test_backend_state_is_optional() {
+        // 旧版本保存的历史记录没有 backend_state 字段
+        let json = r#"{"id":1,"title":null,"time":"12:00","content":[]}"#;
+        let history: ChatHistory = serde_json::from_str(json).unwrap();
+        assert!(history.backend_state.is_none());
+
+        let serialized = serde_json::to_string(&history).unwrap();
+        assert!(!serialized.contains("backend_state"));
     }
 
-    let file = file.unwrap();
+    #[test]
+    fn test_source_path_is_optional() {
+        let json = r#"{"msgtype":"User","time":"12:00","content":"文件内容"}"#;
+        let message: ChatMessage = serde_json::from_str(json).unwrap();
+        assert!(message.source_path.is_none());
+        assert!(!serde_json::to_string(&message).unwrap().contains("source_path"));
 
-    serde_json::to_writer_pretty(file, history)
-        .map_err(|e| format!("Failed to write file: {}", e))?;
-    Ok(())
+        let message = ChatMessage {
+            source_path: Some("/tmp/main.rs".to_string()),
+            ..message
+        };
+        assert_eq!(message.markdown_to_html().source_path.as_deref(), Some("/tmp/main.rs"));
+    }
+
+    fn message(msgtype: ChatMessageType, content: &str, complete: bool) -> ChatMessage {
+        ChatMessage {
+            time: "12:00".to_string(),
+            complete,
+            ..ChatMessage::new(msgtype, content.to_string())
+        }
+    }
+
+    #[test]
+    fn test_preview() {
+        let mut history: ChatHistory =
+            serde_json::from_str(r#"{"id":1,"title":null,"time":"12:00","content":[]}"#).unwrap();
+        assert_eq!(history.preview(10), "");
+
+        history.content.push(ChatMessage {
+            source_path: Some("/tmp/notes.txt".to_string()),
+            ..message(ChatMessageType::User, "文件内容", true)
+        });
+        history.content.push(message(ChatMessageType::User, "什么是\n\n  傅里叶变换？", true));
+        assert_eq!(history.preview(20), "什么是 傅里叶变换？");
+        assert_eq!(history.preview(3), "什么是…");
+    }
+
+    #[test]
+    fn test_remove_empty_and_duplicate_messages() {
+        let mut history: ChatHistory =
+            serde_json::from_str(r#"{"id":1,"title":null,"time":"12:00","content":[]}"#).unwrap();
+        history.content = vec![
+            message(ChatMessageType::User, "问题", true),
+            message(ChatMessageType::Assistant, "回答", true),
+            message(ChatMessageType::Assistant, " \n", true),
+            ChatMessage {
+                starred: true,
+                ..message(ChatMessageType::Assistant, "回答", true)
+            },
+            message(ChatMessageType::User, "问题", true),
+            message(ChatMessageType::User, "问题", true),
+        ];
+        assert_eq!(history.remove_empty_and_duplicate_messages(), 2);
+        let contents: Vec<&str> = history.content.iter().map(|m| m.content.as_str()).collect();
+        // 只合并助手回复，重复的用户消息保留
+        assert_eq!(contents, ["问题", "回答", "问题", "问题"]);
+        assert!(history.content[1].starred);
+        assert_eq!(history.remove_empty_and_duplicate_messages(), 0);
+    }
+
+    #[test]
+    fn test_dominant_language() {
+        let mut history: ChatHistory =
+            serde_json::from_str(r#"{"id":1,"title":null,"time":"12:00","content":[]}"#).unwrap();
+        assert_eq!(history.dominant_language(None), None);
+        assert_eq!(history.dominant_language(Some("hi")), None);
+        assert_eq!(history.dominant_language(Some("ok thanks")), None);
+        // 识别结果不可靠时不采用
+        assert_eq!(history.dominant_language(Some("Could you explain how the Fourier transform works?")), None);
+        assert_eq!(
+            history.dominant_language(Some(
+                "Could you explain how the Fourier transform works and where it is used in signal processing?"
+            )),
+            Some("English")
+        );
+
+        history.content.push(message(ChatMessageType::User, "请解释一下傅里叶变换的原理和应用场景", true));
+        // 上传的文件不参与识别
+        history.content.push(ChatMessage {
+            source_path: Some("/tmp/paper.txt".to_string()),
+            ..message(ChatMessageType::User, "This paper studies the convergence of gradient descent methods.", true)
+        });
+        assert_eq!(history.dominant_language(None), Some("简体中文"));
+    }
+
+    #[test]
+    fn test_drop_partial_turn() {
+        let mut history = ChatHistory {
+            time: "12:00".to_string(),
+            content: vec![
+                message(ChatMessageType::User, "你好", true),
+                message(ChatMessageType::Assistant, "你好！", true),
+                message(ChatMessageType::User, "写一篇长文", true),
+                message(ChatMessageType::Assistant, "第一段", false),
+            ],
+            ..ChatState::empty_chat(1)
+        };
+        assert!(history.has_incomplete_message());
+
+        history.drop_partial_turn();
+        assert_eq!(history.content.len(), 2);
+        assert!(!history.has_incomplete_message());
+
+        // 已完成的对话不受影响
+        history.drop_partial_turn();
+        assert_eq!(history.content.len(), 2);
+    }
+
+    #[test]
+    fn test_pop_last_turn() {
+        let mut history = ChatHistory {
+            time: "12:00".to_string(),
+            content: vec![
+                message(ChatMessageType::System, "摘要", true),
+                message(ChatMessageType::User, "问题", true),
+                message(ChatMessageType::Assistant, "回答", true),
+                ChatMessage::tool_result("wolfram_alpha_compute", "结果".to_string()),
+            ],
+            ..ChatState::empty_chat(1)
+        };
+        assert!(history.pop_last_turn());
+        assert_eq!(history.content.len(), 1);
+        // 不会移除系统消息
+        assert!(!history.pop_last_turn());
+        assert_eq!(history.content.len(), 1);
+    }
+
+    #[test]
+    fn test_pop_failed_turn() {
+        let mut history = ChatHistory {
+            time: "12:00".to_string(),
+            content: vec![
+                message(ChatMessageType::User, "问题", true),
+                message(ChatMessageType::Assistant, "回答", true),
+            ],
+            ..ChatState::empty_chat(1)
+        };
+        // 正常的回答不能重新发送
+        assert_eq!(history.pop_failed_turn(), None);
+        assert_eq!(history.content.len(), 2);
+
+        history.content.push(message(ChatMessageType::User, "再问一次", true));
+        let error = format!("{}error sending request", GENERATION_ERROR_PREFIX);
+        history.content.push(message(ChatMessageType::Assistant, &error, true));
+        assert_eq!(history.pop_failed_turn(), Some("再问一次".to_string()));
+        assert_eq!(history.content.len(), 2);
+    }
+
+    #[test]
+    fn test_backend_state_compatibility() {
+        let state = BackendState {
+            backend: "Gemini".to_string(),
+            model: "gemini-2.5-flash".to_string(),
+            data: "{}".to_string(),
+        };
+        assert!(state.is_compatible("Gemini", "gemini-2.5-flash"));
+        assert!(!state.is_compatible("Gemini", "gemini-2.0-flash"));
+        assert!(!state.is_compatible("DeepSeek", "gemini-2.5-flash"));
+    }
+
+    fn streaming_chat(previous_messages: usize) -> ChatHistory {
+        let mut history: ChatHistory =
+            serde_json::from_str(r#"{"id":1,"title":null,"time":"12:00","content":[]}"#).unwrap();
+        for i in 0..previous_messages {
+            history.content.push(message(ChatMessageType::User, &format!("问题 {}", i), true));
+            let answer = format!(
+                "## 回答 {}\n\n- 要点 $x^{}$\n\n```rust\nfn main() {{}}\n```",
+                i, i
+            );
+            history.content.push(message(ChatMessageType::Assistant, &answer, true));
+        }
+        history.content.push(message(ChatMessageType::Assistant, "", true));
+        history
+    }
+
+    #[test]
+    fn test_streaming_html_matches_full_render() {
+        let mut history = streaming_chat(3);
+        let mut streaming = StreamingHtml::new();
+        for chunk in ["第一段", "**加粗**", "\n\n$$a^2$$"] {
+            history.content.last_mut().unwrap().content.push_str(chunk);
+            let incremental = streaming.render(&history);
+            let full = history.markdown_to_html();
+            for (a, b) in incremental.content.iter().zip(&full.content) {
+                assert_eq!(a.content, b.content);
+            }
+            assert_eq!(incremental.content.len(), full.content.len());
+        }
+
+        // 消息数量减少时（如撤销后重新生成）不会使用过期的缓存
+        history.content.truncate(3);
+        assert_eq!(streaming.render(&history).content.len(), 3);
+    }
+
+    // 基准测试：cargo test --release bench_streaming_html -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_streaming_html() {
+        let reply: String = "流式生成的长回答，包含 **Markdown** 和 $E=mc^2$。\n\n".repeat(400);
+        let reply: String = reply.chars().take(10_000).collect();
+        let chunks: Vec<String> = reply
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(50)
+            .map(|chunk| chunk.iter().collect())
+            .collect();
+
+        let run = |incremental: bool| {
+            let mut history = streaming_chat(20);
+            let mut streaming = StreamingHtml::new();
+            let start = std::time::Instant::now();
+            for chunk in &chunks {
+                history.content.last_mut().unwrap().content.push_str(chunk);
+                let _ = if incremental {
+                    streaming.render(&history)
+                } else {
+                    history.markdown_to_html()
+                };
+            }
+            start.elapsed()
+        };
+
+        let full = run(false);
+        let incremental = run(true);
+        println!(
+            "10k 字符回答，{} 个片段：全量渲染 {:?}，增量渲染 {:?}",
+            chunks.len(),
+            full,
+            incremental
+        );
+        assert!(incremental < full);
+    }
+
+    #[test]
+    fn test_salvage_history() {
+        // 第 2 个对话的消息列表损坏，文件末尾被截断
+        let contents = r#"{
+  "1": {"id": 1, "title": "第一个", "time": "12:00", "content": [
+    {"msgtype": "User", "time": "12:00", "content": "引用 \"9\": {", "complete": true}
+  ]},
+  "2": {"id": 2, "title": null, "time": "12:01", "content": 5},
+  "3": {"id": 3, "title": null, "time": "12:02", "content": []}"#;
+        let (recovered, lost) = salvage_history(contents);
+        let mut ids: Vec<u32> = recovered.keys().copied().collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(lost, vec![2]);
+        assert_eq!(recovered[&3].title.as_deref(), Some("未命名对话 - 3"));
+
+        let (recovered, lost) =
+            salvage_history(r#"{"4": {"id": 4, "title": null, "time": "", "content": []}}"#);
+        assert_eq!(recovered.len(), 1);
+        assert!(lost.is_empty());
+    }
+
+    #[test]
+    fn test_compact_history_file() {
+        let mut chat = ChatHistory {
+            title: Some("未命名对话 - 1".to_string()),
+            ..ChatState::empty_chat(1)
+        };
+        let mut answer = message(ChatMessageType::Assistant, "回答", true);
+        answer.raw_content = Some("回答".to_string());
+        answer.note = Some("  ".to_string());
+        chat.content.push(answer);
+        let mut titled = chat.clone();
+        titled.id = 2;
+        titled.title = Some("手动标题".to_string());
+
+        let path = std::env::temp_dir().join(format!("npulearn-compact-{}.json", std::process::id()));
+        let mut history = HashMap::from([(2, titled), (1, chat)]);
+        save_history_to(&path, &history).unwrap();
+        let report = compact_history_file(&path, &mut history).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(report.chats, 2);
+        assert_eq!(report.removed_fields, 5);
+        assert!(report.size_after < report.size_before);
+        assert!(history[&1].title.is_none());
+        assert_eq!(history[&2].title.as_deref(), Some("手动标题"));
+        // 对话按ID排序保存，重新载入后标题与压缩前相同
+        assert!(contents.find("\"1\"").unwrap() < contents.find("\"2\"").unwrap());
+        let (reloaded, _) = salvage_history(&contents);
+        assert_eq!(reloaded[&1].title.as_deref(), Some("未命名对话 - 1"));
+    }
+
+    #[test]
+    fn test_is_untitled_after_reload() {
+        let untitled = ChatState::empty_chat(1);
+        let mut generated = ChatState::empty_chat(2);
+        generated.content.push(message(
+            ChatMessageType::Assistant,
+            "<|start_title|>A & B<|end_title|>回答",
+            true,
+        ));
+        let manual = ChatHistory {
+            title: Some("手动标题".to_string()),
+            ..ChatState::empty_chat(3)
+        };
+
+        let path = std::env::temp_dir().join(format!("npulearn-untitled-{}.json", std::process::id()));
+        save_history_to(&path, &HashMap::from([(1, untitled), (2, generated), (3, manual)])).unwrap();
+        let reloaded = load_history_from(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // 载入时补全的标题不算手动标题
+        assert_eq!(reloaded[&1].title.as_deref(), Some("未命名对话 - 1"));
+        assert!(reloaded[&1].is_untitled());
+        assert!(reloaded[&2].is_untitled());
+        assert!(!reloaded[&3].is_untitled());
+    }
 }
@@ -1,2 +1,8 @@
 pub mod history;
+pub mod chat_state;
+pub mod export;
+pub mod notebook;
+pub mod outbox;
+pub mod replay;
 pub mod test;
+pub mod timestamp;
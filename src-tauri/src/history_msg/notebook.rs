@@ -0,0 +1,229 @@
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::aibackend::template::extract_response;
+use crate::history_msg::export::ensure_parent_dir;
+use crate::history_msg::history::{raw_title_from_history, ChatHistory, ChatMessageType};
+
+// 排版和绘图用的代码块不是可运行的代码，导出时不生成代码单元
+const NON_RUNNABLE_LANGUAGES: [&str; 5] = ["tool_code", "mermaid", "pintora", "typst", "math"];
+
+/// 消息中的一段内容：普通文字或代码块
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Prose(String),
+    Code { language: String, code: String },
+}
+
+/// 将对话导出为 Jupyter Notebook：助手回答中的代码块成为代码单元，其余内容成为 Markdown 单元
+pub fn chat_to_notebook(chat: &ChatHistory, assistant_name: &str) -> Value {
+    let mut cells = vec![markdown_cell(&format!(
+        "# {}\n\n由 NPULearn 导出于 {}",
+        raw_title_from_history(chat),
+        chrono::Local::now().format("%Y-%m-%d %H:%M")
+    ))];
+
+    for message in &chat.content {
+        match message.msgtype {
+            ChatMessageType::User => cells.push(markdown_cell(&format!(
+                "**用户**：\n\n{}",
+                message.content.trim()
+            ))),
+            ChatMessageType::Tool => cells.push(markdown_cell(&format!(
+                "**工具 {}**：\n\n{}",
+                message.tool_name.as_deref().unwrap_or_default(),
+                message.content.trim()
+            ))),
+            ChatMessageType::System => continue,
+            ChatMessageType::Assistant => {
+                let response =
+                    extract_response(&message.content).unwrap_or_else(|| message.content.clone());
+                let mut prose = format!("**{}**：\n\n", assistant_name);
+                for segment in split_code_blocks(&response) {
+                    match segment {
+                        Segment::Prose(text) => prose.push_str(&text),
+                        Segment::Code { language, code }
+                            if NON_RUNNABLE_LANGUAGES.contains(&language.as_str()) =>
+                        {
+                            prose.push_str(&format!("```{}\n{}```\n", language, code));
+                        }
+                        Segment::Code { language, code } => {
+                            if !prose.trim().is_empty() {
+                                cells.push(markdown_cell(prose.trim()));
+                            }
+                            prose.clear();
+                            cells.push(code_cell(&language, &code));
+                        }
+                    }
+                }
+                if !prose.trim().is_empty() {
+                    cells.push(markdown_cell(prose.trim()));
+                }
+            }
+        }
+    }
+
+    json!({
+        "cells": cells,
+        "metadata": {
+            "kernelspec": {
+                "display_name": "Python 3",
+                "language": "python",
+                "name": "python3"
+            },
+            "language_info": { "name": "python" }
+        },
+        "nbformat": 4,
+        "nbformat_minor": 4
+    })
+}
+
+/// 将对话导出为 .ipynb 文件
+pub fn export_chat_notebook_to(
+    chat: &ChatHistory,
+    assistant_name: &str,
+    path: &str,
+) -> Result<(), String> {
+    let path = Path::new(path);
+    ensure_parent_dir(path)?;
+    let notebook = serde_json::to_string_pretty(&chat_to_notebook(chat, assistant_name))
+        .map_err(|e| format!("无法序列化 Notebook: {}", e))?;
+    std::fs::write(path, notebook).map_err(|e| format!("无法写入导出文件: {}", e))
+}
+
+// Notebook 的 source 按行保存，除最后一行外都保留换行符
+fn source_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+fn markdown_cell(text: &str) -> Value {
+    json!({
+        "cell_type": "markdown",
+        "metadata": {},
+        "source": source_lines(text)
+    })
+}
+
+fn code_cell(language: &str, code: &str) -> Value {
+    // Python 以外的代码在元数据中标注语言，便于编辑器高亮或切换内核
+    let metadata = match language {
+        "" | "python" | "py" | "python3" => json!({}),
+        language => json!({ "language": language }),
+    };
+    json!({
+        "cell_type": "code",
+        "execution_count": null,
+        "metadata": metadata,
+        "outputs": [],
+        "source": source_lines(code.trim_end_matches('\n'))
+    })
+}
+
+/// 按围栏代码块拆分 Markdown，未闭合的代码块视为普通文字
+fn split_code_blocks(markdown: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut prose = String::new();
+    let mut lines = markdown.split_inclusive('\n');
+
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_start();
+        let fence_char = match trimmed.chars().next() {
+            Some(c @ ('`' | '~')) => c,
+            _ => {
+                prose.push_str(line);
+                continue;
+            }
+        };
+        let fence_len = trimmed.chars().take_while(|&c| c == fence_char).count();
+        if fence_len < 3 {
+            prose.push_str(line);
+            continue;
+        }
+        let language = trimmed[fence_len..]
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+
+        // 查找长度不小于开始围栏的结束围栏
+        let mut code = String::new();
+        let mut closed = false;
+        let mut consumed = vec![line];
+        for next in lines.by_ref() {
+            consumed.push(next);
+            let next_trimmed = next.trim();
+            if next_trimmed.len() >= fence_len && next_trimmed.chars().all(|c| c == fence_char) {
+                closed = true;
+                break;
+            }
+            code.push_str(next);
+        }
+
+        if closed {
+            if !prose.is_empty() {
+                segments.push(Segment::Prose(std::mem::take(&mut prose)));
+            }
+            segments.push(Segment::Code { language, code });
+        } else {
+            prose.extend(consumed);
+        }
+    }
+
+    if !prose.is_empty() {
+        segments.push(Segment::Prose(prose));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_msg::chat_state::ChatState;
+    use crate::history_msg::history::ChatMessage;
+
+    fn message(msgtype: ChatMessageType, content: &str) -> ChatMessage {
+        ChatMessage {
+            time: "10:00".to_string(),
+            ..ChatMessage::new(msgtype, content.to_string())
+        }
+    }
+
+    #[test]
+    fn test_chat_to_notebook() {
+        let chat = ChatHistory {
+            title: Some("快速排序".to_string()),
+            time: "2025-01-01 10:00:00".to_string(),
+            content: vec![
+                message(ChatMessageType::User, "用 Python 写快速排序"),
+                message(
+                    ChatMessageType::Assistant,
+                    "实现如下：\n```python\ndef qsort(a):\n    return a\n```\n流程图：\n```mermaid\ngraph TD\n```\n复杂度为 $O(n \\log n)$。\n```c\nint main() {}\n```",
+                ),
+            ],
+            ..ChatState::empty_chat(1)
+        };
+
+        let notebook = chat_to_notebook(&chat, "航小天");
+        let cells = notebook["cells"].as_array().unwrap();
+        let types: Vec<&str> = cells
+            .iter()
+            .map(|cell| cell["cell_type"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            types,
+            vec!["markdown", "markdown", "markdown", "code", "markdown", "code"]
+        );
+        assert_eq!(
+            cells[3]["source"],
+            json!(["def qsort(a):\n", "    return a"])
+        );
+        assert_eq!(cells[3]["metadata"], json!({}));
+        assert!(cells[4]["source"][1]
+            .as_str()
+            .unwrap()
+            .starts_with("```mermaid"));
+        assert_eq!(cells[5]["metadata"]["language"], "c");
+        assert_eq!(notebook["nbformat"], 4);
+    }
+}
@@ -0,0 +1,123 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use super::history::data_file_path;
+use super::timestamp;
+
+const OUTBOX_FILE_NAME: &str = "outbox.json";
+
+// 读写待发送队列文件时加锁，避免同时入队和发送时互相覆盖
+static OUTBOX_LOCK: Mutex<()> = Mutex::new(());
+
+/// 因网络错误未能发送、等待联网后重新发送的消息
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub chat_id: u32,
+    pub message: String,
+    pub key_type: String,
+    pub model_name: Option<String>,
+    pub queued_at: String,
+}
+
+impl QueuedMessage {
+    pub fn new(chat_id: u32, message: &str, key_type: &str, model_name: Option<&str>) -> Self {
+        Self {
+            chat_id,
+            message: message.to_string(),
+            key_type: key_type.to_string(),
+            model_name: model_name.map(str::to_string),
+            queued_at: timestamp::now(),
+        }
+    }
+}
+
+/// 将消息加入待发送队列末尾
+pub fn enqueue(message: QueuedMessage) -> Result<(), String> {
+    let _guard = OUTBOX_LOCK.lock().unwrap();
+    let path = data_file_path(OUTBOX_FILE_NAME)?;
+    let mut queue = load_from(&path)?;
+    queue.push(message);
+    save_to(&path, &queue)
+}
+
+/// 取出队列中的所有消息并清空队列
+pub fn take_all() -> Result<Vec<QueuedMessage>, String> {
+    let _guard = OUTBOX_LOCK.lock().unwrap();
+    let path = data_file_path(OUTBOX_FILE_NAME)?;
+    let queue = load_from(&path)?;
+    if !queue.is_empty() {
+        save_to(&path, &[])?;
+    }
+    Ok(queue)
+}
+
+/// 队列中等待发送的消息，按加入顺序排列
+pub fn list() -> Result<Vec<QueuedMessage>, String> {
+    let _guard = OUTBOX_LOCK.lock().unwrap();
+    load_from(&data_file_path(OUTBOX_FILE_NAME)?)
+}
+
+/// 取出队列中最早的消息
+pub fn pop_front() -> Result<Option<QueuedMessage>, String> {
+    let _guard = OUTBOX_LOCK.lock().unwrap();
+    let path = data_file_path(OUTBOX_FILE_NAME)?;
+    let mut queue = load_from(&path)?;
+    if queue.is_empty() {
+        return Ok(None);
+    }
+    let first = queue.remove(0);
+    save_to(&path, &queue)?;
+    Ok(Some(first))
+}
+
+/// 将消息放回队首，用于重新发送失败后保持原来的发送顺序
+pub fn push_front(message: QueuedMessage) -> Result<(), String> {
+    let _guard = OUTBOX_LOCK.lock().unwrap();
+    let path = data_file_path(OUTBOX_FILE_NAME)?;
+    let mut queue = load_from(&path)?;
+    queue.insert(0, message);
+    save_to(&path, &queue)
+}
+
+fn load_from(path: &Path) -> Result<Vec<QueuedMessage>, String> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("无法读取待发送队列: {}", e))?;
+    if contents.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(&contents).map_err(|e| format!("无法解析待发送队列: {}", e))
+}
+
+fn save_to(path: &Path, queue: &[QueuedMessage]) -> Result<(), String> {
+    let contents =
+        serde_json::to_string_pretty(queue).map_err(|e| format!("无法序列化待发送队列: {}", e))?;
+    std::fs::write(path, contents).map_err(|e| format!("无法写入待发送队列: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbox_roundtrip() {
+        let path =
+            std::env::temp_dir().join(format!("npulearn-outbox-{}.json", std::process::id()));
+        assert!(load_from(&path).unwrap().is_empty());
+
+        let queue = vec![
+            QueuedMessage::new(1, "什么是傅里叶变换？", "Gemini", Some("gemini-2.5-flash")),
+            QueuedMessage::new(2, "继续", "DeepSeek", None),
+        ];
+        save_to(&path, &queue).unwrap();
+        assert_eq!(load_from(&path).unwrap(), queue);
+
+        save_to(&path, &[]).unwrap();
+        assert!(load_from(&path).unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
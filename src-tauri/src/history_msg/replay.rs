@@ -0,0 +1,100 @@
+use serde::Serialize;
+
+use crate::document_renderer::message_stats::{assistant_message_stats, markdown_message_stats};
+use crate::history_msg::history::{ChatHistory, ChatMessage, ChatMessageType};
+use crate::history_msg::timestamp;
+
+/// 回放中的一步，对应对话中的一条消息
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayStep {
+    pub step: usize,          // 从 1 开始的步骤序号
+    pub message_index: usize, // 消息在对话中的索引
+    pub msgtype: ChatMessageType,
+    pub time: String,
+    pub content: String,             // 回放使用的原始内容
+    pub html: String,                // 渲染后的内容
+    pub has_thinking: bool,          // 助手消息是否带有思考过程等未提取的内容
+    pub reading_minutes: usize,      // 估算阅读时间，便于控制回放节奏
+    pub source_path: Option<String>, // 上传文件生成的消息对应的文件路径
+}
+
+/// 按对话顺序生成回放步骤，full_content 为 true 时助手消息使用包含思考过程的完整回复
+pub fn build_replay(chat: &ChatHistory, full_content: bool) -> Vec<ReplayStep> {
+    chat.content
+        .iter()
+        .enumerate()
+        .map(|(index, message)| {
+            let content = match &message.raw_content {
+                Some(raw) if full_content => raw.clone(),
+                _ => message.content.clone(),
+            };
+            let reading_minutes = match message.msgtype {
+                ChatMessageType::Assistant => assistant_message_stats(&content).reading_minutes,
+                _ => markdown_message_stats(&content).reading_minutes,
+            };
+            let html = ChatMessage {
+                content: content.clone(),
+                ..message.clone()
+            }
+            .render_body();
+
+            ReplayStep {
+                step: index + 1,
+                message_index: index,
+                msgtype: message.msgtype.clone(),
+                time: timestamp::display(&message.time),
+                content,
+                html,
+                has_thinking: message.raw_content.is_some(),
+                reading_minutes,
+                source_path: message.source_path.clone(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_msg::chat_state::ChatState;
+
+    fn message(msgtype: ChatMessageType, content: &str, raw: Option<&str>) -> ChatMessage {
+        ChatMessage {
+            time: "2025-01-01 10:00:00".to_string(),
+            raw_content: raw.map(str::to_string),
+            ..ChatMessage::new(msgtype, content.to_string())
+        }
+    }
+
+    #[test]
+    fn test_build_replay() {
+        let chat = ChatHistory {
+            time: "2025-01-01 10:00:00".to_string(),
+            content: vec![
+                message(ChatMessageType::User, "什么是<栈>？", None),
+                message(
+                    ChatMessageType::Assistant,
+                    "后进先出的结构",
+                    Some("<thought>先解释定义</thought>后进先出的结构"),
+                ),
+            ],
+            ..ChatState::empty_chat(1)
+        };
+
+        let steps = build_replay(&chat, false);
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].step, 1);
+        assert_eq!(steps[0].html, "什么是&lt;栈&gt;？");
+        assert!(!steps[0].has_thinking);
+        assert_eq!(steps[1].message_index, 1);
+        assert_eq!(steps[1].content, "后进先出的结构");
+        assert!(steps[1].has_thinking);
+
+        let steps = build_replay(&chat, true);
+        assert_eq!(steps[0].content, "什么是<栈>？");
+        assert_eq!(
+            steps[1].content,
+            "<thought>先解释定义</thought>后进先出的结构"
+        );
+    }
+}
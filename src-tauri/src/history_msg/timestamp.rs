@@ -0,0 +1,184 @@
+use std::sync::RwLock;
+
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime, SecondsFormat, TimeZone};
+use once_cell::sync::Lazy;
+
+use crate::history_msg::history::ChatMessage;
+
+/// 消息时间的显示格式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampFormat {
+    Auto,     // 今天只显示时间，更早的消息带上“昨天”或日期（默认）
+    Relative, // 相对时间，如“5分钟前”，超过一周显示日期
+    Time,     // 只显示 HH:MM
+    DateTime, // 完整的日期和时间
+}
+
+impl TimestampFormat {
+    pub fn from_setting(value: &str) -> Self {
+        match value {
+            "relative" => Self::Relative,
+            "time" => Self::Time,
+            "datetime" => Self::DateTime,
+            _ => Self::Auto,
+        }
+    }
+}
+
+static FORMAT: Lazy<RwLock<TimestampFormat>> = Lazy::new(|| RwLock::new(TimestampFormat::Auto));
+
+/// 设置消息时间的显示格式：auto、relative、time、datetime
+pub fn set_format(value: &str) {
+    *FORMAT.write().unwrap() = TimestampFormat::from_setting(value);
+}
+
+/// 新消息保存的时间：带时区的 ISO 8601 时间戳
+pub fn now() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Secs, false)
+}
+
+/// 按当前设置格式化保存的消息时间，无法解析的时间原样返回
+pub fn display(time: &str) -> String {
+    display_with(time, *FORMAT.read().unwrap())
+}
+
+/// 按指定格式显示消息时间，如导出文件中使用不随时间变化的完整日期
+pub fn display_with(time: &str, format: TimestampFormat) -> String {
+    format_at(time, format, Local::now())
+}
+
+fn format_at(time: &str, format: TimestampFormat, now: DateTime<Local>) -> String {
+    let Ok(time) = DateTime::parse_from_rfc3339(time) else {
+        return time.to_string();
+    };
+    let time = time.with_timezone(&Local);
+    let days_ago = (now.date_naive() - time.date_naive()).num_days();
+
+    match format {
+        TimestampFormat::Time => time.format("%H:%M").to_string(),
+        TimestampFormat::DateTime => time.format("%Y-%m-%d %H:%M").to_string(),
+        TimestampFormat::Relative => {
+            let elapsed = now - time;
+            if elapsed < Duration::minutes(1) {
+                "刚刚".to_string()
+            } else if elapsed < Duration::hours(1) {
+                format!("{}分钟前", elapsed.num_minutes())
+            } else if days_ago == 0 {
+                format!("{}小时前", elapsed.num_hours())
+            } else if days_ago < 7 {
+                format!("{}天前", days_ago)
+            } else {
+                time.format("%Y-%m-%d").to_string()
+            }
+        }
+        TimestampFormat::Auto => match days_ago {
+            0 => time.format("%H:%M").to_string(),
+            1 => time.format("昨天 %H:%M").to_string(),
+            _ if time.format("%Y").to_string() == now.format("%Y").to_string() => {
+                time.format("%m-%d %H:%M").to_string()
+            }
+            _ => time.format("%Y-%m-%d %H:%M").to_string(),
+        },
+    }
+}
+
+/// 将旧版本保存的 "%H:%M" 时间尽量转换为完整时间戳
+///
+/// 旧记录没有日期，从对话最后更新的日期开始倒序推算：某条消息的时间晚于其后一条消息时，认为它发送于前一天
+pub fn migrate_legacy(messages: &mut [ChatMessage], updated_at: i64) {
+    let Some(last_update) = Local.timestamp_opt(updated_at, 0).single() else {
+        return;
+    };
+    let mut date: NaiveDate = last_update.date_naive();
+    let mut next_time: Option<NaiveTime> = None;
+
+    for message in messages.iter_mut().rev() {
+        if let Ok(time) = DateTime::parse_from_rfc3339(&message.time) {
+            let time = time.with_timezone(&Local);
+            date = time.date_naive();
+            next_time = Some(time.time());
+            continue;
+        }
+        let Ok(time) = NaiveTime::parse_from_str(message.time.trim(), "%H:%M") else {
+            continue;
+        };
+        if next_time.is_some_and(|next| time > next) {
+            date = date.pred_opt().unwrap_or(date);
+        }
+        next_time = Some(time);
+        if let Some(migrated) = Local.from_local_datetime(&date.and_time(time)).earliest() {
+            message.time = migrated.to_rfc3339_opts(SecondsFormat::Secs, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_msg::history::ChatMessageType;
+
+    fn local(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_format_at() {
+        let now = local(2025, 3, 10, 15, 30);
+        let stamp = |time: DateTime<Local>| time.to_rfc3339_opts(SecondsFormat::Secs, false);
+        let yesterday = stamp(local(2025, 3, 9, 9, 5));
+
+        assert_eq!(format_at(&yesterday, TimestampFormat::Time, now), "09:05");
+        assert_eq!(
+            format_at(&yesterday, TimestampFormat::Auto, now),
+            "昨天 09:05"
+        );
+        assert_eq!(
+            format_at(&yesterday, TimestampFormat::Relative, now),
+            "1天前"
+        );
+        assert_eq!(
+            format_at(&yesterday, TimestampFormat::DateTime, now),
+            "2025-03-09 09:05"
+        );
+
+        let recent = stamp(local(2025, 3, 10, 15, 25));
+        assert_eq!(
+            format_at(&recent, TimestampFormat::Relative, now),
+            "5分钟前"
+        );
+        assert_eq!(format_at(&recent, TimestampFormat::Auto, now), "15:25");
+        assert_eq!(
+            format_at(
+                &stamp(local(2024, 12, 31, 8, 0)),
+                TimestampFormat::Auto,
+                now
+            ),
+            "2024-12-31 08:00"
+        );
+        // 未迁移的旧格式原样显示
+        assert_eq!(format_at("12:00", TimestampFormat::Auto, now), "12:00");
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let message = |time: &str| ChatMessage {
+            time: time.to_string(),
+            ..ChatMessage::new(ChatMessageType::User, String::new())
+        };
+        // 23:50 的消息在 00:10 的消息之前，应属于前一天
+        let mut messages = vec![message("23:50"), message("00:10"), message("08:00")];
+        migrate_legacy(&mut messages, local(2025, 3, 10, 8, 1).timestamp());
+
+        let dates: Vec<String> = messages
+            .iter()
+            .map(|m| {
+                DateTime::parse_from_rfc3339(&m.time)
+                    .unwrap()
+                    .with_timezone(&Local)
+                    .format("%m-%d %H:%M")
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(dates, vec!["03-09 23:50", "03-10 00:10", "03-10 08:00"]);
+    }
+}
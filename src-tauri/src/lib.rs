@@ -9,19 +9,24 @@ https://creativecommons.org/licenses/by-nc-sa/4.0/
 */
 
 use aibackend::deepseek::DeepSeekChat;
-use aibackend::gemini::GeminiChat;
+use aibackend::gemini::{GeminiChat, ImageAttachment};
 use aibackend::coze::CozeChat;
+use aibackend::mock::MockChat;
+use aibackend::error::{AiError, AiErrorCode};
 use aibackend::interface::{AIChat, AIChatType};
-use history_msg::history::{get_title_from_history, load_history, save_history};
-use history_msg::history::{ChatHistory, ChatMessage, ChatMessageType};
+use aibackend::stream_flush::FlushThrottle;
+use history_msg::history::{get_title_from_history, load_history, save_history, HistorySnapshot};
+use history_msg::history::{GENERATION_ERROR_PREFIX, REGENERATION_ERROR_PREFIX};
+use history_msg::outbox::QueuedMessage;
+use history_msg::history::{BackendState, ChatHistory, ChatMessage, ChatMessageType, StreamingHtml, TokenUsage};
+use history_msg::chat_state::{ChatLimit, ChatState, EvictedChat};
 #[cfg(target_os = "android")]
 use multi_platform::android::android_file_utils;
-use once_cell::sync::Lazy;
 use regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use tauri::{AppHandle, Emitter, Manager, Window};
+use tauri::{AppHandle, Emitter, Manager, State, Window};
 use xlang_frontend::parser::ast::{build_ast, ASTNode, ASTNodeType};
 use xlang_frontend::parser::lexer::lexer;
 
@@ -38,14 +43,7 @@ mod multi_platform;
 mod setting;
 
 mod history_msg;
-
-// 定义一个全局状态来存储聊天历史
-static CHAT_HISTORY: Lazy<Mutex<HashMap<u32, ChatHistory>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
-
-// 定义当前活跃的对话ID
-static CURRENT_CHAT_ID: Lazy<Mutex<u32>> = Lazy::new(|| Mutex::new(1)); // 默认为对话1
-static NEXT_CHAT_ID: Lazy<Mutex<u32>> = Lazy::new(|| Mutex::new(2)); // 下一个新建对话的ID
+mod logging;
 
 // static SYSTEM_PROMPT: Lazy<String> = Lazy::new(|| {
 //     r#"## Alice's Personality :
@@ -171,71 +169,222 @@ struct ChatHistoryItem {
     id: u32,
     title: String,
     time: String,
+    pinned: bool,
+    disable_cot: bool,
+    sort_order: Option<i64>,
+}
+
+// 对话索引中内容预览的长度（字符数）
+const CHAT_INDEX_PREVIEW_CHARS: usize = 80;
+
+// 对话索引项，用于快速切换对话时一次性搜索所有对话
+#[derive(Clone, Serialize)]
+struct ChatIndexEntry {
+    id: u32,
+    title: String,
+    updated_at: i64,
+    pinned: bool,
+    message_count: usize,
+    preview: String, // 第一条用户消息的开头
 }
 
-fn initialize_history() {
+fn initialize_history(state: &ChatState, retention_days: u32) {
     match load_history() {
         Ok(map) => {
-            // println!("load history: {:?}", map);
-            let mut history = CHAT_HISTORY.lock().unwrap();
-
-            // 检查是否需要更新 NEXT_CHAT_ID
-            if !map.is_empty() {
-                let max_id = map.keys().max().unwrap_or(&3);
-                let mut next_id = NEXT_CHAT_ID.lock().unwrap();
-                if *max_id >= *next_id {
-                    *next_id = max_id + 1;
-                }
+            // 检查上次退出时是否有未完成的生成
+            let incomplete = map.values().filter(|h| h.has_incomplete_message()).count();
+            if incomplete > 0 {
+                println!("检测到 {} 个对话存在未完成的回复，等待用户选择继续或丢弃", incomplete);
             }
 
-            // Move map after we've used it
-            *history = map;
+            state.load(map);
         }
         Err(e) => {
             println!("Failed to load history: {}", e);
+            return;
+        }
+    }
+
+    // 按保留天数清理过期对话，数量由前端启动后读取并提示
+    if retention_days > 0 {
+        match cleanup_chats_older_than(state, retention_days) {
+            Ok(count) => {
+                if count > 0 {
+                    println!("已清理 {} 个超过 {} 天未更新的对话", count, retention_days);
+                }
+                *state.startup_cleanup_count.lock().unwrap() = count;
+            }
+            Err(e) => {
+                println!("Failed to clean up history: {}", e);
+            }
         }
     }
 }
 
+/// 删除超过 days 天未更新的未置顶对话，返回删除的数量
+fn cleanup_chats_older_than(state: &ChatState, days: u32) -> Result<usize, String> {
+    let cutoff = chrono::Local::now().timestamp() - i64::from(days) * 24 * 60 * 60;
+    state.cleanup_chats_before(cutoff)
+}
+
+// 手动清理超过指定天数未更新的对话（置顶的对话除外），返回删除的数量
+#[tauri::command]
+fn cleanup_old_chats(state: State<'_, ChatState>, days: u32) -> Result<usize, String> {
+    if days == 0 {
+        return Err("保留天数必须大于0".to_string());
+    }
+    cleanup_chats_older_than(&state, days)
+}
+
+// 获取启动时自动清理的对话数量，读取后清零，避免重复提示
+#[tauri::command]
+fn take_startup_cleanup_count(state: State<'_, ChatState>) -> usize {
+    std::mem::take(&mut *state.startup_cleanup_count.lock().unwrap())
+}
+
+// 置顶或取消置顶对话
+#[tauri::command]
+fn set_chat_pinned(state: State<'_, ChatState>, chat_id: u32, pinned: bool) -> Result<(), String> {
+    state.set_pinned(chat_id, pinned)
+}
+
+// 按拖动后的顺序保存对话列表的手动排序
+#[tauri::command]
+fn reorder_chats(state: State<'_, ChatState>, ordered_ids: Vec<u32>) -> Result<(), String> {
+    state.reorder_chats(&ordered_ids)
+}
+
+// 复制整个对话并切换到副本，返回副本的ID
+#[tauri::command]
+fn duplicate_chat(window: Window, state: State<'_, ChatState>, chat_id: u32) -> Result<u32, String> {
+    let (new_id, evicted) = state.duplicate_chat(window.label(), chat_id, chat_limit())?;
+    notify_evicted_chats(&window, &evicted);
+    Ok(new_id)
+}
+
 // 获取聊天历史列表
 #[tauri::command]
-fn get_chat_history_items() -> Vec<ChatHistoryItem> {
-    let history = CHAT_HISTORY.lock().unwrap();
+fn get_chat_history_items(state: State<'_, ChatState>) -> Vec<ChatHistoryItem> {
+    chat_history_items(&state)
+}
+
+fn chat_history_items(state: &ChatState) -> Vec<ChatHistoryItem> {
+    let history = state.history.lock().unwrap();
     let mut history_items: Vec<ChatHistoryItem> = history
         .values()
         .map(|h| ChatHistoryItem {
             id: h.id,
             title: get_title_from_history(h),
             time: h.time.clone(),
+            pinned: h.pinned,
+            disable_cot: h.disable_cot,
+            sort_order: h.sort_order,
         })
         .collect();
 
-    // 按ID排序，最新的在前面
-    history_items.sort_by(|a, b| b.id.cmp(&a.id));
+    sort_history_items(&mut history_items);
     history_items
 }
 
-// 获取指定ID的聊天内容
+// 置顶的对话在前，然后是手动排序过的对话（按排序位置），其余按ID排序，最新的在前面
+fn sort_history_items(items: &mut [ChatHistoryItem]) {
+    items.sort_by(|a, b| {
+        compare_history_order((a.pinned, a.sort_order, a.id), (b.pinned, b.sort_order, b.id))
+    });
+}
+
+// 对话列表的排序规则，参数为（是否置顶，手动排序位置，对话ID）
+fn compare_history_order(
+    a: (bool, Option<i64>, u32),
+    b: (bool, Option<i64>, u32),
+) -> std::cmp::Ordering {
+    let (a_pinned, a_order, a_id) = a;
+    let (b_pinned, b_order, b_id) = b;
+    b_pinned
+        .cmp(&a_pinned)
+        .then(a_order.is_none().cmp(&b_order.is_none()))
+        .then(a_order.cmp(&b_order))
+        .then(b_id.cmp(&a_id))
+}
+
+// 获取所有对话的索引（标题、更新时间和内容预览），顺序与对话列表相同
 #[tauri::command]
-fn select_chat_by_id(id: u32) -> Vec<ChatMessage> {
-    let mut current_id = CURRENT_CHAT_ID.lock().unwrap();
-    *current_id = id; // 更新当前对话ID
+fn get_chat_index(state: State<'_, ChatState>) -> Vec<ChatIndexEntry> {
+    let history = state.history.lock().unwrap();
+    let mut chats: Vec<&ChatHistory> = history.values().collect();
+    chats.sort_by(|a, b| {
+        compare_history_order((a.pinned, a.sort_order, a.id), (b.pinned, b.sort_order, b.id))
+    });
+    chats
+        .into_iter()
+        .map(|h| ChatIndexEntry {
+            id: h.id,
+            title: get_title_from_history(h),
+            updated_at: h.updated_at,
+            pinned: h.pinned,
+            message_count: h.content.len(),
+            preview: h.preview(CHAT_INDEX_PREVIEW_CHARS),
+        })
+        .collect()
+}
 
-    let history = CHAT_HISTORY.lock().unwrap();
-    if let Some(chat) = history.get(&id) {
-        ChatMessage::markdown_to_html_vec(&chat.content)
+// 获取存在未完成回复（生成中断）的对话列表
+#[tauri::command]
+fn list_incomplete_chats(state: State<'_, ChatState>) -> Vec<ChatHistoryItem> {
+    let history = state.history.lock().unwrap();
+    let mut items: Vec<ChatHistoryItem> = history
+        .values()
+        .filter(|h| h.has_incomplete_message())
+        .map(|h| ChatHistoryItem {
+            id: h.id,
+            title: get_title_from_history(h),
+            time: h.time.clone(),
+            pinned: h.pinned,
+            disable_cot: h.disable_cot,
+            sort_order: h.sort_order,
+        })
+        .collect();
+    items.sort_by(|a, b| b.id.cmp(&a.id));
+    items
+}
+
+// 处理中断的回复：keep 为 true 时保留已生成的部分（之后可重新生成），否则丢弃该轮对话
+#[tauri::command]
+fn resolve_incomplete_chat(state: State<'_, ChatState>, chat_id: u32, keep: bool) -> Result<Vec<ChatMessage>, String> {
+    let mut history = state.history.lock().unwrap();
+    let Some(chat) = history.get_mut(&chat_id) else {
+        return Err(format!("对话ID {}不存在", chat_id));
+    };
+    if !chat.has_incomplete_message() {
+        return Err(format!("对话 {} 没有未完成的回复", chat_id));
+    }
+
+    if keep {
+        if let Some(last) = chat.content.last_mut() {
+            last.complete = true;
+        }
     } else {
-        vec![]
+        chat.drop_partial_turn();
     }
+    let content = ChatMessage::markdown_to_html_vec(&chat.content);
+
+    save_history(&history)?;
+    Ok(content)
+}
+
+// 获取指定ID的聊天内容
+#[tauri::command]
+fn select_chat_by_id(window: Window, state: State<'_, ChatState>, id: u32) -> Vec<ChatMessage> {
+    ChatMessage::markdown_to_html_vec(&state.select_chat(window.label(), id))
 }
 
 /**
 获取当前聊天内容
 */
 #[tauri::command]
-fn get_chat_html() -> Vec<ChatMessage> {
-    let current_id = *CURRENT_CHAT_ID.lock().unwrap();
-    let history = CHAT_HISTORY.lock().unwrap();
+fn get_chat_html(window: Window, state: State<'_, ChatState>) -> Vec<ChatMessage> {
+    let current_id = state.current_chat_id(window.label());
+    let history = state.history.lock().unwrap();
 
     if let Some(chat) = history.get(&current_id) {
         ChatMessage::markdown_to_html_vec(&chat.content)
@@ -244,484 +393,877 @@ fn get_chat_html() -> Vec<ChatMessage> {
     }
 }
 
+// 按当前的渲染流程重新渲染指定对话的所有消息，渲染功能更新后旧对话无需编辑即可使用新的显示效果
+#[tauri::command]
+fn rerender_chat(state: State<'_, ChatState>, chat_id: u32) -> Result<Vec<ChatMessage>, String> {
+    let history = state.history.lock().unwrap();
+    let chat = history
+        .get(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+    Ok(ChatMessage::markdown_to_html_vec(&chat.content))
+}
+
 /*
 创建新对话
 */
 
 #[tauri::command]
-fn create_new_chat() -> Vec<ChatMessage> {
-    // 获取新ID
-    let mut next_id = NEXT_CHAT_ID.lock().unwrap();
-    let new_id = *next_id;
-    *next_id += 1;
-
-    // 更新当前对话ID
-    let mut current_id = CURRENT_CHAT_ID.lock().unwrap();
-    *current_id = new_id;
-
-    // 创建新对话
-    let now = chrono::Local::now();
-    let today = now.format("%H:%M").to_string();
-    let new_chat = ChatHistory {
-        id: new_id,
-        title: None, //deprecated
-        time: today.clone(),
-        content: vec![],
-    };
+fn create_new_chat(window: Window, state: State<'_, ChatState>) -> Result<Vec<ChatMessage>, String> {
+    create_chat_with_limit(&window, &state)?;
+    // 新对话没有消息
+    Ok(vec![])
+}
 
-    let content = new_chat.content.clone();
+/// 按设置的对话数量上限创建新对话，因此删除的旧对话通过 chats-evicted 事件通知前端
+fn create_chat_with_limit(window: &Window, state: &ChatState) -> Result<u32, String> {
+    let (new_id, evicted) = state.create_chat_within(window.label(), chat_limit())?;
+    notify_evicted_chats(window, &evicted);
+    Ok(new_id)
+}
 
-    // 添加到历史记录
-    let mut history = CHAT_HISTORY.lock().unwrap();
-    history.insert(new_id, new_chat);
-    save_history(&history).unwrap_or_else(|e| {
-        println!("Failed to save history: {}", e);
-    });
+/// 复制指定对话，对话不存在时返回错误
+fn chat_by_id(state: &ChatState, chat_id: u32) -> Result<ChatHistory, String> {
+    state
+        .history
+        .lock()
+        .unwrap()
+        .get(&chat_id)
+        .cloned()
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))
+}
 
-    ChatMessage::markdown_to_html_vec(&content)
+/// 复制对话中指定位置的消息，对话或消息不存在时返回错误
+fn message_at(state: &ChatState, chat_id: u32, message_index: usize) -> Result<ChatMessage, String> {
+    let history = state.history.lock().unwrap();
+    let chat = history
+        .get(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+    chat.content
+        .get(message_index)
+        .cloned()
+        .ok_or_else(|| format!("消息索引 {} 超出范围", message_index))
 }
 
-// 以流式方式处理用户消息
-#[tauri::command]
-async fn process_message_stream(window: Window, message: String, key_type: String, model_name: Option<String>) {
-    // 克隆窗口以便在新线程中使用
-    let window_clone = window.clone();
-    
-    println!("收到请求 - API类型: {}, 模型名称: {:?}", key_type, model_name);
-    
-    // 获取当前设置以获取人格配置
-    let settings = match setting::setting::load_app_settings("settings.json") {
-        Ok(settings) => settings,
-        Err(e) => {
-            println!("无法加载设置，使用默认设置: {}", e);
-            setting::setting::AppSettings::default()
-        }
-    };
-    
-    println!("使用人格配置: {:?}", settings.persona_config);// 获取API密钥
-    let api_key = match key_type.as_str() {
+/// 设置中的对话数量上限，无法加载设置时不限制
+fn chat_limit() -> ChatLimit {
+    setting::setting::load_app_settings("settings.json")
+        .map(|settings| settings.chat_limit())
+        .unwrap_or(ChatLimit::Unlimited)
+}
+
+/// 通过 chats-evicted 事件通知前端因超出对话数量上限而删除的对话
+fn notify_evicted_chats(window: &Window, evicted: &[EvictedChat]) {
+    if !evicted.is_empty() {
+        println!("对话数量超出上限，已删除对话: {:?}", evicted);
+        let _ = window.emit("chats-evicted", evicted);
+    }
+}
+
+/// 按类型选择 API 密钥，Coze 使用内置密钥
+fn select_api_key(key_type: &str) -> Result<aibackend::apikey::ApiKey, String> {
+    let api_key_type = match key_type {
         "Coze" => {
-            // Coze 使用内置密钥，不需要从配置读取
-            aibackend::apikey::ApiKey {
+            return Ok(aibackend::apikey::ApiKey {
                 key: "built-in".to_string(),
                 name: "Coze Built-in".to_string(),
                 key_type: aibackend::apikey::ApiKeyType::Coze,
-            }
+                priority: 0,
+            })
         }
-        _ => {
-            // 其他类型从配置文件读取
-            let api_key_list = aibackend::apikey::get_api_key_list_or_create("api_keys.json");
-            let key_list = api_key_list.filter_by_type(match key_type.as_str() {
-                "DeepSeek" => aibackend::apikey::ApiKeyType::DeepSeek,
-                "Gemini" => aibackend::apikey::ApiKeyType::Gemini,
-                _ => {
-                    let _ = window_clone.emit("stream-message", "不支持的API密钥类型，请检查设置");
-                    return;
-                }
-            });
+        "Mock" => return Ok(MockChat::built_in_key()),
+        "DeepSeek" => aibackend::apikey::ApiKeyType::DeepSeek,
+        "Gemini" => aibackend::apikey::ApiKeyType::Gemini,
+        _ => return Err("不支持的API密钥类型，请检查设置".to_string()),
+    };
 
-            if key_list.keys.is_empty() {
-                // 如果没有API密钥，发送错误消息
-                let _ = window_clone.emit(
-                    "stream-message",
-                    format!("没有可用的{} API密钥，请在设置中添加", key_type),
-                );
-                return;
-            }
+    let api_key_list = aibackend::apikey::get_api_key_list_or_create("api_keys.json");
+    api_key_list
+        .select_key_by_priority(api_key_type)
+        .ok_or_else(|| format!("没有可用的{} API密钥，请在设置中添加", key_type))
+}
 
-            // 随机选择一个API密钥
-            match key_list.random_key() {
-                Some(key) => key,
-                None => {
-                    let _ = window_clone.emit(
-                        "stream-message",
-                        format!("没有可用的{} API密钥，请在设置中添加", key_type),
-                    );
-                    return;
-                }
-            }
-        }
-    };    // 初始化AI聊天实例
-    let mut chat = match key_type.as_str() {
-        "DeepSeek" => {
-            let model = model_name.as_deref().unwrap_or("deepseek-chat");
-            AIChatType::DeepSeek(DeepSeekChat::new_with_model(model))
-        },
-        "Gemini" => {
-            let model = model_name.as_deref().unwrap_or("gemini-2.5-flash");
-            AIChatType::Gemini(GeminiChat::new_with_model(model))
-        },
-        "Coze" => AIChatType::Coze(CozeChat::new()),
-        _ => {
-            let _ = window_clone.emit("stream-message", "不支持的API密钥类型，请检查设置");
-            return;
-        }
-    };
+/// 按类型和模型名称创建 AI 聊天实例
+fn create_ai_chat(key_type: &str, model_name: Option<&str>) -> Result<AIChatType, String> {
+    let model = model_name.or(default_model(key_type)).unwrap_or_default();
+    match key_type {
+        "DeepSeek" => Ok(AIChatType::DeepSeek(DeepSeekChat::new_with_model(model))),
+        "Gemini" => Ok(AIChatType::Gemini(GeminiChat::new_with_model(model))),
+        "Coze" => Ok(AIChatType::Coze(CozeChat::new())),
+        "Mock" => Ok(AIChatType::Mock(MockChat::new())),
+        _ => Err(format!("不支持的API密钥类型: {}", key_type)),
+    }
+}
 
-    // 获取融合后的系统提示词（包含人格特质）
-    let merged_system_prompt = match merge_persona_with_system_prompt(&settings) {
-        Ok(prompt) => prompt,
-        Err(e) => {
-            let error_msg = format!("人格配置错误: {}", e);
-            let _ = window_clone.emit("stream-message", &error_msg);
+/// 后端的默认模型，未指定模型或所选模型不可用时使用；Coze 等不区分模型的后端返回 None
+fn default_model(key_type: &str) -> Option<&'static str> {
+    match key_type {
+        "DeepSeek" => Some("deepseek-chat"),
+        "Gemini" => Some("gemini-2.5-flash"),
+        _ => None,
+    }
+}
+
+/// 将流式生成中的部分回复写入历史记录，该回复标记为未完成；在后台保存，不阻塞生成
+fn autosave_partial_response(state: &ChatState, chat_id: u32, user_message: &str, partial: &str) {
+    let snapshot = {
+        let mut history = state.history.lock().unwrap();
+        let Some(chat) = history.get_mut(&chat_id) else {
             return;
-        }
+        };
+        chat.drop_partial_turn();
+        chat.content.push(ChatMessage::new(ChatMessageType::User, user_message.to_string()));
+        chat.content.push(ChatMessage {
+            complete: false,
+            ..ChatMessage::new(ChatMessageType::Assistant, partial.to_string())
+        });
+        HistorySnapshot::capture(&history)
     };
-    let _ = chat.set_system_prompt(merged_system_prompt);
-
-    // 获取当前聊天上下文
-    let current_chat_id = *CURRENT_CHAT_ID.lock().unwrap();
-    let current_chat_context = {
-        let history = CHAT_HISTORY.lock().unwrap();
-        if let Some(history_chat) = history.get(&current_chat_id) {
-            history_chat.clone()
-        } else {
-            ChatHistory {
-                id: current_chat_id,
-                title: None,
-                time: String::new(),
-                content: vec![],
-            }
+    match snapshot {
+        Ok(snapshot) => {
+            tauri::async_runtime::spawn_blocking(move || {
+                snapshot.save().unwrap_or_else(|e| {
+                    println!("Failed to autosave history: {}", e);
+                });
+            });
         }
+        Err(e) => println!("Failed to autosave history: {}", e),
+    }
+}
+
+/// 流式接收到的原始回复与最终回复（经过模板提取）不同时返回原始回复，用于单独保存
+fn distinct_raw_response(raw_response: String, response: &str) -> Option<String> {
+    (!raw_response.is_empty() && raw_response != response).then_some(raw_response)
+}
+
+/// 让推理模型的思考过程通过 stream-reasoning 事件发送到前端，返回累积的思考过程
+fn attach_reasoning_stream(chat: &mut AIChatType, window: &Window, chat_id: u32) -> Arc<Mutex<String>> {
+    let reasoning = Arc::new(Mutex::new(String::new()));
+    let sink = {
+        let reasoning = Arc::clone(&reasoning);
+        let window = window.clone();
+        aibackend::deepseek::ReasoningSink::new(move |text: String| {
+            reasoning.lock().unwrap().push_str(&text);
+            let _ = window.emit("stream-reasoning", serde_json::json!({ "chat_id": chat_id, "text": text }));
+        })
     };
+    chat.set_reasoning_sink(sink);
+    reasoning
+}
 
-    // 加载聊天历史到AI聊天实例
-    if let Err(e) = chat.load_from(&current_chat_context) {
-        println!("无法加载聊天历史: {}", e);
+/// 将推理模型的思考过程与回答按 COT 模板的格式合并为原始回复，查看原始回复或回放时可显示思考过程
+fn with_reasoning(reasoning: &str, raw_response: Option<String>, response: &str) -> Option<String> {
+    let reasoning = reasoning.trim();
+    if reasoning.is_empty() {
+        return raw_response;
     }
+    Some(format!(
+        "<|start_header|>think<|end_header|>\n{}\n<|start_header|>typeset_and_respond<|end_header|>\n{}",
+        reasoning,
+        raw_response.as_deref().unwrap_or(response)
+    ))
+}
 
-    // 创建临时用户消息，用于实时显示
-    let mut cloned_context = current_chat_context.clone();
-    cloned_context.content.push(ChatMessage {
-        msgtype: ChatMessageType::User,
-        time: chrono::Local::now().format("%H:%M").to_string(),
-        content: message.clone(),
+/// 将一轮完成的问答写入对话并保存，替换自动保存的未完成回复；返回助手回复在对话中的位置
+fn record_chat_turn(
+    state: &ChatState,
+    chat_id: u32,
+    user_message: &str,
+    response: String,
+    raw_response: Option<String>,
+    usage: Option<TokenUsage>,
+    backend_state: BackendState,
+) -> Option<usize> {
+    let mut history = state.history.lock().unwrap();
+    let chat = history.get_mut(&chat_id)?;
+    // 去除回答中泄露的系统指令标记
+    let response = aibackend::template::strip_leaked_scaffolding(&response);
+    chat.backend_state = Some(backend_state);
+    // 移除自动保存的未完成回复
+    chat.drop_partial_turn();
+    // 添加用户消息和助手响应
+    chat.content.push(ChatMessage::new(ChatMessageType::User, user_message.to_string()));
+    chat.content.push(ChatMessage {
+        raw_content: raw_response,
+        usage,
+        ..ChatMessage::new(ChatMessageType::Assistant, response)
     });
+    chat.touch();
+    let reply_index = chat.content.len() - 1;
 
-    // 临时显示用户消息
-    let content: &ChatHistory = &ChatHistory::markdown_to_html(&cloned_context);
-    let _ = window_clone.emit("stream-message", content);
-
-    // 显示正在加载
-    cloned_context.content.push(ChatMessage {
-        msgtype: ChatMessageType::Assistant,
-        time: chrono::Local::now().format("%H:%M").to_string(),
-        content: "正在思考...".to_string(),
+    // 保存历史记录
+    save_history(&history).unwrap_or_else(|e| {
+        println!("Failed to save history: {}", e);
     });
+    Some(reply_index)
+}
 
-    let content: &ChatHistory = &ChatHistory::markdown_to_html(&cloned_context);
-    let _ = window_clone.emit("stream-message", content);
-
-    // 创建一个锁定的变量用于存储累积的响应内容
-    let accumulated_markdown = Arc::new(Mutex::new(String::new()));
-
-    // 创建一个回调函数，用于处理流式响应的每个部分
-    let callback = {
-        let window_clone = window_clone.clone();
-        let mut cloned_context = cloned_context.clone();
-        let accumulated_markdown = Arc::clone(&accumulated_markdown);
-
-        // 移除"正在思考..."消息
-        if !cloned_context.content.is_empty() {
-            cloned_context.content.pop();
+/// 记录生成失败的一轮对话，返回用于显示的对话（不含“正在思考...”占位消息）
+///
+/// error_message 为 None 时不写入历史记录，只移除自动保存的部分回复；
+/// 否则用户消息和错误信息一起写入对话，便于之后重新发送
+fn record_failed_turn(
+    state: &ChatState,
+    chat_id: u32,
+    context: &ChatHistory,
+    user_message: &str,
+    error_message: Option<String>,
+) -> ChatHistory {
+    let mut display = context.clone();
+    let mut history = state.history.lock().unwrap();
+    let chat = history.get_mut(&chat_id);
+
+    match (chat, error_message) {
+        (Some(chat), None) => {
+            if !chat.has_incomplete_message() {
+                return display;
+            }
+            chat.drop_partial_turn();
         }
+        (chat, Some(error_message)) => {
+            let turn = [
+                ChatMessage::new(ChatMessageType::User, user_message.to_string()),
+                ChatMessage::new(ChatMessageType::Assistant, error_message),
+            ];
+            display.content.extend(turn.iter().cloned());
+            display.title = Some(get_title_from_history(&display));
+            let Some(chat) = chat else {
+                return display;
+            };
+            chat.drop_partial_turn();
+            chat.content.extend(turn);
+            chat.touch();
+        }
+        (None, None) => return display,
+    }
 
-        // 添加实际的聊天消息，内容将在回调中更新
-        cloned_context.content.push(ChatMessage {
-            msgtype: ChatMessageType::Assistant,
-            time: chrono::Local::now().format("%H:%M").to_string(),
-            content: String::new(), // 初始为空，将在回调中更新
-        });
-
-        move |text: String| {
-            // 累积流式响应内容
-            let mut accumulated = accumulated_markdown.lock().unwrap();
-            accumulated.push_str(&text);
+    // 保存历史记录
+    save_history(&history).unwrap_or_else(|e| {
+        println!("Failed to save history: {}", e);
+    });
+    display
+}
 
-            // 更新最后一条消息的内容
-            let last_idx = cloned_context.content.len() - 1;
-            cloned_context.content[last_idx].content = accumulated.clone();
+/// 用重新生成的回复替换 message_index 及之后的消息并保存，返回更新后的对话
+fn record_regenerated_reply(
+    state: &ChatState,
+    chat_id: u32,
+    message_index: usize,
+    result: Result<String, String>,
+    raw_response: Option<String>,
+    usage: Option<TokenUsage>,
+    backend_state: BackendState,
+) -> Option<ChatHistory> {
+    let mut history = state.history.lock().unwrap();
+    let chat = history.get_mut(&chat_id)?;
 
-            cloned_context.title = Some(get_title_from_history(&cloned_context));
+    // 截断聊天历史，只保留到用户的消息（丢弃所有后续内容）
+    chat.content.truncate(message_index);
 
-            // 将内容转换为HTML并立即发送到前端
-            let content: &ChatHistory = &ChatHistory::markdown_to_html(&cloned_context);
-            println!("Sending stream message: {}", text.clone());
-            let _ = window_clone.emit("stream-message", content);
+    let (content, raw_content, usage) = match result {
+        Ok(final_response) => {
+            chat.backend_state = Some(backend_state);
+            (aibackend::template::strip_leaked_scaffolding(&final_response), raw_response, usage)
         }
+        Err(e) => (format!("{}{}", REGENERATION_ERROR_PREFIX, e), None, None),
     };
+    // 添加新的助手回复或错误消息
+    chat.content.push(ChatMessage {
+        raw_content,
+        usage,
+        ..ChatMessage::new(ChatMessageType::Assistant, content)
+    });
+    chat.touch();
 
-    // Clone message before moving it into the async block
-    let message_for_async = message.clone();
-    println!("message_for_async: {}", message_for_async);
+    let updated = chat.clone();
+    // 保存历史记录
+    save_history(&history).unwrap_or_else(|e| {
+        println!("Failed to save history: {}", e);
+    });
+    Some(updated)
+}
 
-    // 执行流式响应生成
-    let result = chat
-        .generate_response_stream(api_key, message_for_async, callback)
-        .await;
+/// 应用对话级别的设置覆盖（回答语言、生成参数预设），返回用于该对话的设置
+fn settings_for_chat(settings: &setting::setting::AppSettings, history: &ChatHistory) -> setting::setting::AppSettings {
+    let mut settings = settings.clone();
+    if let Some(language) = &history.output_language {
+        settings.output_language = language.clone();
+    }
+    if let Some(profile) = &history.generation_profile {
+        settings.active_generation_profile = profile.clone();
+    }
+    settings
+}
 
-    // 将结果映射错误为String以使其可以安全地在线程间传递
-    let response_result = result.map_err(|e| e.to_string());
+/// 若对话保存了与当前后端和模型兼容的状态，则恢复到聊天实例中
+fn restore_backend_state(chat: &mut AIChatType, history: &ChatHistory, key_type: &str, model_name: Option<&str>) {
+    let model = model_name.unwrap_or_default();
+    if let Some(state) = &history.backend_state {
+        if !state.is_compatible(key_type, model) {
+            println!("对话 {} 保存的后端状态与当前模型不兼容，忽略", history.id);
+            return;
+        }
+        if let Err(e) = chat.deserialize(state.data.clone()) {
+            println!("无法恢复后端状态: {}", e);
+        }
+    }
+}
 
-    // 处理最终结果
-    match response_result {
-        Ok(final_response) => {
-            // 储存当前对话的内容
-            let current_id = *CURRENT_CHAT_ID.lock().unwrap();
-            let mut history = CHAT_HISTORY.lock().unwrap();
-            if let Some(chat_history) = history.get_mut(&current_id) {
-                // 添加用户消息和助手响应
-                chat_history.content.push(ChatMessage {
-                    msgtype: ChatMessageType::User,
-                    time: chrono::Local::now().format("%H:%M").to_string(),
-                    content: message.clone(),
-                });
-                chat_history.content.push(ChatMessage {
-                    msgtype: ChatMessageType::Assistant,
-                    time: chrono::Local::now().format("%H:%M").to_string(),
-                    content: final_response,
-                });
-                chat_history.time = chrono::Local::now().format("%H:%M").to_string();
+/// 将聊天实例的状态转换为可保存的后端状态（消息由 ChatHistory 保存，这里清空以避免重复）
+fn into_backend_state(mut chat: AIChatType, key_type: &str, model_name: Option<&str>) -> BackendState {
+    let _ = chat.clear_context();
+    BackendState {
+        backend: key_type.to_string(),
+        model: model_name.unwrap_or_default().to_string(),
+        data: chat.serialize(),
+    }
+}
 
-                // 保存历史记录
-                save_history(&history).unwrap_or_else(|e| {
-                    println!("Failed to save history: {}", e);
-                });
-            }
-        }
-        Err(e) => {
-            // 处理错误情况
-            let error_message = format!("生成回复时出错: {}", e);
-
-            // 更新最后一条消息为错误信息
-            let mut cloned_context = current_chat_context.clone();
-            cloned_context.content.push(ChatMessage {
-                msgtype: ChatMessageType::User,
-                time: chrono::Local::now().format("%H:%M").to_string(),
-                content: message.clone(),
-            });
-            cloned_context.content.push(ChatMessage {
-                msgtype: ChatMessageType::Assistant,
-                time: chrono::Local::now().format("%H:%M").to_string(),
-                content: error_message.clone(),
-            });
-            cloned_context.title = Some(get_title_from_history(&cloned_context));
+/// 单次请求的生成参数，仅对本次发送生效，不修改全局设置和对话保存的后端状态
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct GenerationOverrides {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    max_tokens: Option<u32>,
+}
 
-            let content: &ChatHistory = &ChatHistory::markdown_to_html(&cloned_context);
-            let _ = window_clone.emit("stream-message", content);
+impl GenerationOverrides {
+    fn is_empty(&self) -> bool {
+        self.parameters().is_empty()
+    }
 
-            // 储存当前对话的内容，包括错误信息
-            let current_id = *CURRENT_CHAT_ID.lock().unwrap();
-            let mut history = CHAT_HISTORY.lock().unwrap();
-            if let Some(chat) = history.get_mut(&current_id) {
-                chat.content.push(ChatMessage {
-                    msgtype: ChatMessageType::User,
-                    time: chrono::Local::now().format("%H:%M").to_string(),
-                    content: message.clone(),
-                });
-                chat.content.push(ChatMessage {
-                    msgtype: ChatMessageType::Assistant,
-                    time: chrono::Local::now().format("%H:%M").to_string(),
-                    content: error_message,
-                });
-                chat.time = chrono::Local::now().format("%H:%M").to_string();
-                // 保存历史记录
-                save_history(&history).unwrap_or_else(|e| {
-                    println!("Failed to save history: {}", e);
-                });
-            }
+    /// 转换为 set_parameter 使用的参数名和值
+    fn parameters(&self) -> Vec<(&'static str, String)> {
+        let mut parameters = Vec::new();
+        if let Some(temperature) = self.temperature {
+            parameters.push(("temperature", temperature.to_string()));
+        }
+        if let Some(top_p) = self.top_p {
+            parameters.push(("top_p", top_p.to_string()));
+        }
+        if let Some(top_k) = self.top_k {
+            parameters.push(("top_k", top_k.to_string()));
         }
+        if let Some(max_tokens) = self.max_tokens {
+            parameters.push(("max_tokens", max_tokens.to_string()));
+        }
+        parameters
     }
 
-    // 通知前端流式传输完成
-    let _ = window_clone.emit("stream-complete", "");
+    /// 应用到聊天实例，后端不支持的参数（如 DeepSeek 的 top_k）会被跳过
+    fn apply(&self, chat: &mut AIChatType) {
+        apply_generation_parameters(chat, self.parameters());
+    }
 
-    // 主线程立即返回，不会被阻塞
 }
 
-// Create a wrapper trait for ASTNode serialization
-trait ASTSerializer {
-    fn serialize(&self) -> String;
+/// 只对本次请求生效的参数应用前后的后端状态
+struct RequestParamsSnapshot {
+    before: serde_json::Value,
+    applied: serde_json::Value,
 }
 
-// Implement the trait for ASTNode
-impl ASTSerializer for ASTNode<'_> {
-    fn serialize(&self) -> String {
-        use serde_json::{json, to_string_pretty};
+/// 应用只对本次请求生效、不保存到后端状态的参数：生成参数预设、对话参数（优先于预设）和单次请求参数（优先于两者）。
+/// 预设随时可能被修改或清除，因此每次请求重新应用，返回的快照用于保存后端状态前撤销这些参数
+fn apply_request_parameters(
+    chat: &mut AIChatType,
+    chat_settings: &setting::setting::AppSettings,
+    history: &ChatHistory,
+    overrides: Option<&GenerationOverrides>,
+) -> RequestParamsSnapshot {
+    let before = serde_json::from_str(&chat.serialize()).unwrap_or_default();
+    if let Some(profile) = chat_settings.active_generation_profile() {
+        println!("使用生成参数预设: {}", profile.name);
+        apply_generation_parameters(chat, profile.parameters());
+    }
+    for (key, value) in &history.chat_parameters {
+        if let Err(e) = chat.set_parameter(key.clone(), value.clone()) {
+            println!("忽略对话参数 {}: {}", key, e);
+        }
+    }
+    if let Some(overrides) = overrides.filter(|overrides| !overrides.is_empty()) {
+        println!("应用单次请求参数: {:?}", overrides);
+        overrides.apply(chat);
+    }
+    let applied = serde_json::from_str(&chat.serialize()).unwrap_or_default();
+    RequestParamsSnapshot { before, applied }
+}
 
-        // 递归函数，将 ASTNode 转换为 serde_json::Value
-        fn node_to_value(node: &ASTNode) -> serde_json::Value {
-            // 构建开始和结束 token 的信息
-            let start_token = node.start_token.map(|t| {
-                json!({
-                    "token" : t.token,
-                    "type":t.token_type._to_string(),
-                    "origin_token":t.origin_token.clone(),
-                    "position" : t.position
-                })
-            });
+impl RequestParamsSnapshot {
+    /// 将保存的后端状态中被本次请求参数修改过的字段还原为应用前的值，本轮中后端对其他字段的修改保持不变
+    fn revert(&self, backend_state: &mut BackendState) {
+        let Ok(mut data) = serde_json::from_str::<serde_json::Value>(&backend_state.data) else {
+            return;
+        };
+        revert_changed_fields(&mut data, &self.before, &self.applied);
+        backend_state.data = data.to_string();
+    }
+}
 
-            let end_token = node.end_token.map(|t| {
-                json!({
-                    "token" : t.token,
-                    "type":t.token_type._to_string(),
-                    "origin_token":t.origin_token.clone(),
-                    "position" : t.position
-                })
-            });
+/// 对比 before 和 applied，将 target 中发生变化的字段（逐层比较对象）还原为 before 中的值
+fn revert_changed_fields(target: &mut serde_json::Value, before: &serde_json::Value, applied: &serde_json::Value) {
+    let (Some(target), Some(before), Some(applied)) = (target.as_object_mut(), before.as_object(), applied.as_object())
+    else {
+        return;
+    };
+    for (key, applied_value) in applied {
+        match before.get(key) {
+            Some(before_value) if before_value == applied_value => {}
+            Some(before_value) if before_value.is_object() && applied_value.is_object() => {
+                if let Some(target_value) = target.get_mut(key) {
+                    revert_changed_fields(target_value, before_value, applied_value);
+                }
+            }
+            Some(before_value) => {
+                target.insert(key.clone(), before_value.clone());
+            }
+            None => {
+                target.remove(key);
+            }
+        }
+    }
+    for (key, before_value) in before {
+        if !applied.contains_key(key) {
+            target.insert(key.clone(), before_value.clone());
+        }
+    }
+}
 
-            // 递归处理所有子节点
-            let children = node.children.iter().map(node_to_value).collect::<Vec<_>>();
+/// 下一次发送时实际使用的模型和生成参数，未设置的参数为 None，表示使用模型服务的默认值
+#[derive(Clone, Debug, Serialize)]
+struct EffectiveParams {
+    backend: String,
+    model: Option<String>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    generation_profile: Option<String>, // 生效的生成参数预设
+    system_prompt_source: String,       // 系统提示词的来源
+    output_language: String,
+    cot_enabled: bool,
+}
 
-            // 构建完整的节点 JSON 对象
-            json!({
-                "node_type": format!("{:?}", node.node_type),
-                "start_token": start_token,
-                "end_token": end_token,
-                "children": children
-            })
-        }
-        // 将 ASTNode 转换为 JSON 值，然后格式化为字符串
-        match to_string_pretty(&node_to_value(self)) {
-            Ok(json_str) => json_str,
-            Err(err) => format!("{{\"error\": \"序列化 AST 失败: {}\"}}", err),
+/// 按发送消息时相同的顺序（对话保存的后端状态、生成参数预设、对话参数）解析对话实际使用的参数，不包含单次请求参数
+fn effective_params(
+    settings: &setting::setting::AppSettings,
+    history: &ChatHistory,
+    key_type: &str,
+    model_name: Option<&str>,
+) -> Result<EffectiveParams, String> {
+    let model = model_name
+        .map(str::to_string)
+        .or_else(|| settings.model_selection.get(key_type).cloned())
+        .filter(|model| !model.is_empty())
+        .map(|model| settings.resolve_model_alias(&model));
+    let chat_settings = settings_for_chat(settings, history);
+
+    let mut chat = create_ai_chat(key_type, model.as_deref())?;
+    restore_backend_state(&mut chat, history, key_type, model.as_deref());
+    apply_request_parameters(&mut chat, &chat_settings, history, None);
+    apply_chat_cot(&mut chat, history);
+    let profile = chat_settings.active_generation_profile();
+
+    let state: serde_json::Value = serde_json::from_str(&chat.serialize()).unwrap_or_default();
+    let persona = &chat_settings.persona_config;
+    Ok(EffectiveParams {
+        backend: key_type.to_string(),
+        model,
+        temperature: serialized_number(&state, "temperature").map(|value| value as f32),
+        max_tokens: serialized_number(&state, "max_tokens").map(|value| value as u32),
+        top_p: serialized_number(&state, "top_p").map(|value| value as f32),
+        top_k: serialized_number(&state, "top_k").map(|value| value as u32),
+        generation_profile: profile.map(|profile| profile.name.clone()),
+        system_prompt_source: if persona.use_custom {
+            "自定义人格".to_string()
+        } else {
+            format!("预设人格: {}", persona.preset_persona)
+        },
+        output_language: Some(chat_settings.output_language.trim())
+            .filter(|language| !language.is_empty())
+            .unwrap_or(DEFAULT_OUTPUT_LANGUAGE)
+            .to_string(),
+        cot_enabled: chat.uses_cot(),
+    })
+}
+
+/// 从聊天实例序列化的状态中读取数值参数：Gemini 和 DeepSeek 保存为同名字段，Coze 和 Mock 以字符串保存在 parameters 中
+fn serialized_number(state: &serde_json::Value, key: &str) -> Option<f64> {
+    state
+        .get(key)
+        .and_then(serde_json::Value::as_f64)
+        .or_else(|| state.get("parameters")?.get(key)?.as_str()?.parse().ok())
+}
+
+/// 应用设置中的停止序列，Coze 等不支持的后端会跳过
+fn apply_stop_sequences(chat: &mut AIChatType, settings: &setting::setting::AppSettings) {
+    // 未配置停止序列时显式清除，避免沿用之前保存的值
+    let stop = settings.model_config.stop.clone().unwrap_or_default();
+    let value = serde_json::to_string(&stop).unwrap_or_default();
+    apply_generation_parameters(chat, vec![("stop", value)]);
+}
+
+/// 按对话设置开启或关闭 COT 模板，关闭时只使用人格等基础系统提示
+fn apply_chat_cot(chat: &mut AIChatType, history: &ChatHistory) {
+    apply_generation_parameters(chat, vec![("cot", (!history.disable_cot).to_string())]);
+}
+
+/// 通过 set_parameter 设置生成参数，跳过后端不支持的参数
+fn apply_generation_parameters(chat: &mut AIChatType, parameters: Vec<(&'static str, String)>) {
+    for (key, value) in parameters {
+        if let Err(e) = chat.set_parameter(key.to_string(), value) {
+            println!("忽略生成参数 {}: {}", key, e);
         }
     }
 }
 
-// AST节点修正器
-struct ASTOptimizer;
+/// 一轮回复中后端工具调用结果的记录：回复写入历史记录前完成的结果暂存，
+/// 随回复一起插入在助手消息之后；之后才完成的结果插入在该回复已有的工具结果之后，
+/// 即使用户已经开始下一轮提问也不会打乱顺序；回复失败时丢弃
+#[derive(Clone, Default)]
+struct ToolMessageLog(Arc<Mutex<ToolLogState>>);
 
-impl ASTOptimizer {
-    /// 修正AST节点，优化表达式结构
-    pub fn optimize(node: &mut ASTNode) {
-        while Self::optimize_recursive(node) {}
+enum ToolLogState {
+    Pending(Vec<ChatMessage>),
+    Recorded(usize), // 下一个工具结果的插入位置
+    Discarded,
+}
+
+impl Default for ToolLogState {
+    fn default() -> Self {
+        ToolLogState::Pending(Vec::new())
     }
+}
 
-    fn optimize_recursive(node: &mut ASTNode) -> bool {
-        // 首先递归处理所有子节点
-        let mut optimized = false;
-        for child in &mut node.children {
-            optimized |= Self::optimize_recursive(child);
+impl ToolMessageLog {
+    fn push(&self, state: &ChatState, chat_id: u32, message: ChatMessage) {
+        let mut log = self.0.lock().unwrap();
+        match &mut *log {
+            ToolLogState::Pending(messages) => messages.push(message),
+            ToolLogState::Recorded(next_index) => match state.insert_messages(chat_id, *next_index, vec![message]) {
+                Ok(end) => *next_index = end,
+                Err(e) => println!("无法记录工具调用结果: {}", e),
+            },
+            ToolLogState::Discarded => {}
         }
+    }
 
-        // 然后处理当前节点的优化
-        match &node.node_type {
-            ASTNodeType::LambdaCall => {
-                optimized |= Self::optimize_lambda_call(node);
+    /// 回复已写入历史记录后调用，reply_index 为助手回复的位置，暂存的工具结果插入在其后
+    fn record(&self, state: &ChatState, chat_id: u32, reply_index: usize) {
+        let mut log = self.0.lock().unwrap();
+        let next_index = reply_index + 1;
+        if let ToolLogState::Pending(messages) = std::mem::replace(&mut *log, ToolLogState::Recorded(next_index)) {
+            match state.insert_messages(chat_id, next_index, messages) {
+                Ok(end) => *log = ToolLogState::Recorded(end),
+                Err(e) => println!("无法记录工具调用结果: {}", e),
             }
-            ASTNodeType::Expressions => {
-                optimized |= Self::optimize_expressions(node);
-            }
-            _ => {}
         }
-        return optimized;
     }
 
-    /// 优化Lambda调用节点
-    /// 处理 (expressions)() -> expressions() 的情况
-    fn optimize_lambda_call(node: &mut ASTNode) -> bool {
-        // 检查第一个子节点是否是表达式
-        if let Some(first_child) = node.children.get(0) {
-            if matches!(first_child.node_type, ASTNodeType::Expressions) {
-                // 如果是表达式，将表达式提升，并将表达式最后一个子节点作为lambda调用的第一个子节点
-                let expressions_node = &first_child.children;
-                let last_child = expressions_node.last().cloned();
-                if last_child.is_none() {
-                    return false;
-                }
-                let last_child = last_child.unwrap();
-                let mut nodes = expressions_node[..expressions_node.len() - 1].to_vec();
-                let mut children = vec![last_child];
-                children.extend_from_slice(&node.children[1..]);
-                let new_children = ASTNode {
-                    node_type: ASTNodeType::LambdaCall,
-                    start_token: node.start_token,
-                    end_token: node.end_token,
-                    children,
-                };
-                nodes.push(new_children);
+    fn discard(&self) {
+        *self.0.lock().unwrap() = ToolLogState::Discarded;
+    }
+}
 
-                let new_node = ASTNode {
-                    node_type: ASTNodeType::Expressions,
-                    start_token: node.start_token,
-                    end_token: node.end_token,
-                    children: nodes,
-                };
+// 在后台执行流式回复中可以由后端完成的工具调用（目前为 Wolfram Alpha 计算），
+// 通过 tool-result 事件发送渲染结果，index 和 offset 对应代码块在回复中的位置；
+// 调用和结果同时作为工具消息记录到对话中
+fn spawn_streamed_tool_call(
+    window: &Window,
+    chat_id: u32,
+    block: document_renderer::tool_code::ToolCodeBlock,
+    tool_log: ToolMessageLog,
+) {
+    use document_renderer::tool_code::{parse_server_tool, ServerTool};
+
+    let Some(tool) = parse_server_tool(&block.code) else {
+        return;
+    };
+    let window = window.clone();
+    tauri::async_runtime::spawn(async move {
+        let name = tool.name();
+        let ServerTool::Wolfram { query, image_only } = tool;
+        let mut options = ai_utils::wolframalpha::WolframQueryOptions::default();
+        let theme = *options
+            .theme
+            .get_or_insert_with(|| app_wolfram_theme(&window));
+        let _permit = aibackend::concurrency::acquire().await;
+        let results = document_renderer::wolfram::wolfram_alpha_compute(&query, image_only, &options)
+            .await
+            .map(document_renderer::wolfram::fallback_if_no_pods);
+        let record = match &results {
+            Ok(results) => document_renderer::wolfram::format_to_markdown(results),
+            Err(e) => format!("计算失败: {}", e),
+        };
+        tool_log.push(
+            &window.state::<ChatState>(),
+            chat_id,
+            ChatMessage::tool_result(name, format!("查询：`{}`\n\n{}", query, record)),
+        );
+        let result = results
+            .map(|results| document_renderer::wolfram::format_to_html_themed(&results, theme));
+        let _ = window.emit(
+            "tool-result",
+            serde_json::json!({
+                "chat_id": chat_id,
+                "index": block.index,
+                "offset": block.offset,
+                "tool": name,
+                "query": query,
+                "image_only": image_only,
+                "html": result.as_ref().ok(),
+                "error": result.as_ref().err(),
+            }),
+        );
+    });
+}
 
-                *node = new_node;
-                return true;
-            }
+/// 流式回复的显示和工具调用，发送消息和重新生成共用：累积回复内容，按刷新间隔把正在生成的消息发送到前端，
+/// 并在后台执行回复中已完整输出的后端工具调用
+struct ReplyStream {
+    window: Window,
+    chat_id: u32,
+    display: ChatHistory, // 用于显示的对话，最后一条为正在生成的助手消息
+    text: String,
+    streaming_html: StreamingHtml,
+    flush_throttle: FlushThrottle,
+    tool_scanner: document_renderer::tool_code::ToolCodeScanner,
+    tool_log: ToolMessageLog,
+}
+
+impl ReplyStream {
+    fn new(
+        window: &Window,
+        chat_id: u32,
+        mut display: ChatHistory,
+        settings: &setting::setting::AppSettings,
+        tool_log: ToolMessageLog,
+    ) -> Self {
+        // 添加实际的助手消息，内容随流式回复更新
+        display.content.push(ChatMessage::new(ChatMessageType::Assistant, String::new()));
+        Self {
+            window: window.clone(),
+            chat_id,
+            display,
+            text: String::new(),
+            streaming_html: StreamingHtml::new(),
+            flush_throttle: FlushThrottle::new(settings.stream_flush_interval_ms, settings.stream_flush_chars),
+            tool_scanner: document_renderer::tool_code::ToolCodeScanner::new(),
+            tool_log,
         }
-        return false;
     }
 
-    /// 优化表达式节点
-    /// 处理 (expressions); xxx -> expressions; xxx 的情况
-    fn optimize_expressions(node: &mut ASTNode) -> bool {
-        if !matches!(node.node_type, ASTNodeType::Expressions) {
-            return false;
-        }
+    fn text(&self) -> &str {
+        &self.text
+    }
 
-        let mut optimized = false;
+    /// 追加流式回复的片段：立即执行新出现的完整 tool_code 代码块，合并细小的片段，达到字符数或时间间隔后再刷新界面
+    fn push(&mut self, chunk: &str) {
+        self.text.push_str(chunk);
+        for block in self.tool_scanner.scan(&self.text) {
+            spawn_streamed_tool_call(&self.window, self.chat_id, block, self.tool_log.clone());
+        }
+        if self.flush_throttle.push(chunk) {
+            self.flush();
+        }
+    }
 
-        let mut flattened_children = Vec::new();
+    /// 将当前内容发送到前端（只重新渲染正在生成的消息）
+    fn flush(&mut self) {
+        let last_idx = self.display.content.len() - 1;
+        self.display.content[last_idx].content = self.text.clone();
+        self.display.title = Some(get_title_from_history(&self.display));
+        let content = self.streaming_html.render(&self.display);
+        let _ = self.window.emit("stream-message", &content);
+    }
 
-        for child in &node.children {
-            if matches!(child.node_type, ASTNodeType::Expressions) {
-                // 如果子节点也是表达式，则展开其子节点
-                flattened_children.extend(child.children.clone());
-                optimized = true;
-            } else {
-                flattened_children.push(child.clone());
+    /// 定时刷新合并中的片段，避免模型停顿时最后几个片段一直不显示；返回的任务句柄释放时停止刷新
+    fn spawn_stall_flush(reply: &Arc<Mutex<Self>>) -> Option<StallFlush> {
+        let interval = reply.lock().unwrap().flush_throttle.interval();
+        if interval.is_zero() {
+            return None;
+        }
+        let reply = Arc::clone(reply);
+        Some(StallFlush(tauri::async_runtime::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let mut reply = reply.lock().unwrap();
+                if reply.flush_throttle.poll() {
+                    reply.flush();
+                }
             }
+        })))
+    }
+
+    /// 回复生成成功后调用，执行回复末尾的 tool_code 代码块
+    fn finish(&mut self) {
+        for block in self.tool_scanner.finish(&self.text) {
+            spawn_streamed_tool_call(&self.window, self.chat_id, block, self.tool_log.clone());
         }
+    }
+}
 
-        node.children = flattened_children;
-        return optimized;
+/// 释放时停止定时刷新流式回复的任务
+struct StallFlush(tauri::async_runtime::JoinHandle<()>);
+
+impl Drop for StallFlush {
+    fn drop(&mut self) {
+        self.0.abort();
     }
 }
 
-// ANSI颜色控制字符清理函数
-fn strip_ansi_colors(text: &str) -> String {
-    // 匹配所有ANSI转义序列的正则表达式
-    // 包括：颜色、光标位置、清屏等控制序列
-    let re = regex::Regex::new(r"\x1b\[[0-9;]*[a-zA-Z]").unwrap();
-    re.replace_all(text, "").to_string()
+// 生成过程中显示的占位消息，不会写入历史记录
+const THINKING_PLACEHOLDER: &str = "正在思考...";
+
+/// 释放时发送 stream-complete 事件，前端收到后重新加载对话内容替换占位消息；
+/// 任务出错返回、被取消或 panic 时同样会释放，避免“正在思考...”一直留在界面上
+struct StreamCompletion(Window);
+
+impl Drop for StreamCompletion {
+    fn drop(&mut self) {
+        let _ = self.0.emit("stream-complete", "");
+    }
+}
+
+// 通过独立的 stream-error 事件发送错误类别和信息，避免错误被当作模型回复显示；
+// persisted 表示错误是否已写入对话历史，未写入时附带用户的原始消息以便前端恢复到输入框
+fn emit_stream_error(window: &Window, error: &AiError, persisted: bool, prompt: Option<&str>) {
+    let _ = window.emit("stream-error", stream_error_payload(error, persisted, prompt));
+}
+
+fn stream_error_payload(error: &AiError, persisted: bool, prompt: Option<&str>) -> serde_json::Value {
+    serde_json::json!({
+        "code": error.code,
+        "message": error.message,
+        "persisted": persisted,
+        "prompt": prompt,
+    })
 }
 
+// 以流式方式处理用户消息
 #[tauri::command]
-fn parse_code(code: String) -> Result<String, String> {
-    let tokens = lexer::tokenize(&code);
-    let tokens = lexer::reject_comment(&tokens);
-    let ast = build_ast(&tokens);
-    match ast {
-        Ok(mut ast) => {
-            // 应用AST优化
-            ASTOptimizer::optimize(&mut ast);
-            let serialized_ast = ast.serialize();
-            Ok(serialized_ast)
+async fn process_message_stream(
+    window: Window,
+    message: String,
+    key_type: String,
+    model_name: Option<String>,
+    overrides: Option<GenerationOverrides>,
+    images: Option<Vec<ImageAttachment>>,
+) {
+    // 错误已通过 stream-error 事件通知前端
+    let _ = send_message_stream(window, message, key_type, model_name, overrides, images, false).await;
+}
+
+/// 发送消息并以流式方式接收回复，返回最后一次尝试的结果；from_outbox 表示消息来自待发送队列，
+/// 再次遇到网络错误时放回队首而不是队尾
+async fn send_message_stream(
+    window: Window,
+    message: String,
+    key_type: String,
+    model_name: Option<String>,
+    overrides: Option<GenerationOverrides>,
+    images: Option<Vec<ImageAttachment>>,
+    from_outbox: bool,
+) -> Result<(), AiError> {
+    let settings = setting::setting::load_app_settings("settings.json").unwrap_or_default();
+
+    // 依次尝试主后端和备用后端，备用后端使用设置中为其选择的模型；所选模型不存在时先改用该后端的默认模型重试一次。
+    // 所有尝试结束后才通知前端生成完成
+    let mut completion = None;
+    let mut backend = key_type;
+    let mut model_name = model_name;
+    let mut model_retried = false;
+    let mut fallbacks = settings.fallback_backends(&backend).into_iter().peekable();
+    loop {
+        let retry_model = default_model(&backend).filter(|&default| {
+            !model_retried
+                && model_name.as_deref().map(|name| settings.resolve_model_alias(name)).as_deref() != Some(default)
+        });
+        let mut attempt = BackendAttempt {
+            can_fall_back: fallbacks.peek().is_some(),
+            can_retry_model: retry_model.is_some(),
+            from_outbox,
+            reported: false,
+            completion: &mut completion,
+        };
+        let result = stream_with_backend(
+            window.clone(),
+            message.clone(),
+            backend.clone(),
+            model_name.clone(),
+            overrides.clone(),
+            images.clone(),
+            &mut attempt,
+        )
+        .await;
+        let Err(error) = result else {
+            return Ok(());
+        };
+        if attempt.reported {
+            return Err(error);
         }
-        Err(e) => {
-            let error_msg = e.format(&tokens, code.clone());
-            // 清理错误信息中的ANSI颜色控制字符
-            Err(strip_ansi_colors(&error_msg))
+        if let Some(default) = retry_model.filter(|_| error.is_model_not_found()) {
+            println!("模型 {:?} 不可用（{}），改用默认模型 {} 重试", model_name, error.message, default);
+            let _ = window.emit(
+                "model-fallback",
+                serde_json::json!({ "backend": backend, "from": model_name, "to": default, "reason": error.message }),
+            );
+            model_name = Some(default.to_string());
+            model_retried = true;
+            continue;
         }
+        let Some(next) = fallbacks.next() else {
+            return Err(error);
+        };
+        println!("{} 不可用（{}），改用 {} 重试", backend, error.message, next);
+        let _ = window.emit(
+            "backend-fallback",
+            serde_json::json!({ "from": backend, "to": next, "reason": error.message }),
+        );
+        model_name = settings.model_selection.get(&next).cloned();
+        model_retried = false;
+        backend = next;
     }
 }
 
-#[tauri::command]
-async fn regenerate_message(
+/// 一次使用指定后端生成回复的尝试
+struct BackendAttempt<'a> {
+    can_fall_back: bool, // 是否还有备用后端可以尝试
+    can_retry_model: bool, // 所选模型不存在时是否可以改用默认模型重试
+    from_outbox: bool, // 消息来自待发送队列
+    reported: bool, // 错误已通知前端并记录，调用方不再重试
+    completion: &'a mut Option<StreamCompletion>, // 在所有尝试结束后才释放，避免前端提前结束生成状态
+}
+
+impl BackendAttempt<'_> {
+    /// 错误已通知前端，作为最终结果返回
+    fn fail(&mut self, error: AiError) -> Result<(), AiError> {
+        self.reported = true;
+        Err(error)
+    }
+}
+
+/// 使用指定后端生成回复。遇到基础设施错误（密钥失效、限流、网络或服务端错误）且尚未输出任何内容时，
+/// 若还有备用后端则不记录错误，返回 Err 由调用方改用下一个后端；所选模型不存在时同样返回 Err，
+/// 由调用方改用默认模型重试；其余错误自行通知前端并记录，通过 BackendAttempt::fail 返回
+async fn stream_with_backend(
     window: Window,
-    message_index: usize,
+    message: String,
     key_type: String,
     model_name: Option<String>,
-) -> Result<(), String> {
+    overrides: Option<GenerationOverrides>,
+    images: Option<Vec<ImageAttachment>>,
+    attempt: &mut BackendAttempt<'_>,
+) -> Result<(), AiError> {
     // 克隆窗口以便在新线程中使用
     let window_clone = window.clone();
-
+    
+    println!("收到请求 - API类型: {}, 模型名称: {:?}", key_type, model_name);
+    
     // 获取当前设置以获取人格配置
-    let current_settings = match setting::setting::load_app_settings("settings.json") {
+    let settings = match setting::setting::load_app_settings("settings.json") {
         Ok(settings) => settings,
         Err(e) => {
             println!("无法加载设置，使用默认设置: {}", e);
@@ -729,105 +1271,576 @@ async fn regenerate_message(
         }
     };
 
-    // 创建一个新线程处理消息重新生成
-    // 获取当前聊天ID
-    let current_id = *CURRENT_CHAT_ID.lock().unwrap();
+    // 将前端使用的模型别名转换为真实的模型ID
+    let model_name = model_name.map(|name| settings.resolve_model_alias(&name));
+    
+    println!("使用人格配置: {:?}", settings.persona_config);
 
-    // 从锁定的历史中获取聊天记录的克隆，避免长时间持有锁
-    let chat_clone = {
-        let history = CHAT_HISTORY.lock().unwrap();
-        match history.get(&current_id) {
-            Some(chat) => chat.clone(),
-            None => {
-                let _ = window_clone.emit("stream-message", "找不到当前对话");
-                let _ = window_clone.emit("stream-complete", "");
-                return Ok(());
+    // 获取API密钥，没有可用密钥时可以改用备用后端
+    let api_key = match select_api_key(&key_type) {
+        Ok(key) => key,
+        Err(e) => {
+            let error = AiError::new(AiErrorCode::Config, e);
+            if attempt.can_fall_back {
+                return Err(error);
             }
+            emit_stream_error(&window_clone, &error, false, Some(message.as_str()));
+            return attempt.fail(error);
         }
     };
 
-    // 检查消息索引是否有效
-    if message_index >= chat_clone.content.len() {
-        let _ = window_clone.emit("stream-message", "无效的消息索引");
-        let _ = window_clone.emit("stream-complete", "");
-        return Ok(());
-    }
+    // 初始化AI聊天实例
+    let mut chat = match create_ai_chat(&key_type, model_name.as_deref()) {
+        Ok(chat) => chat,
+        Err(e) => {
+            let error = AiError::new(AiErrorCode::Config, e);
+            emit_stream_error(&window_clone, &error, false, Some(message.as_str()));
+            return attempt.fail(error);
+        }
+    };
 
-    // 检查是否是助手消息
-    if chat_clone.content[message_index].msgtype != ChatMessageType::Assistant {
-        let _ = window_clone.emit("stream-message", "只能重新生成助手的消息");
-        let _ = window_clone.emit("stream-complete", "");
-        return Ok(());
-    }    // 获取API密钥
-    let api_key = match key_type.as_str() {
-        "Coze" => {
-            // Coze 使用内置密钥，不需要从配置读取
-            aibackend::apikey::ApiKey {
-                key: "built-in".to_string(),
-                name: "Coze Built-in".to_string(),
-                key_type: aibackend::apikey::ApiKeyType::Coze,
+    // 获取当前聊天上下文
+    let state = window.state::<ChatState>();
+    let current_chat_id = state.current_chat_id(window.label());
+    let mut current_chat_context = {
+        let history = state.history.lock().unwrap();
+        if let Some(history_chat) = history.get(&current_chat_id) {
+            history_chat.clone()
+        } else {
+            ChatHistory {
+                time: String::new(),
+                ..ChatState::empty_chat(current_chat_id)
             }
         }
-        _ => {
-            // 其他类型从配置文件读取
-            let api_key_list = aibackend::apikey::get_api_key_list_or_create("api_keys.json");
-            let key_list = api_key_list.filter_by_type(match key_type.as_str() {
-                "DeepSeek" => aibackend::apikey::ApiKeyType::DeepSeek,
-                "Gemini" => aibackend::apikey::ApiKeyType::Gemini,
-                _ => {
-                    let _ = window_clone.emit("stream-message", "不支持的API密钥类型，请检查设置");
-                    return Ok(());
-                }
-            });
+    };
 
-            if key_list.keys.is_empty() {
-                // 如果没有API密钥，发送错误消息
-                let _ = window_clone.emit(
-                    "stream-message",
-                    format!("没有可用的{} API密钥，请在设置中添加", key_type),
-                );
-                return Ok(());
-            }
+    // 优先恢复对话保存的后端状态，使按对话设置的模型参数得以保留
+    restore_backend_state(&mut chat, &current_chat_context, &key_type, model_name.as_deref());
 
-            // 随机选择一个API密钥
-            match key_list.random_key() {
-                Some(key) => key,
-                None => {
-                    let _ = window_clone.emit(
-                        "stream-message",
-                        format!("没有可用的{} API密钥，请在设置中添加", key_type),
-                    );
-                    return Ok(());
+    // 未设置回答语言时按提问语言固定该对话的回答语言，使用英文开始的对话会一直用英文回答
+    if settings.auto_detect_language
+        && settings.output_language.trim().is_empty()
+        && current_chat_context.output_language.is_none()
+        && !current_chat_context.language_locked
+    {
+        if let Some(language) = current_chat_context
+            .dominant_language(Some(message.as_str()))
+            .filter(|&language| language != DEFAULT_OUTPUT_LANGUAGE)
+        {
+            println!("识别到对话语言为 {}，设置为该对话的回答语言", language);
+            current_chat_context.output_language = Some(language.to_string());
+            let mut history = state.history.lock().unwrap();
+            if let Some(chat) = history.get_mut(&current_chat_id) {
+                chat.output_language = Some(language.to_string());
+                if let Err(e) = save_history(&history) {
+                    println!("Failed to save history: {}", e);
                 }
             }
         }
-    };// 初始化AI聊天实例
-    let mut ai_chat = match key_type.as_str() {
-        "DeepSeek" => {
-            let model = model_name.as_deref().unwrap_or("deepseek-chat");
-            AIChatType::DeepSeek(DeepSeekChat::new_with_model(model))
-        },
-        "Gemini" => {
-            let model = model_name.as_deref().unwrap_or("gemini-2.5-flash");
-            AIChatType::Gemini(GeminiChat::new_with_model(model))
-        },
-        "Coze" => AIChatType::Coze(CozeChat::new()),
-        _ => {
-            let _ = window_clone.emit("stream-message", "不支持的API密钥类型，请检查设置");
-            return Ok(());
-        }
-    };
+    }
 
     // 获取融合后的系统提示词（包含人格特质）
-    let merged_system_prompt = match setting::setting::merge_persona_with_system_prompt(&current_settings) {
+    let chat_settings = settings_for_chat(&settings, &current_chat_context);
+    let merged_system_prompt = match merge_persona_with_system_prompt(&chat_settings) {
         Ok(prompt) => prompt,
         Err(e) => {
-            let error_msg = format!("人格配置错误: {}", e);
-            let _ = window_clone.emit("stream-message", &error_msg);
-            return Ok(());
+            let error = AiError::new(AiErrorCode::Config, format!("人格配置错误: {}", e));
+            emit_stream_error(&window_clone, &error, false, Some(message.as_str()));
+            return attempt.fail(error);
         }
     };
-    let _ = ai_chat.set_system_prompt(merged_system_prompt);
+    let _ = chat.set_system_prompt(merged_system_prompt);
+
+    // 应用 Gemini 安全过滤等级
+    if let AIChatType::Gemini(_) = chat {
+        if let Err(e) = chat.set_parameter("safety_level".to_string(), settings.gemini_safety_level.clone()) {
+            println!("无法设置安全等级: {}", e);
+        }
+    }
+
+    apply_stop_sequences(&mut chat, &settings);
+    apply_chat_cot(&mut chat, &current_chat_context);
+
+    // 应用生成参数预设（对话设置优先于全局设置）、对话参数和单次请求参数，回复后撤销，不保存到对话中
+    let request_params = apply_request_parameters(&mut chat, &chat_settings, &current_chat_context, overrides.as_ref());
+
+    // 加载聊天历史到AI聊天实例
+    if let Err(e) = chat.load_from(&current_chat_context) {
+        println!("无法加载聊天历史: {}", e);
+    }
+
+    // 附加本轮提问的图片（目前仅 Gemini 支持），历史记录中只保留图片数量
+    let images = images.unwrap_or_default();
+    let has_images = !images.is_empty();
+    let message = if images.is_empty() {
+        message
+    } else {
+        let image_count = images.len();
+        let attached = match &mut chat {
+            AIChatType::Gemini(gemini) => gemini.attach_images(images).await,
+            _ => Err("当前模型不支持图片，请切换到 Gemini".to_string()),
+        };
+        if let Err(e) = attached {
+            let error = AiError::new(AiErrorCode::Config, e);
+            emit_stream_error(&window_clone, &error, false, Some(message.as_str()));
+            return attempt.fail(error);
+        }
+        format!("{}\n\n[附带 {} 张图片]", message, image_count)
+    };
+
+    // 创建临时用户消息，用于实时显示
+    let mut cloned_context = current_chat_context.clone();
+    cloned_context.content.push(ChatMessage::new(ChatMessageType::User, message.clone()));
+
+    // 临时显示用户消息
+    let content: &ChatHistory = &ChatHistory::markdown_to_html(&cloned_context);
+    let _ = window_clone.emit("stream-message", content);
+
+    // 显示正在加载；此后无论生成成功、失败还是被取消，结束时都会通知前端重新加载对话以替换占位消息
+    attempt
+        .completion
+        .get_or_insert_with(|| StreamCompletion(window_clone.clone()));
+    cloned_context.content.push(ChatMessage::new(ChatMessageType::Assistant, THINKING_PLACEHOLDER.to_string()));
+
+    let content: &ChatHistory = &ChatHistory::markdown_to_html(&cloned_context);
+    let _ = window_clone.emit("stream-message", content);
+
+    // 推理模型的思考过程通过 stream-reasoning 事件单独发送
+    let reasoning = attach_reasoning_stream(&mut chat, &window_clone, current_chat_id);
+
+    // 回复中工具调用的结果，在回复写入历史记录后追加到对话中
+    let tool_log = ToolMessageLog::default();
+
+    // 显示时用实际的回复替换"正在思考..."消息
+    let mut display_context = cloned_context.clone();
+    display_context.content.pop();
+    let reply = Arc::new(Mutex::new(ReplyStream::new(
+        &window_clone,
+        current_chat_id,
+        display_context,
+        &settings,
+        tool_log.clone(),
+    )));
+    let stall_flush = ReplyStream::spawn_stall_flush(&reply);
+
+    // 创建一个回调函数，用于处理流式响应的每个部分
+    let callback = {
+        let window_clone = window_clone.clone();
+        let reply = Arc::clone(&reply);
+
+        // 流式生成过程中的自动保存状态
+        let autosave_enabled = settings.auto_save;
+        let autosave_chunks = settings.autosave_interval_chunks.max(1);
+        let autosave_interval = std::time::Duration::from_secs(settings.autosave_interval_secs);
+        let mut chunks_since_save = 0u32;
+        let mut last_save = std::time::Instant::now();
+        let user_message = message.clone();
+
+        move |text: String| {
+            let mut reply = reply.lock().unwrap();
+            reply.push(&text);
+
+            // 定期保存未完成的回复，避免生成过程中崩溃导致内容丢失
+            if autosave_enabled {
+                chunks_since_save += 1;
+                if chunks_since_save >= autosave_chunks || last_save.elapsed() >= autosave_interval {
+                    autosave_partial_response(
+                        &window_clone.state::<ChatState>(),
+                        current_chat_id,
+                        &user_message,
+                        reply.text(),
+                    );
+                    chunks_since_save = 0;
+                    last_save = std::time::Instant::now();
+                }
+            }
+        }
+    };
+
+    // Clone message before moving it into the async block
+    let message_for_async = message.clone();
+
+    // 执行流式响应生成
+    let request_start = std::time::Instant::now();
+    let key_value = api_key.key.clone();
+    let result = chat
+        .generate_response_stream(api_key, message_for_async, callback)
+        .await;
+    drop(stall_flush);
+    let usage = chat.take_usage();
+
+    // 将结果映射错误为String以使其可以安全地在线程间传递
+    let response_result = result.map_err(|e| e.to_string());
+    record_api_key_health(&key_value, &response_result);
+    logging::log_request(&logging::RequestLog {
+        backend: &key_type,
+        model: model_name.as_deref(),
+        latency_ms: request_start.elapsed().as_millis(),
+        error: response_result.as_ref().err().map(|e| e.as_str()),
+        message: &message,
+    });
+
+    let mut backend_state = into_backend_state(chat, &key_type, model_name.as_deref());
+    request_params.revert(&mut backend_state);
+    let accumulated = {
+        let mut reply = reply.lock().unwrap();
+        if response_result.is_ok() {
+            reply.finish();
+        }
+        reply.text().to_string()
+    };
+
+    // 处理最终结果
+    match response_result {
+        Ok(final_response) => {
+            // 通知前端实际回答的后端，启用备用后端时可能与选择的不同
+            let _ = window_clone.emit(
+                "stream-backend",
+                serde_json::json!({ "backend": key_type, "model": model_name }),
+            );
+            // 储存到发起请求的对话中（生成期间用户可能已切换对话）
+            let raw_response = distinct_raw_response(accumulated, &final_response);
+            let raw_response = with_reasoning(&reasoning.lock().unwrap(), raw_response, &final_response);
+            match record_chat_turn(&state, current_chat_id, &message, final_response, raw_response, usage, backend_state) {
+                Some(reply_index) => tool_log.record(&state, current_chat_id, reply_index),
+                None => tool_log.discard(),
+            }
+        }
+        Err(e) => {
+            // 尚未输出内容时的基础设施错误交给备用后端重试，模型不存在时改用默认模型重试，
+            // 内容错误（如被安全策略拦截）不重试
+            let error = AiError::classify(e.as_str());
+            let retryable = (attempt.can_fall_back && error.is_infrastructure())
+                || (attempt.can_retry_model && error.is_model_not_found());
+            if retryable && accumulated.is_empty() {
+                tool_log.discard();
+                return Err(error);
+            }
+
+            // 网络错误通常是暂时的，默认不写入历史记录，用户消息恢复到输入框
+            let persisted = settings.persist_errors_in_history || !error.is_network();
+            let error_message = persisted.then(|| format!("{}{}", GENERATION_ERROR_PREFIX, e));
+            tool_log.discard();
+            let display = record_failed_turn(&state, current_chat_id, &current_chat_context, &message, error_message);
+
+            let content: &ChatHistory = &ChatHistory::markdown_to_html(&display);
+            let _ = window_clone.emit("stream-message", content);
+
+            // 网络错误时把消息放入待发送队列，联网后自动重新发送；附带的图片无法保存，仍恢复到输入框。
+            // 重新发送队列中的消息失败时放回队首，保持原来的发送顺序
+            let queued = !persisted && !has_images && {
+                let queued_message = QueuedMessage::new(current_chat_id, &message, &key_type, model_name.as_deref());
+                let result = if attempt.from_outbox {
+                    history_msg::outbox::push_front(queued_message)
+                } else {
+                    history_msg::outbox::enqueue(queued_message)
+                };
+                result.map_err(|e| println!("无法加入待发送队列: {}", e)).is_ok()
+            };
+            if queued {
+                let mut payload = stream_error_payload(&error, false, None);
+                payload["queued"] = serde_json::Value::Bool(true);
+                let _ = window_clone.emit("stream-error", payload);
+            } else {
+                emit_stream_error(&window_clone, &error, persisted, (!persisted).then_some(message.as_str()));
+            }
+            return attempt.fail(error);
+        }
+    }
+
+    // completion 由调用方在所有尝试结束后释放并通知前端流式传输完成
+    Ok(())
+}
+
+/// 根据请求结果更新所用密钥的状态，供设置界面显示
+fn record_api_key_health<T>(key: &str, result: &Result<T, String>) {
+    match result {
+        Ok(_) => aibackend::apikey::record_key_success(key),
+        Err(e) => aibackend::apikey::record_key_failure(key, AiError::classify(e.as_str()).code, e),
+    }
+}
+
+// Create a wrapper trait for ASTNode serialization
+trait ASTSerializer {
+    fn serialize(&self) -> String;
+}
+
+// Implement the trait for ASTNode
+impl ASTSerializer for ASTNode<'_> {
+    fn serialize(&self) -> String {
+        use serde_json::{json, to_string_pretty};
+
+        // 递归函数，将 ASTNode 转换为 serde_json::Value
+        fn node_to_value(node: &ASTNode) -> serde_json::Value {
+            // 构建开始和结束 token 的信息
+            let start_token = node.start_token.map(|t| {
+                json!({
+                    "token" : t.token,
+                    "type":t.token_type._to_string(),
+                    "origin_token":t.origin_token.clone(),
+                    "position" : t.position
+                })
+            });
+
+            let end_token = node.end_token.map(|t| {
+                json!({
+                    "token" : t.token,
+                    "type":t.token_type._to_string(),
+                    "origin_token":t.origin_token.clone(),
+                    "position" : t.position
+                })
+            });
+
+            // 递归处理所有子节点
+            let children = node.children.iter().map(node_to_value).collect::<Vec<_>>();
+
+            // 构建完整的节点 JSON 对象
+            json!({
+                "node_type": format!("{:?}", node.node_type),
+                "start_token": start_token,
+                "end_token": end_token,
+                "children": children
+            })
+        }
+        // 将 ASTNode 转换为 JSON 值，然后格式化为字符串
+        match to_string_pretty(&node_to_value(self)) {
+            Ok(json_str) => json_str,
+            Err(err) => format!("{{\"error\": \"序列化 AST 失败: {}\"}}", err),
+        }
+    }
+}
+
+// AST节点修正器
+struct ASTOptimizer;
+
+impl ASTOptimizer {
+    /// 修正AST节点，优化表达式结构
+    pub fn optimize(node: &mut ASTNode) {
+        while Self::optimize_recursive(node) {}
+    }
+
+    fn optimize_recursive(node: &mut ASTNode) -> bool {
+        // 首先递归处理所有子节点
+        let mut optimized = false;
+        for child in &mut node.children {
+            optimized |= Self::optimize_recursive(child);
+        }
+
+        // 然后处理当前节点的优化
+        match &node.node_type {
+            ASTNodeType::LambdaCall => {
+                optimized |= Self::optimize_lambda_call(node);
+            }
+            ASTNodeType::Expressions => {
+                optimized |= Self::optimize_expressions(node);
+            }
+            _ => {}
+        }
+        return optimized;
+    }
+
+    /// 优化Lambda调用节点
+    /// 处理 (expressions)() -> expressions() 的情况
+    fn optimize_lambda_call(node: &mut ASTNode) -> bool {
+        // 检查第一个子节点是否是表达式
+        if let Some(first_child) = node.children.get(0) {
+            if matches!(first_child.node_type, ASTNodeType::Expressions) {
+                // 如果是表达式，将表达式提升，并将表达式最后一个子节点作为lambda调用的第一个子节点
+                let expressions_node = &first_child.children;
+                let last_child = expressions_node.last().cloned();
+                if last_child.is_none() {
+                    return false;
+                }
+                let last_child = last_child.unwrap();
+                let mut nodes = expressions_node[..expressions_node.len() - 1].to_vec();
+                let mut children = vec![last_child];
+                children.extend_from_slice(&node.children[1..]);
+                let new_children = ASTNode {
+                    node_type: ASTNodeType::LambdaCall,
+                    start_token: node.start_token,
+                    end_token: node.end_token,
+                    children,
+                };
+                nodes.push(new_children);
+
+                let new_node = ASTNode {
+                    node_type: ASTNodeType::Expressions,
+                    start_token: node.start_token,
+                    end_token: node.end_token,
+                    children: nodes,
+                };
+
+                *node = new_node;
+                return true;
+            }
+        }
+        return false;
+    }
+
+    /// 优化表达式节点
+    /// 处理 (expressions); xxx -> expressions; xxx 的情况
+    fn optimize_expressions(node: &mut ASTNode) -> bool {
+        if !matches!(node.node_type, ASTNodeType::Expressions) {
+            return false;
+        }
+
+        let mut optimized = false;
+
+        let mut flattened_children = Vec::new();
+
+        for child in &node.children {
+            if matches!(child.node_type, ASTNodeType::Expressions) {
+                // 如果子节点也是表达式，则展开其子节点
+                flattened_children.extend(child.children.clone());
+                optimized = true;
+            } else {
+                flattened_children.push(child.clone());
+            }
+        }
+
+        node.children = flattened_children;
+        return optimized;
+    }
+}
+
+// ANSI颜色控制字符清理函数
+fn strip_ansi_colors(text: &str) -> String {
+    // 匹配所有ANSI转义序列的正则表达式
+    // 包括：颜色、光标位置、清屏等控制序列
+    let re = regex::Regex::new(r"\x1b\[[0-9;]*[a-zA-Z]").unwrap();
+    re.replace_all(text, "").to_string()
+}
+
+#[tauri::command]
+fn parse_code(code: String) -> Result<String, String> {
+    let tokens = lexer::tokenize(&code);
+    let tokens = lexer::reject_comment(&tokens);
+    let ast = build_ast(&tokens);
+    match ast {
+        Ok(mut ast) => {
+            // 应用AST优化
+            ASTOptimizer::optimize(&mut ast);
+            let serialized_ast = ast.serialize();
+            Ok(serialized_ast)
+        }
+        Err(e) => {
+            let error_msg = e.format(&tokens, code.clone());
+            // 清理错误信息中的ANSI颜色控制字符
+            Err(strip_ansi_colors(&error_msg))
+        }
+    }
+}
+
+// 检查模型生成代码的语法：xlang 使用完整的解析流程，其他语言做括号与引号配对检查
+#[tauri::command]
+fn check_generated_code(code: String, language: String) -> ai_utils::code_check::CodeCheckResult {
+    if language.eq_ignore_ascii_case("xlang") {
+        return match parse_code(code) {
+            Ok(_) => ai_utils::code_check::CodeCheckResult::from_errors(Vec::new()),
+            Err(e) => ai_utils::code_check::CodeCheckResult::from_errors(vec![e]),
+        };
+    }
+    ai_utils::code_check::check_balance(&code, &language)
+}
+
+#[tauri::command]
+async fn regenerate_message(
+    window: Window,
+    message_index: usize,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<(), String> {
+    // 克隆窗口以便在新线程中使用
+    let window_clone = window.clone();
+
+    // 获取当前设置以获取人格配置
+    let current_settings = match setting::setting::load_app_settings("settings.json") {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("无法加载设置，使用默认设置: {}", e);
+            setting::setting::AppSettings::default()
+        }
+    };
+    let model_name = model_name.map(|name| current_settings.resolve_model_alias(&name));
+
+    // 创建一个新线程处理消息重新生成
+    // 获取当前聊天ID
+    let state = window.state::<ChatState>();
+    let current_id = state.current_chat_id(window.label());
+
+    // 从锁定的历史中获取聊天记录的克隆，避免长时间持有锁
+    let chat_clone = {
+        let history = state.history.lock().unwrap();
+        match history.get(&current_id) {
+            Some(chat) => chat.clone(),
+            None => {
+                let _ = window_clone.emit("stream-message", "找不到当前对话");
+                let _ = window_clone.emit("stream-complete", "");
+                return Ok(());
+            }
+        }
+    };
+
+    // 检查消息索引是否有效
+    if message_index >= chat_clone.content.len() {
+        let _ = window_clone.emit("stream-message", "无效的消息索引");
+        let _ = window_clone.emit("stream-complete", "");
+        return Ok(());
+    }
+
+    // 检查是否是助手消息
+    if chat_clone.content[message_index].msgtype != ChatMessageType::Assistant {
+        let _ = window_clone.emit("stream-message", "只能重新生成助手的消息");
+        let _ = window_clone.emit("stream-complete", "");
+        return Ok(());
+    }
+
+    // 获取API密钥，Coze 和离线模拟后端使用内置密钥
+    let api_key = match select_api_key(&key_type) {
+        Ok(key) => key,
+        Err(e) => {
+            let _ = window_clone.emit("stream-message", e);
+            return Ok(());
+        }
+    };
+
+    // 初始化AI聊天实例
+    let mut ai_chat = match create_ai_chat(&key_type, model_name.as_deref()) {
+        Ok(chat) => chat,
+        Err(e) => {
+            let _ = window_clone.emit("stream-message", e);
+            return Ok(());
+        }
+    };
+
+    // 优先恢复对话保存的后端状态，使按对话设置的模型参数得以保留
+    restore_backend_state(&mut ai_chat, &chat_clone, &key_type, model_name.as_deref());
+
+    // 获取融合后的系统提示词（包含人格特质）
+    let merged_system_prompt = match setting::setting::merge_persona_with_system_prompt(&settings_for_chat(&current_settings, &chat_clone)) {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            let error_msg = format!("人格配置错误: {}", e);
+            let _ = window_clone.emit("stream-message", &error_msg);
+            return Ok(());
+        }
+    };
+    let _ = ai_chat.set_system_prompt(merged_system_prompt);
+
+    // 应用 Gemini 安全过滤等级
+    if let AIChatType::Gemini(_) = ai_chat {
+        if let Err(e) = ai_chat.set_parameter("safety_level".to_string(), current_settings.gemini_safety_level.clone()) {
+            println!("无法设置安全等级: {}", e);
+        }
+    }
+    apply_stop_sequences(&mut ai_chat, &current_settings);
+    apply_chat_cot(&mut ai_chat, &chat_clone);
+    let request_params = apply_request_parameters(
+        &mut ai_chat,
+        &settings_for_chat(&current_settings, &chat_clone),
+        &chat_clone,
+        None,
+    );
 
     // 截断聊天历史，只保留到用户的消息（丢弃所有后续内容）
     let mut chat_history: ChatHistory = chat_clone.clone();
@@ -844,234 +1857,949 @@ async fn regenerate_message(
     // 创建用于显示的上下文
     let mut display_context = chat_history.clone();
 
-    // 添加"正在思考..."消息
-    display_context.content.push(ChatMessage {
-        msgtype: ChatMessageType::Assistant,
-        time: chrono::Local::now().format("%H:%M").to_string(),
-        content: "正在思考...".to_string(),
-    });
+    // 添加"正在思考..."消息，结束时由 _completion 通知前端重新加载对话
+    let _completion = StreamCompletion(window_clone.clone());
+    display_context.content.push(ChatMessage::new(ChatMessageType::Assistant, THINKING_PLACEHOLDER.to_string()));
 
     // 显示临时状态
     let display_content = &ChatHistory::markdown_to_html(&display_context);
     let _ = window_clone.emit("stream-message", display_content);
 
-    // 创建一个锁定的变量用于存储累积的响应内容
-    let accumulated_markdown = Arc::new(Mutex::new(String::new()));
+    // 推理模型的思考过程通过 stream-reasoning 事件单独发送
+    let reasoning = attach_reasoning_stream(&mut ai_chat, &window_clone, current_id);
+
+    // 回复中工具调用的结果，在回复写入历史记录后插入到回复之后
+    let tool_log = ToolMessageLog::default();
+    let reply = Arc::new(Mutex::new(ReplyStream::new(
+        &window_clone,
+        current_id,
+        chat_history.clone(),
+        &current_settings,
+        tool_log.clone(),
+    )));
+    let stall_flush = ReplyStream::spawn_stall_flush(&reply);
 
     // 创建一个回调函数，用于处理流式响应的每个部分
     let callback = {
-        let window_clone = window_clone.clone();
-        let mut display_context = chat_history.clone();
-        let accumulated_markdown = Arc::clone(&accumulated_markdown);
-
-        // 添加实际的聊天消息，内容将在回调中更新
-        display_context.content.push(ChatMessage {
-            msgtype: ChatMessageType::Assistant,
-            time: chrono::Local::now().format("%H:%M").to_string(),
-            content: String::new(), // 初始为空，将在回调中更新
-        });
-
-        move |text: String| {
-            // 累积流式响应内容
-            let mut accumulated = accumulated_markdown.lock().unwrap();
-            accumulated.push_str(&text);
-
-            // 更新最后一条消息的内容
-            let last_idx = display_context.content.len() - 1;
-            display_context.content[last_idx].content = accumulated.clone();
-            display_context.title = Some(get_title_from_history(&display_context));
-            // 将内容转换为HTML并立即发送到前端
-            let content = &ChatHistory::markdown_to_html(&display_context);
-            println!("Sending stream message: {}", text.clone());
-            let _ = window_clone.emit("stream-message", content);
-        }
+        let reply = Arc::clone(&reply);
+        move |text: String| reply.lock().unwrap().push(&text)
     };
     // 使用regenerate_response_stream方法重新生成响应
+    let request_start = std::time::Instant::now();
+    let key_value = api_key.key.clone();
     let result = ai_chat.regenerate_response_stream(api_key, callback).await;
+    drop(stall_flush);
+    let usage = ai_chat.take_usage();
 
     // 将结果映射错误为String以使其可以安全地在线程间传递
     let response_result = result.map_err(|e| e.to_string());
-    // 完成后，获取锁并更新实际的历史记录
-    let mut history = CHAT_HISTORY.lock().unwrap();
-    let chat = match history.get_mut(&current_id) {
-        Some(chat) => chat,
-        None => {
-            let _ = window_clone.emit("stream-complete", "");
-            return Ok(()); // 如果此时找不到对话，直接返回
+    record_api_key_health(&key_value, &response_result);
+    logging::log_request(&logging::RequestLog {
+        backend: &key_type,
+        model: model_name.as_deref(),
+        latency_ms: request_start.elapsed().as_millis(),
+        error: response_result.as_ref().err().map(|e| e.as_str()),
+        message: chat_clone.content[..message_index]
+            .iter()
+            .rev()
+            .find(|m| m.msgtype == ChatMessageType::User)
+            .map(|m| m.content.as_str())
+            .unwrap_or(""),
+    });
+    let mut backend_state = into_backend_state(ai_chat, &key_type, model_name.as_deref());
+    request_params.revert(&mut backend_state);
+    // 完成后更新实际的历史记录，如果此时找不到对话，直接返回
+    let failed = response_result.is_err();
+    let accumulated = {
+        let mut reply = reply.lock().unwrap();
+        if !failed {
+            reply.finish();
         }
+        reply.text().to_string()
     };
+    let raw_response = response_result
+        .as_ref()
+        .ok()
+        .and_then(|response| {
+            let raw_response = distinct_raw_response(accumulated, response);
+            with_reasoning(&reasoning.lock().unwrap(), raw_response, response)
+        });
+    let Some(updated_chat) = record_regenerated_reply(&state, current_id, message_index, response_result, raw_response, usage, backend_state) else {
+        tool_log.discard();
+        return Ok(());
+    };
+    if failed {
+        tool_log.discard();
+        // 显示错误消息
+        let display_content = &ChatHistory::markdown_to_html(&updated_chat);
+        let _ = window_clone.emit("stream-message", display_content);
+    } else {
+        // 重新生成的回复位于 message_index，工具结果插入在其后
+        tool_log.record(&state, current_id, message_index);
+    }
 
-    // 截断聊天历史，只保留到用户的消息（丢弃所有后续内容）
-    chat.content.truncate(message_index);
+    Ok(())
+}
 
-    // 处理最终结果
-    match response_result {
-        Ok(final_response) => {
-            // 添加新的助手回复
-            chat.content.push(ChatMessage {
-                msgtype: ChatMessageType::Assistant,
-                time: chrono::Local::now().format("%H:%M").to_string(),
-                content: final_response,
-            });
+// 删除指定的对话
+#[tauri::command]
+fn delete_chat(window: Window, state: State<'_, ChatState>, id: u32) -> Result<(), String> {
+    state.delete_chat(window.label(), id)
+}
 
-            chat.time = chrono::Local::now().format("%H:%M").to_string();
-            // 保存历史记录
-            save_history(&history).unwrap_or_else(|e| {
-                println!("Failed to save history: {}", e);
-            });
+// 重命名对话
+#[tauri::command]
+fn rename_chat(state: State<'_, ChatState>, id: u32, new_title: String) -> Result<(), String> {
+    state.rename_chat(id, new_title)
+}
+
+// 请求模型生成对话标题的提示词
+const CHAT_TITLE_PROMPT: &str = "请为以上对话拟一个不超过 15 个字的中文标题，概括讨论的主题。只输出标题本身，不要添加引号、标点或其他说明。";
+
+// 生成的标题最多保留的字符数
+const MAX_GENERATED_TITLE_CHARS: usize = 30;
+
+/// 根据模型名称判断所属的后端类型
+fn key_type_for_model(model: &str) -> Option<&'static str> {
+    let model = model.trim_start_matches("models/");
+    if model.starts_with("gemini") {
+        Some("Gemini")
+    } else if model.starts_with("deepseek") {
+        Some("DeepSeek")
+    } else {
+        None
+    }
+}
+
+/// 选择生成标题使用的后端：优先使用设置中的标题模型，未设置、无法识别或缺少对应的 API 密钥时使用对话当前的模型
+fn select_title_backend(
+    settings: &setting::setting::AppSettings,
+    key_type: &str,
+    model_name: Option<&str>,
+) -> Result<(aibackend::apikey::ApiKey, AIChatType), String> {
+    if let Some(model) = settings.title_model() {
+        if let Some(title_key_type) = key_type_for_model(&model) {
+            match select_api_key(title_key_type) {
+                Ok(api_key) => return Ok((api_key, create_ai_chat(title_key_type, Some(&model))?)),
+                Err(e) => println!("标题模型 {} 不可用，使用对话当前的模型: {}", model, e),
+            }
         }
-        Err(e) => {
-            // 处理错误情况
-            let error_message = format!("重新生成回复时出错: {}", e);
+    }
+    Ok((select_api_key(key_type)?, create_ai_chat(key_type, model_name)?))
+}
 
-            // 添加错误消息
-            chat.content.push(ChatMessage {
-                msgtype: ChatMessageType::Assistant,
-                time: chrono::Local::now().format("%H:%M").to_string(),
-                content: error_message.clone(),
-            });
+/// 整理模型返回的标题：取第一行，去掉引号、书名号和“标题：”前缀，并限制长度
+fn clean_generated_title(response: &str) -> String {
+    let line = response
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    let line = line
+        .trim_start_matches("标题：")
+        .trim_start_matches("标题:")
+        .trim_matches(|c: char| "\"'“”‘’《》「」*#。".contains(c) || c.is_whitespace())
+        .trim_end_matches('.');
+    line.chars().take(MAX_GENERATED_TITLE_CHARS).collect()
+}
 
-            chat.time = chrono::Local::now().format("%H:%M").to_string();
-            // 显示错误消息
-            let display_context = chat.clone();
-            let display_content = &ChatHistory::markdown_to_html(&display_context);
-            let _ = window_clone.emit("stream-message", display_content);
-            // 保存历史记录
-            save_history(&history).unwrap_or_else(|e| {
-                println!("Failed to save history: {}", e);
-            });
+/// 使用模型为对话生成标题并保存，返回生成的标题
+async fn generate_title_for_chat(
+    state: &ChatState,
+    settings: &setting::setting::AppSettings,
+    chat_id: u32,
+    key_type: &str,
+    model_name: Option<&str>,
+) -> Result<String, String> {
+    let chat_clone = {
+        let history = state.history.lock().unwrap();
+        history
+            .get(&chat_id)
+            .cloned()
+            .ok_or_else(|| format!("对话ID {}不存在", chat_id))?
+    };
+    if chat_clone.content.is_empty() {
+        return Err("对话没有消息，无法生成标题".to_string());
+    }
+
+    let model_name = model_name.map(|name| settings.resolve_model_alias(name));
+    let (api_key, mut ai_chat) = select_title_backend(settings, key_type, model_name.as_deref())?;
+    ai_chat.load_from(&chat_clone).map_err(|e| e.to_string())?;
+
+    let response = ai_chat
+        .generate_response_stream(api_key, CHAT_TITLE_PROMPT.to_string(), |_: String| {})
+        .await
+        .map_err(|e| format!("生成标题失败: {}", e))?;
+    let response = aibackend::template::extract_response(&response).unwrap_or(response);
+    let title = clean_generated_title(&response);
+    if title.is_empty() {
+        return Err("模型没有返回有效的标题".to_string());
+    }
+
+    state.rename_chat(chat_id, title.clone())?;
+    Ok(title)
+}
+
+// 使用模型为对话生成标题并保存，返回生成的标题
+#[tauri::command]
+async fn generate_chat_title(
+    state: State<'_, ChatState>,
+    chat_id: u32,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<String, String> {
+    let settings = setting::setting::get_settings()?;
+    generate_title_for_chat(&state, &settings, chat_id, &key_type, model_name.as_deref()).await
+}
+
+// 使用指定的人格配置回答示例问题，便于在选择人格前比较回答风格，结果不写入历史记录
+#[tauri::command]
+async fn preview_persona_response(
+    persona: setting::setting::PersonaConfig,
+    sample_question: String,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<String, String> {
+    if sample_question.trim().is_empty() {
+        return Err("示例问题不能为空".to_string());
+    }
+
+    let mut settings = setting::setting::get_settings()?;
+    settings.persona_config = persona;
+    let system_prompt = setting::setting::merge_persona_with_system_prompt(&settings)?;
+    let model_name = model_name.map(|name| settings.resolve_model_alias(&name));
+
+    let api_key = select_api_key(&key_type)?;
+    let mut ai_chat = create_ai_chat(&key_type, model_name.as_deref())?;
+    ai_chat.set_system_prompt(system_prompt).map_err(|e| e.to_string())?;
+
+    let _permit = aibackend::concurrency::acquire().await;
+    let response = ai_chat
+        .generate_response_stream(api_key, sample_question, |_: String| {})
+        .await
+        .map_err(|e| format!("生成示例回答失败: {}", e))?;
+    Ok(aibackend::template::extract_response(&response).unwrap_or(response))
+}
+
+// 前缀续写（DeepSeek Beta）：以 prefix 作为回复的开头让模型继续生成，用于约束回复的格式。
+// 对话末尾是助手回复时替换该回复，是用户消息时追加新的回复，返回更新后的消息
+#[tauri::command]
+async fn complete_with_prefix(
+    state: State<'_, ChatState>,
+    chat_id: u32,
+    prefix: String,
+    model_name: Option<String>,
+) -> Result<Vec<ChatMessage>, String> {
+    if prefix.is_empty() {
+        return Err("续写前缀不能为空".to_string());
+    }
+
+    let mut context = {
+        let history = state.history.lock().unwrap();
+        history
+            .get(&chat_id)
+            .cloned()
+            .ok_or_else(|| format!("对话ID {}不存在", chat_id))?
+    };
+    if context.content.last().is_some_and(|m| m.msgtype == ChatMessageType::Assistant) {
+        context.content.pop();
+    }
+    if !context.content.last().is_some_and(|m| m.msgtype == ChatMessageType::User) {
+        return Err("对话末尾没有需要回答的用户消息".to_string());
+    }
+    let message_index = context.content.len();
+
+    let settings = setting::setting::get_settings()?;
+    let model_name = model_name.map(|name| settings.resolve_model_alias(&name));
+    let chat_settings = settings_for_chat(&settings, &context);
+    let system_prompt = merge_persona_with_system_prompt(&chat_settings)?;
+    let api_key = select_api_key("DeepSeek")?;
+
+    let mut ai_chat = create_ai_chat("DeepSeek", model_name.as_deref())?;
+    restore_backend_state(&mut ai_chat, &context, "DeepSeek", model_name.as_deref());
+    let request_params = apply_request_parameters(&mut ai_chat, &chat_settings, &context, None);
+    ai_chat.set_system_prompt(system_prompt).map_err(|e| e.to_string())?;
+    ai_chat.load_from(&context).map_err(|e| format!("无法加载聊天历史: {}", e))?;
+    let AIChatType::DeepSeek(deepseek) = &mut ai_chat else {
+        return Err("前缀续写仅支持 DeepSeek".to_string());
+    };
+
+    let key_value = api_key.key.clone();
+    let result = deepseek
+        .complete_with_prefix(api_key, &prefix)
+        .await
+        .map_err(|e| e.to_string());
+    record_api_key_health(&key_value, &result);
+    let response = result.map_err(|e| format!("前缀续写失败: {}", e))?;
+    let usage = deepseek.take_usage();
+
+    let mut backend_state = into_backend_state(ai_chat, "DeepSeek", model_name.as_deref());
+    request_params.revert(&mut backend_state);
+    let updated = record_regenerated_reply(&state, chat_id, message_index, Ok(response), None, usage, backend_state)
+        .ok_or_else(|| "续写期间对话已被删除".to_string())?;
+    Ok(ChatMessage::markdown_to_html_vec(&updated.content))
+}
+
+// 批量生成标题时两次请求之间的间隔，避免短时间内大量请求触发限流
+const TITLE_REQUEST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+// 为所有对话（或仅为没有手动标题的对话）重新生成标题，通过 title-progress 事件报告进度，返回成功生成的数量
+#[tauri::command]
+async fn regenerate_all_titles(
+    window: Window,
+    state: State<'_, ChatState>,
+    only_untitled: bool,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<usize, String> {
+    let settings = setting::setting::get_settings()?;
+    let mut chat_ids: Vec<u32> = {
+        let history = state.history.lock().unwrap();
+        history
+            .values()
+            .filter(|chat| !chat.content.is_empty())
+            .filter(|chat| !only_untitled || chat.is_untitled())
+            .map(|chat| chat.id)
+            .collect()
+    };
+    chat_ids.sort_unstable();
+
+    let total = chat_ids.len();
+    let mut renamed = 0;
+    for (index, chat_id) in chat_ids.into_iter().enumerate() {
+        if index > 0 {
+            tokio::time::sleep(TITLE_REQUEST_INTERVAL).await;
+        }
+        let permit = aibackend::concurrency::acquire().await;
+        let result =
+            generate_title_for_chat(&state, &settings, chat_id, &key_type, model_name.as_deref())
+                .await;
+        drop(permit);
+        if result.is_ok() {
+            renamed += 1;
+        }
+        let _ = window.emit(
+            "title-progress",
+            serde_json::json!({
+                "chat_id": chat_id,
+                "done": index + 1,
+                "total": total,
+                "title": result.as_ref().ok(),
+                "error": result.as_ref().err(),
+            }),
+        );
+    }
+    Ok(renamed)
+}
+
+// 删除指定对话中的特定消息
+#[tauri::command]
+fn delete_chat_message(state: State<'_, ChatState>, chat_id: u32, message_index: usize) -> Result<Vec<ChatMessage>, String> {
+    let content = state.delete_message(chat_id, message_index)?;
+    // 返回更新后的对话内容
+    Ok(ChatMessage::markdown_to_html_vec(&content))
+}
+
+// 设置消息的笔记，传入空内容时删除笔记
+#[tauri::command]
+fn set_message_note(state: State<'_, ChatState>, chat_id: u32, message_index: usize, note: Option<String>) -> Result<(), String> {
+    state.set_message_note(chat_id, message_index, note)
+}
+
+// 收藏或取消收藏消息，返回切换后的收藏状态
+#[tauri::command]
+fn toggle_message_star(state: State<'_, ChatState>, chat_id: u32, message_index: usize) -> Result<bool, String> {
+    state.toggle_message_star(chat_id, message_index)
+}
+
+// 列出所有对话中收藏的消息
+#[tauri::command]
+fn list_starred_messages(state: State<'_, ChatState>) -> Vec<history_msg::chat_state::StarredMessage> {
+    state.starred_messages()
+}
+
+// 新建对话并立即发送消息，返回新对话的ID；回复通过流式事件发送，一次调用即可从快捷键开始提问
+#[tauri::command]
+async fn quick_ask(
+    window: Window,
+    message: String,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<u32, String> {
+    if message.trim().is_empty() {
+        return Err("消息不能为空".to_string());
+    }
+    let chat_id = create_chat_with_limit(&window, &window.state::<ChatState>())?;
+    tauri::async_runtime::spawn(process_message_stream(
+        window, message, key_type, model_name, None, None,
+    ));
+    Ok(chat_id)
+}
+
+// 最后一条回复是生成失败的错误信息时，移除失败的一轮并重新发送其中的用户消息
+#[tauri::command]
+async fn resend_last_message(
+    window: Window,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<(), String> {
+    let message = {
+        let state = window.state::<ChatState>();
+        let chat_id = state.current_chat_id(window.label());
+        let mut history = state.history.lock().unwrap();
+        let Some(chat) = history.get_mut(&chat_id) else {
+            return Err(format!("对话ID {}不存在", chat_id));
+        };
+        let Some(message) = chat.pop_failed_turn() else {
+            return Err("最后一条回复不是错误信息，无需重新发送".to_string());
+        };
+        save_history(&history)?;
+        message
+    };
+
+    process_message_stream(window, message, key_type, model_name, None, None).await;
+    Ok(())
+}
+
+// 列出等待联网后发送的消息
+#[tauri::command]
+fn list_outbox() -> Result<Vec<QueuedMessage>, String> {
+    history_msg::outbox::list()
+}
+
+// 清空待发送队列，丢弃其中的消息
+#[tauri::command]
+fn clear_outbox() -> Result<usize, String> {
+    Ok(history_msg::outbox::take_all()?.len())
+}
+
+// 按顺序重新发送队列中的消息，返回成功发送的数量
+//
+// 发送期间切换到消息所属的对话，结束后切换回原来的对话；再次遇到网络错误时停止发送，该消息回到队首
+#[tauri::command]
+async fn flush_outbox(window: Window) -> Result<usize, String> {
+    let previous_chat_id = window.state::<ChatState>().current_chat_id(window.label());
+    let result = send_outbox_messages(&window).await;
+    window.state::<ChatState>().set_current_chat_id(window.label(), previous_chat_id);
+    result
+}
+
+async fn send_outbox_messages(window: &Window) -> Result<usize, String> {
+    let mut sent = 0;
+    while let Some(queued) = history_msg::outbox::pop_front()? {
+        {
+            let state = window.state::<ChatState>();
+            if !state.history.lock().unwrap().contains_key(&queued.chat_id) {
+                println!("对话 {} 已删除，丢弃待发送消息", queued.chat_id);
+                continue;
+            }
+            state.set_current_chat_id(window.label(), queued.chat_id);
+        }
+
+        let result = send_message_stream(
+            window.clone(),
+            queued.message,
+            queued.key_type,
+            queued.model_name,
+            None,
+            None,
+            true,
+        )
+        .await;
+        match result {
+            Ok(()) => sent += 1,
+            Err(error) if error.is_network() => break,
+            // 其他错误已记录到对话中，继续发送后面的消息
+            Err(error) => println!("待发送消息发送失败: {}", error.message),
+        }
+    }
+    Ok(sent)
+}
+
+// 撤销指定对话的最后一轮问答
+#[tauri::command]
+fn undo_last_turn(state: State<'_, ChatState>, chat_id: u32) -> Result<Vec<ChatMessage>, String> {
+    let mut history = state.history.lock().unwrap();
+    let Some(chat) = history.get_mut(&chat_id) else {
+        return Err(format!("对话ID {}不存在", chat_id));
+    };
+    if !chat.pop_last_turn() {
+        return Err("没有可以撤销的对话".to_string());
+    }
+    let content = ChatMessage::markdown_to_html_vec(&chat.content);
+
+    save_history(&history)?;
+    Ok(content)
+}
+
+/// 清理对话的结果
+#[derive(Serialize)]
+struct CleanedChat {
+    removed: usize,             // 删除的消息数量
+    content: Vec<ChatMessage>, // 清理后的消息
+}
+
+// 删除对话中内容为空的消息，并合并内容相同的连续助手回复
+#[tauri::command]
+fn clean_chat(state: State<'_, ChatState>, chat_id: u32) -> Result<CleanedChat, String> {
+    let mut history = state.history.lock().unwrap();
+    let Some(chat) = history.get_mut(&chat_id) else {
+        return Err(format!("对话ID {}不存在", chat_id));
+    };
+    let removed = chat.remove_empty_and_duplicate_messages();
+    let content = ChatMessage::markdown_to_html_vec(&chat.content);
+    println!("清理对话 {}，删除了 {} 条消息", chat_id, removed);
+
+    if removed > 0 {
+        save_history(&history)?;
+    }
+    Ok(CleanedChat { removed, content })
+}
+
+// 未设置回答语言时使用的默认语言
+const DEFAULT_OUTPUT_LANGUAGE: &str = "简体中文";
+
+// 识别对话中用户消息的主要语言，返回的名称可直接用作对话的回答语言
+#[tauri::command]
+fn detect_chat_language(state: State<'_, ChatState>, chat_id: u32) -> Result<String, String> {
+    let history = state.history.lock().unwrap();
+    let chat = history
+        .get(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+    chat.dominant_language(None)
+        .map(str::to_string)
+        .ok_or_else(|| "对话内容过少，无法识别语言".to_string())
+}
+
+// 设置对话级别的回答语言，传入空值时恢复使用全局设置
+#[tauri::command]
+fn set_chat_output_language(state: State<'_, ChatState>, chat_id: u32, language: Option<String>) -> Result<(), String> {
+    let mut history = state.history.lock().unwrap();
+    let Some(chat) = history.get_mut(&chat_id) else {
+        return Err(format!("对话ID {}不存在", chat_id));
+    };
+    chat.output_language = language
+        .map(|language| language.trim().to_string())
+        .filter(|language| !language.is_empty());
+    // 用户清除语言后不再自动识别，避免下一条消息又固定为识别出的语言
+    chat.language_locked = true;
+
+    save_history(&history)?;
+    Ok(())
+}
+
+// 切换生成参数预设：指定 chat_id 时仅对该对话生效，否则修改全局设置；名称为空时取消预设
+#[tauri::command]
+fn apply_generation_profile(state: State<'_, ChatState>, name: String, chat_id: Option<u32>) -> Result<(), String> {
+    let mut settings = setting::setting::get_settings()?;
+    let name = name.trim().to_string();
+    if !name.is_empty() && settings.find_generation_profile(&name).is_none() {
+        return Err(format!("生成参数预设 {} 不存在", name));
+    }
+
+    match chat_id {
+        Some(chat_id) => {
+            let mut history = state.history.lock().unwrap();
+            let chat = history
+                .get_mut(&chat_id)
+                .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+            chat.generation_profile = Some(name).filter(|name| !name.is_empty());
+            save_history(&history)
+        }
+        None => {
+            settings.active_generation_profile = name;
+            setting::setting::save_settings(settings)
+        }
+    }
+}
+
+// 开启或关闭指定对话的 COT（思维链）模板，关闭后适合简单的快速问答
+#[tauri::command]
+fn set_chat_cot(state: State<'_, ChatState>, chat_id: u32, enabled: bool) -> Result<(), String> {
+    let mut history = state.history.lock().unwrap();
+    let chat = history
+        .get_mut(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+    chat.disable_cot = !enabled;
+    save_history(&history)
+}
+
+// 获取模型别名（别名 -> 真实模型ID）
+#[tauri::command]
+fn list_model_aliases() -> Result<HashMap<String, String>, String> {
+    Ok(setting::setting::get_settings()?.model_aliases)
+}
+
+// 获取指定消息的原始回复（含思维链等未经提取的内容），没有单独保存时返回消息内容
+#[tauri::command]
+fn get_raw_response(state: State<'_, ChatState>, chat_id: u32, message_index: usize) -> Result<String, String> {
+    let message = message_at(&state, chat_id, message_index)?;
+    Ok(message.raw_content.unwrap_or(message.content))
+}
+
+// 获取指定消息的纯文本内容（去除思维链和 Markdown 格式），用于复制
+#[tauri::command]
+fn get_message_plaintext(state: State<'_, ChatState>, chat_id: u32, message_index: usize) -> Result<String, String> {
+    let message = message_at(&state, chat_id, message_index)?;
+
+    match message.msgtype {
+        ChatMessageType::Assistant => Ok(document_renderer::plaintext::message_to_plaintext(&message.content)),
+        _ => Ok(message.content),
+    }
+}
+
+// 解析指定消息中的 tool_code 代码块，返回识别出的函数名和参数，用于排查排版调用没有正确渲染的原因
+#[tauri::command]
+fn parse_tool_calls_in_message(state: State<'_, ChatState>, chat_id: u32, message_index: usize) -> Result<Vec<aibackend::template::ToolCallInfo>, String> {
+    let message = message_at(&state, chat_id, message_index)?;
+    Ok(aibackend::template::inspect_tool_calls(&message.content))
+}
+
+// 统计指定消息的字数并估算阅读时间（助手消息只统计用户可见的回答部分）
+#[tauri::command]
+fn message_stats(state: State<'_, ChatState>, chat_id: u32, message_index: usize) -> Result<document_renderer::message_stats::MessageStats, String> {
+    let message = message_at(&state, chat_id, message_index)?;
+
+    match message.msgtype {
+        ChatMessageType::Assistant => Ok(document_renderer::message_stats::assistant_message_stats(&message.content)),
+        _ => Ok(document_renderer::message_stats::markdown_message_stats(&message.content)),
+    }
+}
+
+// 比较两段回答（如重新生成前后或不同模型的回答），返回可供前端高亮的差异片段
+#[tauri::command]
+fn diff_responses(text_a: String, text_b: String) -> Vec<document_renderer::text_diff::DiffSpan> {
+    document_renderer::text_diff::diff_texts(&text_a, &text_b)
+}
+
+// 估算对话已占用的上下文与模型上下文长度的比例，接近上限时前端提醒用户
+#[tauri::command]
+fn get_context_usage(state: State<'_, ChatState>, chat_id: u32, model: String) -> Result<aibackend::context_usage::ContextUsage, String> {
+    let model = setting::setting::load_app_settings("settings.json")
+        .map(|settings| settings.resolve_model_alias(&model))
+        .unwrap_or(model);
+    let history = state.history.lock().unwrap();
+    let Some(chat) = history.get(&chat_id) else {
+        return Err(format!("对话ID {}不存在", chat_id));
+    };
+    Ok(aibackend::context_usage::context_usage(chat, &model))
+}
+
+// 按设置中的单价计算对话费用，没有记录用量的回复按字符数估算；优先使用对话保存的后端状态中的模型，否则使用传入的模型
+#[tauri::command]
+fn get_chat_cost(state: State<'_, ChatState>, chat_id: u32, model: Option<String>) -> Result<aibackend::cost::CostBreakdown, String> {
+    let settings = setting::setting::load_app_settings("settings.json").unwrap_or_default();
+    let history = state.history.lock().unwrap();
+    let Some(chat) = history.get(&chat_id) else {
+        return Err(format!("对话ID {}不存在", chat_id));
+    };
+    let model = chat
+        .backend_state
+        .as_ref()
+        .map(|backend_state| backend_state.model.clone())
+        .or_else(|| model.map(|model| settings.resolve_model_alias(&model)))
+        .unwrap_or_default();
+    Ok(aibackend::cost::chat_cost(chat, &model, &settings.model_prices, &settings.price_currency))
+}
+
+// 提取指定消息中的所有代码块（语言和内容），便于前端逐块复制或保存
+#[tauri::command]
+fn extract_code_blocks(state: State<'_, ChatState>, chat_id: u32, message_index: usize) -> Result<Vec<document_renderer::code_blocks::CodeBlock>, String> {
+    let message = message_at(&state, chat_id, message_index)?;
+
+    match message.msgtype {
+        ChatMessageType::Assistant => Ok(document_renderer::code_blocks::extract_message_code_blocks(&message.content)),
+        _ => Ok(document_renderer::code_blocks::extract_code_blocks(&message.content)),
+    }
+}
+
+// 将对话中内嵌的图片（如 Wolfram 绘图结果）导出到指定目录，目录需在允许访问的文件范围内
+#[tauri::command]
+fn export_chat_images(state: State<'_, ChatState>, app_handle: AppHandle, chat_id: u32, dir: String) -> Result<Vec<String>, String> {
+    let dir = std::path::PathBuf::from(dir);
+    if !app_handle.fs_scope().is_allowed(&dir) {
+        return Err(format!("没有访问目录 {:?} 的权限", dir));
+    }
+
+    let chat = chat_by_id(&state, chat_id)?;
+
+    let written = history_msg::export::export_chat_images_to(&chat, &dir)?;
+    println!("对话 {} 的 {} 张图片已导出到: {:?}", chat_id, written.len(), dir);
+    Ok(written)
+}
+
+// 在后端将 Typst 代码编译为 SVG，作为前端 typst.ts 渲染器不可用时（如导出）的备用方案
+#[tauri::command]
+async fn render_typst(code: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || document_renderer::typst_renderer::render_typst_svg(&code))
+        .await
+        .map_err(|e| format!("Typst 渲染任务失败: {}", e))?
+}
+
+// 在后端使用 mermaid-cli 将 Mermaid 图表渲染为 SVG，供导出等无浏览器的场景使用，图表无效时返回错误信息
+#[tauri::command]
+async fn render_mermaid(code: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || document_renderer::mermaid_renderer::render_mermaid_svg(&code))
+        .await
+        .map_err(|e| format!("Mermaid 渲染任务失败: {}", e))?
+}
+
+// 在后端将 LaTeX 公式渲染为 MathML，供导出等无浏览器的场景使用，渲染失败时返回原始代码
+#[tauri::command]
+fn render_katex(code: String) -> String {
+    document_renderer::katex_renderer::render_katex_or_source(&code, true)
+}
+
+// 所有排版工具的名称和说明，用于在设置中选择启用的工具
+#[tauri::command]
+fn list_typeset_tools() -> Vec<(String, String)> {
+    aibackend::template::default_typeset_tools()
+        .into_iter()
+        .map(|tool| (tool.name, tool.description))
+        .collect()
+}
+
+// 将指定对话导出为自包含的静态HTML文件，Typst 和 Mermaid 的渲染在阻塞线程中执行
+#[tauri::command]
+async fn export_chat_html(state: State<'_, ChatState>, chat_id: u32, path: String) -> Result<(), String> {
+    let chat = chat_by_id(&state, chat_id)?;
+
+    let settings = setting::setting::load_app_settings("settings.json").unwrap_or_default();
+    let export_path = path.clone();
+    tokio::task::spawn_blocking(move || {
+        history_msg::export::export_chat_html_to(&chat, settings.assistant_name(), &export_path)
+    })
+    .await
+    .map_err(|e| format!("导出任务失败: {}", e))??;
+    println!("对话 {} 已导出到: {}", chat_id, path);
+    Ok(())
+}
+
+// 将指定对话导出为 Markdown 文件，排版调用和 Wolfram 计算在后端渲染后内嵌，无法渲染时保留原始代码
+#[tauri::command]
+async fn export_chat_markdown(state: State<'_, ChatState>, chat_id: u32, path: String) -> Result<(), String> {
+    use document_renderer::tool_code::ServerTool;
+
+    let chat = chat_by_id(&state, chat_id)?;
+
+    let mut tool_results = std::collections::HashMap::new();
+    for (code, tool) in history_msg::export::collect_server_tool_calls(&chat) {
+        let ServerTool::Wolfram { query, image_only } = tool;
+        let options = ai_utils::wolframalpha::WolframQueryOptions::default();
+        let _permit = aibackend::concurrency::acquire().await;
+        match document_renderer::wolfram::wolfram_alpha_compute(&query, image_only, &options).await {
+            Ok(results) => {
+                let results = document_renderer::wolfram::fallback_if_no_pods(results);
+                tool_results.insert(code, document_renderer::wolfram::format_to_markdown(&results));
+            }
+            Err(e) => println!("导出时 Wolfram 计算失败，保留原始代码: {}", e),
         }
     }
 
-    // 通知前端流式传输完成
-    let _ = window_clone.emit("stream-complete", "");
+    let settings = setting::setting::load_app_settings("settings.json").unwrap_or_default();
+    let export_path = path.clone();
+    tokio::task::spawn_blocking(move || {
+        history_msg::export::export_chat_markdown_to(&chat, settings.assistant_name(), &tool_results, &export_path)
+    })
+    .await
+    .map_err(|e| format!("导出任务失败: {}", e))??;
+    println!("对话 {} 已导出为 Markdown: {}", chat_id, path);
+    Ok(())
+}
+
+// 将指定对话导出为 JSON 文件，可分享给他人后通过 open_exported_chat 继续对话
+#[tauri::command]
+fn export_chat_json(state: State<'_, ChatState>, chat_id: u32, path: String) -> Result<(), String> {
+    let chat = chat_by_id(&state, chat_id)?;
+
+    history_msg::export::export_chat_json_to(&chat, &path)?;
+    println!("对话 {} 已导出为 JSON: {}", chat_id, path);
+    Ok(())
+}
+
+// 打开他人分享的导出对话文件，作为新对话加入历史记录并切换到该对话，返回新对话的ID
+#[tauri::command]
+fn open_exported_chat(window: Window, state: State<'_, ChatState>, path: String) -> Result<u32, String> {
+    let (new_id, evicted) = open_exported_chat_file(&state, window.label(), &path, chat_limit())?;
+    notify_evicted_chats(&window, &evicted);
+    println!("已打开导出的对话 {}，新对话ID: {}", path, new_id);
+    Ok(new_id)
+}
+
+/// 读取并校验导出的对话文件，按对话数量上限加入历史记录并设为窗口的当前对话
+fn open_exported_chat_file(
+    state: &ChatState,
+    window: &str,
+    path: &str,
+    limit: ChatLimit,
+) -> Result<(u32, Vec<EvictedChat>), String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("无法读取文件: {}", e))?;
+    let chat = history_msg::export::parse_exported_chat(&contents)?;
+    state.add_imported_chat(window, chat, limit)
+}
+
+// 将指定对话导出为 Jupyter Notebook，助手回答中的代码块成为可运行的代码单元
+#[tauri::command]
+fn export_chat_notebook(state: State<'_, ChatState>, chat_id: u32, path: String) -> Result<(), String> {
+    let chat = chat_by_id(&state, chat_id)?;
 
+    let settings = setting::setting::load_app_settings("settings.json").unwrap_or_default();
+    history_msg::notebook::export_chat_notebook_to(&chat, settings.assistant_name(), &path)?;
+    println!("对话 {} 已导出为 Notebook: {}", chat_id, path);
     Ok(())
 }
 
-// 删除指定的对话
+// 压缩历史记录文件：删除冗余字段后按对话ID排序重新写入，返回文件大小的变化
 #[tauri::command]
-fn delete_chat(id: u32) -> Result<(), String> {
-    let mut history = CHAT_HISTORY.lock().unwrap();
-
-    // 检查对话是否存在
-    if !history.contains_key(&id) {
-        return Err(format!("对话ID {}不存在", id));
-    }
+fn compact_history(state: State<'_, ChatState>) -> Result<history_msg::history::CompactReport, String> {
+    let mut history = state.history.lock().unwrap();
+    let report = history_msg::history::compact_history(&mut history)?;
+    println!(
+        "历史记录压缩完成：{} 个对话，删除 {} 个冗余字段，文件大小 {} -> {} 字节",
+        report.chats, report.removed_fields, report.size_before, report.size_after
+    );
+    Ok(report)
+}
 
-    // 如果删除的是当前活跃对话，则将当前对话ID设为另一个值
-    let mut current_id = CURRENT_CHAT_ID.lock().unwrap();
-    if *current_id == id {
-        // 寻找另一个可用的ID，优先选择最新的对话
-        if let Some(&new_id) = history.keys().filter(|&&k| k != id).max() {
-            *current_id = new_id;
-        } else {
-            // 如果没有其他对话，创建一个新的空对话
-            let mut next_id = NEXT_CHAT_ID.lock().unwrap();
-            *current_id = *next_id;
-            *next_id += 1;
-
-            // 创建新对话
-            let now = chrono::Local::now();
-            let today = now.format("%H:%M").to_string();
-            history.insert(
-                *current_id,
-                ChatHistory {
-                    id: *current_id,
-                    title: None, // deprecated
-                    time: today.clone(),
-                    content: vec![],
-                },
-            );
+// 修复损坏的历史记录文件：备份原文件，恢复能够解析的对话并重新加载
+#[tauri::command]
+fn repair_history(
+    state: State<'_, ChatState>,
+) -> Result<history_msg::history::RepairReport, String> {
+    let (mut recovered, report) = history_msg::history::repair_history()?;
+
+    // 保留内存中文件里没有的对话，例如加载失败后新建的对话
+    let current = state.history.lock().unwrap().clone();
+    let mut kept = 0;
+    for (id, chat) in current {
+        if let std::collections::hash_map::Entry::Vacant(entry) = recovered.entry(id) {
+            entry.insert(chat);
+            kept += 1;
         }
     }
+    state.load(recovered);
+    if kept > 0 {
+        save_history(&state.history.lock().unwrap())?;
+    }
 
-    // 删除对话
-    history.remove(&id);
+    println!(
+        "历史记录修复完成：恢复 {} 个对话，丢弃 {} 个，原文件备份于 {}",
+        report.recovered, report.lost, report.backup_path
+    );
+    Ok(report)
+}
 
-    // 保存更新后的历史记录
-    save_history(&history).map_err(|e| e.to_string())?;
+// 按顺序返回对话的回放步骤，便于逐条回顾过去的辅导过程
+#[tauri::command]
+fn get_chat_replay(state: State<'_, ChatState>, chat_id: u32) -> Result<Vec<history_msg::replay::ReplayStep>, String> {
+    let chat = chat_by_id(&state, chat_id)?;
 
-    Ok(())
+    let full_content = setting::setting::load_app_settings("settings.json")
+        .map(|settings| settings.replay_full_content)
+        .unwrap_or(false);
+    Ok(history_msg::replay::build_replay(&chat, full_content))
 }
 
-// 重命名对话
+// 获取日志文件路径，便于用户在反馈问题时附上日志
 #[tauri::command]
-fn rename_chat(id: u32, new_title: String) -> Result<(), String> {
-    let mut history = CHAT_HISTORY.lock().unwrap();
+fn get_log_path() -> Result<String, String> {
+    logging::get_log_path()
+        .map(|path| path.to_string_lossy().to_string())
+        .ok_or_else(|| "日志尚未初始化".to_string())
+}
 
-    // 检查对话是否存在
-    if let Some(chat) = history.get_mut(&id) {
-        chat.title = Some(new_title);
-        // 保存更新后的历史记录
-        save_history(&history).map_err(|e| e.to_string())?;
-        Ok(())
-    } else {
-        Err(format!("对话ID {}不存在", id))
-    }
+// 获取当前活跃的聊天ID
+#[tauri::command]
+fn get_current_chat_id(window: Window, state: State<'_, ChatState>) -> u32 {
+    state.current_chat_id(window.label())
 }
 
-// 删除指定对话中的特定消息
+// 在新窗口中打开指定对话，各窗口独立记录当前对话，便于并排比较
+// 在 Windows 上同步命令中创建窗口会死锁，因此使用异步命令
 #[tauri::command]
-fn delete_chat_message(chat_id: u32, message_index: usize) -> Result<Vec<ChatMessage>, String> {
-    {
-        let mut history = CHAT_HISTORY.lock().unwrap();
+async fn open_chat_window(app_handle: AppHandle, state: State<'_, ChatState>, chat_id: u32) -> Result<(), String> {
+    if !state.history.lock().unwrap().contains_key(&chat_id) {
+        return Err(format!("对话ID {}不存在", chat_id));
+    }
 
-        // 检查对话是否存在
-        let Some(chat) = history.get_mut(&chat_id) else {
-            return Err(format!("对话ID {}不存在", chat_id));
-        };
-        // 检查消息索引是否有效
-        if message_index >= chat.content.len() {
-            return Err(format!("消息索引 {} 超出范围", message_index));
+    #[cfg(desktop)]
+    {
+        let label = format!("chat-{}", chrono::Local::now().timestamp_millis());
+        state.set_current_chat_id(&label, chat_id);
+        let title = state
+            .history
+            .lock()
+            .unwrap()
+            .get(&chat_id)
+            .map(get_title_from_history)
+            .unwrap_or_default();
+        let result = tauri::WebviewWindowBuilder::new(&app_handle, &label, tauri::WebviewUrl::default())
+            .title(format!("NPULearn - {}", title))
+            .inner_size(800.0, 600.0)
+            .decorations(false)
+            .build();
+        if let Err(e) = result {
+            state.remove_window(&label);
+            return Err(format!("无法创建窗口: {}", e));
         }
-
-        // 删除消息
-        chat.content.remove(message_index);
+        Ok(())
     }
+    #[cfg(mobile)]
     {
-        let history = CHAT_HISTORY.lock().unwrap();
-
-        // 保存更新后的历史记录
-        save_history(&history).map_err(|e| e.to_string())?;
-        let Some(chat) = history.get(&chat_id) else {
-            return Err(format!("对话ID {}不存在", chat_id));
-        };
-
-        // 返回更新后的对话内容
-        Ok(ChatMessage::markdown_to_html_vec(&chat.content))
+        let _ = app_handle;
+        Err("移动端不支持多窗口".to_string())
     }
 }
 
-// 获取当前活跃的聊天ID
-#[tauri::command]
-fn get_current_chat_id() -> u32 {
-    *CURRENT_CHAT_ID.lock().unwrap()
-}
-
 // 检查当前聊天ID是否存在
 #[tauri::command]
-fn check_current_chat_id() -> bool {
-    let current_id = *CURRENT_CHAT_ID.lock().unwrap();
-    let history = CHAT_HISTORY.lock().unwrap();
+fn check_current_chat_id(window: Window, state: State<'_, ChatState>) -> bool {
+    let current_id = state.current_chat_id(window.label());
+    let history = state.history.lock().unwrap();
     history.contains_key(&current_id)
 }
 
+// 按应用主题选择 Wolfram 结果的主题，跟随系统时使用窗口当前的主题
+fn app_wolfram_theme(window: &Window) -> ai_utils::wolframalpha::WolframTheme {
+    use ai_utils::wolframalpha::WolframTheme;
+
+    let theme = setting::setting::get_settings()
+        .map(|settings| settings.theme)
+        .unwrap_or_default();
+    match theme.as_str() {
+        "dark" => WolframTheme::Dark,
+        "light" => WolframTheme::Light,
+        _ => match window.theme() {
+            Ok(tauri::Theme::Dark) => WolframTheme::Dark,
+            _ => WolframTheme::Light,
+        },
+    }
+}
+
 // 添加Wolfram Alpha计算命令
 #[tauri::command]
 async fn wolfram_alpha_compute(
+    window: Window,
     query: String,
     image_only: bool,
     format: Option<String>,
+    options: Option<ai_utils::wolframalpha::WolframQueryOptions>,
 ) -> Result<Vec<document_renderer::wolfram::WolframResult>, String> {
-    // 调用Wolfram Alpha计算函数
-    let results = document_renderer::wolfram::wolfram_alpha_compute(&query, image_only).await?;
+    // 调用Wolfram Alpha计算函数，未指定时使用默认的超时时间和消息数量限制，主题跟随应用设置
+    let mut options = options.unwrap_or_default();
+    let theme = *options
+        .theme
+        .get_or_insert_with(|| app_wolfram_theme(&window));
+    let results =
+        document_renderer::wolfram::wolfram_alpha_compute(&query, image_only, &options).await?;
+    // 没有找到结果时返回提示和相关查询，而不是空结果
+    let results = document_renderer::wolfram::fallback_if_no_pods(results);
 
     // 如果指定了HTML格式，则直接返回HTML字符串
     if let Some(format_type) = format {
         if format_type == "html" {
             // 将结果转换为HTML，然后放入一个包含单个结果的向量中返回
-            let html = document_renderer::wolfram::format_to_html(&results);
+            let html = document_renderer::wolfram::format_to_html_themed(&results, theme);
             return Ok(vec![document_renderer::wolfram::WolframResult {
                 title: Some("HTML结果".to_string()),
                 plaintext: Some(html),
@@ -1100,8 +2828,66 @@ async fn wolfram_alpha_compute(
     Ok(results)
 }
 
+// 查询 Wolfram Alpha 并整理为结构化的数学结果（输入、结果、步骤、图像）
+#[tauri::command]
+async fn wolfram_extract_math(
+    window: Window,
+    query: String,
+    options: Option<ai_utils::wolframalpha::WolframQueryOptions>,
+) -> Result<document_renderer::wolfram::WolframMath, String> {
+    let mut options = options.unwrap_or_default();
+    options
+        .theme
+        .get_or_insert_with(|| app_wolfram_theme(&window));
+    let results =
+        document_renderer::wolfram::wolfram_alpha_compute(&query, false, &options).await?;
+    Ok(document_renderer::wolfram::extract_math(&results))
+}
+
+// 识别图片中的文字，识别过程中通过 ocr-progress 事件发送已识别的内容，完成后返回全文
+#[tauri::command]
+async fn ocr_image_stream(window: Window, file_path: String) -> Result<String, String> {
+    let image_data = std::fs::read(&file_path).map_err(|e| format!("无法读取图片: {}", e))?;
+    let api_key = select_api_key("Gemini")?;
+
+    let callback = {
+        let window = window.clone();
+        let mut accumulated = String::new();
+        move |text: String| {
+            accumulated.push_str(&text);
+            let _ = window.emit("ocr-progress", &accumulated);
+        }
+    };
+
+    let _permit = aibackend::concurrency::acquire().await;
+    aibackend::gemini::image_to_text_stream(&api_key.key, &image_data, callback)
+        .await
+        .map_err(|e| format!("图片识别失败: {}", e))
+}
+
+// 按分类列出可以上传的文件类型及其扩展名
+#[tauri::command]
+fn supported_document_types() -> Vec<(String, Vec<String>)> {
+    document_reader::supported_document_types()
+}
+
+// 读取多篇文档并合并为一条带目录的消息，用于一次性比较或总结多篇论文
+#[tauri::command]
+async fn read_documents_combined(app_handle: AppHandle, paths: Vec<String>) -> Result<String, String> {
+    document_reader::read_documents_combined(&paths, |path| {
+        let app_handle = app_handle.clone();
+        async move { process_file(&app_handle, &path).await }
+    })
+    .await
+}
+
+// 上传文件并添加到当前对话，开启自动总结时返回 true，表示已开始生成总结
 #[tauri::command]
-async fn upload_file_from_local(window: Window) -> Result<(), String> {
+async fn upload_file_from_local(
+    window: Window,
+    key_type: Option<String>,
+    model_name: Option<String>,
+) -> Result<bool, String> {
     // 获取应用句柄
     let app_handle = window.app_handle();
 
@@ -1113,8 +2899,14 @@ async fn upload_file_from_local(window: Window) -> Result<(), String> {
                 // Pass app_handle
                 Ok(file_content) => {
                     // 将文件内容作为用户消息添加到当前对话
-                    add_file_content_as_message(window.clone(), file_content, file_path).await?;
-                    Ok(())
+                    add_file_content_as_message(
+                        window.clone(),
+                        file_content,
+                        file_path,
+                        key_type,
+                        model_name,
+                    )
+                    .await
                 }
                 Err(e) => Err(format!("处理文件失败: {}", e)),
             }
@@ -1125,149 +2917,59 @@ async fn upload_file_from_local(window: Window) -> Result<(), String> {
         }
     }
 }
-async fn select_file(app_handle: &AppHandle) -> Result<String, String> {
+/// 按文档读取器支持的类型创建带过滤器的文件选择对话框
+fn file_dialog_with_filters(
+    app_handle: &AppHandle,
+) -> tauri_plugin_dialog::FileDialogBuilder<tauri::Wry> {
     use tauri_plugin_dialog::DialogExt;
+
+    let types = document_reader::supported_document_types();
+    let all_extensions: Vec<&str> = types
+        .iter()
+        .flat_map(|(_, extensions)| extensions.iter().map(String::as_str))
+        .collect();
+
+    let mut dialog = app_handle
+        .dialog()
+        .file()
+        .add_filter("所有支持的文件", &all_extensions);
+    for (category, extensions) in &types {
+        let extensions: Vec<&str> = extensions.iter().map(String::as_str).collect();
+        dialog = dialog.add_filter(category, &extensions);
+    }
+    dialog.add_filter("所有文件", &["*"])
+}
+
+async fn select_file(app_handle: &AppHandle) -> Result<String, String> {
     use tokio::sync::oneshot;
 
     let (sender, receiver) = oneshot::channel();
     // 在Android上使用不同的文件选择策略
     #[cfg(target_os = "android")]
     {
-        app_handle
-            .dialog()
-            .file()
-            .add_filter(
-                "文档文件",
-                &["txt", "md", "markdown", "doc", "docx", "rtf", "pdf"],
-            )
-            .add_filter(
-                "编程文件",
-                &[
-                    "rs", "py", "js", "ts", "java", "c", "cpp", "go", "php", "rb",
-                ],
-            )
-            .add_filter(
-                "配置文件",
-                &["json", "xml", "yaml", "yml", "toml", "cfg", "conf", "ini"],
-            )
-            .add_filter("所有文件", &["*"])
-            .pick_file(move |file_path_option| {
-                let result = match file_path_option {
-                    Some(path_buf) => {
-                        // path_buf is PathBuf, convert to string
-                        let path_str = path_buf.to_string();
-                        println!("Selected URI/path on Android: {}", path_str);
-                        Ok(path_str)
-                    }
-                    None => Err("用户取消了文件选择".to_string()),
-                };
-                let _ = sender.send(result);
-            });
+        file_dialog_with_filters(app_handle).pick_file(move |file_path_option| {
+            let result = match file_path_option {
+                Some(path_buf) => {
+                    // path_buf is PathBuf, convert to string
+                    let path_str = path_buf.to_string();
+                    println!("Selected URI/path on Android: {}", path_str);
+                    Ok(path_str)
+                }
+                None => Err("用户取消了文件选择".to_string()),
+            };
+            let _ = sender.send(result);
+        });
     }
 
     #[cfg(not(target_os = "android"))]
     {
-        app_handle
-            .dialog()
-            .file()
-            .add_filter(
-                "所有支持的文件",
-                &[
-                    // 文档类型
-                    "txt",
-                    "md",
-                    "markdown",
-                    "doc",
-                    "docx",
-                    "rtf",
-                    "pdf",
-                    // 编程语言
-                    "rs",
-                    "py",
-                    "js",
-                    "ts",
-                    "jsx",
-                    "tsx",
-                    "java",
-                    "c",
-                    "cpp",
-                    "h",
-                    "hpp",
-                    "cs",
-                    "go",
-                    "php",
-                    "rb",
-                    "swift",
-                    "kt",
-                    "scala",
-                    "dart",
-                    "lua",
-                    "r",
-                    "perl",
-                    "pl",
-                    "sql",
-                    "sh",
-                    "bash",
-                    "zsh",
-                    "ps1",
-                    "psm1",
-                    "bat",
-                    "cmd",
-                    // 配置和数据文件
-                    "json",
-                    "xml",
-                    "html",
-                    "htm",
-                    "css",
-                    "scss",
-                    "sass",
-                    "less",
-                    "yaml",
-                    "yml",
-                    "toml",
-                    "cfg",
-                    "conf",
-                    "ini",
-                    "env",
-                    "log",
-                    "csv",
-                    "tsv",
-                    "properties",
-                    // 其他
-                    "vue",
-                    "svelte",
-                    "makefile",
-                    "dockerfile",
-                    "gitignore",
-                    "gitattributes",
-                    "diff",
-                    "patch",
-                    "vbs",
-                    "wsf",
-                ],
-            )
-            .add_filter(
-                "文档文件",
-                &["txt", "md", "markdown", "doc", "docx", "rtf", "pdf"],
-            )
-            .add_filter(
-                "编程文件",
-                &[
-                    "rs", "py", "js", "ts", "java", "c", "cpp", "go", "php", "rb",
-                ],
-            )
-            .add_filter(
-                "配置文件",
-                &["json", "xml", "yaml", "yml", "toml", "cfg", "conf", "ini"],
-            )
-            .add_filter("所有文件", &["*"])
-            .pick_file(move |file_path| {
-                let result = match file_path {
-                    Some(path) => Ok(path.to_string()),
-                    None => Err("用户取消了文件选择".to_string()),
-                };
-                let _ = sender.send(result);
-            });
+        file_dialog_with_filters(app_handle).pick_file(move |file_path| {
+            let result = match file_path {
+                Some(path) => Ok(path.to_string()),
+                None => Err("用户取消了文件选择".to_string()),
+            };
+            let _ = sender.send(result);
+        });
     }
 
     // 等待用户选择文件
@@ -1286,7 +2988,10 @@ async fn process_file(app_handle: &AppHandle, file_path_or_uri: &str) -> Result<
     {
         let local_path =
             android_file_utils::resolve_uri_to_local_path(app_handle, file_path_or_uri).await?;
-        document_reader::read_document(&local_path).await
+        let result = document_reader::read_document(&local_path).await;
+        // content URI 复制到缓存目录的临时文件读取后即删除，重新读取时会再次复制
+        android_file_utils::remove_local_copy(file_path_or_uri, &local_path);
+        result
     }
     #[cfg(not(target_os = "android"))]
     {
@@ -1298,55 +3003,32 @@ async fn process_file(app_handle: &AppHandle, file_path_or_uri: &str) -> Result<
 async fn add_file_content_as_message(
     window: Window,
     content: String,
-    _file_path: String,
-) -> Result<(), String> {
-    // 检查当前是否有选择的对话，如果没有则创建新对话
-    let current_id = {
-        let current_id = *CURRENT_CHAT_ID.lock().unwrap();
-        if current_id == 0 {
-            // 创建新对话
-            let mut next_id = NEXT_CHAT_ID.lock().unwrap();
-            let new_id = *next_id;
-            *next_id += 1;
-
-            // 更新当前对话ID
-            let mut current_id_guard = CURRENT_CHAT_ID.lock().unwrap();
-            *current_id_guard = new_id;
-
-            // 创建新对话
-            let now = chrono::Local::now();
-            let today = now.format("%H:%M").to_string();
-            let new_chat = ChatHistory {
-                id: new_id,
-                title: None,
-                time: today.clone(),
-                content: vec![],
-            };
-
-            // 添加到历史记录
-            let mut history = CHAT_HISTORY.lock().unwrap();
-            history.insert(new_id, new_chat);
-            save_history(&history).map_err(|e| e.to_string())?;
-
-            new_id
-        } else {
-            current_id
-        }
+    file_path: String,
+    key_type: Option<String>,
+    model_name: Option<String>,
+) -> Result<bool, String> {
+    let state = window.state::<ChatState>();
+    // 当前对话不存在（例如已被删除）时创建新对话，并受 max_chats 限制
+    let current_id = state.current_chat_id(window.label());
+    let current_exists = state.history.lock().unwrap().contains_key(&current_id);
+    let current_id = if current_exists {
+        current_id
+    } else {
+        create_chat_with_limit(&window, &state)?
     };
 
     // 添加用户消息到当前对话
     {
-        let mut history = CHAT_HISTORY.lock().unwrap();
+        let mut history = state.history.lock().unwrap();
         if let Some(chat) = history.get_mut(&current_id) {
             // 添加用户消息
             chat.content.push(ChatMessage {
-                msgtype: ChatMessageType::User,
-                time: chrono::Local::now().format("%H:%M").to_string(),
-                content,
+                source_path: Some(file_path.clone()),
+                ..ChatMessage::new(ChatMessageType::User, content)
             });
 
             // 更新对话时间
-            chat.time = chrono::Local::now().format("%H:%M").to_string();
+            chat.touch();
 
             // 保存历史记录
             save_history(&history).map_err(|e| e.to_string())?;
@@ -1357,7 +3039,7 @@ async fn add_file_content_as_message(
 
     // 通知前端更新聊天内容
     let current_chat = {
-        let history = CHAT_HISTORY.lock().unwrap();
+        let history = state.history.lock().unwrap();
         history.get(&current_id).cloned()
     };
 
@@ -1367,13 +3049,216 @@ async fn add_file_content_as_message(
         let _ = window.emit("stream-complete", "");
     }
 
-    Ok(())
+    // 开启自动总结时立即请求模型总结上传的文件，生成过程与普通消息相同
+    let settings = setting::setting::get_settings()?;
+    let Some(key_type) = key_type.filter(|_| settings.auto_summarize) else {
+        return Ok(false);
+    };
+    let file_name = std::path::Path::new(&file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(file_path);
+    let prompt = settings.auto_summarize_prompt(&file_name);
+    tauri::async_runtime::spawn(process_message_stream(
+        window, prompt, key_type, model_name, None, None,
+    ));
+    Ok(true)
+}
+
+// 重新读取上传文件生成的消息，用文件的最新内容替换消息内容
+#[tauri::command]
+async fn refresh_uploaded_file(
+    app_handle: AppHandle,
+    state: State<'_, ChatState>,
+    chat_id: u32,
+    message_index: usize,
+) -> Result<Vec<ChatMessage>, String> {
+    let source_path = message_at(&state, chat_id, message_index)?
+        .source_path
+        .ok_or_else(|| "该消息不是由上传文件生成的".to_string())?;
+
+    // 读取文件期间不持有历史记录锁
+    let content = process_file(&app_handle, &source_path)
+        .await
+        .map_err(|e| format!("重新读取文件失败: {}", e))?;
+
+    let mut history = state.history.lock().unwrap();
+    let message = history
+        .get_mut(&chat_id)
+        .and_then(|chat| chat.content.get_mut(message_index))
+        .filter(|message| message.source_path.as_deref() == Some(source_path.as_str()))
+        .ok_or_else(|| "读取文件期间消息已被修改".to_string())?;
+    message.content = content;
+    message.time = history_msg::timestamp::now();
+    let content = ChatMessage::markdown_to_html_vec(&history[&chat_id].content);
+
+    save_history(&history)?;
+    Ok(content)
+}
+
+// 对话摘要消息的前缀
+const CONTEXT_SUMMARY_HEADER: &str = "📝 早期对话摘要：";
+
+// 请求模型生成摘要的提示词
+const CONTEXT_SUMMARY_PROMPT: &str = "请用简洁的中文总结以上对话的要点，包括用户的问题、已经得出的结论以及尚未解决的事项。摘要将替代这些对话作为后续交流的上下文，请保留关键的公式、代码和数据。";
+
+// 将较早的对话压缩为一条摘要，仅保留最近 keep_last_n 条消息，原始消息存档以便撤销
+#[tauri::command]
+async fn summarize_old_context(
+    state: State<'_, ChatState>,
+    chat_id: u32,
+    keep_last_n: usize,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<Vec<ChatMessage>, String> {
+    let chat_clone = {
+        let history = state.history.lock().unwrap();
+        history
+            .get(&chat_id)
+            .cloned()
+            .ok_or_else(|| format!("对话ID {}不存在", chat_id))?
+    };
+
+    if chat_clone.content.len() <= keep_last_n {
+        return Err("对话消息数量不足，无需压缩".to_string());
+    }
+    let split = chat_clone.content.len() - keep_last_n;
+    let old_messages = chat_clone.content[..split].to_vec();
+
+    // 使用待压缩的消息作为上下文请求摘要
+    let api_key = select_api_key(&key_type)?;
+    let mut ai_chat = create_ai_chat(&key_type, model_name.as_deref())?;
+    let mut summary_context = chat_clone.clone();
+    summary_context.content = old_messages.clone();
+    ai_chat.load_from(&summary_context).map_err(|e| e.to_string())?;
+
+    let response = ai_chat
+        .generate_response_stream(api_key, CONTEXT_SUMMARY_PROMPT.to_string(), |_: String| {})
+        .await
+        .map_err(|e| format!("生成摘要失败: {}", e))?;
+    let summary = aibackend::template::extract_response(&response).unwrap_or(response);
+    let summary_content = format!("{}\n{}", CONTEXT_SUMMARY_HEADER, summary.trim());
+
+    let mut history = state.history.lock().unwrap();
+    let chat = history
+        .get_mut(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+
+    // 生成摘要期间对话可能被修改，确认待压缩的消息未发生变化
+    let unchanged = chat.content.len() >= split
+        && chat.content[..split]
+            .iter()
+            .zip(old_messages.iter())
+            .all(|(a, b)| a.content == b.content);
+    if !unchanged {
+        return Err("对话在生成摘要期间被修改，请重试".to_string());
+    }
+
+    // 标题可能来自被压缩的消息，先固定下来
+    if chat.title.is_none() {
+        chat.title = Some(get_title_from_history(chat));
+    }
+
+    let archived: Vec<ChatMessage> = chat.content.drain(..split).collect();
+    chat.content.insert(
+        0,
+        ChatMessage::new(ChatMessageType::System, summary_content.clone()),
+    );
+    chat.context_archive.push(history_msg::history::ContextArchive {
+        summary: summary_content,
+        messages: archived,
+    });
+    let content = ChatMessage::markdown_to_html_vec(&chat.content);
+
+    save_history(&history)?;
+    Ok(content)
+}
+
+// 撤销最近一次对话压缩，用存档的原始消息替换摘要
+#[tauri::command]
+fn restore_summarized_context(state: State<'_, ChatState>, chat_id: u32) -> Result<Vec<ChatMessage>, String> {
+    let mut history = state.history.lock().unwrap();
+    let chat = history
+        .get_mut(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+
+    let Some(archive) = chat.context_archive.last() else {
+        return Err("该对话没有可撤销的压缩记录".to_string());
+    };
+    let summary_matches = chat
+        .content
+        .first()
+        .map(|m| m.msgtype == ChatMessageType::System && m.content == archive.summary)
+        .unwrap_or(false);
+    if !summary_matches {
+        return Err("对话摘要已被修改或删除，无法撤销压缩".to_string());
+    }
+
+    let archive = chat.context_archive.pop().unwrap();
+    chat.content.splice(0..1, archive.messages);
+    let content = ChatMessage::markdown_to_html_vec(&chat.content);
+
+    save_history(&history)?;
+    Ok(content)
+}
+
+// 为指定对话设置模型参数（如 temperature、top_k），参数随对话保存并在后续请求中生效，优先于生成参数预设
+#[tauri::command]
+fn set_chat_parameter(
+    state: State<'_, ChatState>,
+    chat_id: u32,
+    key_type: String,
+    model_name: Option<String>,
+    key: String,
+    value: String,
+) -> Result<(), String> {
+    let mut history = state.history.lock().unwrap();
+    let chat_history = history
+        .get_mut(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+
+    // 先检查当前后端是否接受该参数
+    let mut chat = create_ai_chat(&key_type, model_name.as_deref())?;
+    chat.set_parameter(key.clone(), value.clone()).map_err(|e| e.to_string())?;
+    chat_history.chat_parameters.insert(key, value);
+
+    save_history(&history)
+}
+
+// 查看对话下一次发送时实际使用的模型、生成参数和系统提示词来源
+#[tauri::command]
+fn get_effective_params(
+    state: State<'_, ChatState>,
+    chat_id: u32,
+    key_type: String,
+    model_name: Option<String>,
+) -> Result<EffectiveParams, String> {
+    let settings = setting::setting::load_app_settings("settings.json").unwrap_or_default();
+    let history = state.history.lock().unwrap();
+    let chat_history = history
+        .get(&chat_id)
+        .ok_or_else(|| format!("对话ID {}不存在", chat_id))?;
+    effective_params(&settings, chat_history, &key_type, model_name.as_deref())
 }
 
 // 添加获取Gemini模型列表的命令
 #[tauri::command]
 async fn get_gemini_models(key_type: String) -> Result<Vec<String>, String> {
-    println!("🔍 [DEBUG] get_gemini_models called with key_type: {}", key_type);
+    load_gemini_models(key_type, false).await
+}
+
+// 强制重新获取模型列表，忽略缓存
+#[tauri::command]
+async fn refresh_models(key_type: String) -> Result<Vec<String>, String> {
+    match key_type.as_str() {
+        "Gemini" => load_gemini_models(key_type, true).await,
+        "DeepSeek" => get_deepseek_models(key_type).await,
+        _ => Err(format!("不支持刷新模型列表的类型: {}", key_type)),
+    }
+}
+
+async fn load_gemini_models(key_type: String, force_refresh: bool) -> Result<Vec<String>, String> {
+    println!("🔍 [DEBUG] load_gemini_models called with key_type: {}, force_refresh: {}", key_type, force_refresh);
     
     if key_type != "Gemini" {
         println!("❌ [DEBUG] Unsupported key_type: {}", key_type);
@@ -1393,9 +3278,9 @@ async fn get_gemini_models(key_type: String) -> Result<Vec<String>, String> {
     
     // Use the first available API key
     let api_key = &gemini_keys.keys[0];
-    println!("🔑 [DEBUG] Using API key: {}...", &api_key.key[..std::cmp::min(10, api_key.key.len())]);
+    println!("🔑 [DEBUG] Using API key: {}", logging::redact::mask_api_key(&api_key.key));
     
-    match aibackend::gemini::fetch_available_models(&api_key.key).await {
+    match aibackend::gemini::fetch_available_models_cached(&api_key.key, force_refresh).await {
         Ok(models) => {
             println!("✅ [DEBUG] Successfully fetched model list, count: {}", models.len());
             println!("📋 [DEBUG] Model list: {:?}", models);
@@ -1433,6 +3318,50 @@ async fn get_gemini_models(key_type: String) -> Result<Vec<String>, String> {
     }
 }
 
+// 添加获取DeepSeek模型列表的命令
+#[tauri::command]
+async fn get_deepseek_models(key_type: String) -> Result<Vec<String>, String> {
+    println!("🔍 [DEBUG] get_deepseek_models called with key_type: {}", key_type);
+
+    if key_type != "DeepSeek" {
+        println!("❌ [DEBUG] Unsupported key_type: {}", key_type);
+        return Err("Only DeepSeek model fetching is supported".to_string());
+    }
+
+    // 接口不可用时的静态模型列表
+    let default_models = vec!["deepseek-chat".to_string(), "deepseek-reasoner".to_string()];
+
+    // Get API keys
+    let api_key_list = aibackend::apikey::get_api_key_list_or_create("api_keys.json");
+    let deepseek_keys = api_key_list.filter_by_type(aibackend::apikey::ApiKeyType::DeepSeek);
+
+    println!("🔑 [DEBUG] Found {} DeepSeek API keys", deepseek_keys.keys.len());
+
+    if deepseek_keys.keys.is_empty() {
+        println!("⚠️ [DEBUG] No DeepSeek API keys found, using default list");
+        return Ok(default_models);
+    }
+
+    // Use the first available API key
+    let api_key = &deepseek_keys.keys[0];
+
+    match aibackend::deepseek::fetch_available_models(&api_key.key).await {
+        Ok(models) if !models.is_empty() => {
+            println!("🚀 [DEBUG] Returning dynamically fetched model list: {:?}", models);
+            Ok(models)
+        }
+        Ok(_) => {
+            println!("⚠️ [DEBUG] API returned empty model list, using default list");
+            Ok(default_models)
+        }
+        Err(e) => {
+            println!("❌ [DEBUG] Failed to fetch DeepSeek model list: {}", e);
+            println!("📋 [DEBUG] Returning fallback model list: {:?}", default_models);
+            Ok(default_models)
+        }
+    }
+}
+
 // 移除测试模块
 // mod test_coze;
 
@@ -1447,31 +3376,104 @@ pub fn run() {
         .plugin(tauri_plugin_opener::init())
         .invoke_handler(tauri::generate_handler![
             get_chat_html,
+            rerender_chat,
             get_chat_history_items,
+            get_chat_index,
             select_chat_by_id,
             get_current_chat_id,
+            open_chat_window,
+            cleanup_old_chats,
+            take_startup_cleanup_count,
+            set_chat_pinned,
+            reorder_chats,
+            duplicate_chat,
+            set_chat_cot,
+            list_model_aliases,
+            get_log_path,
             create_new_chat,
             process_message_stream,
             regenerate_message,
             parse_code,
+            check_generated_code,
             delete_chat,
             rename_chat,
+            generate_chat_title,
+            preview_persona_response,
+            regenerate_all_titles,
             delete_chat_message,
+            set_message_note,
+            toggle_message_star,
+            list_starred_messages,
             check_current_chat_id,
             upload_file_from_local, // 添加文件上传命令
+            supported_document_types,
+            read_documents_combined,
             aibackend::apikey::get_api_key_list_or_create,
             aibackend::apikey::try_save_api_key_list,
+            aibackend::apikey::list_api_keys_status,
             setting::setting::get_settings,
             setting::setting::save_settings,
             setting::setting::get_default_settings,
+            setting::setting::export_settings,
+            setting::setting::import_settings,
+            list_typeset_tools,
             setting::setting::get_persona_prompt,
             setting::setting::select_save_directory,
             wolfram_alpha_compute, // 添加新的Wolfram Alpha计算命令
+            wolfram_extract_math,
+            ocr_image_stream,
+            refresh_uploaded_file,
             get_gemini_models, // 添加获取Gemini模型列表的命令
+            get_deepseek_models, // 添加获取DeepSeek模型列表的命令
+            refresh_models,
+            export_chat_html,
+            export_chat_notebook,
+            export_chat_markdown,
+            export_chat_json,
+            open_exported_chat,
+            repair_history,
+            compact_history,
+            get_chat_replay,
+            export_chat_images,
+            render_typst,
+            render_mermaid,
+            render_katex,
+            set_chat_parameter,
+            get_effective_params,
+            set_chat_output_language,
+            detect_chat_language,
+            clean_chat,
+            complete_with_prefix,
+            apply_generation_profile,
+            get_raw_response,
+            get_message_plaintext,
+            extract_code_blocks,
+            message_stats,
+            parse_tool_calls_in_message,
+            diff_responses,
+            get_context_usage,
+            get_chat_cost,
+            list_incomplete_chats,
+            resolve_incomplete_chat,
+            summarize_old_context,
+            restore_summarized_context,
+            undo_last_turn,
+            resend_last_message,
+            quick_ask,
+            list_outbox,
+            clear_outbox,
+            flush_outbox,
             //new add code
 
         ])
         .plugin(tauri_plugin_fs::init())
+        .manage(ChatState::default())
+        .on_window_event(|window, event| {
+            // 窗口关闭后不再记录其当前对话
+            if let tauri::WindowEvent::Destroyed = event {
+                window.state::<ChatState>().remove_window(window.label());
+            }
+        })
         .setup(|app| {
             // allowed the given directory
             let scope = app.fs_scope();
@@ -1489,6 +3491,13 @@ pub fn run() {
                 eprintln!("Failed to get app_local_data_dir");
             }
 
+            // 初始化文件日志
+            if let Some(app_local_data_dir) = &checked_app_local_data_dir {
+                if let Err(e) = logging::init(app_local_data_dir.join("logs")) {
+                    eprintln!("{}", e);
+                }
+            }
+
             if let Ok(app_config_dir) = path.app_config_dir() {
                 println!("app_config_dir: {:?}", app_config_dir);
                 let result = scope.allow_directory(&app_config_dir, false);
@@ -1508,15 +3517,614 @@ pub fn run() {
             );
 
             setting::setting::init(handle.clone(), checked_app_config_dir.clone().unwrap());
+            // 将保存的设置应用到各模块
+            let mut retention_days = 0;
+            if let Ok(settings) = setting::setting::load_app_settings("settings.json") {
+                setting::setting::apply_runtime_settings(&settings);
+                retention_days = settings.history_retention_days;
+            }
 
             let app_local_data_dir = path.app_local_data_dir()?;
-            history_msg::history::init(handle.clone(), app_local_data_dir.clone());
-            initialize_history();
+            history_msg::history::init(app_local_data_dir.clone());
+            initialize_history(&app.state::<ChatState>(), retention_days);
             Ok(())
         })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 历史记录文件目录是进程级设置，相关测试需要串行执行
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn new_chat_state(name: &str) -> (ChatState, std::sync::MutexGuard<'static, ()>) {
+        let guard = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("npulearn-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        history_msg::history::init(dir);
+        (ChatState::default(), guard)
+    }
+
+    fn mock_chat(response: &str) -> AIChatType {
+        let mut chat = create_ai_chat("Mock", Some("mock")).unwrap();
+        chat.set_parameter("chunk_delay_ms".to_string(), "0".to_string()).unwrap();
+        chat.set_parameter("response".to_string(), response.to_string()).unwrap();
+        chat
+    }
+
+    fn chat_contents(state: &ChatState, chat_id: u32) -> Vec<String> {
+        state.history.lock().unwrap()[&chat_id]
+            .content
+            .iter()
+            .map(|m| m.content.clone())
+            .collect()
+    }
+
+    /// 序列化后的后端状态
+    fn chat_json(chat: &AIChatType) -> serde_json::Value {
+        serde_json::from_str(&chat.serialize()).unwrap()
+    }
+
+    /// 新建指定后端并应用设置，返回应用后的后端状态
+    fn applied_chat_json(backend: &str, apply: impl FnOnce(&mut AIChatType)) -> serde_json::Value {
+        let mut chat = create_ai_chat(backend, None).unwrap();
+        apply(&mut chat);
+        chat_json(&chat)
+    }
+
+    #[test]
+    fn test_chat_lifecycle_with_mock_backend() {
+        let (state, _guard) = new_chat_state("lifecycle");
+
+        // 新建对话
+        assert_eq!(state.create_chat("main").unwrap(), 2);
+        assert_eq!(state.current_chat_id("main"), 2);
+        assert_eq!(*state.next_chat_id.lock().unwrap(), 3);
+
+        // 发送消息
+        let mut chat = mock_chat("第一次回答");
+        let api_key = select_api_key("Mock").unwrap();
+        let response = tauri::async_runtime::block_on(chat.generate_response_stream(
+            api_key.clone(),
+            "问题".to_string(),
+            |_| {},
+        ))
+        .unwrap();
+        let backend_state = into_backend_state(chat, "Mock", Some("mock"));
+        record_chat_turn(&state, 2, "问题", response, None, None, backend_state);
+        assert_eq!(chat_contents(&state, 2), vec!["问题", "第一次回答"]);
+
+        // 重新生成助手回复，恢复保存的后端状态
+        let history = state.history.lock().unwrap()[&2].clone();
+        let mut chat = create_ai_chat("Mock", Some("mock")).unwrap();
+        restore_backend_state(&mut chat, &history, "Mock", Some("mock"));
+        chat.set_parameter("response".to_string(), "第二次回答".to_string()).unwrap();
+        chat.load_from(&history).unwrap();
+        let response =
+            tauri::async_runtime::block_on(chat.regenerate_response_stream(api_key, |_| {}))
+                .map_err(|e| e.to_string());
+        let backend_state = into_backend_state(chat, "Mock", Some("mock"));
+        assert!(record_regenerated_reply(&state, 2, 1, response, None, None, backend_state).is_some());
+        assert_eq!(chat_contents(&state, 2), vec!["问题", "第二次回答"]);
+
+        // 重命名并删除消息
+        state.rename_chat(2, "测试对话".to_string()).unwrap();
+        assert_eq!(state.delete_message(2, 1).unwrap().len(), 1);
+        assert!(state.delete_message(2, 5).is_err());
+
+        // 保存的历史记录可以完整读回
+        let loaded = load_history().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&2].title.as_deref(), Some("测试对话"));
+        assert_eq!(loaded[&2].content.len(), 1);
+        assert_eq!(loaded[&2].content[0].content, "问题");
+
+        // 删除最后一个对话时自动创建新的空对话
+        state.delete_chat("main", 2).unwrap();
+        assert_eq!(state.current_chat_id("main"), 3);
+        assert_eq!(*state.next_chat_id.lock().unwrap(), 4);
+        assert!(state.delete_chat("main", 2).is_err());
+        assert_eq!(load_history().unwrap().keys().copied().collect::<Vec<_>>(), vec![3]);
+
+        // 重新载入历史记录后，新对话的ID不会与已有对话冲突
+        let reloaded = ChatState::default();
+        reloaded.load(load_history().unwrap());
+        assert_eq!(reloaded.create_chat("main").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_failed_send_leaves_no_placeholder() {
+        let (state, _guard) = new_chat_state("failed-send");
+        state.create_chat("main").unwrap();
+        let context = state.history.lock().unwrap()[&2].clone();
+
+        // 后端在输出任何内容前就返回错误
+        let mut chat = mock_chat("不会输出");
+        chat.set_parameter("error".to_string(), "模拟的服务器错误".to_string()).unwrap();
+        let chunks = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&chunks);
+        let error = tauri::async_runtime::block_on(chat.generate_response_stream(
+            select_api_key("Mock").unwrap(),
+            "问题".to_string(),
+            move |_| *counter.lock().unwrap() += 1,
+        ))
+        .unwrap_err()
+        .to_string();
+        assert_eq!(*chunks.lock().unwrap(), 0);
+
+        // 错误写入对话：显示内容和历史记录中都是用户消息加错误信息
+        let error_message = format!("{}{}", GENERATION_ERROR_PREFIX, error);
+        let display = record_failed_turn(&state, 2, &context, "问题", Some(error_message.clone()));
+        let contents: Vec<String> = display.content.iter().map(|m| m.content.clone()).collect();
+        assert_eq!(contents, vec!["问题".to_string(), error_message]);
+        assert_eq!(chat_contents(&state, 2), contents);
+
+        // 错误不写入对话：移除自动保存的部分回复，显示恢复为发送前的对话
+        let context = state.history.lock().unwrap()[&2].clone();
+        autosave_partial_response(&state, 2, "第二个问题", "部分回复");
+        let display = record_failed_turn(&state, 2, &context, "第二个问题", None);
+        assert_eq!(display.content.len(), 2);
+        assert_eq!(chat_contents(&state, 2), contents);
+        assert!(display.content.iter().all(|m| m.content != THINKING_PLACEHOLDER));
+    }
+
+    #[test]
+    fn test_delete_current_chat_selects_latest() {
+        let (state, _guard) = new_chat_state("delete");
+
+        state.create_chat("main").unwrap();
+        state.create_chat("main").unwrap();
+        state.create_chat("main").unwrap();
+        state.select_chat("main", 3);
+        state.delete_chat("main", 3).unwrap();
+        assert_eq!(state.current_chat_id("main"), 4);
+
+        // 删除非当前对话不影响当前选择
+        state.delete_chat("main", 2).unwrap();
+        assert_eq!(state.current_chat_id("main"), 4);
+        assert_eq!(state.history.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_windows_track_current_chat_independently() {
+        let (state, _guard) = new_chat_state("windows");
+
+        state.create_chat("main").unwrap();
+        state.create_chat("main").unwrap();
+        state.select_chat("chat-1", 2);
+        assert_eq!(state.current_chat_id("main"), 3);
+        assert_eq!(state.current_chat_id("chat-1"), 2);
 
+        // 删除其他窗口正在显示的对话时，该窗口切换到最新的对话
+        state.delete_chat("main", 2).unwrap();
+        assert_eq!(state.current_chat_id("chat-1"), 3);
+        assert_eq!(state.current_chat_id("main"), 3);
 
+        state.remove_window("chat-1");
+        assert!(!state.current_chat_ids.lock().unwrap().contains_key("chat-1"));
+    }
+
+    #[test]
+    fn test_cleanup_old_chats_skips_pinned() {
+        let (state, _guard) = new_chat_state("retention");
+
+        state.create_chat("main").unwrap();
+        state.create_chat("main").unwrap();
+        state.create_chat("main").unwrap();
+        {
+            let mut history = state.history.lock().unwrap();
+            let expired = chrono::Local::now().timestamp() - 40 * 24 * 60 * 60;
+            for id in [2, 3, 4] {
+                history.get_mut(&id).unwrap().updated_at = expired;
+            }
+            history.get_mut(&3).unwrap().pinned = true;
+            history.get_mut(&4).unwrap().touch();
+        }
+
+        assert_eq!(cleanup_chats_older_than(&state, 30).unwrap(), 1);
+        let mut remaining: Vec<u32> = load_history().unwrap().keys().copied().collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![3, 4]);
+        assert_eq!(cleanup_chats_older_than(&state, 30).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reorder_chats() {
+        let (state, _guard) = new_chat_state("reorder");
+
+        let first = state.create_chat("main").unwrap();
+        let second = state.create_chat("main").unwrap();
+        let third = state.create_chat("main").unwrap();
+        let fourth = state.create_chat("main").unwrap();
+        state.set_pinned(second, true).unwrap();
+        state.reorder_chats(&[third, first]).unwrap();
+        assert!(state.reorder_chats(&[third, 999]).is_err());
+
+        let ids: Vec<u32> = chat_history_items(&state).iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![second, third, first, fourth]);
+        assert_eq!(load_history().unwrap()[&first].sort_order, Some(1));
+    }
+
+    #[test]
+    fn test_create_chat_within_limit() {
+        let (state, _guard) = new_chat_state("max_chats");
+
+        let first = state.create_chat("main").unwrap();
+        let second = state.create_chat("main").unwrap();
+        {
+            let mut history = state.history.lock().unwrap();
+            history.get_mut(&first).unwrap().updated_at = 0;
+            history.get_mut(&second).unwrap().pinned = true;
+        }
+
+        let mut settings = setting::setting::AppSettings::default();
+        assert_eq!(settings.chat_limit(), ChatLimit::Unlimited);
+        settings.max_chats = 2;
+        settings.max_chats_policy = "reject".to_string();
+        assert!(state.create_chat_within("main", settings.chat_limit()).is_err());
+
+        settings.max_chats_policy = "evict".to_string();
+        let (new_id, evicted) = state.create_chat_within("main", settings.chat_limit()).unwrap();
+        assert_eq!(evicted.iter().map(|chat| chat.id).collect::<Vec<_>>(), vec![first]);
+        let mut remaining: Vec<u32> = load_history().unwrap().keys().copied().collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![second, new_id]);
+    }
+
+    #[test]
+    fn test_open_exported_chat() {
+        let (state, _guard) = new_chat_state("open_exported");
+
+        let first = state.create_chat("main").unwrap();
+        let second = state.create_chat("main").unwrap();
+        let exported = {
+            let mut history = state.history.lock().unwrap();
+            history.get_mut(&first).unwrap().updated_at = 0;
+            let chat = history.get_mut(&second).unwrap();
+            chat.pinned = true;
+            chat.content.push(ChatMessage::new(ChatMessageType::User, "求极限".to_string()));
+            chat.clone()
+        };
+        let path = std::env::temp_dir().join(format!("npulearn-shared-{}.json", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        history_msg::export::export_chat_json_to(&exported, &path).unwrap();
+
+        // 达到上限且不允许删除时拒绝打开，历史记录保持不变
+        assert!(open_exported_chat_file(&state, "main", &path, ChatLimit::Reject(2)).is_err());
+        assert_eq!(state.history.lock().unwrap().len(), 2);
+
+        // 允许删除时删除最久未更新的未置顶对话，打开的对话成为当前对话
+        let (new_id, evicted) =
+            open_exported_chat_file(&state, "main", &path, ChatLimit::EvictOldest(2)).unwrap();
+        assert_eq!(evicted.iter().map(|chat| chat.id).collect::<Vec<_>>(), vec![first]);
+        assert_eq!(state.current_chat_id("main"), new_id);
+        let history = load_history().unwrap();
+        let opened = &history[&new_id];
+        assert_eq!(opened.id, new_id);
+        assert_eq!(opened.content[0].content, "求极限");
+        assert!(!opened.pinned);
+        assert!(!history.contains_key(&first));
+
+        // 文件格式不正确时报错，不创建对话
+        std::fs::write(&path, r#"{"messages":[]}"#).unwrap();
+        assert!(open_exported_chat_file(&state, "main", &path, ChatLimit::Unlimited).is_err());
+        assert_eq!(state.history.lock().unwrap().len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_duplicate_chat() {
+        let (state, _guard) = new_chat_state("duplicate");
+
+        let id = state.create_chat("main").unwrap();
+        {
+            let mut history = state.history.lock().unwrap();
+            let chat = history.get_mut(&id).unwrap();
+            chat.title = Some("实验 <A>".to_string());
+            chat.pinned = true;
+            chat.updated_at = 0;
+            chat.content.push(ChatMessage {
+                time: "10:00".to_string(),
+                ..ChatMessage::new(ChatMessageType::User, "你好".to_string())
+            });
+        }
+
+        let (copy_id, evicted) = state.duplicate_chat("main", id, ChatLimit::Unlimited).unwrap();
+        assert!(evicted.is_empty());
+        assert_ne!(copy_id, id);
+        assert_eq!(state.current_chat_id("main"), copy_id);
+        let history = load_history().unwrap();
+        let copy = &history[&copy_id];
+        assert_eq!(copy.title.as_deref(), Some("实验 <A> (副本)"));
+        assert_eq!(copy.content.len(), 1);
+        assert!(!copy.pinned);
+        assert!(copy.updated_at > 0);
+        assert_eq!(history[&id].title.as_deref(), Some("实验 <A>"));
+        assert!(state.duplicate_chat("main", 999, ChatLimit::Unlimited).is_err());
+
+        // 达到上限时删除其他对话，被复制的对话即使最久未更新也会保留
+        state.history.lock().unwrap().get_mut(&id).unwrap().pinned = false;
+        assert!(state.duplicate_chat("main", id, ChatLimit::Reject(2)).is_err());
+        let (_, evicted) = state.duplicate_chat("main", id, ChatLimit::EvictOldest(2)).unwrap();
+        assert_eq!(evicted.iter().map(|chat| chat.id).collect::<Vec<_>>(), vec![copy_id]);
+        assert!(load_history().unwrap().contains_key(&id));
+    }
+
+    #[test]
+    fn test_message_notes_and_stars() {
+        let (state, _guard) = new_chat_state("notes");
+
+        let first = state.create_chat("main").unwrap();
+        let second = state.create_chat("main").unwrap();
+        {
+            let mut history = state.history.lock().unwrap();
+            for (id, updated_at) in [(first, 1), (second, 2)] {
+                let chat = history.get_mut(&id).unwrap();
+                chat.title = Some(format!("对话{}", id));
+                chat.updated_at = updated_at;
+                chat.content.push(ChatMessage::new(ChatMessageType::User, "问题".to_string()));
+                chat.content.push(ChatMessage::new(ChatMessageType::Assistant, "回答".to_string()));
+            }
+        }
+
+        // 笔记去除首尾空白，空笔记视为删除
+        state.set_message_note(first, 1, Some("  复习这里  ".to_string())).unwrap();
+        assert_eq!(load_history().unwrap()[&first].content[1].note.as_deref(), Some("复习这里"));
+        state.set_message_note(first, 1, Some("   ".to_string())).unwrap();
+        assert!(load_history().unwrap()[&first].content[1].note.is_none());
+        assert!(state.set_message_note(first, 5, Some("笔记".to_string())).is_err());
+        assert!(state.set_message_note(999, 0, None).is_err());
+
+        assert!(state.toggle_message_star(first, 1).unwrap());
+        assert!(state.toggle_message_star(second, 0).unwrap());
+        assert!(load_history().unwrap()[&first].content[1].starred);
+        assert!(state.toggle_message_star(first, 5).is_err());
+
+        // 最近更新的对话中的收藏在前
+        state.set_message_note(first, 1, Some("重点".to_string())).unwrap();
+        let starred = state.starred_messages();
+        let positions: Vec<(u32, usize)> =
+            starred.iter().map(|m| (m.chat_id, m.message_index)).collect();
+        assert_eq!(positions, vec![(second, 0), (first, 1)]);
+        assert_eq!(starred[1].chat_title, format!("对话{}", first));
+        assert_eq!(starred[1].content, "回答");
+        assert_eq!(starred[1].note.as_deref(), Some("重点"));
+
+        // 再次切换取消收藏
+        assert!(!state.toggle_message_star(second, 0).unwrap());
+        assert_eq!(state.starred_messages().len(), 1);
+    }
+
+    #[test]
+    fn test_late_tool_results_follow_their_reply() {
+        let (state, _guard) = new_chat_state("tool-log");
+        let id = state.create_chat("main").unwrap();
+        let backend_state = || into_backend_state(mock_chat(""), "Mock", Some("mock"));
+
+        let tool_log = ToolMessageLog::default();
+        tool_log.push(&state, id, ChatMessage::tool_result("wolfram_alpha_compute", "结果1".to_string()));
+        let reply_index = record_chat_turn(&state, id, "问题1", "回答1".to_string(), None, None, backend_state()).unwrap();
+        tool_log.record(&state, id, reply_index);
+
+        // 下一轮提问开始后才完成的计算结果仍记录在第一轮回复的工具结果之后
+        record_chat_turn(&state, id, "问题2", "回答2".to_string(), None, None, backend_state());
+        tool_log.push(&state, id, ChatMessage::tool_result("wolfram_alpha_compute", "结果2".to_string()));
+        assert_eq!(
+            chat_contents(&state, id),
+            vec!["问题1", "回答1", "结果1", "结果2", "问题2", "回答2"]
+        );
+    }
+
+    #[test]
+    fn test_generation_overrides() {
+        assert!(GenerationOverrides::default().is_empty());
+
+        let overrides = GenerationOverrides {
+            temperature: Some(0.2),
+            top_k: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(
+            overrides.parameters(),
+            vec![("temperature", "0.2".to_string()), ("top_k", "20".to_string())]
+        );
+
+        // DeepSeek 不支持 top_k，应用时跳过而不影响其他参数
+        let state = applied_chat_json("DeepSeek", |chat| overrides.apply(chat));
+        assert!((state["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_request_parameters_are_not_saved() {
+        let mut settings = setting::setting::AppSettings::default();
+        settings.active_generation_profile = "精确".to_string();
+        let mut history = ChatState::empty_chat(1);
+        history.chat_parameters.insert("top_p".to_string(), "0.5".to_string());
+        let overrides = GenerationOverrides {
+            temperature: Some(0.3),
+            ..Default::default()
+        };
+
+        let mut chat = create_ai_chat("Mock", Some("mock")).unwrap();
+        chat.set_parameter("max_tokens".to_string(), "512".to_string()).unwrap();
+        let snapshot = apply_request_parameters(&mut chat, &settings, &history, Some(&overrides));
+        // 对话参数优先于预设，单次请求参数优先于两者
+        let state = chat_json(&chat);
+        assert_eq!(state["parameters"]["top_p"], "0.5");
+        assert_eq!(state["parameters"]["temperature"], "0.3");
+
+        // 本轮中后端对状态的其他修改会保留
+        chat.set_system_prompt("新的系统提示词".to_string()).unwrap();
+        let mut backend_state = into_backend_state(chat, "Mock", Some("mock"));
+        snapshot.revert(&mut backend_state);
+        let state: serde_json::Value = serde_json::from_str(&backend_state.data).unwrap();
+        assert_eq!(state["parameters"], serde_json::json!({ "max_tokens": "512" }));
+        assert_eq!(state["system_prompt"], "新的系统提示词");
+    }
+
+    #[test]
+    fn test_chat_generation_profile_overrides_global() {
+        let mut settings = setting::setting::AppSettings::default();
+        settings.active_generation_profile = "精确".to_string();
+        let mut history = history_msg::history::ChatHistory {
+            generation_profile: Some("发散".to_string()),
+            ..ChatState::empty_chat(1)
+        };
+        let profile = settings_for_chat(&settings, &history).active_generation_profile().cloned().unwrap();
+        assert_eq!(profile.name, "发散");
+
+        // DeepSeek 支持惩罚参数，Gemini 会跳过它们
+        let state = applied_chat_json("DeepSeek", |chat| apply_generation_parameters(chat, profile.parameters()));
+        assert!((state["presence_penalty"].as_f64().unwrap() - 0.6).abs() < 1e-6);
+        let state = applied_chat_json("Gemini", |chat| apply_generation_parameters(chat, profile.parameters()));
+        assert!((state["temperature"].as_f64().unwrap() - 1.1).abs() < 1e-6);
+
+        history.generation_profile = None;
+        assert_eq!(settings_for_chat(&settings, &history).active_generation_profile().unwrap().name, "精确");
+    }
+
+    #[test]
+    fn test_effective_params() {
+        let mut settings = setting::setting::AppSettings::default();
+        settings.active_generation_profile = "精确".to_string();
+        let mut history = ChatState::empty_chat(1);
+
+        let params = effective_params(&settings, &history, "Gemini", None).unwrap();
+        assert_eq!(params.model.as_deref(), Some("gemini-2.0-flash"));
+        assert_eq!(params.generation_profile.as_deref(), Some("精确"));
+        assert!((params.temperature.unwrap() - 0.2).abs() < 1e-6);
+        assert_eq!(params.output_language, DEFAULT_OUTPUT_LANGUAGE);
+
+        // 对话保存的参数（Mock 保存在 parameters 中）与对话级预设
+        let mut chat = create_ai_chat("Mock", Some("mock")).unwrap();
+        chat.set_parameter("max_tokens".to_string(), "512".to_string()).unwrap();
+        history.backend_state = Some(into_backend_state(chat, "Mock", Some("mock")));
+        history.generation_profile = Some("发散".to_string());
+        history.disable_cot = true;
+        let params = effective_params(&settings, &history, "Mock", None).unwrap();
+        assert_eq!(params.max_tokens, Some(512));
+        assert!((params.temperature.unwrap() - 1.1).abs() < 1e-6);
+        assert_eq!(params.top_k, None);
+        assert!(!params.cot_enabled);
+
+        // 对话参数优先于预设
+        history.chat_parameters.insert("temperature".to_string(), "0.4".to_string());
+        let params = effective_params(&settings, &history, "Mock", None).unwrap();
+        assert!((params.temperature.unwrap() - 0.4).abs() < 1e-6);
+
+        // 与后端构建系统提示时的判断一致：推理模型和关闭 COT 的对话不使用 COT 模板
+        history = ChatState::empty_chat(1);
+        assert!(effective_params(&settings, &history, "Gemini", None).unwrap().cot_enabled);
+        assert!(effective_params(&settings, &history, "DeepSeek", Some("deepseek-chat")).unwrap().cot_enabled);
+        assert!(!effective_params(&settings, &history, "DeepSeek", Some("deepseek-reasoner")).unwrap().cot_enabled);
+        history.disable_cot = true;
+        assert!(!effective_params(&settings, &history, "Gemini", None).unwrap().cot_enabled);
+    }
+
+    #[test]
+    fn test_assistant_name_replaces_preset_identity() {
+        let mut settings = setting::setting::AppSettings::default();
+        let prompt = merge_persona_with_system_prompt(&settings).unwrap();
+        assert!(prompt.contains("**Name**: 航小天"));
+
+        settings.assistant_name = "小北".to_string();
+        let prompt = merge_persona_with_system_prompt(&settings).unwrap();
+        assert!(prompt.contains("**Name**: 小北"));
+        assert!(!prompt.contains("航小天"));
+        assert!(!prompt.contains("{assistant_name}"));
+
+        // 所有预设人格的描述都使用设置的名称
+        settings.persona_config.preset_persona = "researcher".to_string();
+        let prompt = merge_persona_with_system_prompt(&settings).unwrap();
+        assert!(prompt.contains("**Description**: 小北是"));
+    }
+
+    #[test]
+    fn test_title_model_selection() {
+        let mut settings = setting::setting::AppSettings::default();
+        assert_eq!(settings.title_model().as_deref(), Some("gemini-2.5-flash"));
+        settings.title_model = "快速".to_string();
+        settings.model_aliases.insert("快速".to_string(), "deepseek-chat".to_string());
+        assert_eq!(settings.title_model().as_deref(), Some("deepseek-chat"));
+        settings.title_model = " ".to_string();
+        assert_eq!(settings.title_model(), None);
+
+        assert_eq!(key_type_for_model("models/gemini-2.0-flash"), Some("Gemini"));
+        assert_eq!(key_type_for_model("deepseek-reasoner"), Some("DeepSeek"));
+        assert_eq!(key_type_for_model("coze"), None);
+
+        assert_eq!(clean_generated_title("标题：《快速排序的复杂度》\n其他"), "快速排序的复杂度");
+        assert_eq!(clean_generated_title("  \"Rust 所有权\"。 "), "Rust 所有权");
+        assert_eq!(clean_generated_title(""), "");
+    }
+
+    #[test]
+    fn test_auto_summarize_prompt() {
+        let mut settings = setting::setting::AppSettings::default();
+        assert!(!settings.auto_summarize);
+        assert!(settings.auto_summarize_prompt("论文.pdf").contains("《论文.pdf》"));
+
+        settings.auto_summarize_prompt = "用三句话总结 {name}".to_string();
+        assert_eq!(settings.auto_summarize_prompt("a.md"), "用三句话总结 a.md");
+    }
+
+    #[test]
+    fn test_resolve_model_alias() {
+        let mut settings = setting::setting::AppSettings::default();
+        settings.model_aliases.insert("快速".to_string(), "gemini-2.0-flash".to_string());
+        assert_eq!(settings.resolve_model_alias("快速"), "gemini-2.0-flash");
+        assert_eq!(settings.resolve_model_alias("deepseek-chat"), "deepseek-chat");
+    }
+
+    #[test]
+    fn test_apply_stop_sequences() {
+        let mut settings = setting::setting::AppSettings::default();
+        settings.model_config.stop = Some(vec!["###".to_string(), String::new()]);
+        for backend in ["DeepSeek", "Gemini"] {
+            let state = applied_chat_json(backend, |chat| apply_stop_sequences(chat, &settings));
+            assert_eq!(state["stop"], serde_json::json!(["###"]));
+        }
+
+        // 清空停止序列后会清除已保存的值
+        let mut chat = create_ai_chat("DeepSeek", None).unwrap();
+        apply_stop_sequences(&mut chat, &settings);
+        settings.model_config.stop = Some(Vec::new());
+        apply_stop_sequences(&mut chat, &settings);
+        assert!(chat_json(&chat)["stop"].is_null());
+
+        // 未配置停止序列时同样清除
+        settings.model_config.stop = Some(vec!["###".to_string()]);
+        apply_stop_sequences(&mut chat, &settings);
+        settings.model_config.stop = None;
+        apply_stop_sequences(&mut chat, &settings);
+        assert!(chat_json(&chat)["stop"].is_null());
+    }
+
+    #[test]
+    fn test_apply_chat_cot() {
+        let mut history = ChatState::empty_chat(1);
+        history.disable_cot = true;
+        for backend in ["DeepSeek", "Gemini"] {
+            let state = applied_chat_json(backend, |chat| apply_chat_cot(chat, &history));
+            assert_eq!(state["cot_disabled"], serde_json::json!(true));
+        }
+
+        // 恢复的后端状态关闭了 COT，但对话已重新开启
+        let mut chat = create_ai_chat("DeepSeek", None).unwrap();
+        apply_chat_cot(&mut chat, &history);
+        history.disable_cot = false;
+        apply_chat_cot(&mut chat, &history);
+        assert_eq!(chat_json(&chat)["cot_disabled"], serde_json::json!(false));
+    }
+
+    #[test]
+    fn test_distinct_raw_response() {
+        assert_eq!(distinct_raw_response("回答".to_string(), "回答"), None);
+        assert_eq!(distinct_raw_response(String::new(), "回答"), None);
+        let raw = "<think>推理过程</think><|start_header|><|typeset_and_respond|><|end_header|>回答";
+        assert_eq!(distinct_raw_response(raw.to_string(), "回答").as_deref(), Some(raw));
+    }
+}
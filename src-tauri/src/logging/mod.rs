@@ -0,0 +1,236 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+pub mod redact;
+
+use redact::redact_secrets;
+
+// 单个日志文件的最大大小，超过后轮转
+const MAX_LOG_SIZE: u64 = 2 * 1024 * 1024;
+// 保留的历史日志文件数量（npulearn.log.1 ~ npulearn.log.N）
+const MAX_LOG_FILES: usize = 3;
+const LOG_FILE_NAME: &str = "npulearn.log";
+
+static LOG_PATH: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+
+// 调试日志：开启后记录请求的完整消息内容
+static DEBUG_LOGGING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_debug_logging(enabled: bool) {
+    DEBUG_LOGGING.store(enabled, Ordering::Relaxed);
+    log::set_max_level(if enabled {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    });
+}
+
+pub fn is_debug_logging() -> bool {
+    DEBUG_LOGGING.load(Ordering::Relaxed)
+}
+
+/// 当前日志文件路径，日志尚未初始化时为 None
+pub fn get_log_path() -> Option<PathBuf> {
+    LOG_PATH.lock().unwrap().clone()
+}
+
+/// 按大小轮转的文件日志
+struct RotatingFileLogger {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFileLogger {
+    fn new(path: PathBuf, max_size: u64, max_files: usize) -> Self {
+        Self {
+            path,
+            file: Mutex::new(None),
+            max_size,
+            max_files,
+        }
+    }
+
+    fn write_line(&self, line: &str) -> std::io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        let current_size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if current_size > 0 && current_size + line.len() as u64 > self.max_size {
+            // 轮转前先关闭当前文件
+            *file = None;
+            rotate_files(&self.path, self.max_files)?;
+        }
+
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let handle = file.as_mut().unwrap();
+        handle.write_all(line.as_bytes())?;
+        handle.flush()
+    }
+}
+
+/// 将 log -> log.1 -> log.2 ... 依次后移，超出保留数量的最旧文件被删除
+fn rotate_files(path: &Path, max_files: usize) -> std::io::Result<()> {
+    let numbered = |index: usize| PathBuf::from(format!("{}.{}", path.display(), index));
+
+    let oldest = numbered(max_files);
+    if oldest.exists() {
+        std::fs::remove_file(&oldest)?;
+    }
+    for index in (1..max_files).rev() {
+        let from = numbered(index);
+        if from.exists() {
+            std::fs::rename(&from, numbered(index + 1))?;
+        }
+    }
+    if max_files > 0 {
+        std::fs::rename(path, numbered(1))?;
+    } else {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+impl Log for RotatingFileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_log_line(record.level(), record.target(), &record.args().to_string());
+        if let Err(e) = self.write_line(&line) {
+            eprintln!("写入日志失败: {}", e);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// 格式化一行日志，写入前屏蔽其中的 API 密钥和令牌
+fn format_log_line(level: Level, target: &str, message: &str) -> String {
+    format!(
+        "{} [{}] {}: {}\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+        level,
+        target,
+        redact_secrets(message)
+    )
+}
+
+/// 初始化文件日志，日志写入 `log_dir/npulearn.log`
+pub fn init(log_dir: PathBuf) -> Result<(), String> {
+    std::fs::create_dir_all(&log_dir).map_err(|e| format!("无法创建日志目录: {}", e))?;
+    let path = log_dir.join(LOG_FILE_NAME);
+
+    let logger = RotatingFileLogger::new(path.clone(), MAX_LOG_SIZE, MAX_LOG_FILES);
+    // 日志器在整个程序生命周期内存在
+    let logger: &'static RotatingFileLogger = Box::leak(Box::new(logger));
+    log::set_logger(logger).map_err(|e| format!("无法初始化日志: {}", e))?;
+    set_debug_logging(is_debug_logging());
+
+    *LOG_PATH.lock().unwrap() = Some(path);
+    Ok(())
+}
+
+/// 一次 AI 请求的记录
+pub struct RequestLog<'a> {
+    pub backend: &'a str,
+    pub model: Option<&'a str>,
+    pub latency_ms: u128,
+    pub error: Option<&'a str>,
+    pub message: &'a str, // 用户消息，仅在调试日志开启时记录
+}
+
+/// 记录一次 AI 请求的后端、模型、状态、耗时和错误
+pub fn log_request(request: &RequestLog) {
+    let mut line = format!(
+        "backend={} model={} status={} latency_ms={}",
+        request.backend,
+        request.model.unwrap_or("default"),
+        if request.error.is_some() {
+            "error"
+        } else {
+            "ok"
+        },
+        request.latency_ms
+    );
+    if let Some(error) = request.error {
+        line.push_str(&format!(" error={:?}", error));
+    }
+    if is_debug_logging() {
+        line.push_str(&format!(" message={:?}", request.message));
+    }
+
+    if request.error.is_some() {
+        log::error!(target: "request", "{}", line);
+    } else {
+        log::info!(target: "request", "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotating_file_logger() {
+        let dir = std::env::temp_dir().join(format!("npulearn-log-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOG_FILE_NAME);
+
+        let logger = RotatingFileLogger::new(path.clone(), 64, 2);
+        for i in 0..10 {
+            logger
+                .write_line(&format!("line {:02} {}\n", i, "x".repeat(20)))
+                .unwrap();
+        }
+
+        assert!(path.exists());
+        assert!(dir.join(format!("{}.1", LOG_FILE_NAME)).exists());
+        assert!(dir.join(format!("{}.2", LOG_FILE_NAME)).exists());
+        assert!(!dir.join(format!("{}.3", LOG_FILE_NAME)).exists());
+        // 最新的内容始终写在当前日志文件中
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert!(current.contains("line 09"));
+        assert!(std::fs::metadata(&path).unwrap().len() <= 64);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_format_log_line_redacts_keys() {
+        let error = "error sending request for url (https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:generateContent?key=AIzaSyTestSecret0123456789)";
+        let line = format_log_line(
+            Level::Error,
+            "request",
+            &format!("backend=Gemini error={:?}", error),
+        );
+        assert!(!line.contains("AIzaSyTestSecret0123456789"));
+        let key_values: Vec<&str> = line
+            .match_indices("key=")
+            .map(|(index, _)| &line[index + 4..])
+            .collect();
+        assert!(key_values.iter().all(|value| value.starts_with("***")));
+    }
+}
@@ -10,6 +10,37 @@ use url::Url; // For parsing URI and getting path segments
               // Assuming urlencoding crate is available as it's used in lib.rs for Android URI decoding
               // use urlencoding;
 
+/// Reads the bytes behind a content URI. On Android tauri-plugin-fs opens `content://`
+/// URIs through the ContentResolver, so this works for Downloads, app-private and
+/// cloud-provider (e.g. Google Drive) documents alike.
+pub fn read_content_uri(app_handle: &AppHandle, uri_string: &str) -> Result<Vec<u8>, String> {
+    let uri_url = tauri::Url::parse(uri_string)
+        .map_err(|e| format!("Failed to parse URI '{}': {}", uri_string, e))?;
+
+    app_handle
+        .fs()
+        .read(uri_url) // Pass as tauri::Url which implements Into<FilePath>
+        .map_err(|e| {
+            format!(
+                "Failed to read content from URI '{}' using tauri-plugin-fs: {}. Ensure plugin is configured and URI is accessible.",
+                uri_string, e
+            )
+        })
+}
+
+// Subdirectory of the app cache that holds temporary copies of content URIs
+const CONTENT_URI_CACHE_DIR: &str = "content_uri";
+
+/// Deletes the temporary copy made by `resolve_uri_to_local_path` for a `content://` URI.
+/// Paths resolved from `file://` URIs or absolute paths are the user's own files and are kept.
+pub fn remove_local_copy(uri_string: &str, local_path: &str) {
+    if uri_string.starts_with("content://") {
+        if let Err(e) = fs::remove_file(local_path) {
+            eprintln!("Failed to remove cached copy '{}': {}", local_path, e);
+        }
+    }
+}
+
 // Helper to get a usable file name from a content URI for the copied file
 #[allow(dead_code)]
 fn get_file_name_for_copy(uri_string: &str) -> Result<String, String> {
@@ -58,7 +89,8 @@ fn get_file_name_for_copy(uri_string: &str) -> Result<String, String> {
 }
 
 /// Resolves an Android URI to an absolute local file path.
-/// For `content://` URIs, this involves copying the file to the app's local data directory.
+/// For `content://` URIs, this involves copying the file to the app's cache directory;
+/// call `remove_local_copy` once the copy has been read.
 /// For `file://` URIs, it attempts to convert directly to a path.
 #[allow(dead_code)]
 pub async fn resolve_uri_to_local_path(
@@ -76,18 +108,19 @@ pub async fn resolve_uri_to_local_path(
             })
             .map(|p| p.to_string_lossy().into_owned())
     } else if uri_string.starts_with("content://") {
-        // For content URIs, copy the file to app's local data directory
+        // For content URIs, copy the file to the app's cache directory
         let file_name_for_copy = get_file_name_for_copy(uri_string)?;
 
         let local_data_dir = app_handle
             .path()
-            .app_local_data_dir()
-            .map_err(|_| "Failed to get app local data directory".to_string())?;
+            .app_cache_dir()
+            .map_err(|_| "Failed to get app cache directory".to_string())?
+            .join(CONTENT_URI_CACHE_DIR);
 
         if !local_data_dir.exists() {
             fs::create_dir_all(&local_data_dir).map_err(|e| {
                 format!(
-                    "Failed to create app local data directory '{}': {}",
+                    "Failed to create content URI cache directory '{}': {}",
                     local_data_dir.display(),
                     e
                 )
@@ -96,20 +129,8 @@ pub async fn resolve_uri_to_local_path(
 
         let destination_path = local_data_dir.join(&file_name_for_copy);
 
-        // Read content from URI using tauri-plugin-fs.
-        // This is the critical part that assumes tauri-plugin-fs can handle content:// URIs.
-        let uri_url = tauri::Url::parse(uri_string)
-            .map_err(|e| format!("Failed to parse URI '{}': {}", uri_string, e))?;
-
-        let file_content = app_handle
-            .fs()
-            .read(uri_url) // Pass as tauri::Url which implements Into<FilePath>
-            .map_err(|e| {
-                format!(
-                    "Failed to read content from URI '{}' using tauri-plugin-fs: {}. Ensure plugin is configured and URI is accessible.",
-                    uri_string, e
-                )
-            })?;
+        // Read content from URI through the ContentResolver (via tauri-plugin-fs).
+        let file_content = read_content_uri(app_handle, uri_string)?;
 
         // Write content to the destination file
         let mut dest_file = File::create(&destination_path).map_err(|e| {
@@ -1,6 +1,7 @@
 import { AppEvents, chatHistory, isLoading, isStreaming } from "./eventBus";
 import { invoke } from "@tauri-apps/api/core";
-import { ChatMessage } from "./types";
+import { ask } from "@tauri-apps/plugin-dialog";
+import { ChatHistoryItem, ChatMessage } from "./types";
 
 // 创建新对话
 async function createNewChat() {
@@ -21,7 +22,7 @@ async function createNewChat() {
         AppEvents.showNotification("已创建新对话", "success");
     } catch (error) {
         console.error("创建新对话失败:", error);
-        AppEvents.showNotification("创建新对话失败", "error");
+        AppEvents.showNotification(`创建新对话失败: ${error}`, "error");
     } finally {
         isLoading.value = false;
     }
@@ -68,4 +69,35 @@ async function loadChatHistory() {
     }
 }
 
-export { loadChatHistory, selectHistory, createNewChat };
\ No newline at end of file
+// 检查上次退出时中断的回复，询问用户保留还是丢弃
+async function recoverIncompleteChats() {
+    try {
+        const incompleteChats = await invoke("list_incomplete_chats") as ChatHistoryItem[];
+        for (const chat of incompleteChats) {
+            const keep = await ask(`对话「${chat.title}」中有一条回复在生成过程中被中断，是否保留已生成的部分？\n选择“否”将丢弃这一轮对话。`, {
+                title: "恢复中断的回复",
+                kind: "warning",
+            });
+            await invoke("resolve_incomplete_chat", { chatId: chat.id, keep });
+        }
+        if (incompleteChats.length > 0) {
+            await loadChatHistory();
+        }
+    } catch (error) {
+        console.error("恢复中断的回复失败:", error);
+    }
+}
+
+// 提示启动时按保留天数自动清理的对话数量
+async function reportStartupCleanup() {
+    try {
+        const count = await invoke("take_startup_cleanup_count") as number;
+        if (count > 0) {
+            AppEvents.showNotification(`已自动清理 ${count} 个过期对话`, "info");
+        }
+    } catch (error) {
+        console.error("获取自动清理结果失败:", error);
+    }
+}
+
+export { loadChatHistory, selectHistory, createNewChat, recoverIncompleteChats, reportStartupCleanup };
\ No newline at end of file
@@ -4,6 +4,19 @@ interface ChatHistoryItem {
     id: number;
     title: string;
     time: string;
+    pinned?: boolean;
+    disable_cot?: boolean;
+    sort_order?: number | null;
+}
+
+// 快速切换对话使用的对话索引
+interface ChatIndexEntry {
+    id: number;
+    title: string;
+    updated_at: number;
+    pinned: boolean;
+    message_count: number;
+    preview: string;
 }
 
 // 定义完整的聊天历史结构
@@ -12,13 +25,122 @@ interface ChatHistory {
     title: string;
     time: string;
     content: ChatMessage[];
+    output_language?: string;
 }
 
 // 定义聊天消息的类型
 interface ChatMessage {
-    msgtype: 'User' | 'System' | 'Assistant';
+    msgtype: 'User' | 'System' | 'Assistant' | 'Tool';
+    time: string;
+    content: string;
+    complete?: boolean;
+    source_path?: string;
+    note?: string;     // 用户添加的复习笔记
+    starred?: boolean; // 是否已收藏
+    tool_name?: string; // 工具消息对应的工具名称
+}
+
+// 因网络错误等待联网后重新发送的消息
+interface QueuedMessage {
+    chat_id: number;
+    message: string;
+    key_type: string;
+    model_name: string | null;
+    queued_at: string;
+}
+
+// 所有对话中收藏的消息
+interface StarredMessage {
+    chat_id: number;
+    chat_title: string;
+    message_index: number;
+    msgtype: 'User' | 'System' | 'Assistant' | 'Tool';
     time: string;
     content: string;
+    note?: string;
+}
+
+// 定义消息中代码块的类型
+interface CodeBlock {
+    language: string;
+    content: string;
+}
+
+// 对话下一次发送时实际使用的模型和生成参数，为 null 的参数使用模型服务的默认值
+interface EffectiveParams {
+    backend: string;
+    model: string | null;
+    temperature: number | null;
+    max_tokens: number | null;
+    top_p: number | null;
+    top_k: number | null;
+    generation_profile: string | null;
+    system_prompt_source: string;
+    output_language: string;
+    cot_enabled: boolean;
+}
+
+// 消息中一个 tool_code 代码块的解析结果
+interface ToolCallInfo {
+    index: number;
+    code: string;
+    name: string | null;
+    args: Record<string, unknown>;
+    error: string | null;
+}
+
+// 两段回答比较得到的差异片段
+interface DiffSpan {
+    kind: 'unchanged' | 'added' | 'removed';
+    text: string;
+}
+
+// 对话上下文的估算占用情况
+interface ContextUsage {
+    estimated_tokens: number;
+    context_limit: number;
+    ratio: number;
+    warning: boolean;
+}
+
+// 按设置中的单价估算的对话费用
+interface CostBreakdown {
+    model: string;
+    priced: boolean;
+    estimated: boolean; // 是否有回复的用量是按字符数估算的
+    currency: string;
+    prompt_tokens: number;
+    completion_tokens: number;
+    prompt_cost: number;
+    completion_cost: number;
+    total_cost: number;
+}
+
+// 对话回放中的一步
+interface ReplayStep {
+    step: number;
+    message_index: number;
+    msgtype: 'User' | 'System' | 'Assistant' | 'Tool';
+    time: string;
+    content: string;
+    html: string;
+    has_thinking: boolean;
+    reading_minutes: number;
+    source_path?: string;
+}
+
+// 随消息发送的图片（base64 编码，不含 data: 前缀）
+interface ImageAttachment {
+    mime_type: string;
+    data: string;
+}
+
+// 单次发送使用的生成参数，未设置的参数沿用对话或全局配置
+interface GenerationOverrides {
+    temperature?: number;
+    top_p?: number;
+    top_k?: number;
+    max_tokens?: number;
 }
 
-export type { ChatHistoryItem, ChatHistory, ChatMessage };
\ No newline at end of file
+export type { ChatHistoryItem, ChatIndexEntry, ChatHistory, ChatMessage, CodeBlock, ContextUsage, CostBreakdown, DiffSpan, EffectiveParams, GenerationOverrides, ImageAttachment, QueuedMessage, ReplayStep, StarredMessage, ToolCallInfo };
\ No newline at end of file
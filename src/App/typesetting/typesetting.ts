@@ -204,8 +204,12 @@ async function collectCodeBlockActions(codeElements: NodeListOf<Element>, batch:
     // 获取代码内容
     const codeContent = el.textContent || '';
 
+    // 获取代码语言（来自 language-xxx 类名）
+    const languageClass = Array.from(el.classList).find(cls => cls.startsWith('language-'));
+    const language = languageClass ? languageClass.substring('language-'.length) : '';
+
     // 收集需要添加按钮的元素
-    batch.actionElements.push({ element: preElement, content: codeContent });
+    batch.actionElements.push({ element: preElement, content: codeContent, language });
   }
 }
 
@@ -224,6 +228,10 @@ async function applyBatchOperations(batch: any): Promise<void> {
   // 3. 为常规代码块添加复制按钮
   for (const item of batch.actionElements) {
     await addCopyButtonToCodeBlock(item.element, item.content);
+    // 流式传输期间代码尚不完整，结束后再检查语法
+    if (!isStreaming.value && item.language) {
+      await addSyntaxCheckBadge(item.element, item.content, item.language);
+    }
   }
 
   // 4. 现在处理所有工具代码块内容
@@ -593,6 +601,25 @@ async function highlightToolCodeElements(container: HTMLDivElement): Promise<voi
   });
 }
 
+// 检查生成代码的语法，发现问题时为代码块添加提示标记
+async function addSyntaxCheckBadge(preElement: Element, codeContent: string, language: string): Promise<void> {
+  try {
+    const result = await invoke<{ checked: boolean; valid: boolean; errors: string[] }>(
+      "check_generated_code",
+      { code: codeContent, language }
+    );
+    if (!result.checked || result.valid) return;
+
+    const badge = document.createElement('span');
+    badge.className = 'code-syntax-badge';
+    badge.textContent = '语法可能有误';
+    badge.title = result.errors.join('\n');
+    preElement.appendChild(badge);
+  } catch (error) {
+    console.error("代码语法检查失败:", error);
+  }
+}
+
 // 为代码块添加复制按钮
 async function addCopyButtonToCodeBlock(preElement: Element, codeContent: string): Promise<void> {
   // 生成唯一ID
@@ -4,6 +4,16 @@ import { AppEvents } from '../eventBus';
 // 缓存机制，避免重复渲染时多次调用API
 const wolframCache = new Map<string, any>();
 
+/**
+ * 写入后端在流式生成过程中提前计算好的HTML结果，渲染对应的 tool_code 时直接使用
+ * @param query 查询内容
+ * @param imageOnly 是否仅返回图像
+ * @param html 后端渲染的HTML
+ */
+export function primeWolframCache(query: string, imageOnly: boolean, html: string) {
+    wolframCache.set(`${query}-${imageOnly}-html`, [{ title: 'HTML结果', plaintext: html }]);
+}
+
 /**
  * 处理wolfram_alpha_compute API调用
  * @param apiInfo API调用信息
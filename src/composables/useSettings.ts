@@ -6,7 +6,8 @@ import { applyFontSize, applyTheme } from '../themeUtils';
 export enum ApiKeyType {
     Gemini = "Gemini",
     DeepSeek = "DeepSeek",
-    Coze = "Coze"
+    Coze = "Coze",
+    Mock = "Mock"
 }
 
 // 定义模型信息接口
@@ -36,9 +37,28 @@ export const SUPPORTED_MODELS: Record<ApiKeyType, ModelInfo[]> = {
     [ApiKeyType.Coze]: [
         { name: 'coze-bot', displayName: 'Coze Bot', isReasoning: false, description: '使用内置Bot ID' },
     ],
+    [ApiKeyType.Mock]: [
+        { name: 'mock', displayName: '离线模拟', isReasoning: false, description: '无需密钥和网络，返回预设回复，用于测试和演示' },
+    ],
 };
 
 // 定义人格配置接口
+// 生成参数预设
+export interface GenerationProfile {
+    name: string;
+    temperature: number;
+    top_p?: number | null;
+    frequency_penalty?: number | null;
+    presence_penalty?: number | null;
+}
+
+// 模型单价（每百万 token），model 按前缀匹配模型名称
+export interface ModelPrice {
+    model: string;
+    prompt: number;
+    completion: number;
+}
+
 export interface PersonaConfig {
     use_custom: boolean;
     preset_persona: string;
@@ -55,11 +75,49 @@ export interface Settings {
     model_config: {
         temperature: number;
         max_tokens: number;
+        stop?: string[] | null;
     };
     model_selection: {
         [key in ApiKeyType]: string;
     };
     persona_config: PersonaConfig;
+    gemini_safety_level: 'none' | 'low' | 'default';
+    safe_rendering: boolean;
+    autosave_interval_chunks: number;
+    autosave_interval_secs: number;
+    stream_flush_interval_ms: number;
+    stream_flush_chars: number;
+    output_language: string;
+    auto_detect_language: boolean;
+    answer_verbosity: 'concise' | 'normal' | 'detailed';
+    cot_verbosity: 'full' | 'compact' | 'off';
+    safe_mode: boolean;
+    max_image_dimension: number;
+    image_jpeg_quality: number;
+    debug_logging: boolean;
+    history_retention_days: number;
+    generation_profiles: GenerationProfile[];
+    active_generation_profile: string;
+    upload_template: string;
+    upload_code_fence: 'always' | 'auto' | 'never';
+    enable_response_cache: boolean;
+    response_cache_ttl_secs: number;
+    max_chats: number;
+    max_chats_policy: 'evict' | 'reject';
+    backend_fallback_chain: string[];
+    model_aliases: Record<string, string>;
+    replay_full_content: boolean;
+    persist_errors_in_history: boolean;
+    assistant_name: string;
+    title_model: string;
+    max_concurrency: number;
+    auto_summarize: boolean;
+    auto_summarize_prompt: string;
+    timestamp_format: 'auto' | 'relative' | 'time' | 'datetime';
+    wolfram_proxy: string;
+    disabled_typeset_tools: string[];
+    model_prices: ModelPrice[];
+    price_currency: string;
 }
 
 // 定义 ApiKey 接口
@@ -67,6 +125,7 @@ export interface ApiKey {
     key: string;
     name: string;
     key_type: ApiKeyType;
+    priority: number; // 数值越大越优先使用，0-255
 }
 
 // 实现 APIKeyList 类
@@ -146,12 +205,62 @@ export function useSettingsProvider() {
             [ApiKeyType.Gemini]: 'gemini-2.0-flash',
             [ApiKeyType.DeepSeek]: 'deepseek-chat',
             [ApiKeyType.Coze]: 'coze-bot',
+            [ApiKeyType.Mock]: 'mock',
         },
         persona_config: {
             use_custom: false,
             preset_persona: 'academic',
             custom_persona: '',
         },
+        gemini_safety_level: 'none',
+        safe_rendering: false,
+        autosave_interval_chunks: 20,
+        autosave_interval_secs: 5,
+        stream_flush_interval_ms: 50,
+        stream_flush_chars: 200,
+        output_language: '',
+        auto_detect_language: false,
+        answer_verbosity: 'normal',
+        cot_verbosity: 'full',
+        safe_mode: false,
+        max_image_dimension: 2048,
+        image_jpeg_quality: 85,
+        debug_logging: false,
+        history_retention_days: 0,
+        generation_profiles: [
+            { name: '精确', temperature: 0.2, top_p: 0.8 },
+            { name: '平衡', temperature: 0.7 },
+            { name: '发散', temperature: 1.1, top_p: 0.95, frequency_penalty: 0.5, presence_penalty: 0.6 },
+        ],
+        active_generation_profile: '',
+        upload_template: '',
+        upload_code_fence: 'always',
+        enable_response_cache: false,
+        response_cache_ttl_secs: 3600,
+        max_chats: 0,
+        max_chats_policy: 'evict',
+        backend_fallback_chain: [],
+        model_aliases: {},
+        replay_full_content: false,
+        persist_errors_in_history: false,
+        assistant_name: '航小天',
+        title_model: 'gemini-2.5-flash',
+        max_concurrency: 3,
+        auto_summarize: false,
+        auto_summarize_prompt: '',
+        timestamp_format: 'auto',
+        wolfram_proxy: '',
+        disabled_typeset_tools: [],
+        model_prices: [
+            { model: 'deepseek-chat', prompt: 0.27, completion: 1.10 },
+            { model: 'deepseek-reasoner', prompt: 0.55, completion: 2.19 },
+            { model: 'gemini-2.5-pro', prompt: 1.25, completion: 10 },
+            { model: 'gemini-2.5-flash', prompt: 0.30, completion: 2.50 },
+            { model: 'gemini-2.0-flash', prompt: 0.10, completion: 0.40 },
+            { model: 'gemini-1.5-pro', prompt: 1.25, completion: 5 },
+            { model: 'gemini-1.5-flash', prompt: 0.075, completion: 0.30 },
+        ],
+        price_currency: '$',
     });    // 记录保存前的主题和字体大小，用于关闭设置时恢复
     const theme_before_save = ref<'system' | 'light' | 'dark'>('system');
     const font_size_before_save = ref<'small' | 'medium' | 'large'>('medium');
@@ -169,7 +278,8 @@ export function useSettingsProvider() {
     const newApiKey = reactive({
         key: '',
         name: '',
-        key_type: ApiKeyType.Gemini
+        key_type: ApiKeyType.Gemini,
+        priority: 0
     });
 
     const isAddingKey = ref(false);
@@ -213,6 +323,8 @@ export function useSettingsProvider() {
                 return "DeepSeek";
             case ApiKeyType.Coze:
                 return "Coze";
+            case ApiKeyType.Mock:
+                return "离线模拟";
             default:
                 return "未知类型";
         }
@@ -221,7 +333,7 @@ export function useSettingsProvider() {
     // 获取当前选择的模型是否为推理模型
     function isCurrentModelReasoning(apiKeyType: ApiKeyType): boolean {
         const selectedModel = settings.value.model_selection[apiKeyType];
-        const models = SUPPORTED_MODELS[apiKeyType];
+        const models = getAvailableModels(apiKeyType);
         const modelInfo = models.find(m => m.name === selectedModel);
         return modelInfo?.isReasoning || false;
     }    // 更新模型选择
@@ -233,11 +345,19 @@ export function useSettingsProvider() {
         saveSettings();
     }    // 获取指定API密钥类型的可用模型
     function getAvailableModels(apiKeyType: ApiKeyType): ModelInfo[] {
-        return SUPPORTED_MODELS[apiKeyType] || [];
+        const models = SUPPORTED_MODELS[apiKeyType] || [];
+        // 指向该服务模型的别名也作为可选模型，由后端转换为真实的模型ID
+        const aliases = Object.entries(settings.value.model_aliases)
+            .filter(([alias]) => !models.some(m => m.name === alias))
+            .flatMap(([alias, target]) => {
+                const targetInfo = models.find(m => m.name === target);
+                return targetInfo ? [{ ...targetInfo, name: alias, displayName: `${alias}（${targetInfo.displayName}）` }] : [];
+            });
+        return [...models, ...aliases];
     }
 
     // 动态获取Gemini模型列表
-    async function fetchGeminiModels(): Promise<void> {
+    async function fetchGeminiModels(forceRefresh: boolean = false): Promise<void> {
         if (isLoadingGeminiModels.value) {
             return; // 如果正在加载，避免重复请求
         }
@@ -245,7 +365,9 @@ export function useSettingsProvider() {
         isLoadingGeminiModels.value = true;
         geminiModelsError.value = null; try {
             console.log('🔄 [DEBUG] Fetching Gemini model list...');
-            const models = await invoke("get_gemini_models", { keyType: "Gemini" }) as string[];
+            // 后端会缓存模型列表，强制刷新时绕过缓存
+            const command = forceRefresh ? "refresh_models" : "get_gemini_models";
+            const models = await invoke(command, { keyType: "Gemini" }) as string[];
             console.log('📦 [DEBUG] Model list returned from backend:', models);
 
             if (models && models.length > 0) {
@@ -296,7 +418,28 @@ export function useSettingsProvider() {
 
     // 刷新Gemini模型列表
     async function refreshGeminiModels(): Promise<void> {
-        await fetchGeminiModels();
+        await fetchGeminiModels(true);
+    }
+
+    // 动态获取DeepSeek模型列表
+    async function fetchDeepSeekModels(): Promise<void> {
+        try {
+            const models = await invoke("get_deepseek_models", { keyType: "DeepSeek" }) as string[];
+            if (models && models.length > 0) {
+                SUPPORTED_MODELS[ApiKeyType.DeepSeek] = models.map(modelName => {
+                    const known = SUPPORTED_MODELS[ApiKeyType.DeepSeek].find(m => m.name === modelName);
+                    const isReasoning = modelName.includes('reasoner');
+                    return known || {
+                        name: modelName,
+                        displayName: modelName,
+                        isReasoning: isReasoning,
+                        description: isReasoning ? '推理模型，具备强化思维链能力' : undefined
+                    };
+                });
+            }
+        } catch (error) {
+            console.error('获取DeepSeek模型列表失败:', error);
+        }
     }
 
     // 获取选中的预设人格信息
@@ -331,7 +474,8 @@ export function useSettingsProvider() {
                             keyList.addKey({
                                 key: key.key,
                                 name: key.name,
-                                key_type: key.key_type as ApiKeyType
+                                key_type: key.key_type as ApiKeyType,
+                                priority: key.priority ?? 0
                             });
                         }
                     });
@@ -376,7 +520,8 @@ export function useSettingsProvider() {
         const key: ApiKey = {
             key: newApiKey.key,
             name: newApiKey.name,
-            key_type: newApiKey.key_type
+            key_type: newApiKey.key_type,
+            priority: Math.min(255, Math.max(0, Math.round(newApiKey.priority || 0)))
         };
 
         apiKeys.value.addKey(key);
@@ -384,13 +529,18 @@ export function useSettingsProvider() {
 
         // 如果添加的是Gemini密钥，自动刷新模型列表
         const isGeminiKey = newApiKey.key_type === ApiKeyType.Gemini;
+        const isDeepSeekKey = newApiKey.key_type === ApiKeyType.DeepSeek;
 
         // 重置表单
         newApiKey.key = '';
         newApiKey.name = '';
+        newApiKey.priority = 0;
         isAddingKey.value = false;
 
         showNotification("API 密钥已添加", "success");
+        if (isDeepSeekKey) {
+            fetchDeepSeekModels();
+        }
         if (isGeminiKey) {
             console.log('🔍 [DEBUG] Detected new Gemini key added, auto-fetching latest model list...');
             fetchGeminiModels().catch(error => {
@@ -494,6 +644,43 @@ export function useSettingsProvider() {
                 if (typeof settingsData.auto_save === 'boolean') settings.value.auto_save = settingsData.auto_save;
                 if (settingsData.save_path) settings.value.save_path = settingsData.save_path;
                 if (settingsData.api_model) settings.value.api_model = settingsData.api_model;
+                if (settingsData.gemini_safety_level) settings.value.gemini_safety_level = settingsData.gemini_safety_level;
+                if (typeof settingsData.safe_rendering === 'boolean') settings.value.safe_rendering = settingsData.safe_rendering;
+                if (typeof settingsData.autosave_interval_chunks === 'number') settings.value.autosave_interval_chunks = settingsData.autosave_interval_chunks;
+                if (typeof settingsData.autosave_interval_secs === 'number') settings.value.autosave_interval_secs = settingsData.autosave_interval_secs;
+                if (typeof settingsData.stream_flush_interval_ms === 'number') settings.value.stream_flush_interval_ms = settingsData.stream_flush_interval_ms;
+                if (typeof settingsData.stream_flush_chars === 'number') settings.value.stream_flush_chars = settingsData.stream_flush_chars;
+                if (typeof settingsData.output_language === 'string') settings.value.output_language = settingsData.output_language;
+                if (typeof settingsData.auto_detect_language === 'boolean') settings.value.auto_detect_language = settingsData.auto_detect_language;
+                if (settingsData.answer_verbosity) settings.value.answer_verbosity = settingsData.answer_verbosity;
+                if (settingsData.cot_verbosity) settings.value.cot_verbosity = settingsData.cot_verbosity;
+                if (typeof settingsData.safe_mode === 'boolean') settings.value.safe_mode = settingsData.safe_mode;
+                if (typeof settingsData.max_image_dimension === 'number') settings.value.max_image_dimension = settingsData.max_image_dimension;
+                if (typeof settingsData.image_jpeg_quality === 'number') settings.value.image_jpeg_quality = settingsData.image_jpeg_quality;
+                if (typeof settingsData.debug_logging === 'boolean') settings.value.debug_logging = settingsData.debug_logging;
+                if (typeof settingsData.history_retention_days === 'number') settings.value.history_retention_days = settingsData.history_retention_days;
+                if (Array.isArray(settingsData.generation_profiles)) settings.value.generation_profiles = settingsData.generation_profiles;
+                if (typeof settingsData.active_generation_profile === 'string') settings.value.active_generation_profile = settingsData.active_generation_profile;
+                if (typeof settingsData.upload_template === 'string') settings.value.upload_template = settingsData.upload_template;
+                if (settingsData.upload_code_fence) settings.value.upload_code_fence = settingsData.upload_code_fence;
+                if (typeof settingsData.enable_response_cache === 'boolean') settings.value.enable_response_cache = settingsData.enable_response_cache;
+                if (typeof settingsData.response_cache_ttl_secs === 'number') settings.value.response_cache_ttl_secs = settingsData.response_cache_ttl_secs;
+                if (typeof settingsData.max_chats === 'number') settings.value.max_chats = settingsData.max_chats;
+                if (settingsData.max_chats_policy) settings.value.max_chats_policy = settingsData.max_chats_policy;
+                if (Array.isArray(settingsData.backend_fallback_chain)) settings.value.backend_fallback_chain = settingsData.backend_fallback_chain;
+                if (settingsData.model_aliases) settings.value.model_aliases = settingsData.model_aliases;
+                if (typeof settingsData.replay_full_content === 'boolean') settings.value.replay_full_content = settingsData.replay_full_content;
+                if (typeof settingsData.persist_errors_in_history === 'boolean') settings.value.persist_errors_in_history = settingsData.persist_errors_in_history;
+                if (typeof settingsData.assistant_name === 'string') settings.value.assistant_name = settingsData.assistant_name;
+                if (typeof settingsData.title_model === 'string') settings.value.title_model = settingsData.title_model;
+                if (typeof settingsData.max_concurrency === 'number') settings.value.max_concurrency = settingsData.max_concurrency;
+                if (typeof settingsData.auto_summarize === 'boolean') settings.value.auto_summarize = settingsData.auto_summarize;
+                if (typeof settingsData.auto_summarize_prompt === 'string') settings.value.auto_summarize_prompt = settingsData.auto_summarize_prompt;
+                if (settingsData.timestamp_format) settings.value.timestamp_format = settingsData.timestamp_format;
+                if (typeof settingsData.wolfram_proxy === 'string') settings.value.wolfram_proxy = settingsData.wolfram_proxy;
+                if (Array.isArray(settingsData.disabled_typeset_tools)) settings.value.disabled_typeset_tools = settingsData.disabled_typeset_tools;
+                if (Array.isArray(settingsData.model_prices)) settings.value.model_prices = settingsData.model_prices;
+                if (typeof settingsData.price_currency === 'string') settings.value.price_currency = settingsData.price_currency;
 
                 // 更新模型配置
                 if (settingsData.model_config) {
@@ -503,6 +690,9 @@ export function useSettingsProvider() {
                     if (typeof settingsData.model_config.max_tokens === 'number') {
                         settings.value.model_config.max_tokens = settingsData.model_config.max_tokens;
                     }
+                    if (Array.isArray(settingsData.model_config.stop)) {
+                        settings.value.model_config.stop = settingsData.model_config.stop;
+                    }
                 }
 
                 // 特别处理model_selection字段
@@ -572,9 +762,9 @@ export function useSettingsProvider() {
         (newFontSize) => {
             applyFontSize(newFontSize);
         }
-    );    // 获取需要配置API密钥的类型（排除Coze）
+    );    // 获取需要配置API密钥的类型（排除Coze和离线模拟）
     function getConfigurableApiKeyTypes(): ApiKeyType[] {
-        return Object.values(ApiKeyType).filter(type => type !== ApiKeyType.Coze);
+        return Object.values(ApiKeyType).filter(type => type !== ApiKeyType.Coze && type !== ApiKeyType.Mock);
     }
 
     // 获取所有API类型（用于模型选择）
@@ -614,7 +804,8 @@ export function useSettingsProvider() {
         updateModelSelection,
         getAvailableModels,
         fetchGeminiModels,
-        refreshGeminiModels
+        refreshGeminiModels,
+        fetchDeepSeekModels
     }); 
 
     // 创建全局实例对象
@@ -654,6 +845,7 @@ export function useSettingsProvider() {
         getAvailableModels,
         fetchGeminiModels,
         refreshGeminiModels,
+        fetchDeepSeekModels,
         getSelectedPresetInfo
     };    // 保存全局实例
     globalSettingsInstance = instance;